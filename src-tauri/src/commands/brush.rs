@@ -1,8 +1,11 @@
-use crate::engine::DocumentManager;
+use super::{emit_document_modified, write_manager};
+use crate::engine::{sample_bilinear, BrushTip, DirtyRect, DocumentManager};
 use crate::error::{AppError, AppResult};
-use serde::Deserialize;
-use std::sync::Mutex;
-use tauri::State;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tauri::{AppHandle, State};
+use uuid::Uuid;
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -21,8 +24,314 @@ pub struct BrushStrokeSettings {
     pub hardness: f64,    // 0-100
     pub opacity: f64,     // 0-100
     pub flow: f64,        // 0-100
-    #[allow(dead_code)]
     pub spacing: f64,     // percentage
+    /// Registered brush tip to stamp instead of the round hardness falloff
+    #[serde(default)]
+    pub tip_id: Option<String>,
+    /// Random offset across the stroke, as a percentage of the brush size (0-1000)
+    #[serde(default)]
+    pub scatter: f64,
+    /// Random size reduction per stamp (0-100)
+    #[serde(default)]
+    pub size_jitter: f64,
+    /// Random tip rotation per stamp, 100 being up to a half turn either way
+    #[serde(default)]
+    pub angle_jitter: f64,
+    /// Random hue shift per stamp, 100 being up to 180 degrees either way
+    #[serde(default)]
+    pub hue_jitter: f64,
+    /// Seeds the jitter, so replaying a stroke with the same settings lands
+    /// every stamp in the same place. Without one each stroke gets a fresh
+    /// seed, returned with its result.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Watercolor look: the stroke deposits as one even wash with the color
+    /// pooling along its outer edge
+    #[serde(default)]
+    pub wet_edges: bool,
+    /// Force the stroke onto a straight line (shift-drag)
+    #[serde(default)]
+    pub constrain: Option<ConstrainMode>,
+    /// How the eraser removes pixels; ignored when painting
+    #[serde(default)]
+    pub eraser_mode: EraserMode,
+    /// Dodge or burn the pixels under the brush instead of painting
+    #[serde(default)]
+    pub tone: Option<ToneAdjust>,
+}
+
+/// Dodge/burn settings: lighten or darken the tones in `range` by up to
+/// `exposure` percent per stamp
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ToneAdjust {
+    pub kind: ToneKind,
+    pub range: ToneRange,
+    pub exposure: f64, // 0-100
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ToneKind {
+    Dodge,
+    Burn,
+}
+
+/// Which tones a dodge or burn affects most
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ToneRange {
+    Shadows,
+    Midtones,
+    Highlights,
+}
+
+impl ToneRange {
+    /// How strongly a pixel of luminance `l` (0.0-1.0) belongs to the range
+    fn weight(self, l: f64) -> f64 {
+        match self {
+            ToneRange::Shadows => (1.0 - l) * (1.0 - l),
+            ToneRange::Midtones => 1.0 - (2.0 * l - 1.0) * (2.0 * l - 1.0),
+            ToneRange::Highlights => l * l,
+        }
+    }
+}
+
+/// How erasing reduces a layer's alpha to reveal the layers beneath.
+///
+/// Both modes edit the layer's own pixels. Erasing into a layer mask, or
+/// sampling the merged image, needs layer masks, which documents don't have
+/// yet; those modes will be added alongside them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EraserMode {
+    /// Fade alpha by the brush coverage, honouring hardness, flow and pressure
+    #[default]
+    Soft,
+    /// Clear every pixel the brush touches completely
+    Hard,
+}
+
+/// How a constrained stroke is straightened, from its first point to its last
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConstrainMode {
+    /// Straight line between the two points
+    Straight,
+    /// Straight line snapped to the nearest multiple of this many degrees
+    Angle(f64),
+}
+
+impl BrushStrokeSettings {
+    /// Reject non-finite or out-of-range settings before they reach the stamp loop.
+    ///
+    /// The brush may be at most twice the larger side of the paintable area;
+    /// anything bigger covers it anyway and only costs time.
+    fn validate(&self, width: u32, height: u32) -> AppResult<()> {
+        let max_size = width.max(height).max(1) as f64 * 2.0;
+        if !self.size.is_finite() || self.size <= 0.0 || self.size > max_size {
+            return Err(AppError::InvalidOperation(format!(
+                "Brush size must be between 0 and {}",
+                max_size
+            )));
+        }
+
+        for (label, value) in [
+            ("hardness", self.hardness),
+            ("opacity", self.opacity),
+            ("flow", self.flow),
+            ("size jitter", self.size_jitter),
+            ("angle jitter", self.angle_jitter),
+            ("hue jitter", self.hue_jitter),
+        ] {
+            if !value.is_finite() || !(0.0..=100.0).contains(&value) {
+                return Err(AppError::InvalidOperation(format!(
+                    "Brush {} must be between 0 and 100",
+                    label
+                )));
+            }
+        }
+
+        if !self.spacing.is_finite() || self.spacing < 0.0 {
+            return Err(AppError::InvalidOperation(
+                "Brush spacing must be a non-negative number".into(),
+            ));
+        }
+
+        if !self.scatter.is_finite() || !(0.0..=1000.0).contains(&self.scatter) {
+            return Err(AppError::InvalidOperation(
+                "Brush scatter must be between 0 and 1000".into(),
+            ));
+        }
+
+        if let Some(tone) = &self.tone {
+            if !tone.exposure.is_finite() || !(0.0..=100.0).contains(&tone.exposure) {
+                return Err(AppError::InvalidOperation(
+                    "Dodge/burn exposure must be between 0 and 100".into(),
+                ));
+            }
+        }
+
+        if let Some(ConstrainMode::Angle(step)) = self.constrain {
+            if !step.is_finite() || step <= 0.0 || step > 360.0 {
+                return Err(AppError::InvalidOperation(
+                    "Constrain angle must be between 0 and 360 degrees".into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How far outside the canvas a stroke point may lie, in canvas sizes
+const MAX_POINT_OVERSHOOT: f64 = 4.0;
+
+impl BrushStrokePoint {
+    /// Check the point against a `width` x `height` canvas. Points far off the
+    /// canvas can't affect it and would overflow the pixel math.
+    fn validate(&self, width: u32, height: u32) -> AppResult<()> {
+        if !self.x.is_finite() || !self.y.is_finite() {
+            return Err(AppError::InvalidOperation(
+                "Stroke point coordinates must be finite".into(),
+            ));
+        }
+
+        let within_reach = |value: f64, len: u32| {
+            let margin = len as f64 * MAX_POINT_OVERSHOOT;
+            (-margin..=len as f64 + margin).contains(&value)
+        };
+        if !within_reach(self.x, width) || !within_reach(self.y, height) {
+            return Err(AppError::InvalidOperation(format!(
+                "Stroke point ({}, {}) is too far outside the canvas",
+                self.x, self.y
+            )));
+        }
+
+        if let Some(pressure) = self.pressure {
+            if !pressure.is_finite() || !(0.0..=1.0).contains(&pressure) {
+                return Err(AppError::InvalidOperation(
+                    "Stroke point pressure must be between 0 and 1".into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Register a grayscale brush tip from base64 encoded image bytes (PNG etc.).
+///
+/// White paints at full strength and black not at all. Returns the tip id to
+/// pass as `tipId` in the stroke settings.
+#[tauri::command]
+pub fn register_brush_tip(
+    manager: State<'_, RwLock<DocumentManager>>,
+    image_base64: String,
+) -> AppResult<String> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let bytes = STANDARD
+        .decode(&image_base64)
+        .map_err(|e| AppError::InvalidOperation(format!("Invalid base64 data: {}", e)))?;
+    let image = image::load_from_memory(&bytes)
+        .map_err(|e| AppError::ImageError(format!("Failed to decode brush tip: {}", e)))?;
+    let tip = BrushTip::from_image(&image)?;
+
+    let mut manager = write_manager(&manager);
+
+    Ok(manager.register_brush_tip(tip))
+}
+
+/// A brush preset file: JSON with the stroke settings and, optionally, a tip
+/// image path relative to the file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BrushPresetFile {
+    name: String,
+    size: f64,
+    hardness: f64,
+    spacing: f64,
+    #[serde(default = "full_strength")]
+    opacity: f64,
+    #[serde(default = "full_strength")]
+    flow: f64,
+    #[serde(default)]
+    scatter: f64,
+    #[serde(default)]
+    size_jitter: f64,
+    #[serde(default)]
+    angle_jitter: f64,
+    #[serde(default)]
+    hue_jitter: f64,
+    #[serde(default)]
+    wet_edges: bool,
+    #[serde(default)]
+    tip: Option<String>,
+}
+
+fn full_strength() -> f64 {
+    100.0
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoadedBrushPreset {
+    /// Pass as `presetId` to `apply_brush_stroke`
+    pub preset_id: String,
+    pub name: String,
+}
+
+/// Load a JSON brush preset, registering its tip image if it names one.
+#[tauri::command]
+pub fn load_brush_preset(
+    manager: State<'_, RwLock<DocumentManager>>,
+    path: String,
+) -> AppResult<LoadedBrushPreset> {
+    let json = std::fs::read_to_string(&path)
+        .map_err(|e| AppError::IoError(format!("Failed to read brush preset: {}", e)))?;
+    let preset: BrushPresetFile = serde_json::from_str(&json)
+        .map_err(|e| AppError::SerializationError(format!("Invalid brush preset: {}", e)))?;
+
+    let tip = preset
+        .tip
+        .as_deref()
+        .map(|tip| {
+            let tip_path: PathBuf = Path::new(&path)
+                .parent()
+                .map_or_else(|| PathBuf::from(tip), |dir| dir.join(tip));
+            let image = image::open(&tip_path)
+                .map_err(|e| AppError::ImageError(format!("Failed to open brush tip: {}", e)))?;
+            BrushTip::from_image(&image)
+        })
+        .transpose()?;
+
+    let mut manager = write_manager(&manager);
+
+    let settings = BrushStrokeSettings {
+        size: preset.size,
+        hardness: preset.hardness,
+        opacity: preset.opacity,
+        flow: preset.flow,
+        spacing: preset.spacing,
+        tip_id: tip.map(|tip| manager.register_brush_tip(tip)),
+        scatter: preset.scatter,
+        size_jitter: preset.size_jitter,
+        angle_jitter: preset.angle_jitter,
+        hue_jitter: preset.hue_jitter,
+        seed: None,
+        wet_edges: preset.wet_edges,
+        constrain: None,
+        eraser_mode: EraserMode::default(),
+        tone: None,
+    };
+    // Sizes are checked against the canvas per stroke; check the rest now
+    settings.validate(u32::MAX, u32::MAX)?;
+
+    Ok(LoadedBrushPreset {
+        preset_id: manager.register_brush_preset(settings),
+        name: preset.name,
+    })
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -33,34 +342,161 @@ pub struct BrushColor {
     pub a: f64,  // 0-1
 }
 
+/// Where a brush stroke landed and the seed its jitter used
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BrushStrokeResult {
+    /// Document-space rectangle touched by the stroke (the union of all stamp
+    /// bounds), or None if no stamp landed on the layer
+    pub dirty: Option<DirtyRect>,
+    /// Pass back as `seed` to replay the stroke exactly
+    pub seed: u64,
+}
+
+/// Apply a brush stroke to a layer.
+///
+/// The brush comes from either `settings` or a loaded preset's `preset_id`;
+/// passing both is rejected rather than silently ignoring one of them.
+///
+/// Returns the area the stroke touched and the seed it was jittered with.
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub fn apply_brush_stroke(
-    manager: State<'_, Mutex<DocumentManager>>,
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
     doc_id: String,
     layer_id: String,
     points: Vec<BrushStrokePoint>,
-    settings: BrushStrokeSettings,
+    settings: Option<BrushStrokeSettings>,
+    preset_id: Option<String>,
     color: BrushColor,
     is_eraser: bool,
-) -> AppResult<()> {
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+) -> AppResult<BrushStrokeResult> {
+    let mut manager = write_manager(&manager);
 
+    let settings = stroke_settings(&manager, settings, preset_id)?;
+
+    let result = stroke_layer(
+        &mut manager,
+        &doc_id,
+        &layer_id,
+        &points,
+        &settings,
+        &color,
+        is_eraser,
+    )?;
+    if result.dirty.is_some() {
+        emit_document_modified(&app, &manager, &doc_id);
+    }
+    Ok(result)
+}
+
+/// The brush a stroke asked for: its own settings or a preset, but not both
+fn stroke_settings(
+    manager: &DocumentManager,
+    settings: Option<BrushStrokeSettings>,
+    preset_id: Option<String>,
+) -> AppResult<BrushStrokeSettings> {
+    match (preset_id, settings) {
+        (Some(preset_id), None) => Ok(manager.get_brush_preset(&preset_id)?.clone()),
+        (None, Some(settings)) => Ok(settings),
+        (Some(_), Some(_)) => Err(AppError::InvalidOperation(
+            "Brush stroke takes settings or a preset, not both".into(),
+        )),
+        (None, None) => Err(AppError::InvalidOperation(
+            "Brush stroke needs settings or a preset".into(),
+        )),
+    }
+}
+
+/// Stamp a stroke onto a layer and record it as one undo step
+pub fn stroke_layer(
+    manager: &mut DocumentManager,
+    doc_id: &str,
+    layer_id: &str,
+    points: &[BrushStrokePoint],
+    settings: &BrushStrokeSettings,
+    color: &BrushColor,
+    is_eraser: bool,
+) -> AppResult<BrushStrokeResult> {
     // Get the document to find layer dimensions
     let doc = manager
-        .get(&doc_id)
-        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?;
+        .get(doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
 
     let layer = doc
-        .get_layer(&layer_id)
-        .ok_or_else(|| AppError::LayerNotFound(layer_id.clone()))?;
+        .get_layer(layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
 
     // Check if layer is locked
-    if layer.locked {
+    if layer.pixels_locked() {
         return Err(AppError::InvalidOperation("Layer is locked".into()));
     }
 
+    let mode = match (is_eraser, settings.tone, layer.lock_alpha) {
+        // Dodge and burn only change color, so locked transparency is fine
+        (false, Some(tone), _) => StampMode::Tone(tone),
+        (false, None, false) => StampMode::Paint,
+        (false, None, true) => StampMode::PaintPreserveAlpha,
+        (true, Some(_), _) => {
+            return Err(AppError::InvalidOperation(
+                "Cannot dodge or burn with the eraser".into(),
+            ))
+        }
+        (true, None, false) if settings.eraser_mode == EraserMode::Hard => StampMode::EraseHard,
+        (true, None, false) => StampMode::Erase,
+        (true, None, true) => {
+            return Err(AppError::InvalidOperation(
+                "Cannot erase on a layer with locked transparency".into(),
+            ))
+        }
+    };
+
+    // The layer may grow to the document size, so brushes are sized against that
+    settings.validate(doc.width, doc.height)?;
+    for point in points {
+        point.validate(doc.width, doc.height)?;
+    }
+    if !color.a.is_finite() || !(0.0..=1.0).contains(&color.a) {
+        return Err(AppError::InvalidOperation(
+            "Brush color alpha must be between 0 and 1".into(),
+        ));
+    }
+    if let Some(tip_id) = &settings.tip_id {
+        manager.get_brush_tip(tip_id)?;
+    }
+    let points = match settings.constrain {
+        Some(constrain) => constrain_points(points, constrain, settings),
+        None => interpolate_points(points, settings),
+    };
+
+    let (doc_width, doc_height) = (doc.width, doc.height);
+    let layer_rect = DirtyRect {
+        x: layer.x,
+        y: layer.y,
+        width: layer.width,
+        height: layer.height,
+    };
+
+    // Grow a layer smaller than the canvas so painting past its edge isn't cut
+    // off. Erasing or painting with locked transparency can't add pixels there.
+    // Undo then needs the whole layer as it was; otherwise only the stroke's
+    // area is saved once it is known.
+    let mut before = None;
+    if mode == StampMode::Paint {
+        let bounds =
+            stroke_bounds(&points, settings).and_then(|b| b.clip_to(doc_width, doc_height));
+        if let Some(bounds) = bounds.filter(|b| layer_rect.union(b) != layer_rect) {
+            before = Some(manager.capture_pixels(doc_id, &[layer_id.to_string()])?);
+            manager.expand_layer(doc_id, layer_id, &bounds)?;
+        }
+    }
+
+    let layer = manager
+        .get(doc_id)
+        .and_then(|doc| doc.get_layer(layer_id))
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
     let layer_width = layer.width as usize;
     let layer_height = layer.height as usize;
     let layer_x = layer.x;
@@ -68,63 +504,610 @@ pub fn apply_brush_stroke(
 
     // Get mutable pixel data
     let pixels = manager
-        .get_layer_pixels(&layer_id)
-        .ok_or_else(|| AppError::LayerNotFound(layer_id.clone()))?
+        .get_layer_pixels(layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?
         .clone();
 
     let mut pixels = pixels;
+    let tip = settings
+        .tip_id
+        .as_deref()
+        .map(|tip_id| manager.get_brush_tip(tip_id))
+        .transpose()?;
 
-    // Apply brush stroke
-    for point in &points {
-        apply_brush_stamp(
-            &mut pixels,
+    // Wet edges collect the whole stroke first and deposit it in one pass
+    let mut wet_edges = if settings.wet_edges {
+        stroke_bounds(&points, settings)
+            .map(|b| DirtyRect {
+                x: b.x - layer_x,
+                y: b.y - layer_y,
+                ..b
+            })
+            .and_then(|b| b.clip_to(layer_width as u32, layer_height as u32))
+            .map(WetEdgeBuffer::new)
+    } else {
+        None
+    };
+
+    // Apply brush stroke, accumulating the bounds of every stamp
+    let seed = settings
+        .seed
+        .unwrap_or_else(|| Uuid::new_v4().as_u64_pair().0);
+    let mut rng = StrokeRng::new(seed);
+    let mut dirty: Option<DirtyRect> = None;
+    for index in 0..points.len() {
+        let (stamp, stamp_color) = jitter_stamp(&points, index, settings, color, &mut rng);
+        let stamp_bounds = apply_brush_stamp(
             layer_width,
             layer_height,
             layer_x,
             layer_y,
-            point,
-            &settings,
-            &color,
-            is_eraser,
+            &stamp,
+            settings,
+            tip,
+            |px, py, alpha, spine| match &mut wet_edges {
+                Some(buffer) => buffer.record(px, py, alpha, spine, &stamp_color),
+                None => {
+                    let idx = (py * layer_width + px) * 4;
+                    stamp_pixel(&mut pixels, idx, alpha, &stamp_color, mode)
+                }
+            },
         );
+
+        if let Some(bounds) = stamp_bounds {
+            dirty = Some(match dirty {
+                Some(rect) => rect.union(&bounds),
+                None => bounds,
+            });
+        }
+    }
+
+    if let Some(buffer) = &wet_edges {
+        buffer.deposit(&mut pixels, layer_width, color.a, mode);
+    }
+
+    // Convert the dirty rect from layer space to document space
+    let dirty = match dirty {
+        Some(rect) => DirtyRect {
+            x: rect.x + layer_x,
+            y: rect.y + layer_y,
+            ..rect
+        },
+        None => return Ok(BrushStrokeResult { dirty: None, seed }),
+    };
+
+    let before = match before {
+        Some(before) => before,
+        None => manager.capture_pixel_region(doc_id, layer_id, &dirty)?,
+    };
+
+    // Save modified pixels back and recomposite only the touched region
+    manager.set_layer_pixels_in_region(doc_id, layer_id, pixels, &dirty)?;
+    let history_name = match mode {
+        StampMode::Erase | StampMode::EraseHard => "Eraser",
+        StampMode::Tone(tone) if tone.kind == ToneKind::Dodge => "Dodge",
+        StampMode::Tone(_) => "Burn",
+        StampMode::Paint | StampMode::PaintPreserveAlpha => "Brush Stroke",
+    };
+    manager.push_pixel_history(doc_id, history_name, before);
+
+    Ok(BrushStrokeResult {
+        dirty: Some(dirty),
+        seed,
+    })
+}
+
+/// Push pixels along a stroke (forward warp / liquify).
+///
+/// Between consecutive points, pixels within the brush radius are displaced in
+/// the stroke direction, weighted by the hardness falloff, pressure and
+/// `opacity * flow` (the warp strength). Returns the document-space rectangle
+/// touched, or None if the stroke missed the layer.
+#[tauri::command]
+pub fn apply_warp_stroke(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    points: Vec<BrushStrokePoint>,
+    settings: BrushStrokeSettings,
+) -> AppResult<Option<DirtyRect>> {
+    let mut manager = write_manager(&manager);
+
+    let dirty = warp_layer(&mut manager, &doc_id, &layer_id, &points, &settings)?;
+
+    if dirty.is_some() {
+        emit_document_modified(&app, &manager, &doc_id);
+    }
+    Ok(dirty)
+}
+
+/// Warp a layer along a stroke and record it as one undo step.
+///
+/// Only size, hardness, opacity, flow and pressure shape a warp; settings
+/// that only make sense for stamped strokes (tips, scatter, jitter, wet
+/// edges, dodge/burn, constrained lines) are rejected rather than ignored.
+pub fn warp_layer(
+    manager: &mut DocumentManager,
+    doc_id: &str,
+    layer_id: &str,
+    points: &[BrushStrokePoint],
+    settings: &BrushStrokeSettings,
+) -> AppResult<Option<DirtyRect>> {
+    let doc = manager
+        .get(doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+    let layer = doc
+        .get_layer(layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+    if layer.pixels_locked() {
+        return Err(AppError::InvalidOperation("Layer is locked".into()));
+    }
+
+    // Sized against the document like painting, so a brush that fits the
+    // canvas works on a small layer too
+    settings.validate(doc.width, doc.height)?;
+    for point in points {
+        point.validate(doc.width, doc.height)?;
+    }
+    for (label, unsupported) in [
+        ("brush tips", settings.tip_id.is_some()),
+        ("scatter", settings.scatter > 0.0),
+        (
+            "jitter",
+            settings.size_jitter > 0.0 || settings.angle_jitter > 0.0 || settings.hue_jitter > 0.0,
+        ),
+        ("wet edges", settings.wet_edges),
+        ("dodge/burn", settings.tone.is_some()),
+        ("constrained strokes", settings.constrain.is_some()),
+    ] {
+        if unsupported {
+            return Err(AppError::InvalidOperation(format!(
+                "The warp brush doesn't support {}",
+                label
+            )));
+        }
     }
 
-    // Save modified pixels back
-    manager.set_layer_pixels(&layer_id, pixels);
+    let (layer_width, layer_height) = (layer.width, layer.height);
+    let (layer_x, layer_y) = (layer.x, layer.y);
+    let lock_alpha = layer.lock_alpha;
 
-    // Mark document as modified
-    if let Some(doc) = manager.get_mut(&doc_id) {
-        doc.modified_at = chrono::Utc::now().timestamp_millis();
+    let mut pixels = manager
+        .get_layer_pixels(layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?
+        .clone();
+
+    let mut dirty: Option<DirtyRect> = None;
+    for segment in points.windows(2) {
+        let segment_bounds = apply_warp_segment(
+            &mut pixels,
+            layer_width,
+            layer_height,
+            (layer_x, layer_y),
+            &segment[0],
+            &segment[1],
+            settings,
+            lock_alpha,
+        );
+
+        if let Some(bounds) = segment_bounds {
+            dirty = Some(match dirty {
+                Some(rect) => rect.union(&bounds),
+                None => bounds,
+            });
+        }
     }
 
-    Ok(())
+    let dirty = match dirty {
+        Some(rect) => DirtyRect {
+            x: rect.x + layer_x,
+            y: rect.y + layer_y,
+            ..rect
+        },
+        None => return Ok(None),
+    };
+
+    let before = manager.capture_pixel_region(doc_id, layer_id, &dirty)?;
+    manager.set_layer_pixels_in_region(doc_id, layer_id, pixels, &dirty)?;
+    manager.push_pixel_history(doc_id, "Warp", before);
+
+    Ok(Some(dirty))
 }
 
-fn apply_brush_stamp(
+/// Displace pixels around `to` by the movement from `from`, sampling the
+/// buffer as it was before this segment. With `lock_alpha` pixels move in
+/// color but keep their alpha. Returns the layer-space bounds touched.
+#[allow(clippy::too_many_arguments)]
+fn apply_warp_segment(
     pixels: &mut [u8],
+    layer_width: u32,
+    layer_height: u32,
+    layer_origin: (i32, i32),
+    from: &BrushStrokePoint,
+    to: &BrushStrokePoint,
+    settings: &BrushStrokeSettings,
+    lock_alpha: bool,
+) -> Option<DirtyRect> {
+    let radius = settings.size / 2.0;
+    let strength =
+        (settings.opacity / 100.0) * (settings.flow / 100.0) * to.pressure.unwrap_or(1.0);
+
+    let move_x = to.x - from.x;
+    let move_y = to.y - from.y;
+    if strength <= 0.0 || (move_x == 0.0 && move_y == 0.0) {
+        return None;
+    }
+
+    // Brush center relative to the layer
+    let center_x = to.x - layer_origin.0 as f64;
+    let center_y = to.y - layer_origin.1 as f64;
+
+    let min_x = ((center_x - radius).floor() as i32).max(0);
+    let max_x = ((center_x + radius).ceil() as i32).min(layer_width as i32 - 1);
+    let min_y = ((center_y - radius).floor() as i32).max(0);
+    let max_y = ((center_y + radius).ceil() as i32).min(layer_height as i32 - 1);
+
+    if max_x < min_x || max_y < min_y {
+        return None;
+    }
+
+    // Copy only the dab plus the distance a pixel can be pulled from; samples
+    // never leave this window except where it is clipped to the layer edge,
+    // and clamping there matches clamping to the layer
+    let reach_x = (move_x.abs() * strength).ceil() as i32 + 1;
+    let reach_y = (move_y.abs() * strength).ceil() as i32 + 1;
+    let source_x = (min_x - reach_x).max(0);
+    let source_y = (min_y - reach_y).max(0);
+    let source_width = ((max_x + reach_x).min(layer_width as i32 - 1) - source_x + 1) as usize;
+    let source_height = ((max_y + reach_y).min(layer_height as i32 - 1) - source_y + 1) as usize;
+
+    let mut source = Vec::with_capacity(source_width * source_height * 4);
+    for row in source_y as usize..source_y as usize + source_height {
+        let start = (row * layer_width as usize + source_x as usize) * 4;
+        source.extend_from_slice(&pixels[start..start + source_width * 4]);
+    }
+
+    let inner_radius = radius * settings.hardness / 100.0;
+    let falloff_range = radius - inner_radius;
+
+    for py in min_y..=max_y {
+        for px in min_x..=max_x {
+            let cx = px as f64 + 0.5;
+            let cy = py as f64 + 0.5;
+            let dist = ((cx - center_x).powi(2) + (cy - center_y).powi(2)).sqrt();
+
+            if dist > radius {
+                continue;
+            }
+
+            let weight = if dist <= inner_radius || falloff_range <= 0.0 {
+                1.0
+            } else {
+                let falloff = 1.0 - ((dist - inner_radius) / falloff_range);
+                falloff * falloff
+            };
+
+            // Pull the pixel from behind the stroke direction
+            let amount = strength * weight;
+            let mut sample = sample_bilinear(
+                &source,
+                source_width as u32,
+                source_height as u32,
+                cx - move_x * amount - source_x as f64,
+                cy - move_y * amount - source_y as f64,
+            );
+
+            let idx = (py as usize * layer_width as usize + px as usize) * 4;
+            if lock_alpha {
+                sample[3] = pixels[idx + 3];
+            }
+            pixels[idx..idx + 4].copy_from_slice(&sample);
+        }
+    }
+
+    Some(DirtyRect {
+        x: min_x,
+        y: min_y,
+        width: (max_x - min_x + 1) as u32,
+        height: (max_y - min_y + 1) as u32,
+    })
+}
+
+/// Document-space bounds of every stamp in a stroke, allowing for scatter and
+/// tip rotation
+fn stroke_bounds(points: &[BrushStrokePoint], settings: &BrushStrokeSettings) -> Option<DirtyRect> {
+    let tip_reach = if settings.tip_id.is_some() {
+        std::f64::consts::SQRT_2
+    } else {
+        1.0
+    };
+    let radius = settings.size / 2.0 * tip_reach + settings.scatter / 100.0 * settings.size;
+
+    points
+        .iter()
+        .map(|point| {
+            let min_x = (point.x - radius).floor() as i32;
+            let min_y = (point.y - radius).floor() as i32;
+            DirtyRect {
+                x: min_x,
+                y: min_y,
+                width: ((point.x + radius).ceil() as i32 - min_x + 1) as u32,
+                height: ((point.y + radius).ceil() as i32 - min_y + 1) as u32,
+            }
+        })
+        .reduce(|a, b| a.union(&b))
+}
+
+/// Upper bound on stamps in one stroke, however far off-canvas its points are
+const MAX_STROKE_STAMPS: usize = 100_000;
+
+/// Smallest stamp spacing, as a percentage of the brush size; 0 would never
+/// advance along the stroke
+const MIN_SPACING_PERCENT: f64 = 1.0;
+
+/// Distance between stamps in pixels, at least one
+fn stamp_spacing(settings: &BrushStrokeSettings) -> f64 {
+    (settings.spacing.max(MIN_SPACING_PERCENT) / 100.0 * settings.size).max(1.0)
+}
+
+/// Fill in stamps between the stroke's points, `spacing` percent of the brush
+/// size apart along the path, so fast strokes don't leave gaps. Position,
+/// pressure and time are interpolated; repeated points stamp once.
+fn interpolate_points(
+    points: &[BrushStrokePoint],
+    settings: &BrushStrokeSettings,
+) -> Vec<BrushStrokePoint> {
+    let Some(first) = points.first() else {
+        return Vec::new();
+    };
+
+    let spacing = stamp_spacing(settings);
+    let mut stamps = vec![first.clone()];
+    // Distance covered since the last stamp
+    let mut travelled = 0.0;
+
+    for pair in points.windows(2) {
+        let (from, to) = (&pair[0], &pair[1]);
+        let length = (to.x - from.x).hypot(to.y - from.y);
+        if length == 0.0 {
+            continue;
+        }
+
+        let mut distance = spacing - travelled;
+        while distance <= length && stamps.len() < MAX_STROKE_STAMPS {
+            stamps.push(lerp_point(from, to, distance / length));
+            distance += spacing;
+        }
+        travelled = length - (distance - spacing);
+    }
+
+    // End the stroke where the pointer stopped
+    if travelled > 0.0 && stamps.len() < MAX_STROKE_STAMPS {
+        stamps.extend(points.last().cloned());
+    }
+    stamps
+}
+
+/// The point a fraction `t` of the way from `from` to `to`
+fn lerp_point(from: &BrushStrokePoint, to: &BrushStrokePoint, t: f64) -> BrushStrokePoint {
+    let pressure = match (from.pressure, to.pressure) {
+        (None, None) => None,
+        (a, b) => {
+            let (a, b) = (a.unwrap_or(1.0), b.unwrap_or(1.0));
+            Some(a + (b - a) * t)
+        }
+    };
+
+    BrushStrokePoint {
+        x: from.x + (to.x - from.x) * t,
+        y: from.y + (to.y - from.y) * t,
+        pressure,
+        timestamp: from.timestamp + (to.timestamp.saturating_sub(from.timestamp) as f64 * t) as u64,
+    }
+}
+
+/// Replace a stroke with stamps spaced evenly along the straight line from its
+/// first point to its last, pressure and time easing between the two.
+///
+/// With an angle step the line keeps its length along the snapped direction.
+fn constrain_points(
+    points: &[BrushStrokePoint],
+    constrain: ConstrainMode,
+    settings: &BrushStrokeSettings,
+) -> Vec<BrushStrokePoint> {
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return Vec::new();
+    };
+
+    let (mut dx, mut dy) = (last.x - first.x, last.y - first.y);
+    if let ConstrainMode::Angle(step) = constrain {
+        let step = step.to_radians();
+        let angle = (dy.atan2(dx) / step).round() * step;
+        let length = dx * angle.cos() + dy * angle.sin();
+        (dx, dy) = (length * angle.cos(), length * angle.sin());
+    }
+
+    let spacing = stamp_spacing(settings);
+    let steps = ((dx.hypot(dy) / spacing).ceil() as usize).clamp(1, MAX_STROKE_STAMPS);
+    let first_pressure = first.pressure.unwrap_or(1.0);
+    let last_pressure = last.pressure.unwrap_or(1.0);
+
+    (0..=steps)
+        .map(|i| {
+            let t = i as f64 / steps as f64;
+            BrushStrokePoint {
+                x: first.x + dx * t,
+                y: first.y + dy * t,
+                pressure: Some(first_pressure + (last_pressure - first_pressure) * t),
+                timestamp: first.timestamp
+                    + (last.timestamp.saturating_sub(first.timestamp) as f64 * t) as u64,
+            }
+        })
+        .collect()
+}
+
+/// A single dab of the brush after jitter has been applied
+struct Stamp {
+    x: f64,
+    y: f64,
+    pressure: f64,
+    size: f64,
+    /// Tip rotation in radians
+    angle: f64,
+}
+
+/// SplitMix64, kept in-tree so a seed reproduces the same stroke across builds
+struct StrokeRng(u64);
+
+impl StrokeRng {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// Uniform value in -1.0..1.0
+    fn next_signed(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+    }
+}
+
+/// Work out where and how the stamp for `points[index]` lands.
+///
+/// Every stamp draws the same number of random values whichever jitters are
+/// enabled, so a seed always maps to the same sequence.
+fn jitter_stamp(
+    points: &[BrushStrokePoint],
+    index: usize,
+    settings: &BrushStrokeSettings,
+    color: &BrushColor,
+    rng: &mut StrokeRng,
+) -> (Stamp, BrushColor) {
+    let point = &points[index];
+    let (scatter, size, angle, hue) = (
+        rng.next_signed(),
+        rng.next_signed().abs(),
+        rng.next_signed(),
+        rng.next_signed(),
+    );
+
+    // Scatter across the stroke direction, taken from the neighbouring points
+    let prev = &points[index.saturating_sub(1)];
+    let next = &points[(index + 1).min(points.len() - 1)];
+    let (dir_x, dir_y) = (next.x - prev.x, next.y - prev.y);
+    let length = (dir_x * dir_x + dir_y * dir_y).sqrt();
+    let (normal_x, normal_y) = if length > 0.0 {
+        (-dir_y / length, dir_x / length)
+    } else {
+        (0.0, 1.0)
+    };
+    let offset = scatter * settings.scatter / 100.0 * settings.size;
+
+    let stamp = Stamp {
+        x: point.x + normal_x * offset,
+        y: point.y + normal_y * offset,
+        pressure: point.pressure.unwrap_or(1.0),
+        size: settings.size * (1.0 - size * settings.size_jitter / 100.0),
+        angle: angle * settings.angle_jitter / 100.0 * std::f64::consts::PI,
+    };
+
+    let color = if settings.hue_jitter > 0.0 {
+        let degrees = hue * settings.hue_jitter / 100.0 * 180.0;
+        let [r, g, b] = rotate_hue([color.r, color.g, color.b], degrees);
+        BrushColor { r, g, b, ..*color }
+    } else {
+        color.clone()
+    };
+
+    (stamp, color)
+}
+
+/// Rotate an RGB color's hue by `degrees` around the gray axis
+fn rotate_hue(rgb: [u8; 3], degrees: f64) -> [u8; 3] {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let [r, g, b] = rgb.map(|c| c as f64);
+
+    let out = [
+        (0.213 + cos * 0.787 - sin * 0.213) * r
+            + (0.715 - cos * 0.715 - sin * 0.715) * g
+            + (0.072 - cos * 0.072 + sin * 0.928) * b,
+        (0.213 - cos * 0.213 + sin * 0.143) * r
+            + (0.715 + cos * 0.285 + sin * 0.140) * g
+            + (0.072 - cos * 0.072 - sin * 0.283) * b,
+        (0.213 - cos * 0.213 - sin * 0.787) * r
+            + (0.715 - cos * 0.715 + sin * 0.715) * g
+            + (0.072 + cos * 0.928 + sin * 0.072) * b,
+    ];
+    out.map(|c| c.round().clamp(0.0, 255.0) as u8)
+}
+
+/// How a stamp changes the pixels under it
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StampMode {
+    Paint,
+    /// Paint color only, keeping each pixel's alpha (transparency lock)
+    PaintPreserveAlpha,
+    Erase,
+    /// Erase to full transparency wherever the stamp has any coverage
+    EraseHard,
+    /// Lighten or darken the existing color
+    Tone(ToneAdjust),
+}
+
+/// Work out the coverage of one stamp, handing each covered layer pixel to
+/// `deposit` as `(x, y, alpha, spine)`. `spine` is the distance from the stamp
+/// center relative to the brush radius (0.0-1.0).
+#[allow(clippy::too_many_arguments)]
+fn apply_brush_stamp(
     layer_width: usize,
     layer_height: usize,
     layer_x: i32,
     layer_y: i32,
-    point: &BrushStrokePoint,
+    stamp: &Stamp,
     settings: &BrushStrokeSettings,
-    color: &BrushColor,
-    is_eraser: bool,
-) {
-    let radius = settings.size / 2.0;
-    let pressure = point.pressure.unwrap_or(1.0);
+    tip: Option<&BrushTip>,
+    mut deposit: impl FnMut(usize, usize, f64, f64),
+) -> Option<DirtyRect> {
+    let radius = stamp.size / 2.0;
+    if radius <= 0.0 {
+        return None;
+    }
 
     // Calculate effective opacity based on flow and pressure
-    let base_opacity = (settings.opacity / 100.0) * (settings.flow / 100.0) * pressure;
+    let base_opacity = (settings.opacity / 100.0) * (settings.flow / 100.0) * stamp.pressure;
+
+    // Calculate brush bounds relative to layer; a rotated tip reaches its corners
+    let brush_x = stamp.x - layer_x as f64;
+    let brush_y = stamp.y - layer_y as f64;
+    let reach = if tip.is_some() && stamp.angle != 0.0 {
+        radius * std::f64::consts::SQRT_2
+    } else {
+        radius
+    };
 
-    // Calculate brush bounds relative to layer
-    let brush_x = point.x - layer_x as f64;
-    let brush_y = point.y - layer_y as f64;
+    let min_x = ((brush_x - reach).floor() as i32).max(0);
+    let max_x = ((brush_x + reach).ceil() as i32).min(layer_width as i32 - 1);
+    let min_y = ((brush_y - reach).floor() as i32).max(0);
+    let max_y = ((brush_y + reach).ceil() as i32).min(layer_height as i32 - 1);
 
-    let min_x = ((brush_x - radius).floor() as i32).max(0) as usize;
-    let max_x = ((brush_x + radius).ceil() as i32).min(layer_width as i32 - 1) as usize;
-    let min_y = ((brush_y - radius).floor() as i32).max(0) as usize;
-    let max_y = ((brush_y + radius).ceil() as i32).min(layer_height as i32 - 1) as usize;
+    // Stamp lies entirely outside the layer
+    if max_x < min_x || max_y < min_y {
+        return None;
+    }
+
+    let bounds = DirtyRect {
+        x: min_x,
+        y: min_y,
+        width: (max_x - min_x + 1) as u32,
+        height: (max_y - min_y + 1) as u32,
+    };
+    let (min_x, max_x) = (min_x as usize, max_x as usize);
+    let (min_y, max_y) = (min_y as usize, max_y as usize);
 
     // Precompute hardness factor for falloff
     // hardness of 100 = hard edge, 0 = soft edge
@@ -132,11 +1115,30 @@ fn apply_brush_stamp(
     let inner_radius = radius * hardness;
     let falloff_range = radius - inner_radius;
 
+    // A tip is scaled so its longer side spans the brush size
+    let tip_scale = tip.map(|tip| tip.width.max(tip.height) as f64 / stamp.size);
+    let (sin, cos) = stamp.angle.sin_cos();
+
     for py in min_y..=max_y {
         for px in min_x..=max_x {
             let dx = px as f64 - brush_x;
             let dy = py as f64 - brush_y;
             let dist = (dx * dx + dy * dy).sqrt();
+            let spine = (dist / radius).min(1.0);
+
+            if let (Some(tip), Some(scale)) = (tip, tip_scale) {
+                // Undo the stamp rotation to find the tip pixel underneath
+                let (u, v) = (dx * cos + dy * sin, dy * cos - dx * sin);
+                let coverage = tip.sample(
+                    u * scale + tip.width as f64 / 2.0,
+                    v * scale + tip.height as f64 / 2.0,
+                );
+                let alpha = base_opacity * coverage;
+                if alpha > 0.0 {
+                    deposit(px, py, alpha, spine);
+                }
+                continue;
+            }
 
             if dist > radius {
                 continue;
@@ -156,31 +1158,147 @@ fn apply_brush_stamp(
                 continue;
             }
 
-            let idx = (py * layer_width + px) * 4;
-            if idx + 3 >= pixels.len() {
+            deposit(px, py, alpha, spine);
+        }
+    }
+
+    Some(bounds)
+}
+
+/// Opacity of the wash at the stroke center relative to its edge
+const WET_EDGE_INTERIOR: f32 = 0.4;
+
+/// What a wet-edge stroke has laid down at one pixel so far
+#[derive(Debug, Clone, Copy)]
+struct WetSample {
+    /// Strongest stamp alpha seen; overlapping stamps don't build up
+    strength: f32,
+    /// Distance to the nearest stamp center relative to the radius
+    spine: f32,
+    /// Color of that nearest stamp
+    color: [u8; 3],
+}
+
+/// Per-stroke accumulation buffer for wet edges, covering layer-space `bounds`.
+///
+/// Measuring the edge from the nearest stamp center, rather than per stamp,
+/// keeps the rim on the outside of the stroke instead of leaving a ring
+/// around every dab.
+struct WetEdgeBuffer {
+    bounds: DirtyRect,
+    samples: Vec<WetSample>,
+}
+
+impl WetEdgeBuffer {
+    fn new(bounds: DirtyRect) -> Self {
+        let empty = WetSample {
+            strength: 0.0,
+            spine: f32::INFINITY,
+            color: [0; 3],
+        };
+        Self {
+            samples: vec![empty; bounds.width as usize * bounds.height as usize],
+            bounds,
+        }
+    }
+
+    fn record(&mut self, px: usize, py: usize, alpha: f64, spine: f64, color: &BrushColor) {
+        let x = px as i64 - self.bounds.x as i64;
+        let y = py as i64 - self.bounds.y as i64;
+        if x < 0 || y < 0 || x >= self.bounds.width as i64 || y >= self.bounds.height as i64 {
+            return;
+        }
+
+        let sample = &mut self.samples[y as usize * self.bounds.width as usize + x as usize];
+        sample.strength = sample.strength.max(alpha as f32);
+        if (spine as f32) <= sample.spine {
+            sample.spine = spine as f32;
+            sample.color = [color.r, color.g, color.b];
+        }
+    }
+
+    /// Paint the collected stroke onto the layer
+    fn deposit(&self, pixels: &mut [u8], layer_width: usize, color_alpha: f64, mode: StampMode) {
+        let width = self.bounds.width as usize;
+        for (i, sample) in self.samples.iter().enumerate() {
+            if sample.strength <= 0.0 {
                 continue;
             }
 
-            if is_eraser {
-                // Eraser: reduce alpha
-                let current_alpha = pixels[idx + 3] as f64 / 255.0;
-                let new_alpha = (current_alpha * (1.0 - alpha)).max(0.0);
-                pixels[idx + 3] = (new_alpha * 255.0) as u8;
-            } else {
-                // Normal brush: blend color
-                blend_pixel(
-                    &mut pixels[idx..idx + 4],
-                    color.r,
-                    color.g,
-                    color.b,
-                    (alpha * color.a * 255.0) as u8,
-                );
+            let edge = WET_EDGE_INTERIOR + (1.0 - WET_EDGE_INTERIOR) * sample.spine.powi(3);
+            let [r, g, b] = sample.color;
+            let color = BrushColor {
+                r,
+                g,
+                b,
+                a: color_alpha,
+            };
+
+            let px = self.bounds.x as usize + i % width;
+            let py = self.bounds.y as usize + i / width;
+            let alpha = (sample.strength * edge) as f64;
+            stamp_pixel(pixels, (py * layer_width + px) * 4, alpha, &color, mode);
+        }
+    }
+}
+
+/// Apply one stamp sample of strength `alpha` to the pixel at `idx`
+fn stamp_pixel(pixels: &mut [u8], idx: usize, alpha: f64, color: &BrushColor, mode: StampMode) {
+    if idx + 3 >= pixels.len() {
+        return;
+    }
+
+    match mode {
+        StampMode::EraseHard => {
+            if alpha > 0.0 {
+                pixels[idx + 3] = 0;
             }
         }
+        StampMode::Erase => {
+            // Eraser: reduce alpha
+            let current_alpha = pixels[idx + 3] as f64 / 255.0;
+            let new_alpha = (current_alpha * (1.0 - alpha)).max(0.0);
+            pixels[idx + 3] = (new_alpha * 255.0) as u8;
+        }
+        StampMode::Tone(tone) => tone_pixel(&mut pixels[idx..idx + 4], alpha, tone),
+        StampMode::Paint | StampMode::PaintPreserveAlpha => {
+            // Normal brush: blend color
+            blend_pixel(
+                &mut pixels[idx..idx + 4],
+                color.r,
+                color.g,
+                color.b,
+                (alpha * color.a * 255.0) as u8,
+                mode == StampMode::PaintPreserveAlpha,
+            );
+        }
     }
 }
 
-fn blend_pixel(dst: &mut [u8], src_r: u8, src_g: u8, src_b: u8, src_a: u8) {
+/// Dodge (screen toward white) or burn (multiply toward black) one pixel,
+/// weighted by the stamp coverage and how well its tone fits the range
+fn tone_pixel(dst: &mut [u8], alpha: f64, tone: ToneAdjust) {
+    if dst[3] == 0 {
+        return;
+    }
+
+    let [r, g, b] = [dst[0], dst[1], dst[2]].map(|c| c as f64 / 255.0);
+    let l = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let strength = alpha * tone.exposure / 100.0 * tone.range.weight(l);
+
+    for c in &mut dst[..3] {
+        let v = *c as f64 / 255.0;
+        let out = match tone.kind {
+            ToneKind::Dodge => v + (1.0 - v) * strength,
+            ToneKind::Burn => v * (1.0 - strength),
+        };
+        *c = (out * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+}
+
+/// Blend a color over `dst`. With `preserve_alpha`, only the color channels
+/// change and transparent pixels stay untouched.
+fn blend_pixel(dst: &mut [u8], src_r: u8, src_g: u8, src_b: u8, src_a: u8, preserve_alpha: bool) {
     if src_a == 0 {
         return;
     }
@@ -188,6 +1306,19 @@ fn blend_pixel(dst: &mut [u8], src_r: u8, src_g: u8, src_b: u8, src_a: u8) {
     let src_alpha = src_a as f64 / 255.0;
     let dst_alpha = dst[3] as f64 / 255.0;
 
+    if preserve_alpha {
+        if dst[3] == 0 {
+            return;
+        }
+
+        // Mix the color in proportion to the source alpha, keeping dst alpha
+        for (c, src_c) in [src_r, src_g, src_b].into_iter().enumerate() {
+            let mixed = src_c as f64 * src_alpha + dst[c] as f64 * (1.0 - src_alpha);
+            dst[c] = mixed.round().clamp(0.0, 255.0) as u8;
+        }
+        return;
+    }
+
     // Porter-Duff "over" compositing
     let out_alpha = src_alpha + dst_alpha * (1.0 - src_alpha);
 
@@ -215,3 +1346,688 @@ fn blend_pixel(dst: &mut [u8], src_r: u8, src_g: u8, src_b: u8, src_a: u8) {
     dst[2] = (out_b * 255.0).clamp(0.0, 255.0) as u8;
     dst[3] = (out_alpha * 255.0).clamp(0.0, 255.0) as u8;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Background;
+
+    fn brush(size: f64, spacing: f64) -> BrushStrokeSettings {
+        serde_json::from_value(serde_json::json!({
+            "size": size,
+            "hardness": 100.0,
+            "opacity": 100.0,
+            "flow": 100.0,
+            "spacing": spacing,
+        }))
+        .unwrap()
+    }
+
+    fn point(x: f64, y: f64) -> BrushStrokePoint {
+        BrushStrokePoint {
+            x,
+            y,
+            pressure: None,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn interpolation_fills_the_gap_between_far_apart_points() {
+        let settings = brush(10.0, 25.0);
+        let stamps = interpolate_points(&[point(0.0, 0.0), point(200.0, 0.0)], &settings);
+
+        assert_eq!(stamps.first().unwrap().x, 0.0);
+        assert_eq!(stamps.last().unwrap().x, 200.0);
+        for pair in stamps.windows(2) {
+            let gap = pair[1].x - pair[0].x;
+            assert!(gap > 0.0 && gap <= stamp_spacing(&settings) + 1e-9);
+        }
+    }
+
+    #[test]
+    fn a_stroke_between_two_far_points_paints_a_continuous_line() {
+        let mut manager = DocumentManager::new();
+        let doc = manager
+            .create_with_background("Test", 220, 20, 72, Background::Transparent)
+            .unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        let color = BrushColor {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 1.0,
+        };
+
+        stroke_layer(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &[point(10.0, 10.0), point(210.0, 10.0)],
+            &brush(6.0, 25.0),
+            &color,
+            false,
+        )
+        .unwrap();
+
+        let pixels = manager.get_layer_pixels(&layer_id).unwrap();
+        for x in 10..=210 {
+            assert!(pixels[(10 * 220 + x) * 4 + 3] > 0, "gap at x = {}", x);
+        }
+    }
+
+    #[test]
+    fn points_far_outside_the_canvas_are_rejected() {
+        let mut manager = DocumentManager::new();
+        let doc = manager
+            .create_with_background("Test", 100, 50, 72, Background::Transparent)
+            .unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        let color = BrushColor {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 1.0,
+        };
+
+        for far in [point(1e10, 10.0), point(10.0, -1e10), point(550.0, 10.0)] {
+            let result = stroke_layer(
+                &mut manager,
+                &doc.id,
+                &layer_id,
+                &[point(10.0, 10.0), far],
+                &brush(6.0, 25.0),
+                &color,
+                false,
+            );
+            assert!(matches!(result, Err(AppError::InvalidOperation(_))));
+        }
+
+        // Strokes that merely run off the edge still paint
+        let dirty = stroke_layer(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &[point(90.0, 10.0), point(300.0, 10.0)],
+            &brush(6.0, 25.0),
+            &color,
+            false,
+        )
+        .unwrap()
+        .dirty
+        .unwrap();
+        assert_eq!(dirty.x + dirty.width as i32, 100);
+    }
+
+    #[test]
+    fn unseeded_strokes_get_a_fresh_seed_that_replays_them() {
+        let mut settings = brush(6.0, 25.0);
+        settings.scatter = 300.0;
+        let color = BrushColor {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 1.0,
+        };
+        let paint = |settings: &BrushStrokeSettings| {
+            let mut manager = DocumentManager::new();
+            let doc = manager
+                .create_with_background("Test", 64, 64, 72, Background::Transparent)
+                .unwrap();
+            let layer_id = doc.layers[0].id.clone();
+            let points = [point(10.0, 32.0), point(54.0, 32.0)];
+            let result = stroke_layer(
+                &mut manager,
+                &doc.id,
+                &layer_id,
+                &points,
+                settings,
+                &color,
+                false,
+            )
+            .unwrap();
+            (
+                result.seed,
+                manager.get_layer_pixels(&layer_id).unwrap().clone(),
+            )
+        };
+
+        let (first_seed, first) = paint(&settings);
+        let (second_seed, _) = paint(&settings);
+        assert_ne!(first_seed, second_seed);
+
+        settings.seed = Some(first_seed);
+        let (replay_seed, replay) = paint(&settings);
+        assert_eq!(replay_seed, first_seed);
+        assert_eq!(replay, first);
+    }
+
+    #[test]
+    fn a_stroke_takes_settings_or_a_preset_but_not_both() {
+        let mut manager = DocumentManager::new();
+        let preset_id = manager.register_brush_preset(brush(40.0, 10.0));
+
+        let preset = stroke_settings(&manager, None, Some(preset_id.clone())).unwrap();
+        assert_eq!(preset.size, 40.0);
+        let own = stroke_settings(&manager, Some(brush(5.0, 25.0)), None).unwrap();
+        assert_eq!(own.size, 5.0);
+
+        let both = stroke_settings(&manager, Some(brush(5.0, 25.0)), Some(preset_id));
+        assert!(matches!(both, Err(AppError::InvalidOperation(_))));
+        let neither = stroke_settings(&manager, None, None);
+        assert!(matches!(neither, Err(AppError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn repeated_points_stamp_once() {
+        let points = [point(5.0, 5.0), point(5.0, 5.0), point(5.0, 5.0)];
+        let stamps = interpolate_points(&points, &brush(10.0, 25.0));
+
+        assert_eq!(stamps.len(), 1);
+        assert_eq!((stamps[0].x, stamps[0].y), (5.0, 5.0));
+    }
+
+    #[test]
+    fn zero_spacing_is_clamped_to_the_minimum() {
+        let settings = brush(200.0, 0.0);
+        assert_eq!(stamp_spacing(&settings), 2.0);
+
+        let stamps = interpolate_points(&[point(0.0, 0.0), point(20.0, 0.0)], &settings);
+        let xs: Vec<f64> = stamps.iter().map(|p| p.x).collect();
+        assert_eq!(xs, (0..=10).map(|i| i as f64 * 2.0).collect::<Vec<_>>());
+    }
+
+    /// A 16x16 white layer with a black column at x = 8
+    fn striped_layer(manager: &mut DocumentManager) -> (String, String) {
+        let doc = manager
+            .create_with_background("Test", 16, 16, 72, Background::default())
+            .unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        let pixels = (0..16 * 16)
+            .flat_map(|i| {
+                if i % 16 == 8 {
+                    [0, 0, 0, 255]
+                } else {
+                    [255, 255, 255, 255]
+                }
+            })
+            .collect();
+        manager.set_layer_pixels(&layer_id, pixels);
+        (doc.id, layer_id)
+    }
+
+    fn red_at(manager: &mut DocumentManager, layer_id: &str, x: usize, y: usize) -> u8 {
+        manager.get_layer_pixels(layer_id).unwrap()[(y * 16 + x) * 4]
+    }
+
+    #[test]
+    fn warping_drags_pixels_along_the_stroke() {
+        let mut manager = DocumentManager::new();
+        let (doc_id, layer_id) = striped_layer(&mut manager);
+
+        let dirty = warp_layer(
+            &mut manager,
+            &doc_id,
+            &layer_id,
+            &[point(8.5, 8.5), point(10.5, 8.5)],
+            &brush(6.0, 25.0),
+        )
+        .unwrap()
+        .unwrap();
+
+        // Under the brush the column moves two pixels right...
+        assert_eq!(red_at(&mut manager, &layer_id, 10, 8), 0);
+        assert_eq!(red_at(&mut manager, &layer_id, 8, 8), 255);
+        // ...and outside it nothing moves
+        assert_eq!(red_at(&mut manager, &layer_id, 8, 0), 0);
+        assert_eq!(red_at(&mut manager, &layer_id, 10, 0), 255);
+        assert_eq!((dirty.x, dirty.y, dirty.width, dirty.height), (7, 5, 8, 8));
+
+        manager.undo(&doc_id).unwrap();
+        assert_eq!(red_at(&mut manager, &layer_id, 8, 8), 0);
+        assert_eq!(red_at(&mut manager, &layer_id, 10, 8), 255);
+    }
+
+    #[test]
+    fn a_stationary_warp_changes_nothing() {
+        let mut manager = DocumentManager::new();
+        let (doc_id, layer_id) = striped_layer(&mut manager);
+
+        let dirty = warp_layer(
+            &mut manager,
+            &doc_id,
+            &layer_id,
+            &[point(8.5, 8.5), point(8.5, 8.5)],
+            &brush(6.0, 25.0),
+        )
+        .unwrap();
+        assert_eq!(dirty, None);
+        assert!(manager.undo(&doc_id).is_err());
+    }
+
+    #[test]
+    fn warp_brushes_are_sized_against_the_document() {
+        let mut manager = DocumentManager::new();
+        let (doc_id, _) = striped_layer(&mut manager);
+        let small = manager
+            .add_layer_to_document(&doc_id, "Small", 2, 2)
+            .unwrap();
+
+        let result = warp_layer(
+            &mut manager,
+            &doc_id,
+            &small.id,
+            &[point(0.5, 0.5), point(1.5, 0.5)],
+            &brush(20.0, 25.0),
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn warping_rejects_stamp_only_settings() {
+        let mut manager = DocumentManager::new();
+        let (doc_id, layer_id) = striped_layer(&mut manager);
+        let points = [point(8.5, 8.5), point(10.5, 8.5)];
+
+        let mut scattered = brush(6.0, 25.0);
+        scattered.scatter = 50.0;
+        let mut jittered = brush(6.0, 25.0);
+        jittered.size_jitter = 20.0;
+        let mut tipped = brush(6.0, 25.0);
+        tipped.tip_id = Some("tip".into());
+
+        for settings in [scattered, jittered, tipped] {
+            let result = warp_layer(&mut manager, &doc_id, &layer_id, &points, &settings);
+            assert!(matches!(result, Err(AppError::InvalidOperation(_))));
+        }
+        assert_eq!(red_at(&mut manager, &layer_id, 8, 8), 0);
+    }
+
+    /// Erase one dab at the middle of an opaque 9x9 layer, returning the
+    /// alpha left at its center and at its edge
+    fn erase_dab(mode: &str) -> (u8, u8) {
+        let mut manager = DocumentManager::new();
+        let doc = manager
+            .create_with_background("Test", 9, 9, 72, Background::default())
+            .unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        let settings: BrushStrokeSettings = serde_json::from_value(serde_json::json!({
+            "size": 7.0,
+            "hardness": 50.0,
+            "opacity": 100.0,
+            "flow": 50.0,
+            "spacing": 25.0,
+            "eraserMode": mode,
+        }))
+        .unwrap();
+        let color = BrushColor {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 1.0,
+        };
+
+        stroke_layer(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &[point(4.5, 4.5)],
+            &settings,
+            &color,
+            true,
+        )
+        .unwrap();
+        let pixels = manager.get_layer_pixels(&layer_id).unwrap();
+        (pixels[(4 * 9 + 4) * 4 + 3], pixels[(4 * 9 + 7) * 4 + 3])
+    }
+
+    #[test]
+    fn the_soft_eraser_fades_alpha_by_coverage() {
+        let (center, edge) = erase_dab("soft");
+        assert!((100..=160).contains(&center), "center alpha {}", center);
+        assert!(edge > center, "edge alpha {} vs center {}", edge, center);
+    }
+
+    #[test]
+    fn the_hard_eraser_clears_everything_it_touches() {
+        let (center, edge) = erase_dab("hard");
+        assert_eq!(center, 0);
+        assert_eq!(edge, 0);
+    }
+
+    #[test]
+    fn eraser_modes_that_need_layer_masks_are_not_accepted() {
+        for mode in ["layerMask", "merged"] {
+            assert!(serde_json::from_value::<EraserMode>(serde_json::json!(mode)).is_err());
+        }
+    }
+
+    #[test]
+    fn locked_transparency_paints_only_opaque_pixels() {
+        let mut manager = DocumentManager::new();
+        let doc = manager.create("Test", 8, 1, 72).unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        let pixels = (0..8)
+            .flat_map(|x| {
+                if x < 4 {
+                    [0, 0, 0, 0]
+                } else {
+                    [255, 255, 255, 128]
+                }
+            })
+            .collect();
+        manager.set_layer_pixels(&layer_id, pixels);
+        let layer = manager
+            .get_mut(&doc.id)
+            .unwrap()
+            .get_layer_mut(&layer_id)
+            .unwrap();
+        layer.lock_alpha = true;
+        let blue = BrushColor {
+            r: 0,
+            g: 0,
+            b: 255,
+            a: 1.0,
+        };
+
+        stroke_layer(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &[point(0.5, 0.5), point(7.5, 0.5)],
+            &brush(3.0, 25.0),
+            &blue,
+            false,
+        )
+        .unwrap();
+        let pixels = manager.get_layer_pixels(&layer_id).unwrap();
+        assert_eq!(pixels[..4 * 4], [0; 16]);
+        assert_eq!(pixels[5 * 4..6 * 4], [0, 0, 255, 128]);
+
+        let erased = stroke_layer(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &[point(5.5, 0.5)],
+            &brush(3.0, 25.0),
+            &blue,
+            true,
+        );
+        assert!(matches!(erased, Err(AppError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn a_pixel_locked_layer_rejects_strokes() {
+        let mut manager = DocumentManager::new();
+        let (doc_id, layer_id) = striped_layer(&mut manager);
+        let layer = manager
+            .get_mut(&doc_id)
+            .unwrap()
+            .get_layer_mut(&layer_id)
+            .unwrap();
+        layer.lock_pixels = true;
+        let before = manager.get_layer_pixels(&layer_id).unwrap().clone();
+
+        let result = stroke_layer(
+            &mut manager,
+            &doc_id,
+            &layer_id,
+            &[point(2.5, 2.5), point(12.5, 2.5)],
+            &brush(3.0, 25.0),
+            &BrushColor {
+                r: 255,
+                g: 0,
+                b: 0,
+                a: 1.0,
+            },
+            false,
+        );
+        assert!(matches!(result, Err(AppError::InvalidOperation(_))));
+        assert_eq!(manager.get_layer_pixels(&layer_id).unwrap(), &before);
+    }
+
+    #[test]
+    fn out_of_range_settings_and_pressure_are_rejected() {
+        let mut manager = DocumentManager::new();
+        let doc = manager
+            .create_with_background("Test", 20, 10, 72, Background::Transparent)
+            .unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        let color = BrushColor {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 1.0,
+        };
+        let mut stroke = |settings: &BrushStrokeSettings, points: &[BrushStrokePoint]| {
+            stroke_layer(
+                &mut manager,
+                &doc.id,
+                &layer_id,
+                points,
+                settings,
+                &color,
+                false,
+            )
+        };
+        let at_center = [point(10.0, 5.0)];
+
+        let mut bad_settings = vec![brush(1e9, 25.0), brush(0.0, 25.0)];
+        for (field, value) in [
+            ("size", f64::NAN),
+            ("opacity", 150.0),
+            ("flow", -1.0),
+            ("hardness", f64::INFINITY),
+        ] {
+            let mut settings = brush(4.0, 25.0);
+            match field {
+                "size" => settings.size = value,
+                "opacity" => settings.opacity = value,
+                "flow" => settings.flow = value,
+                _ => settings.hardness = value,
+            }
+            bad_settings.push(settings);
+        }
+        for settings in &bad_settings {
+            let result = stroke(settings, &at_center);
+            assert!(matches!(result, Err(AppError::InvalidOperation(_))));
+        }
+
+        let pressed = BrushStrokePoint {
+            pressure: Some(f64::NAN),
+            ..point(10.0, 5.0)
+        };
+        let result = stroke(&brush(4.0, 25.0), &[pressed]);
+        assert!(matches!(result, Err(AppError::InvalidOperation(_))));
+
+        // The largest allowed brush still paints
+        assert!(stroke(&brush(40.0, 25.0), &at_center)
+            .unwrap()
+            .dirty
+            .is_some());
+    }
+
+    #[test]
+    fn a_brush_tip_shapes_the_stamp() {
+        let mut manager = DocumentManager::new();
+        let doc = manager
+            .create_with_background("Test", 32, 32, 72, Background::Transparent)
+            .unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        // Left half white, right half black: only the left half of a stamp paints
+        let tip =
+            image::GrayImage::from_fn(8, 8, |x, _| image::Luma([if x < 4 { 255 } else { 0 }]));
+        let tip = BrushTip::from_image(&image::DynamicImage::ImageLuma8(tip)).unwrap();
+        let mut settings = brush(16.0, 25.0);
+        settings.tip_id = Some(manager.register_brush_tip(tip));
+        let black = BrushColor {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 1.0,
+        };
+
+        stroke_layer(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &[point(16.0, 16.0)],
+            &settings,
+            &black,
+            false,
+        )
+        .unwrap();
+        let pixels = manager.get_layer_pixels(&layer_id).unwrap().clone();
+        let alpha = |x: usize, y: usize| pixels[(y * 32 + x) * 4 + 3];
+        assert_eq!(alpha(10, 16), 255);
+        assert_eq!(alpha(21, 16), 0);
+        // The square tip fills corners a round stamp would miss
+        assert_eq!(alpha(10, 9), 255);
+
+        settings.tip_id = Some("missing".into());
+        let result = stroke_layer(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &[point(16.0, 16.0)],
+            &settings,
+            &black,
+            false,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn wet_edges_pool_color_along_the_rim_of_the_stroke() {
+        let paint = |wet_edges: bool| {
+            let mut manager = DocumentManager::new();
+            let doc = manager
+                .create_with_background("Test", 64, 32, 72, Background::Transparent)
+                .unwrap();
+            let layer_id = doc.layers[0].id.clone();
+            let mut settings = brush(16.0, 10.0);
+            settings.wet_edges = wet_edges;
+            let black = BrushColor {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 1.0,
+            };
+            stroke_layer(
+                &mut manager,
+                &doc.id,
+                &layer_id,
+                &[point(12.0, 16.0), point(52.0, 16.0)],
+                &settings,
+                &black,
+                false,
+            )
+            .unwrap();
+            manager.get_layer_pixels(&layer_id).unwrap().clone()
+        };
+        let alpha = |pixels: &[u8], x: usize, y: usize| pixels[(y * 64 + x) * 4 + 3];
+
+        let dry = paint(false);
+        let wet = paint(true);
+        assert_eq!(alpha(&dry, 32, 16), 255);
+
+        // One even wash down the middle, however many stamps overlap there
+        let center = alpha(&wet, 32, 16);
+        assert!(center > 0 && center < 128, "center alpha {}", center);
+        assert_eq!(alpha(&wet, 20, 16), center);
+        assert_eq!(alpha(&wet, 44, 16), center);
+
+        // Darker towards the edge, and nothing outside the stroke
+        assert!(alpha(&wet, 32, 22) > center);
+        assert_eq!(alpha(&wet, 32, 28), 0);
+    }
+
+    #[test]
+    fn constrained_strokes_run_straight_or_snap_to_the_angle() {
+        let settings = brush(4.0, 25.0);
+        let wobbly = [point(0.0, 0.0), point(30.0, 25.0), point(100.0, 3.0)];
+
+        let straight = constrain_points(&wobbly, ConstrainMode::Straight, &settings);
+        let end = straight.last().unwrap();
+        assert_eq!((end.x, end.y), (100.0, 3.0));
+        for stamp in &straight {
+            assert!(
+                (stamp.y - stamp.x * 0.03).abs() < 1e-9,
+                "off the line at {:?}",
+                stamp.x
+            );
+        }
+
+        // 3 degrees off horizontal snaps flat, keeping the projected length
+        let snapped = constrain_points(&wobbly, ConstrainMode::Angle(45.0), &settings);
+        let end = snapped.last().unwrap();
+        assert!((end.x - 100.0).abs() < 1e-9 && end.y.abs() < 1e-9);
+        assert!(snapped.iter().all(|stamp| stamp.y.abs() < 1e-9));
+
+        let diagonal = [point(0.0, 0.0), point(10.0, 11.0)];
+        let snapped = constrain_points(&diagonal, ConstrainMode::Angle(45.0), &settings);
+        let end = snapped.last().unwrap();
+        assert!((end.x - end.y).abs() < 1e-9);
+    }
+
+    #[test]
+    fn dodge_and_burn_shift_the_chosen_tones_in_place() {
+        let tone = |kind: ToneKind, range: ToneRange| {
+            let mut manager = DocumentManager::new();
+            let doc = manager
+                .create_with_background("Test", 4, 1, 72, Background::Transparent)
+                .unwrap();
+            let layer_id = doc.layers[0].id.clone();
+            let original = vec![
+                40, 40, 40, 255, 128, 128, 128, 255, 220, 220, 220, 255, 0, 0, 0, 0,
+            ];
+            manager.set_layer_pixels(&layer_id, original);
+            let mut settings = brush(3.0, 25.0);
+            settings.tone = Some(ToneAdjust {
+                kind,
+                range,
+                exposure: 50.0,
+            });
+            let white = BrushColor {
+                r: 255,
+                g: 255,
+                b: 255,
+                a: 1.0,
+            };
+            stroke_layer(
+                &mut manager,
+                &doc.id,
+                &layer_id,
+                &[point(0.5, 0.5), point(3.5, 0.5)],
+                &settings,
+                &white,
+                false,
+            )
+            .unwrap();
+            manager.get_layer_pixels(&layer_id).unwrap().clone()
+        };
+
+        let dodged = tone(ToneKind::Dodge, ToneRange::Midtones);
+        let gains: Vec<i32> = [0, 1, 2]
+            .iter()
+            .map(|&i| dodged[i * 4] as i32 - [40, 128, 220][i])
+            .collect();
+        assert!(gains.iter().all(|&gain| gain > 0), "{:?}", gains);
+        assert!(gains[1] > gains[0] && gains[1] > gains[2], "{:?}", gains);
+        // Tones never paint: alpha is kept and empty pixels stay empty
+        assert_eq!([dodged[3], dodged[7], dodged[11]], [255; 3]);
+        assert_eq!(dodged[12..], [0; 4]);
+
+        // Burn scales values down, so compare how much of each tone it takes
+        let burned = tone(ToneKind::Burn, ToneRange::Shadows);
+        let kept: Vec<f64> = [0, 1, 2]
+            .iter()
+            .map(|&i| burned[i * 4] as f64 / [40.0, 128.0, 220.0][i])
+            .collect();
+        assert!(
+            kept[0] < kept[1] && kept[1] < kept[2] && kept[2] < 1.0,
+            "{:?}",
+            kept
+        );
+    }
+}
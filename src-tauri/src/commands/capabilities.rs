@@ -0,0 +1,111 @@
+use super::filters::FilterParams;
+use crate::engine::BlendMode;
+use crate::error::AppResult;
+use image::ImageFormat;
+use serde::Serialize;
+
+/// A file format and the extensions it's recognized by
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FormatInfo {
+    pub name: String,
+    pub extensions: Vec<&'static str>,
+}
+
+/// What this build of the backend can open, save and apply
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    pub import_formats: Vec<FormatInfo>,
+    pub export_formats: Vec<FormatInfo>,
+    /// `type` tags accepted in `FilterParams`
+    pub filters: Vec<&'static str>,
+    pub blend_modes: Vec<BlendMode>,
+}
+
+fn drkr_format() -> FormatInfo {
+    FormatInfo {
+        name: "DRKR".into(),
+        extensions: vec!["drkr"],
+    }
+}
+
+/// Raster formats the `image` crate was built with support for
+fn raster_formats(enabled: impl Fn(&ImageFormat) -> bool) -> Vec<FormatInfo> {
+    ImageFormat::all()
+        .filter(enabled)
+        .map(|format| FormatInfo {
+            name: format!("{:?}", format),
+            extensions: format.extensions_str().to_vec(),
+        })
+        .collect()
+}
+
+/// List the supported import/export formats, filters and blend modes so the
+/// frontend doesn't have to hardcode them
+#[tauri::command]
+pub fn get_capabilities() -> AppResult<Capabilities> {
+    let mut import_formats = vec![
+        drkr_format(),
+        FormatInfo {
+            name: "SVG".into(),
+            extensions: vec!["svg"],
+        },
+    ];
+    import_formats.extend(raster_formats(ImageFormat::reading_enabled));
+
+    let mut export_formats = vec![drkr_format()];
+    export_formats.extend(raster_formats(ImageFormat::writing_enabled));
+
+    Ok(Capabilities {
+        import_formats,
+        export_formats,
+        filters: FilterParams::TYPES.to_vec(),
+        blend_modes: BlendMode::ALL.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn every_listed_filter_is_a_filter_the_backend_accepts() {
+        let capabilities = get_capabilities().unwrap();
+        let unique: HashSet<_> = capabilities.filters.iter().collect();
+        assert_eq!(unique.len(), capabilities.filters.len());
+
+        for tag in &capabilities.filters {
+            // Filters with parameters fail on a missing field, not on the tag
+            if let Err(e) =
+                serde_json::from_value::<FilterParams>(serde_json::json!({ "type": tag }))
+            {
+                assert!(!e.to_string().contains("unknown variant"), "{}: {}", tag, e);
+            }
+        }
+    }
+
+    #[test]
+    fn blend_modes_and_formats_are_listed() {
+        let capabilities = get_capabilities().unwrap();
+        let names: HashSet<String> = capabilities
+            .blend_modes
+            .iter()
+            .map(|mode| serde_json::to_string(mode).unwrap())
+            .collect();
+        assert_eq!(names.len(), BlendMode::ALL.len());
+
+        let extensions = |formats: &[FormatInfo]| -> Vec<&str> {
+            formats.iter().flat_map(|f| f.extensions.clone()).collect()
+        };
+        let imports = extensions(&capabilities.import_formats);
+        let exports = extensions(&capabilities.export_formats);
+        for extension in ["drkr", "png", "webp"] {
+            assert!(imports.contains(&extension), "{}", extension);
+            assert!(exports.contains(&extension), "{}", extension);
+        }
+        assert!(imports.contains(&"svg"));
+        assert!(!exports.contains(&"svg"));
+    }
+}
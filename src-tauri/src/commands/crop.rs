@@ -1,8 +1,9 @@
+use super::{emit_document_modified, write_manager};
 use crate::engine::DocumentManager;
 use crate::error::{AppError, AppResult};
 use serde::{Deserialize, Serialize};
-use std::sync::Mutex;
-use tauri::State;
+use std::sync::RwLock;
+use tauri::{AppHandle, State};
 
 /// Result of a crop operation, includes updated document info
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,7 +28,8 @@ pub struct CropResult {
 /// * `height` - Height of the new document
 #[tauri::command]
 pub fn crop_document(
-    manager: State<'_, Mutex<DocumentManager>>,
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
     doc_id: String,
     x: i32,
     y: i32,
@@ -41,16 +43,30 @@ pub fn crop_document(
         ));
     }
 
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
+    let before = manager.capture_all_pixels(&doc_id)?;
     let result = manager.crop_document(&doc_id, x, y, width, height)?;
+    manager.push_pixel_history(&doc_id, "Crop", before);
 
     log::info!(
         "Cropped document {} to {}x{} at ({}, {})",
         doc_id, width, height, x, y
     );
 
+    emit_document_modified(&app, &manager, &doc_id);
     Ok(result)
 }
+
+/// Set the largest canvas area, in pixels, that a crop may produce.
+///
+/// Returns the limit applied, which is capped to what pixel buffers can address.
+#[tauri::command]
+pub fn set_max_canvas_area(
+    manager: State<'_, RwLock<DocumentManager>>,
+    max_pixels: u64,
+) -> AppResult<u64> {
+    let mut manager = write_manager(&manager);
+
+    manager.set_max_canvas_area(max_pixels)
+}
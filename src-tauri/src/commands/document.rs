@@ -1,33 +1,143 @@
-use crate::engine::{Document, DocumentManager};
+use super::{
+    emit_document_modified, emit_document_saved, read_manager, run_blocking, write_manager,
+};
+use crate::engine::layer::LayerType;
+use crate::engine::{
+    checked_rgba_buffer_len, Background, Document, DocumentManager, ExtensionFiles, TileCacheStats,
+};
 use crate::error::{AppError, AppResult};
-use crate::io::{DrkrReader, DrkrWriter};
-use std::sync::Mutex;
-use tauri::State;
+use crate::io::{
+    autosave_path, detect_format, list_autosaves, open_image_upright, prune_autosaves, quantize,
+    rasterize_svg, read_animation_frames, write_animated_webp, write_ico, write_indexed,
+    write_sprite_sheet, DrkrReadResult, DrkrReader, DrkrWriter, FileFormat, PixelFormat,
+    SpriteAtlas, SpriteCell, AUTOSAVES_PER_DOCUMENT,
+};
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Cursor, Seek, Write};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use tauri::{AppHandle, State};
 
+/// Create a blank document.
+///
+/// `background` defaults to white; `history_limit` overrides the default undo depth.
 #[tauri::command]
 pub fn create_document(
-    manager: State<'_, Mutex<DocumentManager>>,
+    manager: State<'_, RwLock<DocumentManager>>,
     name: String,
     width: u32,
     height: u32,
     resolution: Option<u32>,
+    background: Option<Background>,
+    history_limit: Option<usize>,
 ) -> AppResult<Document> {
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
-    let doc = manager.create(&name, width, height, resolution.unwrap_or(72));
+    let doc = manager.create_with_background(
+        &name,
+        width,
+        height,
+        resolution.unwrap_or(72),
+        background.unwrap_or_default(),
+    )?;
+    if let Some(limit) = history_limit {
+        manager.set_history_limit(&doc.id, limit)?;
+    }
     Ok(doc)
 }
 
+/// Canvas settings for a new document, from the built-in presets or the user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentTemplate {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub resolution: u32,
+    #[serde(default)]
+    pub background: Background,
+}
+
+/// Either the name of a built-in preset or a full custom template
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TemplateChoice {
+    Preset(String),
+    Custom(DocumentTemplate),
+}
+
+impl TemplateChoice {
+    /// The template to create, looking presets up by name (case-insensitive)
+    fn resolve(self) -> AppResult<DocumentTemplate> {
+        match self {
+            TemplateChoice::Custom(template) => Ok(template),
+            TemplateChoice::Preset(preset) => list_document_templates()
+                .into_iter()
+                .find(|template| template.name.eq_ignore_ascii_case(&preset))
+                .ok_or_else(|| AppError::InvalidOperation(format!("Unknown template: {}", preset))),
+        }
+    }
+}
+
+const WHITE: Background = Background::Color {
+    color: [255, 255, 255, 255],
+};
+
+/// Built-in templates: name, width, height, resolution (ppi) and background
+const TEMPLATE_PRESETS: [(&str, u32, u32, u32, Background); 7] = [
+    ("1080p", 1920, 1080, 72, WHITE),
+    ("4K", 3840, 2160, 72, WHITE),
+    ("A4@300dpi", 2480, 3508, 300, WHITE),
+    ("Letter@300dpi", 2550, 3300, 300, WHITE),
+    ("Instagram Square", 1080, 1080, 72, WHITE),
+    ("Instagram Story", 1080, 1920, 72, WHITE),
+    ("Icon 512", 512, 512, 72, Background::Transparent),
+];
+
+/// The built-in templates, for the new-document dialog
+#[tauri::command]
+pub fn list_document_templates() -> Vec<DocumentTemplate> {
+    TEMPLATE_PRESETS
+        .into_iter()
+        .map(|(name, width, height, ppi, background)| DocumentTemplate {
+            name: name.to_string(),
+            width,
+            height,
+            resolution: ppi,
+            background,
+        })
+        .collect()
+}
+
+/// Create a blank document from a preset name (case-insensitive) or a custom
+/// template. The document is named after the template unless `name` is given.
+#[tauri::command]
+pub fn create_document_from_template(
+    manager: State<'_, RwLock<DocumentManager>>,
+    template: TemplateChoice,
+    name: Option<String>,
+) -> AppResult<Document> {
+    let template = template.resolve()?;
+
+    let mut manager = write_manager(&manager);
+
+    manager.create_with_background(
+        name.as_deref().unwrap_or(&template.name),
+        template.width,
+        template.height,
+        template.resolution,
+        template.background,
+    )
+}
+
 #[tauri::command]
 pub fn get_document(
-    manager: State<'_, Mutex<DocumentManager>>,
+    manager: State<'_, RwLock<DocumentManager>>,
     doc_id: String,
 ) -> AppResult<Document> {
-    let manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let manager = read_manager(&manager);
 
     manager
         .get(&doc_id)
@@ -37,15 +147,9 @@ pub fn get_document(
 
 #[tauri::command]
 pub async fn open_document(
-    manager: State<'_, Mutex<DocumentManager>>,
+    manager: State<'_, RwLock<DocumentManager>>,
     path: String,
 ) -> AppResult<Document> {
-    // Read the file
-    let img = image::open(&path).map_err(|e| AppError::ImageError(e.to_string()))?;
-
-    let width = img.width();
-    let height = img.height();
-
     // Extract filename from path
     let name = std::path::Path::new(&path)
         .file_stem()
@@ -53,12 +157,23 @@ pub async fn open_document(
         .unwrap_or("Untitled")
         .to_string();
 
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    // Animated GIF/APNG files import each frame as its own layer
+    if let Some(frames) = read_animation_frames(&path)? {
+        let mut manager = write_manager(&manager);
+
+        return open_frames_as_layers(&mut manager, &name, frames);
+    }
+
+    // Read the file, turning camera photos upright
+    let img = open_image_upright(&path)?;
+
+    let width = img.width();
+    let height = img.height();
+
+    let mut manager = write_manager(&manager);
 
     // Create document
-    let doc = manager.create(&name, width, height, 72);
+    let doc = manager.create(&name, width, height, 72)?;
 
     // Get the background layer and set its pixels
     if let Some(bg_layer) = doc.layers.first() {
@@ -69,24 +184,156 @@ pub async fn open_document(
     Ok(doc)
 }
 
+/// Open an SVG file by rasterizing it into the background layer of a new document.
+///
+/// `width`/`height` override the SVG's intrinsic size (one alone keeps the viewBox
+/// aspect ratio). `resolution` is the document PPI the SVG is rendered at.
+#[tauri::command]
+pub async fn open_document_svg(
+    manager: State<'_, RwLock<DocumentManager>>,
+    path: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    resolution: Option<u32>,
+) -> AppResult<Document> {
+    let resolution = resolution.unwrap_or(96);
+    let img = rasterize_svg(&path, width, height, resolution)?;
+
+    let name = std::path::Path::new(&path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("Untitled")
+        .to_string();
+
+    let mut manager = write_manager(&manager);
+
+    let doc = manager.create(&name, img.width(), img.height(), resolution)?;
+
+    if let Some(bg_layer) = doc.layers.first() {
+        manager.set_layer_pixels(&bg_layer.id, img.into_raw());
+    }
+
+    log::info!("Opened SVG document from {}", path);
+    Ok(doc)
+}
+
+/// Create a document from raw RGBA pixels, e.g. an image pasted as new.
+///
+/// `pixels_base64` must hold exactly `width * height * 4` bytes, which become
+/// the background layer.
+#[tauri::command]
+pub fn create_document_from_pixels(
+    manager: State<'_, RwLock<DocumentManager>>,
+    name: String,
+    width: u32,
+    height: u32,
+    pixels_base64: String,
+) -> AppResult<Document> {
+    document_from_pixels(
+        &mut write_manager(&manager),
+        &name,
+        width,
+        height,
+        &pixels_base64,
+    )
+}
+
+fn document_from_pixels(
+    manager: &mut DocumentManager,
+    name: &str,
+    width: u32,
+    height: u32,
+    pixels_base64: &str,
+) -> AppResult<Document> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    if width == 0 || height == 0 {
+        return Err(AppError::InvalidOperation(
+            "Document dimensions must be greater than zero".into(),
+        ));
+    }
+
+    let pixels = STANDARD
+        .decode(pixels_base64)
+        .map_err(|e| AppError::InvalidOperation(format!("Invalid base64 data: {}", e)))?;
+
+    let expected = checked_rgba_buffer_len(width, height)?;
+    if pixels.len() != expected {
+        return Err(AppError::InvalidOperation(format!(
+            "Pixel data is {} bytes but a {}x{} image needs {}",
+            pixels.len(),
+            width,
+            height,
+            expected
+        )));
+    }
+
+    let doc = manager.create(name, width, height, 72)?;
+
+    if let Some(bg_layer) = doc.layers.first() {
+        manager.set_layer_pixels(&bg_layer.id, pixels);
+    }
+
+    Ok(doc)
+}
+
+/// Create a document with one layer per animation frame ("Frame 1", "Frame 2", ...),
+/// stacked bottom to top in frame order
+fn open_frames_as_layers(
+    manager: &mut DocumentManager,
+    name: &str,
+    frames: Vec<image::RgbaImage>,
+) -> AppResult<Document> {
+    let (width, height) = frames[0].dimensions();
+    let doc = manager.create(name, width, height, 72)?;
+
+    for (index, frame) in frames.into_iter().enumerate() {
+        let layer_name = format!("Frame {}", index + 1);
+
+        let layer_id = if index == 0 {
+            // Reuse the background layer for the first frame
+            let bg_id = doc.layers[0].id.clone();
+            if let Some(layer) = manager
+                .get_mut(&doc.id)
+                .and_then(|d| d.get_layer_mut(&bg_id))
+            {
+                layer.name = layer_name;
+            }
+            bg_id
+        } else {
+            manager
+                .add_layer_to_document(&doc.id, &layer_name, width, height)?
+                .id
+        };
+
+        manager.set_layer_pixels(&layer_id, frame.into_raw());
+    }
+
+    manager
+        .get(&doc.id)
+        .cloned()
+        .ok_or_else(|| AppError::DocumentNotFound(doc.id.clone()))
+}
+
 #[tauri::command]
 pub async fn save_document(
-    manager: State<'_, Mutex<DocumentManager>>,
+    manager: State<'_, RwLock<DocumentManager>>,
     doc_id: String,
     path: String,
     _format: Option<String>,
 ) -> AppResult<()> {
-    let manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
-    let doc = manager
+    let layer = manager
         .get(&doc_id)
-        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?;
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?
+        .layers
+        .first()
+        .cloned();
 
     // For now, just save the first layer's pixels
     // A real implementation would composite all visible layers
-    if let Some(layer) = doc.layers.first() {
+    if let Some(layer) = layer {
         if let Some(pixels) = manager.get_layer_pixels(&layer.id) {
             let img =
                 image::RgbaImage::from_raw(layer.width, layer.height, pixels.clone()).ok_or_else(
@@ -101,94 +348,605 @@ pub async fn save_document(
     Ok(())
 }
 
+/// Close a document, deleting its crash-recovery autosaves
 #[tauri::command]
 pub fn close_document(
-    manager: State<'_, Mutex<DocumentManager>>,
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
     doc_id: String,
 ) -> AppResult<()> {
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
-    manager.close(&doc_id)
+    manager.close(&doc_id)?;
+    discard_autosaves(&app, &doc_id);
+    Ok(())
 }
 
-/// Save a document in DRKR format
-#[tauri::command]
-pub async fn save_document_drkr(
-    manager: State<'_, Mutex<DocumentManager>>,
-    doc_id: String,
-    path: String,
-) -> AppResult<Document> {
-    // Collect document and pixel data while holding the lock
-    let (doc, layer_pixels) = {
-        let manager = manager.lock().map_err(|_| {
-            AppError::InvalidOperation("Failed to acquire document manager lock".into())
-        })?;
+/// Everything a DRKR save needs, copied out of the manager so encoding can
+/// run without holding the lock
+struct DrkrSaveData {
+    doc: Document,
+    layer_pixels: HashMap<String, Vec<u8>>,
+    merged: RgbaImage,
+    extensions: ExtensionFiles,
+}
 
+impl DrkrSaveData {
+    fn collect(manager: &State<'_, RwLock<DocumentManager>>, doc_id: &str) -> AppResult<Self> {
+        Self::from_manager(&mut write_manager(manager), doc_id)
+    }
+
+    fn from_manager(manager: &mut DocumentManager, doc_id: &str) -> AppResult<Self> {
         let doc = manager
-            .get(&doc_id)
-            .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?
             .clone();
 
-        let layer_pixels = manager
-            .get_all_layer_pixels(&doc_id)
-            .unwrap_or_default();
+        let layer_pixels = manager.get_all_layer_pixels(doc_id)?;
+        let merged = manager.get_export_composite(doc_id)?;
+        let extensions = manager.get_extensions(doc_id).cloned().unwrap_or_default();
 
-        (doc, layer_pixels)
-    };
+        Ok(Self {
+            doc,
+            layer_pixels,
+            merged,
+            extensions,
+        })
+    }
+
+    fn write_to<W: Write + Seek>(self, writer: &mut DrkrWriter<W>) -> AppResult<()> {
+        writer.add_extensions(self.extensions)?;
+        writer.write_document_with_composite(&self.doc, &self.layer_pixels, self.merged)
+    }
+}
+
+/// Save a document in DRKR format.
+///
+/// Layer pixels are stored as WebP unless `pixel_format` asks for PNG.
+#[tauri::command]
+pub async fn save_document_drkr(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    path: String,
+    pixel_format: Option<PixelFormat>,
+) -> AppResult<Document> {
+    // Collect document, pixel data and composite while holding the lock
+    let data = DrkrSaveData::collect(&manager, &doc_id)?;
 
-    // Create the DRKR file (this doesn't need the lock)
-    let mut writer = DrkrWriter::create(&path)?;
-    writer.write_document(&doc, &layer_pixels)?;
-    writer.finish()?;
+    // Encode and write the DRKR file without the lock, off the async runtime
+    let write_path = path.clone();
+    run_blocking(move || {
+        let mut writer =
+            DrkrWriter::create(&write_path)?.with_pixel_format(pixel_format.unwrap_or_default());
+        data.write_to(&mut writer)?;
+        writer.finish()?;
+        Ok(())
+    })
+    .await?;
 
     // Update the source path in the document
     let updated_doc = {
-        let mut manager = manager.lock().map_err(|_| {
-            AppError::InvalidOperation("Failed to acquire document manager lock".into())
-        })?;
+        let mut manager = write_manager(&manager);
         manager.set_source_path(&doc_id, &path)?;
+        emit_document_saved(&app, &manager, &doc_id);
         manager.get(&doc_id).cloned()
             .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?
     };
 
-    log::info!("Saved document '{}' to {}", doc.name, path);
+    log::info!("Saved document '{}' to {}", updated_doc.name, path);
     Ok(updated_doc)
 }
 
+/// Export the composite as an indexed-color PNG or GIF with at most `colors`
+/// palette entries, optionally dithered
+#[tauri::command]
+pub async fn export_indexed(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    path: String,
+    colors: u16,
+    dither: bool,
+) -> AppResult<()> {
+    let merged = {
+        let mut manager = write_manager(&manager);
+        manager.get_export_composite(&doc_id)?
+    };
+
+    // Quantizing is slow on big images, so it runs without the lock and off
+    // the async runtime
+    let write_path = path.clone();
+    let palette_len = run_blocking(move || {
+        let indexed = quantize(&merged, colors, dither)?;
+        write_indexed(&write_path, &indexed)?;
+        Ok(indexed.palette.len())
+    })
+    .await?;
+
+    log::info!(
+        "Exported document {} as {} colors to {}",
+        doc_id,
+        palette_len,
+        path
+    );
+    Ok(())
+}
+
+/// One frame of an animated export: the layers it shows and how long it stays
+/// on screen
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnimationFrame {
+    pub layer_ids: Vec<String>,
+    pub duration_ms: u32,
+}
+
+/// Export an animated WebP, compositing each frame from its listed layers.
+///
+/// A `loop_count` of 0 loops forever.
+#[tauri::command]
+pub async fn export_animated_webp(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    path: String,
+    frames: Vec<AnimationFrame>,
+    loop_count: u16,
+) -> AppResult<()> {
+    let composites = {
+        let mut manager = write_manager(&manager);
+
+        frames
+            .iter()
+            .map(|frame| {
+                let image = manager.composite_layer_subset(&doc_id, &frame.layer_ids)?;
+                Ok((image, frame.duration_ms))
+            })
+            .collect::<AppResult<Vec<_>>>()?
+    };
+
+    let frame_count = composites.len();
+    let write_path = path.clone();
+    run_blocking(move || write_animated_webp(&write_path, &composites, loop_count)).await?;
+
+    log::info!(
+        "Exported document {} as a {}-frame animated WebP to {}",
+        doc_id,
+        frame_count,
+        path
+    );
+    Ok(())
+}
+
+/// Export the composite as an ICO with one image per size in `sizes`
+/// (1-256, e.g. 16, 32, 48 and 256)
+#[tauri::command]
+pub async fn export_ico(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    sizes: Vec<u32>,
+    path: String,
+) -> AppResult<()> {
+    let merged = {
+        let mut manager = write_manager(&manager);
+        manager.get_export_composite(&doc_id)?
+    };
+
+    let write_path = path.clone();
+    run_blocking(move || write_ico(&write_path, &merged, &sizes)).await?;
+
+    log::info!("Exported document {} as an icon to {}", doc_id, path);
+    Ok(())
+}
+
+/// Export every layer that has pixels of its own (not groups or adjustment
+/// layers) as one canvas-sized cell of a PNG sprite sheet, bottom layer
+/// first, with a JSON atlas written beside it
+#[tauri::command]
+pub async fn export_sprite_sheet(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    path: String,
+    columns: Option<u32>,
+) -> AppResult<SpriteAtlas> {
+    let cells = {
+        let mut manager = write_manager(&manager);
+
+        let layers = manager
+            .get(&doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?
+            .layers
+            .clone();
+
+        layers
+            .iter()
+            .filter(|layer| !matches!(layer.layer_type, LayerType::Group | LayerType::Adjustment))
+            .map(|layer| {
+                let image =
+                    manager.composite_layer_subset(&doc_id, std::slice::from_ref(&layer.id))?;
+                Ok(SpriteCell {
+                    layer_id: layer.id.clone(),
+                    name: layer.name.clone(),
+                    image,
+                })
+            })
+            .collect::<AppResult<Vec<_>>>()?
+    };
+
+    let write_path = path.clone();
+    let atlas = run_blocking(move || write_sprite_sheet(&write_path, &cells, columns)).await?;
+
+    log::info!(
+        "Exported document {} as a {}-cell sprite sheet to {}",
+        doc_id,
+        atlas.frames.len(),
+        path
+    );
+    Ok(atlas)
+}
+
 /// Open a document from DRKR format
 #[tauri::command]
 pub async fn open_document_drkr(
-    manager: State<'_, Mutex<DocumentManager>>,
+    manager: State<'_, RwLock<DocumentManager>>,
     path: String,
 ) -> AppResult<Document> {
     // Read the DRKR file (doesn't need the lock)
     let mut reader = DrkrReader::open(&path)?;
     let mut result = reader.read_all()?;
+    refresh_linked_layers(&result.document, &mut result.layer_pixels);
 
     // Set the source path on the document
     result.document.source_path = Some(path.clone());
 
     // Register the document with the manager
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
     let doc = manager.register_loaded_document(result.document, result.layer_pixels);
+    manager.set_extensions(&doc.id, result.extensions);
 
     log::info!("Opened DRKR document from {}", path);
     Ok(doc)
 }
 
-/// List all open document IDs
+/// Re-read the source files of linked image layers at the layers' current size.
+///
+/// Layers whose source is missing or unreadable keep the pixels saved in the
+/// DRKR file.
+fn refresh_linked_layers(document: &Document, layer_pixels: &mut HashMap<String, Vec<u8>>) {
+    for layer in &document.layers {
+        let Some(path) = &layer.linked_path else {
+            continue;
+        };
+
+        match open_image_upright(path) {
+            Ok(img) => {
+                let img = if img.width() == layer.width && img.height() == layer.height {
+                    img
+                } else {
+                    img.resize_exact(
+                        layer.width,
+                        layer.height,
+                        image::imageops::FilterType::Lanczos3,
+                    )
+                };
+                layer_pixels.insert(layer.id.clone(), img.to_rgba8().into_raw());
+            }
+            Err(e) => {
+                log::warn!(
+                    "Linked image {} for layer {} is unavailable, keeping saved pixels: {}",
+                    path,
+                    layer.id,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Salvage a damaged DRKR file by rebuilding the document from whatever layer
+/// folders are still readable.
+///
+/// The result has no source path, so saving it won't overwrite the damaged file.
+#[tauri::command]
+pub async fn recover_drkr(
+    manager: State<'_, RwLock<DocumentManager>>,
+    path: String,
+) -> AppResult<Document> {
+    let stem = std::path::Path::new(&path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("Untitled");
+    let name = format!("{} (Recovered)", stem);
+
+    let mut reader = DrkrReader::open(&path)?;
+    let mut result = reader.recover(&name)?;
+    refresh_linked_layers(&result.document, &mut result.layer_pixels);
+
+    let mut manager = write_manager(&manager);
+
+    let doc = manager.register_loaded_document(result.document, result.layer_pixels);
+    manager.set_extensions(&doc.id, result.extensions);
+
+    log::info!("Recovered {} layer(s) from {}", doc.layers.len(), path);
+    Ok(doc)
+}
+
+/// Directory holding crash-recovery autosaves
+fn recovery_dir(app: &AppHandle) -> AppResult<PathBuf> {
+    app.path_resolver()
+        .app_data_dir()
+        .map(|dir| dir.join("recovery"))
+        .ok_or_else(|| AppError::IoError("App data directory is unavailable".into()))
+}
+
+/// Delete every autosave of a document, logging rather than failing
+fn discard_autosaves(app: &AppHandle, doc_id: &str) {
+    let result = recovery_dir(app).and_then(|dir| prune_autosaves(&dir, doc_id, 0));
+    if let Err(e) = result {
+        log::warn!("Failed to discard autosaves of {}: {}", doc_id, e);
+    }
+}
+
+/// Write a crash-recovery copy of a document to the app data directory.
+///
+/// The document's source path and saved state are left alone; only the newest
+/// few autosaves of each document are kept.
+#[tauri::command]
+pub async fn autosave_document(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<()> {
+    let dir = recovery_dir(&app)?;
+    let data = DrkrSaveData::collect(&manager, &doc_id)?;
+
+    run_blocking(move || {
+        std::fs::create_dir_all(&dir).map_err(|e| AppError::IoError(e.to_string()))?;
+
+        // Write under a temporary name so a crash mid-write can't leave a
+        // truncated file that looks like the newest autosave
+        let path = autosave_path(&dir, &doc_id, chrono::Utc::now().timestamp_millis());
+        let partial_path = path.with_extension("drkr.partial");
+        let mut writer = DrkrWriter::create(&partial_path)?;
+        data.write_to(&mut writer)?;
+        writer.finish()?;
+        std::fs::rename(&partial_path, &path).map_err(|e| AppError::IoError(e.to_string()))?;
+
+        prune_autosaves(&dir, &doc_id, AUTOSAVES_PER_DOCUMENT)
+    })
+    .await
+}
+
+/// A document with an autosave but no open window, e.g. after a crash
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoverableDocument {
+    pub doc_id: String,
+    pub name: String,
+    /// Milliseconds since the epoch
+    pub saved_at: i64,
+}
+
+/// List documents that can be restored with `recover_autosave`, newest first.
+///
+/// Documents that are currently open are left out.
+#[tauri::command]
+pub async fn list_recoverable(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+) -> AppResult<Vec<RecoverableDocument>> {
+    let dir = recovery_dir(&app)?;
+    let open_ids = {
+        let manager = read_manager(&manager);
+        manager.list_documents()
+    };
+
+    run_blocking(move || {
+        let mut recoverable: Vec<RecoverableDocument> = Vec::new();
+        for file in list_autosaves(&dir)? {
+            let listed = recoverable.iter().any(|doc| doc.doc_id == file.doc_id);
+            if listed || open_ids.contains(&file.doc_id) {
+                continue;
+            }
+
+            let document = DrkrReader::open(&file.path).and_then(|mut r| r.read_document_json());
+            match document {
+                Ok(document) => recoverable.push(RecoverableDocument {
+                    doc_id: file.doc_id,
+                    name: document.name,
+                    saved_at: file.saved_at,
+                }),
+                Err(e) => log::warn!(
+                    "Skipping unreadable autosave {}: {}",
+                    file.path.display(),
+                    e
+                ),
+            }
+        }
+        Ok(recoverable)
+    })
+    .await
+}
+
+/// Restore a document from its newest readable autosave.
+///
+/// The result has no source path, so the user picks where to save it.
+#[tauri::command]
+pub async fn recover_autosave(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<Document> {
+    let dir = recovery_dir(&app)?;
+    let target_id = doc_id.clone();
+    let mut result = run_blocking(move || {
+        let mut last_error = AppError::DocumentNotFound(target_id.clone());
+        let files = list_autosaves(&dir)?;
+        for file in files.iter().filter(|f| f.doc_id == target_id) {
+            match DrkrReader::open(&file.path).and_then(|mut r| r.read_all()) {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    log::warn!(
+                        "Skipping unreadable autosave {}: {}",
+                        file.path.display(),
+                        e
+                    );
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    })
+    .await?;
+    refresh_linked_layers(&result.document, &mut result.layer_pixels);
+    result.document.name = format!("{} (Recovered)", result.document.name);
+
+    let mut manager = write_manager(&manager);
+
+    if manager.get(&result.document.id).is_some() {
+        return Err(AppError::InvalidOperation(format!(
+            "Document {} is already open",
+            doc_id
+        )));
+    }
+
+    let doc = manager.register_loaded_document(result.document, result.layer_pixels);
+    manager.set_extensions(&doc.id, result.extensions);
+
+    log::info!("Recovered document '{}' from autosave", doc.name);
+    Ok(doc)
+}
+
+/// Delete a document's autosaves, e.g. when the user declines to recover it
+#[tauri::command]
+pub fn discard_autosave(app: AppHandle, doc_id: String) -> AppResult<()> {
+    prune_autosaves(&recovery_dir(&app)?, &doc_id, 0)
+}
+
+/// Open a DRKR document from base64 encoded archive bytes (e.g. drag-and-drop).
+///
+/// The document has no source path, so the first save needs a path.
+#[tauri::command]
+pub async fn open_document_drkr_bytes(
+    manager: State<'_, RwLock<DocumentManager>>,
+    bytes_base64: String,
+) -> AppResult<Document> {
+    let result = decode_drkr_base64(&bytes_base64)?;
+
+    let mut manager = write_manager(&manager);
+
+    let doc = manager.register_loaded_document(result.document, result.layer_pixels);
+    manager.set_extensions(&doc.id, result.extensions);
+
+    log::info!("Opened DRKR document '{}' from memory", doc.name);
+    Ok(doc)
+}
+
+/// Serialize a document to DRKR archive bytes, returned as base64.
+///
+/// Unlike `save_document_drkr` this doesn't touch the document's source path.
+#[tauri::command]
+pub async fn save_document_drkr_bytes(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    pixel_format: Option<PixelFormat>,
+) -> AppResult<String> {
+    let data = DrkrSaveData::collect(&manager, &doc_id)?;
+
+    run_blocking(move || encode_drkr_base64(data, pixel_format.unwrap_or_default())).await
+}
+
+/// Read a DRKR archive from base64 encoded bytes
+fn decode_drkr_base64(bytes_base64: &str) -> AppResult<DrkrReadResult> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let bytes = STANDARD
+        .decode(bytes_base64)
+        .map_err(|e| AppError::InvalidOperation(format!("Invalid base64 data: {}", e)))?;
+
+    let mut reader = DrkrReader::new(Cursor::new(bytes))?;
+    let mut result = reader.read_all()?;
+    refresh_linked_layers(&result.document, &mut result.layer_pixels);
+    Ok(result)
+}
+
+/// Write a DRKR archive in memory and encode it as base64
+fn encode_drkr_base64(data: DrkrSaveData, pixel_format: PixelFormat) -> AppResult<String> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let mut writer = DrkrWriter::new(Cursor::new(Vec::new())).with_pixel_format(pixel_format);
+    data.write_to(&mut writer)?;
+    let bytes = writer.finish()?.into_inner();
+    Ok(STANDARD.encode(bytes))
+}
+
+/// Save a document as DRKR to a new path, renaming it after the file.
+///
+/// The name is applied before writing so the saved file carries it too, and
+/// restored if the write fails.
+#[tauri::command]
+pub async fn save_document_as(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    path: String,
+    pixel_format: Option<PixelFormat>,
+) -> AppResult<Document> {
+    let previous_name = rename_after_path(&mut write_manager(&manager), &doc_id, &path)?;
+
+    let result = save_document_drkr(app, manager.clone(), doc_id.clone(), path, pixel_format).await;
+
+    if result.is_err() {
+        let mut manager = write_manager(&manager);
+        manager.rename_document(&doc_id, &previous_name)?;
+    }
+
+    result
+}
+
+/// Rename a document after the file stem of `path`, returning its old name
+fn rename_after_path(manager: &mut DocumentManager, doc_id: &str, path: &str) -> AppResult<String> {
+    let name = std::path::Path::new(path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("Untitled");
+
+    let previous_name = manager
+        .get(doc_id)
+        .map(|doc| doc.name.clone())
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+    manager.rename_document(doc_id, name)?;
+    Ok(previous_name)
+}
+
+/// Detect which importer a file needs, from its content rather than its extension
+#[tauri::command]
+pub fn detect_file_format(path: String) -> AppResult<FileFormat> {
+    detect_format(&path)
+}
+
+/// Open any supported file, dispatching on its detected format
+#[tauri::command]
+pub async fn open_any(
+    manager: State<'_, RwLock<DocumentManager>>,
+    path: String,
+) -> AppResult<Document> {
+    match detect_format(&path)? {
+        FileFormat::Drkr => open_document_drkr(manager, path).await,
+        FileFormat::Svg => open_document_svg(manager, path, None, None, None).await,
+        FileFormat::Raster => open_document(manager, path).await,
+        FileFormat::Psd => Err(AppError::InvalidOperation(
+            "PSD files are not supported yet".into(),
+        )),
+    }
+}
+
+/// List all open documents, in the order they were opened
 #[tauri::command]
 pub fn list_documents(
-    manager: State<'_, Mutex<DocumentManager>>,
+    manager: State<'_, RwLock<DocumentManager>>,
 ) -> AppResult<Vec<Document>> {
-    let manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let manager = read_manager(&manager);
 
     Ok(manager.get_all_documents().into_iter().cloned().collect())
 }
@@ -196,13 +954,11 @@ pub fn list_documents(
 /// Set the source path for a document
 #[tauri::command]
 pub fn set_document_path(
-    manager: State<'_, Mutex<DocumentManager>>,
+    manager: State<'_, RwLock<DocumentManager>>,
     doc_id: String,
     path: String,
 ) -> AppResult<Document> {
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
     manager.set_source_path(&doc_id, &path)?;
     manager.get(&doc_id).cloned()
@@ -212,15 +968,413 @@ pub fn set_document_path(
 /// Rename a document
 #[tauri::command]
 pub fn rename_document(
-    manager: State<'_, Mutex<DocumentManager>>,
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
     doc_id: String,
     name: String,
 ) -> AppResult<Document> {
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
     manager.rename_document(&doc_id, &name)?;
+    emit_document_modified(&app, &manager, &doc_id);
     manager.get(&doc_id).cloned()
         .ok_or_else(|| AppError::DocumentNotFound(doc_id))
 }
+
+/// Turn blending in linear light on or off for a document
+#[tauri::command]
+pub fn set_linear_blending(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    enabled: bool,
+) -> AppResult<Document> {
+    let mut manager = write_manager(&manager);
+
+    let doc = manager.set_linear_blending(&doc_id, enabled)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(doc)
+}
+
+/// Change a document's resolution (PPI). With `resample` the pixel dimensions
+/// are scaled to keep the print size; without it the print size changes.
+#[tauri::command]
+pub fn resample_to_dpi(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    new_dpi: u32,
+    resample: bool,
+) -> AppResult<Document> {
+    let mut manager = write_manager(&manager);
+
+    let doc = manager.set_resolution(&doc_id, new_dpi, resample)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(doc)
+}
+
+/// Print size of a document at its resolution
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PhysicalSize {
+    pub width_inches: f64,
+    pub height_inches: f64,
+    pub width_cm: f64,
+    pub height_cm: f64,
+}
+
+/// Export a document's metadata and layer list (without pixels) as JSON, for
+/// syncing it to another window or peer
+#[tauri::command]
+pub fn export_document_state(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<String> {
+    let manager = read_manager(&manager);
+
+    let doc = manager
+        .get(&doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?;
+
+    serde_json::to_string(doc)
+        .map_err(|e| AppError::SerializationError(format!("Failed to serialize state: {}", e)))
+}
+
+/// Apply JSON from `export_document_state`. Layer pixels are synced
+/// separately; every layer in the state must already exist in the document.
+#[tauri::command]
+pub fn apply_document_state(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    state: String,
+) -> AppResult<Document> {
+    let state: Document = serde_json::from_str(&state)
+        .map_err(|e| AppError::SerializationError(format!("Invalid document state: {}", e)))?;
+
+    let mut manager = write_manager(&manager);
+
+    let doc = manager.apply_document_state(&doc_id, state)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(doc)
+}
+
+/// Get the print size of a document in inches and centimeters
+#[tauri::command]
+pub fn get_physical_size(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<PhysicalSize> {
+    let manager = read_manager(&manager);
+
+    let doc = manager
+        .get(&doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?;
+
+    let ppi = doc.resolution.max(1) as f64;
+    let width_inches = doc.width as f64 / ppi;
+    let height_inches = doc.height as f64 / ppi;
+    Ok(PhysicalSize {
+        width_inches,
+        height_inches,
+        width_cm: width_inches * 2.54,
+        height_cm: height_inches * 2.54,
+    })
+}
+
+/// Get the composite of all visible layers as base64 encoded RGBA.
+///
+/// The composite is cached per document and only recomputed after an edit.
+#[tauri::command]
+pub fn get_merged_pixels_base64(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<String> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let mut manager = write_manager(&manager);
+
+    let merged = manager.get_composite(&doc_id)?;
+
+    Ok(STANDARD.encode(merged.as_raw()))
+}
+
+/// Composite only the listed layers (e.g. for solo or before/after previews),
+/// as base64 encoded RGBA at document size
+#[tauri::command]
+pub fn composite_layers_subset(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_ids: Vec<String>,
+) -> AppResult<String> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let mut manager = write_manager(&manager);
+
+    let merged = manager.composite_layer_subset(&doc_id, &layer_ids)?;
+
+    Ok(STANDARD.encode(merged.as_raw()))
+}
+
+/// Per-pixel difference between two documents' composites
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentDiff {
+    /// Base64 encoded RGBA: the absolute difference of each channel, with the
+    /// alpha difference folded into the color channels and alpha kept opaque
+    pub pixels: String,
+    pub width: u32,
+    pub height: u32,
+    /// Mean absolute difference over every channel, 0-255
+    pub mean_absolute_error: f64,
+    /// Largest difference in any single channel, 0-255
+    pub max_error: u8,
+    /// Share of pixels differing in any channel, 0-100
+    pub percent_differing: f64,
+}
+
+/// Composite two documents of the same size and compare them
+#[tauri::command]
+pub fn diff_documents(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id_a: String,
+    doc_id_b: String,
+) -> AppResult<DocumentDiff> {
+    diff_composites(&mut write_manager(&manager), &doc_id_a, &doc_id_b)
+}
+
+fn diff_composites(
+    manager: &mut DocumentManager,
+    doc_id_a: &str,
+    doc_id_b: &str,
+) -> AppResult<DocumentDiff> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let a = manager.get_composite(doc_id_a)?.clone();
+    let b = manager.get_composite(doc_id_b)?;
+    if a.dimensions() != b.dimensions() {
+        return Err(AppError::InvalidOperation(format!(
+            "Document sizes differ: {}x{} and {}x{}",
+            a.width(),
+            a.height(),
+            b.width(),
+            b.height()
+        )));
+    }
+
+    let mut diff = Vec::with_capacity(a.as_raw().len());
+    let mut total_error = 0u64;
+    let mut max_error = 0u8;
+    let mut differing = 0u64;
+    for (pa, pb) in a.pixels().zip(b.pixels()) {
+        let delta = [0, 1, 2, 3].map(|c| pa[c].abs_diff(pb[c]));
+        let pixel_max = delta.iter().copied().max().unwrap_or(0);
+
+        total_error += delta.iter().map(|&d| d as u64).sum::<u64>();
+        max_error = max_error.max(pixel_max);
+        if pixel_max > 0 {
+            differing += 1;
+        }
+
+        diff.extend_from_slice(&[
+            delta[0].max(delta[3]),
+            delta[1].max(delta[3]),
+            delta[2].max(delta[3]),
+            255,
+        ]);
+    }
+
+    let pixel_count = (a.width() as u64 * a.height() as u64).max(1) as f64;
+    Ok(DocumentDiff {
+        pixels: STANDARD.encode(&diff),
+        width: a.width(),
+        height: a.height(),
+        mean_absolute_error: total_error as f64 / (pixel_count * 4.0),
+        max_error,
+        percent_differing: differing as f64 / pixel_count * 100.0,
+    })
+}
+
+/// Set the memory budget, in megabytes, for tiles of suspended documents.
+/// None disables the tile cache and resumes every suspended document.
+#[tauri::command]
+pub fn set_tile_cache_budget(
+    manager: State<'_, RwLock<DocumentManager>>,
+    budget_mb: Option<u32>,
+) -> AppResult<Option<TileCacheStats>> {
+    let mut manager = write_manager(&manager);
+
+    manager.set_tile_cache_budget(budget_mb.map(|mb| mb as usize * 1024 * 1024))?;
+    Ok(manager.tile_cache_stats())
+}
+
+/// Move a document's layer pixels into the tile cache (e.g. when its tab is
+/// in the background), letting cold tiles spill to disk
+#[tauri::command]
+pub fn suspend_document(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<()> {
+    let mut manager = write_manager(&manager);
+
+    manager.suspend_document(&doc_id)
+}
+
+/// Bring a suspended document's layer pixels back into memory for editing
+#[tauri::command]
+pub fn resume_document(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<()> {
+    let mut manager = write_manager(&manager);
+
+    manager.resume_document(&doc_id)
+}
+
+/// Memory and spill file use of the tile cache, or None if it is disabled
+#[tauri::command]
+pub fn get_tile_cache_stats(
+    manager: State<'_, RwLock<DocumentManager>>,
+) -> AppResult<Option<TileCacheStats>> {
+    let manager = read_manager(&manager);
+
+    Ok(manager.tile_cache_stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_round_trip_through_base64_archives() {
+        let mut manager = DocumentManager::new();
+        let doc = manager
+            .create_with_background("Memory", 3, 2, 72, Background::default())
+            .unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        let painted: Vec<u8> = (0..24).map(|i| i * 10).collect();
+        manager.set_layer_pixels(&layer_id, painted.clone());
+
+        let data = DrkrSaveData::from_manager(&mut manager, &doc.id).unwrap();
+        let encoded = encode_drkr_base64(data, PixelFormat::Png).unwrap();
+        let result = decode_drkr_base64(&encoded).unwrap();
+
+        let mut reopened = DocumentManager::new();
+        let doc = reopened.register_loaded_document(result.document, result.layer_pixels);
+        assert_eq!(doc.name, "Memory");
+        assert_eq!(doc.source_path, None);
+        assert_eq!(reopened.get_layer_pixels(&layer_id).unwrap(), &painted);
+    }
+
+    #[test]
+    fn bytes_that_are_not_a_base64_archive_are_rejected() {
+        assert!(matches!(
+            decode_drkr_base64("not base64!"),
+            Err(AppError::InvalidOperation(_))
+        ));
+
+        use base64::{engine::general_purpose::STANDARD, Engine};
+        assert!(decode_drkr_base64(&STANDARD.encode(b"plain text")).is_err());
+    }
+
+    #[test]
+    fn save_as_names_the_document_and_the_file_after_the_path() {
+        let mut manager = DocumentManager::new();
+        let doc = manager.create("Untitled", 2, 2, 72).unwrap();
+
+        let previous =
+            rename_after_path(&mut manager, &doc.id, "/art/Poster v2.final.drkr").unwrap();
+        assert_eq!(previous, "Untitled");
+        assert_eq!(manager.get(&doc.id).unwrap().name, "Poster v2.final");
+
+        let data = DrkrSaveData::from_manager(&mut manager, &doc.id).unwrap();
+        let encoded = encode_drkr_base64(data, PixelFormat::Png).unwrap();
+        let saved = decode_drkr_base64(&encoded).unwrap();
+        assert_eq!(saved.document.name, "Poster v2.final");
+
+        assert!(matches!(
+            rename_after_path(&mut manager, "missing", "/art/a.drkr"),
+            Err(AppError::DocumentNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn pasted_pixels_become_the_background_of_a_new_document() {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let mut manager = DocumentManager::new();
+        let pixels: Vec<u8> = (0..24).collect();
+        let doc =
+            document_from_pixels(&mut manager, "Pasted", 3, 2, &STANDARD.encode(&pixels)).unwrap();
+        assert_eq!((doc.width, doc.height), (3, 2));
+        assert_eq!(
+            manager.get_layer_pixels(&doc.layers[0].id).unwrap(),
+            &pixels
+        );
+
+        let short = STANDARD.encode(&pixels[..20]);
+        assert!(document_from_pixels(&mut manager, "Short", 3, 2, &short).is_err());
+        assert!(document_from_pixels(&mut manager, "Empty", 0, 2, "").is_err());
+        assert!(document_from_pixels(&mut manager, "Bad", 1, 1, "not base64!").is_err());
+        assert_eq!(manager.get_all_documents().len(), 1);
+    }
+
+    #[test]
+    fn diffing_documents_reports_the_changed_pixels() {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let mut manager = DocumentManager::new();
+        let create = |manager: &mut DocumentManager| {
+            manager
+                .create_with_background("Diff", 2, 1, 72, Background::default())
+                .unwrap()
+        };
+        let a = create(&mut manager);
+        let b = create(&mut manager);
+        manager.set_layer_pixels(
+            &b.layers[0].id,
+            vec![255, 255, 255, 255, 255, 155, 255, 255],
+        );
+
+        let diff = diff_composites(&mut manager, &a.id, &b.id).unwrap();
+        assert_eq!((diff.width, diff.height), (2, 1));
+        assert_eq!(diff.max_error, 100);
+        assert_eq!(diff.percent_differing, 50.0);
+        assert_eq!(diff.mean_absolute_error, 100.0 / 8.0);
+        assert_eq!(
+            STANDARD.decode(&diff.pixels).unwrap(),
+            [0, 0, 0, 255, 0, 100, 0, 255]
+        );
+
+        let same = diff_composites(&mut manager, &a.id, &a.id).unwrap();
+        assert_eq!((same.max_error, same.percent_differing), (0, 0.0));
+
+        let other = manager
+            .create_with_background("Other", 3, 1, 72, Background::default())
+            .unwrap();
+        assert!(diff_composites(&mut manager, &a.id, &other.id).is_err());
+    }
+
+    #[test]
+    fn templates_resolve_by_preset_name_or_custom_settings() {
+        let choice =
+            |json: serde_json::Value| serde_json::from_value::<TemplateChoice>(json).unwrap();
+
+        let a4 = choice(serde_json::json!("a4@300DPI")).resolve().unwrap();
+        assert_eq!((a4.width, a4.height, a4.resolution), (2480, 3508, 300));
+        let icon = choice(serde_json::json!("Icon 512")).resolve().unwrap();
+        assert!(matches!(icon.background, Background::Transparent));
+
+        let custom = choice(serde_json::json!({
+            "name": "Banner",
+            "width": 1500,
+            "height": 500,
+            "resolution": 144,
+        }))
+        .resolve()
+        .unwrap();
+        assert_eq!((custom.name.as_str(), custom.width), ("Banner", 1500));
+
+        assert!(choice(serde_json::json!("Postcard")).resolve().is_err());
+    }
+}
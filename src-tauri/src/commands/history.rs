@@ -0,0 +1,125 @@
+use super::{emit_document_modified, read_manager, write_manager};
+use crate::engine::{Document, DocumentManager, HistoryState, SnapshotInfo};
+use crate::error::AppResult;
+use std::sync::RwLock;
+use tauri::{AppHandle, State};
+
+/// Get whether undo/redo are available and the labels of each step
+#[tauri::command]
+pub fn get_history(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<HistoryState> {
+    let manager = read_manager(&manager);
+
+    manager.get_history(&doc_id)
+}
+
+/// Undo the latest step. Adding, removing and reordering layers are reverted,
+/// including the pixels of a removed layer, and so are painting, filters,
+/// fills, transforms and crops.
+#[tauri::command]
+pub fn undo(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<Document> {
+    let mut manager = write_manager(&manager);
+
+    let doc = manager.undo(&doc_id)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(doc)
+}
+
+/// Redo the latest undone step
+#[tauri::command]
+pub fn redo(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<Document> {
+    let mut manager = write_manager(&manager);
+
+    let doc = manager.redo(&doc_id)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(doc)
+}
+
+/// Change how many undo steps a document keeps.
+///
+/// Lowering the limit drops the oldest steps immediately.
+#[tauri::command]
+pub fn set_history_limit(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    limit: usize,
+) -> AppResult<HistoryState> {
+    let mut manager = write_manager(&manager);
+
+    manager.set_history_limit(&doc_id, limit)
+}
+
+/// Start a history group: every edit until `end_history_group` undoes as a
+/// single step named `name` (e.g. one slider drag). Returns the group id.
+#[tauri::command]
+pub fn begin_history_group(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    name: String,
+) -> AppResult<String> {
+    let mut manager = write_manager(&manager);
+
+    manager.begin_history_group(&doc_id, &name)
+}
+
+/// Close the open history group for a document
+#[tauri::command]
+pub fn end_history_group(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<()> {
+    let mut manager = write_manager(&manager);
+
+    manager.end_history_group(&doc_id)
+}
+
+/// Save a named snapshot of the document's current state.
+///
+/// Snapshots hold a full copy of every layer's pixels and are freed when the
+/// document is closed.
+#[tauri::command]
+pub fn create_snapshot(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    name: String,
+) -> AppResult<SnapshotInfo> {
+    let mut manager = write_manager(&manager);
+
+    manager.create_snapshot(&doc_id, &name)
+}
+
+/// List a document's snapshots, oldest first
+#[tauri::command]
+pub fn list_snapshots(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<Vec<SnapshotInfo>> {
+    let manager = read_manager(&manager);
+
+    manager.list_snapshots(&doc_id)
+}
+
+/// Restore a document to a previously saved snapshot
+#[tauri::command]
+pub fn restore_snapshot(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    snapshot_id: String,
+) -> AppResult<Document> {
+    let mut manager = write_manager(&manager);
+
+    let result = manager.restore_snapshot(&doc_id, &snapshot_id)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(result)
+}
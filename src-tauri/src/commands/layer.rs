@@ -1,57 +1,607 @@
-use crate::engine::{DocumentManager, Layer};
+use super::brush::BrushColor;
+use super::{emit_document_modified, read_manager, run_blocking, write_manager};
+use crate::engine::{
+    blend_pixel, BlendMode, Channel, DirtyRect, Document, DocumentManager, Layer, RelativePosition,
+    Selection, SelectionMask, ShapeContent, TextContent,
+};
 use crate::error::{AppError, AppResult};
-use std::sync::Mutex;
-use tauri::State;
+use crate::io::{encode_thumbnail_webp, open_image_upright};
+use image::RgbaImage;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tauri::{AppHandle, State};
 
 use crate::engine::LayerUpdate;
 
 #[tauri::command]
 pub fn add_layer(
-    manager: State<'_, Mutex<DocumentManager>>,
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
     doc_id: String,
     name: String,
 ) -> AppResult<Layer> {
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
     let doc = manager
         .get(&doc_id)
         .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?;
 
     let (width, height) = (doc.width, doc.height);
+    let before = doc.layers.clone();
+
+    let result = manager.add_layer_to_document(&doc_id, &name, width, height)?;
+    manager.push_layer_history(&doc_id, "Add Layer", before);
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(result)
+}
+
+/// Add a new top layer containing a merged copy of all visible layers
+/// (Stamp Visible), leaving the originals untouched
+#[tauri::command]
+pub fn new_layer_from_visible(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<Layer> {
+    let mut manager = write_manager(&manager);
+
+    let layer = manager.new_layer_from_visible(&doc_id)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(layer)
+}
+
+/// Add a new top layer with one channel of a layer spread to grayscale
+#[tauri::command]
+pub fn extract_channel(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    channel: Channel,
+) -> AppResult<Layer> {
+    let mut manager = write_manager(&manager);
+
+    let layer = manager.extract_channel(&doc_id, &layer_id, channel)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(layer)
+}
+
+/// Add a new top layer built from the red channels of same-sized grayscale
+/// layers; `a_layer` is optional and the result is opaque without it
+#[tauri::command]
+pub fn combine_channels(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    r_layer: String,
+    g_layer: String,
+    b_layer: String,
+    a_layer: Option<String>,
+) -> AppResult<Layer> {
+    let mut manager = write_manager(&manager);
+
+    let layer =
+        manager.combine_channels(&doc_id, &r_layer, &g_layer, &b_layer, a_layer.as_deref())?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(layer)
+}
+
+/// Add a text layer rendered from the given content
+#[tauri::command]
+pub fn add_text_layer(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    name: String,
+    content: TextContent,
+) -> AppResult<Layer> {
+    let mut manager = write_manager(&manager);
+
+    let result = manager.add_text_layer(&doc_id, &name, content)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(result)
+}
+
+/// Add a layer linked to an image file, placed with its top-left corner at (x, y).
+///
+/// The file's path is stored with the layer, and its pixels are re-read from
+/// the file whenever the document is opened; if the file is gone, the pixels
+/// saved in the document are used instead.
+#[tauri::command]
+pub async fn link_image_layer(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    path: String,
+    x: i32,
+    y: i32,
+) -> AppResult<Layer> {
+    // Decode before taking the lock
+    let image = open_image_upright(&path)?.to_rgba8();
+    let name = std::path::Path::new(&path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("Linked Image")
+        .to_string();
+
+    let mut manager = write_manager(&manager);
+
+    let layer = manager.add_linked_image_layer(&doc_id, &name, &path, image, x, y)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(layer)
+}
+
+/// Update a text layer's text, font, size or color and re-render it
+#[tauri::command]
+pub fn set_text_layer(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    content: TextContent,
+) -> AppResult<Layer> {
+    let mut manager = write_manager(&manager);
+
+    let layer = manager
+        .get(&doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?
+        .get_layer(&layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.clone()))?;
+
+    if layer.pixels_locked() {
+        return Err(AppError::InvalidOperation("Layer is locked".into()));
+    }
+
+    let result = manager.set_text_content(&doc_id, &layer_id, content)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(result)
+}
+
+/// Add a shape layer rendered from the given geometry and style
+#[tauri::command]
+pub fn add_shape_layer(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    name: String,
+    content: ShapeContent,
+) -> AppResult<Layer> {
+    let mut manager = write_manager(&manager);
+
+    let result = manager.add_shape_layer(&doc_id, &name, content)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(result)
+}
+
+/// Update a shape layer's geometry, fill or stroke and re-render it
+#[tauri::command]
+pub fn set_shape_layer(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    content: ShapeContent,
+) -> AppResult<Layer> {
+    let mut manager = write_manager(&manager);
+
+    let layer = manager
+        .get(&doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?
+        .get_layer(&layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.clone()))?;
+
+    if layer.pixels_locked() {
+        return Err(AppError::InvalidOperation("Layer is locked".into()));
+    }
+
+    let result = manager.set_shape_content(&doc_id, &layer_id, content)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(result)
+}
 
-    manager.add_layer_to_document(&doc_id, &name, width, height)
+/// Bake a text, shape or linked image layer into a plain raster layer
+#[tauri::command]
+pub fn rasterize_layer(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+) -> AppResult<Layer> {
+    let mut manager = write_manager(&manager);
+
+    let layer = manager.rasterize_layer(&doc_id, &layer_id)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(layer)
+}
+
+/// Fill a layer, or the selected part of it, with a solid color.
+///
+/// Returns the document-space rectangle that changed, or None if the selection
+/// doesn't touch the layer.
+#[tauri::command]
+pub fn fill_layer(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    color: BrushColor,
+    selection: Option<Selection>,
+    blend_mode: BlendMode,
+) -> AppResult<Option<DirtyRect>> {
+    let mut manager = write_manager(&manager);
+
+    let region = fill_layer_pixels(
+        &mut manager,
+        &doc_id,
+        &layer_id,
+        &color,
+        selection,
+        &blend_mode,
+    )?;
+
+    if region.is_some() {
+        emit_document_modified(&app, &manager, &doc_id);
+    }
+    Ok(region)
+}
+
+/// Fill `layer_id` with `color` and store one undo step
+fn fill_layer_pixels(
+    manager: &mut DocumentManager,
+    doc_id: &str,
+    layer_id: &str,
+    color: &BrushColor,
+    selection: Option<Selection>,
+    blend_mode: &BlendMode,
+) -> AppResult<Option<DirtyRect>> {
+    let doc = manager
+        .get(doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+    let layer = doc
+        .get_layer(layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+    if layer.pixels_locked() {
+        return Err(AppError::InvalidOperation("Layer is locked".into()));
+    }
+
+    let lock_alpha = layer.lock_alpha;
+    let layer_rect = DirtyRect {
+        x: layer.x,
+        y: layer.y,
+        width: layer.width,
+        height: layer.height,
+    };
+    let mask = selection.map(|s| SelectionMask::from_selection(&s, doc.width, doc.height));
+
+    // Only visit the part of the layer the selection covers
+    let region = match &mask {
+        Some(mask) => match mask.bounds().and_then(|b| b.intersect(&layer_rect)) {
+            Some(region) => region,
+            None => return Ok(None),
+        },
+        None => layer_rect,
+    };
+
+    let mut pixels = manager
+        .get_layer_pixels(layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?
+        .clone();
+
+    let alpha = (color.a.clamp(0.0, 1.0) * 255.0).round() as u32;
+
+    for y in region.y..region.y + region.height as i32 {
+        for x in region.x..region.x + region.width as i32 {
+            let coverage = mask.as_ref().map_or(255, |m| m.coverage(x, y)) as u32;
+            let src_a = ((alpha * coverage + 127) / 255) as u8;
+            if src_a == 0 {
+                continue;
+            }
+
+            let idx = (((y - layer_rect.y) as u32 * layer_rect.width + (x - layer_rect.x) as u32)
+                * 4) as usize;
+            let pixel = &mut pixels[idx..idx + 4];
+            let original_alpha = pixel[3];
+
+            if lock_alpha && original_alpha == 0 {
+                continue;
+            }
+
+            blend_pixel(pixel, [color.r, color.g, color.b, src_a], blend_mode);
+
+            if lock_alpha {
+                pixel[3] = original_alpha;
+            }
+        }
+    }
+
+    let before = manager.capture_pixel_region(doc_id, layer_id, &region)?;
+    manager.set_layer_pixels_in_region(doc_id, layer_id, pixels, &region)?;
+    manager.push_pixel_history(doc_id, "Fill", before);
+
+    Ok(Some(region))
+}
+
+/// Make a layer fully transparent, or only the selected part of it.
+///
+/// Partially selected pixels are cleared in proportion to their coverage.
+/// Returns the document-space rectangle that changed.
+#[tauri::command]
+pub fn clear_layer(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    selection: Option<Selection>,
+) -> AppResult<Option<DirtyRect>> {
+    let mut manager = write_manager(&manager);
+
+    let region = clear_layer_pixels(&mut manager, &doc_id, &layer_id, selection)?;
+
+    if region.is_some() {
+        emit_document_modified(&app, &manager, &doc_id);
+    }
+    Ok(region)
+}
+
+/// Clear `layer_id` and store one undo step
+fn clear_layer_pixels(
+    manager: &mut DocumentManager,
+    doc_id: &str,
+    layer_id: &str,
+    selection: Option<Selection>,
+) -> AppResult<Option<DirtyRect>> {
+    let doc = manager
+        .get(doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+    let layer = doc
+        .get_layer(layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+    if layer.pixels_locked() || layer.lock_alpha {
+        return Err(AppError::InvalidOperation("Layer is locked".into()));
+    }
+
+    let layer_rect = DirtyRect {
+        x: layer.x,
+        y: layer.y,
+        width: layer.width,
+        height: layer.height,
+    };
+    let mask = selection.map(|s| SelectionMask::from_selection(&s, doc.width, doc.height));
+
+    let region = match &mask {
+        Some(mask) => match mask.bounds().and_then(|b| b.intersect(&layer_rect)) {
+            Some(region) => region,
+            None => return Ok(None),
+        },
+        None => layer_rect,
+    };
+
+    let mut pixels = manager
+        .get_layer_pixels(layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?
+        .clone();
+
+    match &mask {
+        Some(mask) => {
+            for y in region.y..region.y + region.height as i32 {
+                for x in region.x..region.x + region.width as i32 {
+                    let coverage = mask.coverage(x, y) as u32;
+                    if coverage == 0 {
+                        continue;
+                    }
+
+                    let idx = (((y - layer_rect.y) as u32 * layer_rect.width
+                        + (x - layer_rect.x) as u32)
+                        * 4) as usize;
+                    let pixel = &mut pixels[idx..idx + 4];
+
+                    if coverage == 255 {
+                        pixel.copy_from_slice(&[0, 0, 0, 0]);
+                    } else {
+                        pixel[3] = (pixel[3] as u32 * (255 - coverage) / 255) as u8;
+                    }
+                }
+            }
+        }
+        None => pixels.fill(0),
+    }
+
+    let before = manager.capture_pixel_region(doc_id, layer_id, &region)?;
+    manager.set_layer_pixels_in_region(doc_id, layer_id, pixels, &region)?;
+    manager.push_pixel_history(doc_id, "Clear", before);
+
+    Ok(Some(region))
+}
+
+/// Place an image file onto an existing layer with its top-left corner at
+/// `x`/`y` (document space), optionally resized to `size` first.
+///
+/// Returns the document-space rectangle that changed.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn place_image_on_layer(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    path: String,
+    x: i32,
+    y: i32,
+    size: Option<(u32, u32)>,
+    blend_mode: BlendMode,
+) -> AppResult<Option<DirtyRect>> {
+    // Decode before taking the lock
+    let img = open_image_upright(&path)?;
+
+    let image = match size {
+        Some((0, _)) | Some((_, 0)) => {
+            return Err(AppError::InvalidOperation(
+                "Placed image size must be non-zero".into(),
+            ))
+        }
+        Some((width, height)) => img
+            .resize_exact(width, height, image::imageops::FilterType::Lanczos3)
+            .to_rgba8(),
+        None => img.to_rgba8(),
+    };
+
+    let mut manager = write_manager(&manager);
+
+    let region = place_pixels(
+        &mut manager,
+        &doc_id,
+        &layer_id,
+        &image,
+        (x, y),
+        &blend_mode,
+    )?;
+
+    if region.is_some() {
+        emit_document_modified(&app, &manager, &doc_id);
+    }
+    Ok(region)
+}
+
+/// Blend `image` onto a layer with its top-left corner at `(x, y)` and store
+/// one undo step
+fn place_pixels(
+    manager: &mut DocumentManager,
+    doc_id: &str,
+    layer_id: &str,
+    image: &RgbaImage,
+    (x, y): (i32, i32),
+    blend_mode: &BlendMode,
+) -> AppResult<Option<DirtyRect>> {
+    let layer = manager
+        .get(doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?
+        .get_layer(layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+    if layer.pixels_locked() {
+        return Err(AppError::InvalidOperation("Layer is locked".into()));
+    }
+
+    let lock_alpha = layer.lock_alpha;
+    let layer_rect = DirtyRect {
+        x: layer.x,
+        y: layer.y,
+        width: layer.width,
+        height: layer.height,
+    };
+    let placed_rect = DirtyRect {
+        x,
+        y,
+        width: image.width(),
+        height: image.height(),
+    };
+
+    let region = match placed_rect.intersect(&layer_rect) {
+        Some(region) => region,
+        None => return Ok(None),
+    };
+
+    let mut pixels = manager
+        .get_layer_pixels(layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?
+        .clone();
+
+    for doc_y in region.y..region.y + region.height as i32 {
+        for doc_x in region.x..region.x + region.width as i32 {
+            let src = image.get_pixel((doc_x - x) as u32, (doc_y - y) as u32).0;
+            if src[3] == 0 {
+                continue;
+            }
+
+            let idx = (((doc_y - layer_rect.y) as u32 * layer_rect.width
+                + (doc_x - layer_rect.x) as u32)
+                * 4) as usize;
+            let pixel = &mut pixels[idx..idx + 4];
+            let original_alpha = pixel[3];
+
+            if lock_alpha && original_alpha == 0 {
+                continue;
+            }
+
+            blend_pixel(pixel, src, blend_mode);
+
+            if lock_alpha {
+                pixel[3] = original_alpha;
+            }
+        }
+    }
+
+    let before = manager.capture_pixel_region(doc_id, layer_id, &region)?;
+    manager.set_layer_pixels_in_region(doc_id, layer_id, pixels, &region)?;
+    manager.push_pixel_history(doc_id, "Place Image", before);
+
+    Ok(Some(region))
 }
 
 #[tauri::command]
 pub fn remove_layer(
-    manager: State<'_, Mutex<DocumentManager>>,
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
     doc_id: String,
     layer_id: String,
 ) -> AppResult<()> {
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
     let doc = manager
         .get_mut(&doc_id)
         .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?;
 
+    let before = doc.layers.clone();
     doc.remove_layer(&layer_id)?;
+    manager.push_layer_history(&doc_id, "Delete Layer", before);
+    emit_document_modified(&app, &manager, &doc_id);
     Ok(())
 }
 
+/// Delete every fully transparent layer (keeping at least one), returning the
+/// ids of the layers removed
+#[tauri::command]
+pub fn prune_empty_layers(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<Vec<String>> {
+    let mut manager = write_manager(&manager);
+
+    let removed = manager.prune_empty_layers(&doc_id)?;
+    if !removed.is_empty() {
+        emit_document_modified(&app, &manager, &doc_id);
+    }
+    Ok(removed)
+}
+
+/// Merge all visible layers into a single background layer
+#[tauri::command]
+pub fn flatten_document(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<Document> {
+    let mut manager = write_manager(&manager);
+
+    let doc = manager.flatten_document(&doc_id)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(doc)
+}
+
 #[tauri::command]
 pub fn update_layer(
-    manager: State<'_, Mutex<DocumentManager>>,
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
     doc_id: String,
     layer_id: String,
     update: LayerUpdate,
 ) -> AppResult<Layer> {
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
     let doc = manager
         .get_mut(&doc_id)
@@ -61,37 +611,130 @@ pub fn update_layer(
         .get_layer_mut(&layer_id)
         .ok_or_else(|| AppError::LayerNotFound(layer_id.clone()))?;
 
-    layer.apply_update(update);
+    layer.apply_update(update)?;
+    let layer = layer.clone();
+    doc.mark_modified();
+
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(layer)
+}
+
+/// Nudge a layer by `dx`/`dy`. With `bake` the pixels are shifted within the
+/// layer's buffer rather than moving the layer itself.
+#[tauri::command]
+pub fn move_layer(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    dx: i32,
+    dy: i32,
+    bake: bool,
+) -> AppResult<Layer> {
+    let mut manager = write_manager(&manager);
+
+    let layer = manager.move_layer(&doc_id, &layer_id, dx, dy, bake)?;
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(layer)
+}
+
+/// Show only one layer in the composite, ignoring visibility, or pass null to
+/// go back to normal. Doesn't modify the document.
+#[tauri::command]
+pub fn set_isolated_layer(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: Option<String>,
+) -> AppResult<()> {
+    let mut manager = write_manager(&manager);
 
-    Ok(layer.clone())
+    manager.set_isolated_layer(&doc_id, layer_id.as_deref())
 }
 
 #[tauri::command]
 pub fn reorder_layers(
-    manager: State<'_, Mutex<DocumentManager>>,
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
     doc_id: String,
     from_index: usize,
     to_index: usize,
 ) -> AppResult<()> {
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
     let doc = manager
         .get_mut(&doc_id)
         .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?;
 
-    doc.reorder_layers(from_index, to_index)
+    let before = doc.layers.clone();
+    doc.reorder_layers(from_index, to_index)?;
+    manager.push_layer_history(&doc_id, "Reorder Layers", before);
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(())
+}
+
+/// Move a layer before or after another layer, identified by id rather than
+/// by index
+#[tauri::command]
+pub fn move_layer_relative(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    position: RelativePosition,
+    anchor_id: String,
+) -> AppResult<()> {
+    let mut manager = write_manager(&manager);
+
+    let doc = manager
+        .get_mut(&doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?;
+
+    let before = doc.layers.clone();
+    doc.move_layer_relative(&layer_id, position, &anchor_id)?;
+    manager.push_layer_history(&doc_id, "Reorder Layers", before);
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(())
+}
+
+/// Get a layer's current index in the bottom-to-top layer list, as expected by
+/// `reorder_layers`
+#[tauri::command]
+pub fn get_layer_index(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+) -> AppResult<usize> {
+    let manager = read_manager(&manager);
+
+    let doc = manager
+        .get(&doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?;
+
+    doc.layer_index(&layer_id)
+        .ok_or(AppError::LayerNotFound(layer_id))
+}
+
+/// Get the ids of a document's layers from bottom to top
+#[tauri::command]
+pub fn get_layers_ordered(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<Vec<String>> {
+    let manager = read_manager(&manager);
+
+    let doc = manager
+        .get(&doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?;
+
+    Ok(doc.layers.iter().map(|l| l.id.clone()).collect())
 }
 
 #[tauri::command]
 pub fn get_layer_pixels(
-    manager: State<'_, Mutex<DocumentManager>>,
+    manager: State<'_, RwLock<DocumentManager>>,
     layer_id: String,
 ) -> AppResult<Vec<u8>> {
-    let manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
     manager
         .get_layer_pixels(&layer_id)
@@ -99,17 +742,84 @@ pub fn get_layer_pixels(
         .ok_or_else(|| AppError::LayerNotFound(layer_id))
 }
 
+/// Get the bounding box of a layer's non-transparent content as `[x, y, width, height]`
+/// in layer-local coordinates, or null if the layer is entirely transparent
+#[tauri::command]
+pub fn get_layer_content_bounds(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+) -> AppResult<Option<(u32, u32, u32, u32)>> {
+    let mut manager = write_manager(&manager);
+
+    manager.get_layer_content_bounds(&doc_id, &layer_id)
+}
+
+/// Eyedropper: get the RGBA color at a document-space point.
+///
+/// Samples `layer_id`, or the merged visible composite when `sample_merged`
+/// is set. Returns null outside the canvas.
+#[tauri::command]
+pub fn sample_color(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    x: i32,
+    y: i32,
+    sample_merged: bool,
+) -> AppResult<Option<[u8; 4]>> {
+    let mut manager = write_manager(&manager);
+
+    manager.sample_color(&doc_id, &layer_id, x, y, sample_merged)
+}
+
+/// Convert a document-space point to `layer_id`'s local pixel coordinates
+#[tauri::command]
+pub fn doc_to_layer_coords(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    x: f64,
+    y: f64,
+) -> AppResult<(f64, f64)> {
+    let manager = read_manager(&manager);
+
+    let layer = manager
+        .get(&doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?
+        .get_layer(&layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.clone()))?;
+    Ok(layer.doc_to_layer(x, y))
+}
+
+/// Convert a point in `layer_id`'s local pixel coordinates to document space
+#[tauri::command]
+pub fn layer_to_doc_coords(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    x: f64,
+    y: f64,
+) -> AppResult<(f64, f64)> {
+    let manager = read_manager(&manager);
+
+    let layer = manager
+        .get(&doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?
+        .get_layer(&layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.clone()))?;
+    Ok(layer.layer_to_doc(x, y))
+}
+
 /// Get layer pixels as base64 encoded string (more efficient for IPC)
 #[tauri::command]
 pub fn get_layer_pixels_base64(
-    manager: State<'_, Mutex<DocumentManager>>,
+    manager: State<'_, RwLock<DocumentManager>>,
     layer_id: String,
 ) -> AppResult<String> {
     use base64::{engine::general_purpose::STANDARD, Engine};
 
-    let manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
     let pixels = manager
         .get_layer_pixels(&layer_id)
@@ -118,10 +828,58 @@ pub fn get_layer_pixels_base64(
     Ok(STANDARD.encode(pixels))
 }
 
+/// Get a base64 WebP preview of every layer, keyed by layer id, each scaled
+/// to fit within `max_size` pixels (for the layers panel)
+#[tauri::command]
+pub async fn get_layer_thumbnails(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    max_size: u32,
+) -> AppResult<HashMap<String, String>> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    if max_size == 0 {
+        return Err(AppError::InvalidOperation(
+            "Thumbnail size must be greater than zero".into(),
+        ));
+    }
+
+    let layers: Vec<(String, u32, u32, Vec<u8>)> = {
+        let mut manager = write_manager(&manager);
+
+        let layers = manager
+            .get(&doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?
+            .layers
+            .clone();
+
+        layers
+            .iter()
+            .filter_map(|layer| {
+                let pixels = manager.get_layer_pixels(&layer.id)?.clone();
+                Some((layer.id.clone(), layer.width, layer.height, pixels))
+            })
+            .collect()
+    };
+
+    // Scaling and encoding every layer is slow, so it runs off the async runtime
+    run_blocking(move || {
+        layers
+            .into_iter()
+            .map(|(layer_id, width, height, pixels)| {
+                let webp = encode_thumbnail_webp(&pixels, width, height, max_size)?;
+                Ok((layer_id, STANDARD.encode(webp)))
+            })
+            .collect()
+    })
+    .await
+}
+
 /// Set layer pixels from base64 encoded string (for syncing frontend to backend)
 #[tauri::command]
 pub fn set_layer_pixels_base64(
-    manager: State<'_, Mutex<DocumentManager>>,
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
     layer_id: String,
     pixels_base64: String,
 ) -> AppResult<()> {
@@ -131,10 +889,245 @@ pub fn set_layer_pixels_base64(
         AppError::InvalidOperation(format!("Invalid base64 data: {}", e))
     })?;
 
-    let mut manager = manager.lock().map_err(|_| {
-        AppError::InvalidOperation("Failed to acquire document manager lock".into())
-    })?;
+    let mut manager = write_manager(&manager);
 
     manager.set_layer_pixels(&layer_id, pixels);
+
+    if let Some(doc_id) = manager.find_layer_document(&layer_id) {
+        emit_document_modified(&app, &manager, &doc_id);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Background;
+
+    fn color(r: u8, g: u8, b: u8, a: f64) -> BrushColor {
+        BrushColor { r, g, b, a }
+    }
+
+    #[test]
+    fn an_opaque_fill_sets_every_pixel_to_the_color() {
+        let mut manager = DocumentManager::new();
+        let doc = manager
+            .create_with_background("Test", 4, 3, 72, Background::default())
+            .unwrap();
+        let layer_id = doc.layers[0].id.clone();
+
+        let region = fill_layer_pixels(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &color(10, 20, 30, 1.0),
+            None,
+            &BlendMode::Normal,
+        )
+        .unwrap();
+        assert_eq!(region.map(|r| (r.width, r.height)), Some((4, 3)));
+        let pixels = manager.get_layer_pixels(&layer_id).unwrap();
+        assert!(pixels.chunks_exact(4).all(|p| p == [10, 20, 30, 255]));
+
+        manager.undo(&doc.id).unwrap();
+        let pixels = manager.get_layer_pixels(&layer_id).unwrap();
+        assert!(pixels.chunks_exact(4).all(|p| p == [255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn a_selection_limits_the_fill() {
+        let mut manager = DocumentManager::new();
+        let doc = manager.create("Test", 4, 4, 72).unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        let selection = Selection::Rectangle {
+            x: 1,
+            y: 1,
+            width: 2,
+            height: 2,
+        };
+
+        fill_layer_pixels(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &color(255, 0, 0, 1.0),
+            Some(selection),
+            &BlendMode::Normal,
+        )
+        .unwrap();
+        let pixels = manager.get_layer_pixels(&layer_id).unwrap();
+        for (i, pixel) in pixels.chunks_exact(4).enumerate() {
+            let (x, y) = (i % 4, i / 4);
+            let inside = (1..3).contains(&x) && (1..3).contains(&y);
+            let expected = if inside { [255, 0, 0, 255] } else { [0; 4] };
+            assert_eq!(pixel, expected, "pixel ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn fills_respect_the_layer_locks() {
+        let mut manager = DocumentManager::new();
+        let doc = manager.create("Test", 2, 1, 72).unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        manager.set_layer_pixels(&layer_id, vec![0, 0, 0, 0, 255, 255, 255, 100]);
+
+        let layer = manager
+            .get_mut(&doc.id)
+            .unwrap()
+            .get_layer_mut(&layer_id)
+            .unwrap();
+        layer.lock_alpha = true;
+        let blue = color(0, 0, 255, 1.0);
+        fill_layer_pixels(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &blue,
+            None,
+            &BlendMode::Normal,
+        )
+        .unwrap();
+        assert_eq!(
+            manager.get_layer_pixels(&layer_id).unwrap(),
+            &[0, 0, 0, 0, 0, 0, 255, 100]
+        );
+
+        let layer = manager
+            .get_mut(&doc.id)
+            .unwrap()
+            .get_layer_mut(&layer_id)
+            .unwrap();
+        layer.locked = true;
+        let result = fill_layer_pixels(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &blue,
+            None,
+            &BlendMode::Normal,
+        );
+        assert!(matches!(result, Err(AppError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn clearing_a_selection_leaves_the_rest_and_undoes() {
+        let mut manager = DocumentManager::new();
+        let doc = manager
+            .create_with_background("Test", 4, 1, 72, Background::default())
+            .unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        let selection = Selection::Rectangle {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+
+        clear_layer_pixels(&mut manager, &doc.id, &layer_id, Some(selection)).unwrap();
+        let alphas: Vec<u8> = manager.get_layer_pixels(&layer_id).unwrap()[3..]
+            .iter()
+            .step_by(4)
+            .copied()
+            .collect();
+        assert_eq!(alphas, [0, 0, 255, 255]);
+
+        clear_layer_pixels(&mut manager, &doc.id, &layer_id, None).unwrap();
+        assert!(manager
+            .get_layer_pixels(&layer_id)
+            .unwrap()
+            .iter()
+            .all(|&b| b == 0));
+
+        // Back-to-back clears coalesce into one undo step
+        manager.undo(&doc.id).unwrap();
+        let pixels = manager.get_layer_pixels(&layer_id).unwrap();
+        assert!(pixels.iter().all(|&b| b == 255));
+    }
+
+    #[test]
+    fn clearing_needs_unlocked_pixels_and_transparency() {
+        let mut manager = DocumentManager::new();
+        let doc = manager
+            .create_with_background("Test", 2, 2, 72, Background::default())
+            .unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        let layer = manager
+            .get_mut(&doc.id)
+            .unwrap()
+            .get_layer_mut(&layer_id)
+            .unwrap();
+        layer.lock_alpha = true;
+
+        let result = clear_layer_pixels(&mut manager, &doc.id, &layer_id, None);
+        assert!(matches!(result, Err(AppError::InvalidOperation(_))));
+        assert!(manager
+            .get_layer_pixels(&layer_id)
+            .unwrap()
+            .iter()
+            .all(|&b| b == 255));
+    }
+
+    #[test]
+    fn placed_images_are_clipped_to_the_layer_and_undoable() {
+        let mut manager = DocumentManager::new();
+        let doc = manager.create("Test", 3, 3, 72).unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        let image = RgbaImage::from_fn(2, 2, |x, _| {
+            if x == 0 {
+                image::Rgba([0, 255, 0, 255])
+            } else {
+                image::Rgba([9, 9, 9, 0])
+            }
+        });
+
+        // Only the right column of the image lands on the layer, and it's transparent
+        let region = place_pixels(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &image,
+            (-1, 1),
+            &BlendMode::Normal,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            (region.x, region.y, region.width, region.height),
+            (0, 1, 1, 2)
+        );
+        assert!(manager
+            .get_layer_pixels(&layer_id)
+            .unwrap()
+            .iter()
+            .all(|&b| b == 0));
+
+        place_pixels(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &image,
+            (2, 2),
+            &BlendMode::Normal,
+        )
+        .unwrap();
+        let pixels = manager.get_layer_pixels(&layer_id).unwrap();
+        assert_eq!(pixels[(2 * 3 + 2) * 4..], [0, 255, 0, 255]);
+
+        let outside = place_pixels(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &image,
+            (5, 5),
+            &BlendMode::Normal,
+        );
+        assert!(outside.unwrap().is_none());
+
+        manager.undo(&doc.id).unwrap();
+        assert!(manager
+            .get_layer_pixels(&layer_id)
+            .unwrap()
+            .iter()
+            .all(|&b| b == 0));
+    }
+}
@@ -1,5 +1,162 @@
 pub mod brush;
+pub mod capabilities;
 pub mod crop;
 pub mod document;
 pub mod filters;
+pub mod history;
 pub mod layer;
+pub mod selection;
+pub mod transform;
+
+use crate::engine::DocumentManager;
+use crate::error::{AppError, AppResult};
+use serde::Serialize;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tauri::{AppHandle, Manager};
+
+/// Lock the document manager for reading.
+///
+/// A lock poisoned by a panic in another command is recovered, with a warning,
+/// so one failed operation doesn't make every later command fail.
+pub fn read_manager(manager: &RwLock<DocumentManager>) -> RwLockReadGuard<'_, DocumentManager> {
+    manager.read().unwrap_or_else(|poisoned| {
+        log::warn!("Recovering document manager lock poisoned by a panic");
+        manager.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Lock the document manager for writing, recovering from poisoning like
+/// [`read_manager`]
+pub fn write_manager(manager: &RwLock<DocumentManager>) -> RwLockWriteGuard<'_, DocumentManager> {
+    manager.write().unwrap_or_else(|poisoned| {
+        log::warn!("Recovering document manager lock poisoned by a panic");
+        manager.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Payload of the `document-modified` and `document-saved` events
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentChangedEvent {
+    pub doc_id: String,
+    pub modified_at: i64,
+}
+
+/// Tell the frontend a document has unsaved changes
+pub fn emit_document_modified(app: &AppHandle, manager: &DocumentManager, doc_id: &str) {
+    emit_document_event(app, manager, doc_id, "document-modified");
+}
+
+/// Tell the frontend a document has just been written to disk
+pub fn emit_document_saved(app: &AppHandle, manager: &DocumentManager, doc_id: &str) {
+    emit_document_event(app, manager, doc_id, "document-saved");
+}
+
+impl DocumentChangedEvent {
+    /// The payload for a document, or None if it's no longer open
+    fn for_document(manager: &DocumentManager, doc_id: &str) -> Option<Self> {
+        manager.get(doc_id).map(|doc| Self {
+            doc_id: doc_id.to_string(),
+            modified_at: doc.modified_at,
+        })
+    }
+}
+
+fn emit_document_event(app: &AppHandle, manager: &DocumentManager, doc_id: &str, event: &str) {
+    let Some(payload) = DocumentChangedEvent::for_document(manager, doc_id) else {
+        return;
+    };
+
+    if let Err(e) = app.emit_all(event, payload) {
+        log::warn!("Failed to emit {} for document {}: {}", event, doc_id, e);
+    }
+}
+
+/// Run CPU-heavy work (encoding, quantizing) on the blocking thread pool so
+/// async commands don't stall the runtime while it runs
+pub async fn run_blocking<T, F>(task: F) -> AppResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> AppResult<T> + Send + 'static,
+{
+    tauri::async_runtime::spawn_blocking(task)
+        .await
+        .map_err(|e| AppError::InvalidOperation(format!("Background task failed: {}", e)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn change_events_carry_the_latest_modification_time() {
+        let mut manager = DocumentManager::new();
+        let doc = manager.create("Test", 1, 1, 72).unwrap();
+        manager.get_mut(&doc.id).unwrap().modified_at = 0;
+
+        manager.rename_document(&doc.id, "Renamed").unwrap();
+        let event = DocumentChangedEvent::for_document(&manager, &doc.id).unwrap();
+        assert!(event.modified_at > 0);
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({ "docId": doc.id, "modifiedAt": event.modified_at })
+        );
+
+        assert!(DocumentChangedEvent::for_document(&manager, "missing").is_none());
+    }
+
+    #[test]
+    fn blocking_work_runs_off_the_calling_thread() {
+        let caller = std::thread::current().id();
+        let worker =
+            tauri::async_runtime::block_on(run_blocking(move || Ok(std::thread::current().id())))
+                .unwrap();
+        assert_ne!(worker, caller);
+
+        let failed: AppResult<()> = tauri::async_runtime::block_on(run_blocking(|| {
+            Err(AppError::InvalidOperation("encode failed".into()))
+        }));
+        assert!(matches!(failed, Err(AppError::InvalidOperation(m)) if m == "encode failed"));
+    }
+
+    #[test]
+    fn readers_share_the_manager_lock() {
+        let lock = RwLock::new(DocumentManager::new());
+        write_manager(&lock).create("Test", 1, 1, 72).unwrap();
+
+        let reader = read_manager(&lock);
+        std::thread::scope(|scope| {
+            let other = scope.spawn(|| lock.try_read().map(|m| m.get_all_documents().len()).ok());
+            assert_eq!(other.join().unwrap(), Some(1));
+        });
+        assert!(lock.try_write().is_err());
+        drop(reader);
+        assert!(lock.try_write().is_ok());
+    }
+
+    #[test]
+    fn a_poisoned_manager_lock_is_recovered() {
+        let lock = RwLock::new(DocumentManager::new());
+        let doc = write_manager(&lock).create("Test", 1, 1, 72).unwrap();
+
+        // A command that panics while holding the lock poisons it
+        let _ = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _manager = write_manager(&lock);
+                    std::panic::resume_unwind(Box::new("command failed"));
+                })
+                .join()
+        });
+        assert!(lock.is_poisoned());
+
+        assert!(read_manager(&lock).get(&doc.id).is_some());
+        assert!(!lock.is_poisoned());
+        write_manager(&lock)
+            .rename_document(&doc.id, "Renamed")
+            .unwrap();
+        assert_eq!(read_manager(&lock).get(&doc.id).unwrap().name, "Renamed");
+    }
+}
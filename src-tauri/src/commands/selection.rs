@@ -0,0 +1,59 @@
+use super::write_manager;
+use crate::engine::{DirtyRect, DocumentManager, Selection, SelectionOp, SelectionTransform};
+use crate::error::AppResult;
+use std::sync::RwLock;
+use tauri::State;
+
+/// Select a layer's opaque pixels, replacing the active selection.
+///
+/// Coverage follows the layer's alpha, so soft edges stay partially selected.
+/// Returns the bounds of the new selection, or None if the layer is empty.
+#[tauri::command]
+pub fn selection_from_layer_alpha(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+) -> AppResult<Option<DirtyRect>> {
+    let mut manager = write_manager(&manager);
+
+    manager.selection_from_layer_alpha(&doc_id, &layer_id)
+}
+
+/// Clear the active selection
+#[tauri::command]
+pub fn clear_selection(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+) -> AppResult<()> {
+    let mut manager = write_manager(&manager);
+
+    manager.clear_selection(&doc_id)
+}
+
+/// Combine a selection shape with the active selection (replace, add,
+/// subtract or intersect). Returns the bounds of the result, or None if
+/// nothing is left selected.
+#[tauri::command]
+pub fn modify_selection(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    op: SelectionOp,
+    selection: Selection,
+) -> AppResult<Option<DirtyRect>> {
+    let mut manager = write_manager(&manager);
+
+    manager.modify_selection(&doc_id, op, &selection)
+}
+
+/// Grow, shrink, feather or invert the active selection. Returns the bounds
+/// of the result, or None if nothing is left selected.
+#[tauri::command]
+pub fn transform_selection(
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    op: SelectionTransform,
+) -> AppResult<Option<DirtyRect>> {
+    let mut manager = write_manager(&manager);
+
+    manager.transform_selection(&doc_id, op)
+}
@@ -0,0 +1,46 @@
+use super::{emit_document_modified, write_manager};
+use crate::engine::{perspective_warp, DocumentManager, Layer};
+use crate::error::{AppError, AppResult};
+use std::sync::RwLock;
+use tauri::{AppHandle, State};
+
+/// Free-transform a layer by moving its four corners.
+///
+/// `corners` are the new document-space positions of the layer's top-left,
+/// top-right, bottom-right and bottom-left corners. The layer is resampled
+/// with bilinear filtering into the quad's bounding box.
+#[tauri::command]
+pub fn perspective_transform_layer(
+    app: AppHandle,
+    manager: State<'_, RwLock<DocumentManager>>,
+    doc_id: String,
+    layer_id: String,
+    corners: [(f64, f64); 4],
+) -> AppResult<Layer> {
+    let mut manager = write_manager(&manager);
+
+    let layer = manager
+        .get(&doc_id)
+        .ok_or_else(|| AppError::DocumentNotFound(doc_id.clone()))?
+        .get_layer(&layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.clone()))?;
+
+    if layer.pixels_locked() || layer.position_locked() {
+        return Err(AppError::InvalidOperation("Layer is locked".into()));
+    }
+
+    let (width, height) = (layer.width, layer.height);
+    let before = manager.capture_pixels(&doc_id, std::slice::from_ref(&layer_id))?;
+
+    let pixels = manager
+        .get_layer_pixels(&layer_id)
+        .ok_or_else(|| AppError::LayerNotFound(layer_id.clone()))?;
+
+    let (bounds, warped) = perspective_warp(pixels, width, height, &corners)?;
+
+    let layer = manager.replace_layer_raster(&doc_id, &layer_id, bounds, warped)?;
+    manager.push_pixel_history(&doc_id, "Perspective Transform", before);
+
+    emit_document_modified(&app, &manager, &doc_id);
+    Ok(layer)
+}
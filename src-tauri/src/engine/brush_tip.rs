@@ -0,0 +1,80 @@
+use crate::error::{AppError, AppResult};
+use image::DynamicImage;
+
+/// Largest side of a brush tip image; stamps are scaled from it anyway
+const MAX_BRUSH_TIP_SIZE: u32 = 4096;
+
+/// A grayscale stamp texture painted in place of the analytic round falloff.
+///
+/// White is full coverage and black none; transparent areas of the source
+/// image count as black.
+#[derive(Debug, Clone)]
+pub struct BrushTip {
+    pub width: u32,
+    pub height: u32,
+    coverage: Vec<u8>,
+}
+
+impl BrushTip {
+    pub fn from_image(image: &DynamicImage) -> AppResult<Self> {
+        let (width, height) = (image.width(), image.height());
+        if width == 0 || height == 0 {
+            return Err(AppError::InvalidOperation(
+                "Brush tip image is empty".into(),
+            ));
+        }
+        if width > MAX_BRUSH_TIP_SIZE || height > MAX_BRUSH_TIP_SIZE {
+            return Err(AppError::InvalidOperation(format!(
+                "Brush tip must be at most {}x{}",
+                MAX_BRUSH_TIP_SIZE, MAX_BRUSH_TIP_SIZE
+            )));
+        }
+
+        let coverage = image
+            .to_luma_alpha8()
+            .pixels()
+            .map(|p| ((p[0] as u32 * p[1] as u32 + 127) / 255) as u8)
+            .collect();
+
+        Ok(Self {
+            width,
+            height,
+            coverage,
+        })
+    }
+
+    /// Bilinear coverage (0.0-1.0) at a point in tip pixel space.
+    ///
+    /// Pixel `(i, j)` is centered on `(i + 0.5, j + 0.5)`; everything past the
+    /// edge of the tip has no coverage.
+    pub fn sample(&self, x: f64, y: f64) -> f64 {
+        let fx = x - 0.5;
+        let fy = y - 0.5;
+        let x0 = fx.floor();
+        let y0 = fy.floor();
+        let tx = fx - x0;
+        let ty = fy - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let mut acc = 0.0;
+        for (dx, dy, weight) in [
+            (0, 0, (1.0 - tx) * (1.0 - ty)),
+            (1, 0, tx * (1.0 - ty)),
+            (0, 1, (1.0 - tx) * ty),
+            (1, 1, tx * ty),
+        ] {
+            let (sx, sy) = (x0 + dx, y0 + dy);
+            if weight == 0.0
+                || sx < 0
+                || sy < 0
+                || sx >= self.width as i64
+                || sy >= self.height as i64
+            {
+                continue;
+            }
+            acc += self.coverage[sy as usize * self.width as usize + sx as usize] as f64 * weight;
+        }
+
+        acc / 255.0
+    }
+}
@@ -0,0 +1,64 @@
+use super::compositor::DirtyRect;
+use crate::error::{AppError, AppResult};
+
+/// Byte length of a `width` x `height` RGBA buffer.
+///
+/// Computed in u64 so large dimensions can't wrap around before being widened;
+/// returns None when the size isn't addressable on this platform.
+pub fn rgba_buffer_len(width: u32, height: u32) -> Option<usize> {
+    let len = (width as u64).checked_mul(height as u64)?.checked_mul(4)?;
+    usize::try_from(len).ok()
+}
+
+/// Like `rgba_buffer_len`, but reports an overflow as an error
+pub fn checked_rgba_buffer_len(width: u32, height: u32) -> AppResult<usize> {
+    rgba_buffer_len(width, height).ok_or_else(|| {
+        AppError::InvalidOperation(format!(
+            "Pixel buffer for {}x{} is too large",
+            width, height
+        ))
+    })
+}
+
+/// Copy the pixels inside `rect` (which must lie within the buffer) out of an
+/// RGBA buffer `width` pixels wide
+pub fn copy_rect(pixels: &[u8], width: u32, rect: &DirtyRect) -> Vec<u8> {
+    let row_len = rect.width as usize * 4;
+    let mut out = Vec::with_capacity(row_len * rect.height as usize);
+    for row in 0..rect.height as usize {
+        let start = ((rect.y as usize + row) * width as usize + rect.x as usize) * 4;
+        out.extend_from_slice(&pixels[start..start + row_len]);
+    }
+    out
+}
+
+/// Write pixels from `copy_rect` back into the same rectangle
+pub fn paste_rect(pixels: &mut [u8], width: u32, rect: &DirtyRect, bytes: &[u8]) {
+    let row_len = rect.width as usize * 4;
+    if row_len == 0 {
+        return;
+    }
+    for (row, src) in bytes.chunks_exact(row_len).enumerate() {
+        let start = ((rect.y as usize + row) * width as usize + rect.x as usize) * 4;
+        pixels[start..start + row_len].copy_from_slice(src);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_lengths_past_u32_do_not_wrap() {
+        assert_eq!(rgba_buffer_len(3, 2), Some(24));
+        // 70000 * 70000 * 4 wraps around in u32 arithmetic
+        if usize::BITS == 64 {
+            assert_eq!(rgba_buffer_len(70_000, 70_000), Some(19_600_000_000));
+        }
+        assert_eq!(rgba_buffer_len(u32::MAX, u32::MAX), None);
+        assert!(matches!(
+            checked_rgba_buffer_len(u32::MAX, u32::MAX),
+            Err(AppError::InvalidOperation(_))
+        ));
+    }
+}
@@ -0,0 +1,58 @@
+use serde::Deserialize;
+
+/// One of the four channels of an RGBA layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+    Alpha,
+}
+
+impl Channel {
+    /// Byte offset of the channel within an RGBA pixel
+    pub fn offset(self) -> usize {
+        match self {
+            Channel::Red => 0,
+            Channel::Green => 1,
+            Channel::Blue => 2,
+            Channel::Alpha => 3,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Red => "Red",
+            Channel::Green => "Green",
+            Channel::Blue => "Blue",
+            Channel::Alpha => "Alpha",
+        }
+    }
+}
+
+/// An opaque grayscale image of one channel of `pixels`
+pub fn extract_channel(pixels: &[u8], channel: Channel) -> Vec<u8> {
+    pixels
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            let value = pixel[channel.offset()];
+            [value, value, value, 255]
+        })
+        .collect()
+}
+
+/// Build an RGBA image from the red channel of each grayscale source; without
+/// an alpha source the result is opaque. All sources must be the same length.
+pub fn combine_channels(red: &[u8], green: &[u8], blue: &[u8], alpha: Option<&[u8]>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(red.len());
+    for i in (0..red.len()).step_by(4) {
+        out.extend_from_slice(&[
+            red[i],
+            green[i],
+            blue[i],
+            alpha.map_or(255, |alpha| alpha[i]),
+        ]);
+    }
+    out
+}
@@ -0,0 +1,556 @@
+use super::buffer::rgba_buffer_len;
+use super::document::Document;
+use super::layer::BlendMode;
+use crate::error::{AppError, AppResult};
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A rectangular region in document space touched by an edit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirtyRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DirtyRect {
+    /// Smallest rectangle containing both rectangles
+    pub fn union(&self, other: &DirtyRect) -> DirtyRect {
+        let min_x = self.x.min(other.x);
+        let min_y = self.y.min(other.y);
+        let max_x = self.right().max(other.right());
+        let max_y = self.bottom().max(other.bottom());
+
+        DirtyRect {
+            x: min_x,
+            y: min_y,
+            width: max_x.abs_diff(min_x),
+            height: max_y.abs_diff(min_y),
+        }
+    }
+
+    /// Overlapping part of both rectangles, or None if they don't overlap
+    pub fn intersect(&self, other: &DirtyRect) -> Option<DirtyRect> {
+        let min_x = self.x.max(other.x);
+        let min_y = self.y.max(other.y);
+        let max_x = self.right().min(other.right());
+        let max_y = self.bottom().min(other.bottom());
+
+        if max_x <= min_x || max_y <= min_y {
+            return None;
+        }
+
+        Some(DirtyRect {
+            x: min_x,
+            y: min_y,
+            width: max_x.abs_diff(min_x),
+            height: max_y.abs_diff(min_y),
+        })
+    }
+
+    /// Right edge (exclusive), saturating rather than overflowing for a
+    /// rectangle reaching past `i32::MAX`
+    fn right(&self) -> i32 {
+        self.x.saturating_add_unsigned(self.width)
+    }
+
+    /// Bottom edge (exclusive), saturating like [`DirtyRect::right`]
+    fn bottom(&self) -> i32 {
+        self.y.saturating_add_unsigned(self.height)
+    }
+
+    /// Clip the rectangle to a `width` x `height` canvas, returning None if nothing remains
+    pub fn clip_to(&self, width: u32, height: u32) -> Option<DirtyRect> {
+        let min_x = self.x.max(0);
+        let min_y = self.y.max(0);
+        let max_x = self.right().min(width as i32);
+        let max_y = self.bottom().min(height as i32);
+
+        if max_x <= min_x || max_y <= min_y {
+            return None;
+        }
+
+        Some(DirtyRect {
+            x: min_x,
+            y: min_y,
+            width: max_x.abs_diff(min_x),
+            height: max_y.abs_diff(min_y),
+        })
+    }
+}
+
+/// Composite all visible layers into a single image
+pub fn composite_layers(
+    doc: &Document,
+    layer_pixels: &HashMap<String, Vec<u8>>,
+) -> AppResult<RgbaImage> {
+    // Create transparent base image
+    let mut result = RgbaImage::new(doc.width, doc.height);
+
+    let full = DirtyRect {
+        x: 0,
+        y: 0,
+        width: doc.width,
+        height: doc.height,
+    };
+    composite_region(doc, layer_pixels, &mut result, &full)?;
+
+    Ok(result)
+}
+
+/// Recomposite only `region` (document space) of an existing composite.
+///
+/// Pixels outside the region are left untouched, so a small edit only costs
+/// as much as the area it changed.
+pub fn composite_region(
+    doc: &Document,
+    layer_pixels: &HashMap<String, Vec<u8>>,
+    target: &mut RgbaImage,
+    region: &DirtyRect,
+) -> AppResult<()> {
+    let region = match region.clip_to(doc.width, doc.height) {
+        Some(region) => region,
+        None => return Ok(()),
+    };
+
+    let region_right = region.x + region.width as i32;
+    let region_bottom = region.y + region.height as i32;
+
+    // Reset the region to transparent before re-blending
+    for y in region.y..region_bottom {
+        for x in region.x..region_right {
+            target.put_pixel(x as u32, y as u32, image::Rgba([0, 0, 0, 0]));
+        }
+    }
+
+    // Composite layers from bottom to top
+    for layer in &doc.layers {
+        if !doc.is_layer_shown(layer) {
+            continue;
+        }
+
+        let pixels = match layer_pixels.get(&layer.id) {
+            Some(pixels) => pixels,
+            None => continue,
+        };
+
+        let expected_len = rgba_buffer_len(layer.width, layer.height);
+        if expected_len.is_none_or(|len| pixels.len() < len) {
+            return Err(AppError::InvalidOperation("Invalid layer pixel data".into()));
+        }
+
+        // Intersect the region with the layer bounds
+        let min_x = region.x.max(layer.x);
+        let min_y = region.y.max(layer.y);
+        let max_x = region_right.min(layer.x + layer.width as i32);
+        let max_y = region_bottom.min(layer.y + layer.height as i32);
+
+        let normal = matches!(layer.blend_mode, BlendMode::Normal);
+
+        // Blend each pixel with the layer's mode, then alpha-composite it over
+        for dst_y in min_y..max_y {
+            for dst_x in min_x..max_x {
+                let src_x = (dst_x - layer.x) as usize;
+                let src_y = (dst_y - layer.y) as usize;
+                let idx = (src_y * layer.width as usize + src_x) * 4;
+                let src_pixel = &pixels[idx..idx + 4];
+                let dst_pixel = target.get_pixel_mut(dst_x as u32, dst_y as u32);
+
+                // Apply layer opacity
+                let mut src_a = src_pixel[3] as f32 / 255.0 * layer.opacity;
+
+                if let Some(blend_if) = &layer.blend_if {
+                    src_a *= blend_if.factor(src_pixel, &dst_pixel.0);
+                }
+
+                if src_a >= 1.0 && normal {
+                    dst_pixel.0.copy_from_slice(src_pixel);
+                } else if src_a > 0.0 {
+                    let dst_a = dst_pixel[3] as f32 / 255.0;
+                    let out_a = src_a + dst_a * (1.0 - src_a);
+
+                    if out_a > 0.0 {
+                        let channel = |v: u8| {
+                            let v = v as f32 / 255.0;
+                            if doc.linear_blending {
+                                srgb_to_linear(v)
+                            } else {
+                                v
+                            }
+                        };
+                        let src_c = [0, 1, 2].map(|c| channel(src_pixel[c]));
+                        let dst_c = [0, 1, 2].map(|c| channel(dst_pixel[c]));
+                        let blended = if normal {
+                            src_c
+                        } else {
+                            blend_color(&layer.blend_mode, src_c, dst_c)
+                        };
+
+                        for c in 0..3 {
+                            // Where the backdrop is transparent the source
+                            // shows through unblended
+                            let mixed = (1.0 - dst_a) * src_c[c] + dst_a * blended[c];
+                            let mut out_c =
+                                (mixed * src_a + dst_c[c] * dst_a * (1.0 - src_a)) / out_a;
+                            if doc.linear_blending {
+                                out_c = linear_to_srgb(out_c);
+                            }
+                            dst_pixel[c] = (out_c * 255.0).clamp(0.0, 255.0) as u8;
+                        }
+                        dst_pixel[3] = (out_a * 255.0) as u8;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Decode a gamma-encoded sRGB channel (0.0-1.0) to linear light
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encode a linear-light channel (0.0-1.0) back to sRGB
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Blend one color channel (0.0-1.0) of `src` onto `dst` per the W3C
+/// compositing spec's separable blend functions.
+///
+/// Non-separable modes (Hue, Saturation, Color, Luminosity) need the whole
+/// color and are handled by [`blend_color`]; here they fall back to Normal.
+pub fn blend_channel(mode: &BlendMode, src: f32, dst: f32) -> f32 {
+    match mode {
+        BlendMode::Multiply => src * dst,
+        BlendMode::Screen => src + dst - src * dst,
+        BlendMode::Overlay => hard_light(dst, src),
+        BlendMode::Darken => src.min(dst),
+        BlendMode::Lighten => src.max(dst),
+        BlendMode::ColorDodge => {
+            if dst == 0.0 {
+                0.0
+            } else if src >= 1.0 {
+                1.0
+            } else {
+                (dst / (1.0 - src)).min(1.0)
+            }
+        }
+        BlendMode::ColorBurn => {
+            if dst >= 1.0 {
+                1.0
+            } else if src == 0.0 {
+                0.0
+            } else {
+                1.0 - ((1.0 - dst) / src).min(1.0)
+            }
+        }
+        BlendMode::HardLight => hard_light(src, dst),
+        BlendMode::SoftLight => {
+            if src <= 0.5 {
+                dst - (1.0 - 2.0 * src) * dst * (1.0 - dst)
+            } else {
+                let d = if dst <= 0.25 {
+                    ((16.0 * dst - 12.0) * dst + 4.0) * dst
+                } else {
+                    dst.sqrt()
+                };
+                dst + (2.0 * src - 1.0) * (d - dst)
+            }
+        }
+        BlendMode::Difference => (src - dst).abs(),
+        BlendMode::Exclusion => src + dst - 2.0 * src * dst,
+        BlendMode::Normal
+        | BlendMode::Hue
+        | BlendMode::Saturation
+        | BlendMode::Color
+        | BlendMode::Luminosity => src,
+    }
+}
+
+/// Blend an RGB `src` (0.0-1.0) onto `dst` with any mode, including the
+/// W3C compositing spec's non-separable ones
+pub fn blend_color(mode: &BlendMode, src: [f32; 3], dst: [f32; 3]) -> [f32; 3] {
+    match mode {
+        BlendMode::Hue => set_lum(set_sat(src, sat(dst)), lum(dst)),
+        BlendMode::Saturation => set_lum(set_sat(dst, sat(src)), lum(dst)),
+        BlendMode::Color => set_lum(src, lum(dst)),
+        BlendMode::Luminosity => set_lum(dst, lum(src)),
+        _ => [0, 1, 2].map(|c| blend_channel(mode, src[c], dst[c])),
+    }
+}
+
+/// Luminosity as the W3C spec defines it (Rec. 601 weights)
+fn lum([r, g, b]: [f32; 3]) -> f32 {
+    0.3 * r + 0.59 * g + 0.11 * b
+}
+
+/// Shift a color to luminosity `l`, clipping back into gamut while keeping
+/// its luminosity
+fn set_lum(color: [f32; 3], l: f32) -> [f32; 3] {
+    let d = l - lum(color);
+    let color = color.map(|c| c + d);
+
+    let l = lum(color);
+    let min = color[0].min(color[1]).min(color[2]);
+    let max = color[0].max(color[1]).max(color[2]);
+    if min < 0.0 {
+        color.map(|c| l + (c - l) * l / (l - min))
+    } else if max > 1.0 {
+        color.map(|c| l + (c - l) * (1.0 - l) / (max - l))
+    } else {
+        color
+    }
+}
+
+fn sat([r, g, b]: [f32; 3]) -> f32 {
+    r.max(g).max(b) - r.min(g).min(b)
+}
+
+/// Give a color saturation `s`, keeping the order of its channels
+fn set_sat(color: [f32; 3], s: f32) -> [f32; 3] {
+    let mut order = [0, 1, 2];
+    order.sort_by(|&a, &b| color[a].total_cmp(&color[b]));
+    let [min, mid, max] = order;
+
+    let mut out = [0.0; 3];
+    if color[max] > color[min] {
+        out[mid] = (color[mid] - color[min]) * s / (color[max] - color[min]);
+        out[max] = s;
+    }
+    out
+}
+
+fn hard_light(src: f32, dst: f32) -> f32 {
+    if src <= 0.5 {
+        dst * 2.0 * src
+    } else {
+        let s = 2.0 * src - 1.0;
+        s + dst - s * dst
+    }
+}
+
+/// Blend a straight-alpha RGBA `src` pixel onto `dst` in place using `mode`,
+/// followed by source-over alpha compositing.
+pub fn blend_pixel(dst: &mut [u8], src: [u8; 4], mode: &BlendMode) {
+    if src[3] == 0 {
+        return;
+    }
+
+    let src_a = src[3] as f32 / 255.0;
+    let dst_a = dst[3] as f32 / 255.0;
+    let out_a = src_a + dst_a * (1.0 - src_a);
+
+    let cs = [0, 1, 2].map(|c| src[c] as f32 / 255.0);
+    let cb = [0, 1, 2].map(|c| dst[c] as f32 / 255.0);
+    let blended = blend_color(mode, cs, cb);
+
+    for c in 0..3 {
+        // Where the backdrop is transparent the source shows through unblended
+        let mixed = (1.0 - dst_a) * cs[c] + dst_a * blended[c];
+        let out_c = (src_a * mixed + dst_a * cb[c] * (1.0 - src_a)) / out_a;
+        dst[c] = (out_c * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+    dst[3] = (out_a * 255.0).round().clamp(0.0, 255.0) as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::document::Background;
+    use crate::engine::layer::{BlendIf, BlendIfRange, Layer};
+
+    /// Composite a 1x1 document: `bottom` under `top`, `top` in `mode`
+    fn composite_pixel(bottom: [u8; 4], top: [u8; 4], mode: BlendMode) -> [u8; 4] {
+        let mut doc = Document::new("Test", 1, 1, 72);
+        let mut layer = Layer::new_raster("Top", 1, 1);
+        layer.blend_mode = mode;
+        doc.layers.push(layer);
+
+        let pixels = HashMap::from([
+            (doc.layers[0].id.clone(), bottom.to_vec()),
+            (doc.layers[1].id.clone(), top.to_vec()),
+        ]);
+        composite_layers(&doc, &pixels).unwrap().get_pixel(0, 0).0
+    }
+
+    fn assert_close(actual: [u8; 4], expected: [u8; 4]) {
+        let close = actual
+            .iter()
+            .zip(expected)
+            .all(|(&a, e)| a.abs_diff(e) <= 1);
+        assert!(close, "got {:?}, expected {:?}", actual, expected);
+    }
+
+    #[test]
+    fn rects_reaching_past_i32_max_saturate_instead_of_overflowing() {
+        let huge = DirtyRect {
+            x: i32::MAX - 10,
+            y: 0,
+            width: 100,
+            height: 100,
+        };
+        let small = DirtyRect {
+            x: -5,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+
+        let union = huge.union(&small);
+        assert_eq!((union.x, union.width), (-5, i32::MAX as u32 + 5));
+        assert_eq!(huge.intersect(&small), None);
+        assert_eq!(huge.clip_to(100, 100), None);
+    }
+
+    #[test]
+    fn multiply_over_an_opaque_layer() {
+        // 200 * 128 / 255, 100 * 255 / 255, 50 * 0 / 255
+        let pixel = composite_pixel([200, 100, 50, 255], [128, 255, 0, 255], BlendMode::Multiply);
+        assert_close(pixel, [100, 100, 0, 255]);
+    }
+
+    #[test]
+    fn multiply_over_a_half_transparent_backdrop_mixes_in_the_source() {
+        // dst_a = 128/255; r: 0.498 * 0.502 + 0.502 * (0.502 * 0.784) = 0.448,
+        // g: 0.498 * 1.0 + 0.502 * 0.392 = 0.695
+        let pixel = composite_pixel([200, 100, 50, 128], [128, 255, 0, 255], BlendMode::Multiply);
+        assert_close(pixel, [114, 177, 0, 255]);
+    }
+
+    #[test]
+    fn multiply_over_nothing_shows_the_source_unblended() {
+        let pixel = composite_pixel([0, 0, 0, 0], [128, 255, 0, 255], BlendMode::Multiply);
+        assert_close(pixel, [128, 255, 0, 255]);
+    }
+
+    #[test]
+    fn hiding_a_colored_background_layer_leaves_the_composite_transparent() {
+        let mut doc = Document::new("Test", 2, 2, 72);
+        doc.background = Background::default();
+        let pixels = HashMap::from([(doc.layers[0].id.clone(), [255u8; 4].repeat(4))]);
+        assert_eq!(
+            composite_layers(&doc, &pixels).unwrap().get_pixel(0, 0).0[3],
+            255
+        );
+
+        doc.layers[0].visible = false;
+        let composite = composite_layers(&doc, &pixels).unwrap();
+        assert!(composite.pixels().all(|p| p.0[3] == 0));
+    }
+
+    #[test]
+    fn compositing_a_region_touches_only_that_region() {
+        let doc = Document::new("Test", 4, 4, 72);
+        let layer_id = doc.layers[0].id.clone();
+        let mut pixels = HashMap::from([(layer_id.clone(), [0u8, 0, 255, 255].repeat(16))]);
+        let mut composite = composite_layers(&doc, &pixels).unwrap();
+
+        // Repaint the whole layer but only recomposite its top-left 2x2 corner
+        pixels.insert(layer_id, [255u8, 0, 0, 255].repeat(16));
+        let corner = DirtyRect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        composite_region(&doc, &pixels, &mut composite, &corner).unwrap();
+
+        assert_eq!(composite.get_pixel(1, 1).0, [255, 0, 0, 255]);
+        assert_eq!(composite.get_pixel(2, 1).0, [0, 0, 255, 255]);
+        assert_eq!(composite.get_pixel(1, 2).0, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn blend_if_hides_dark_pixels_of_the_layer() {
+        let mut doc = Document::new("Test", 2, 1, 72);
+        let mut layer = Layer::new_raster("Top", 2, 1);
+        layer.blend_if = Some(BlendIf {
+            this_layer: BlendIfRange {
+                black_low: 128,
+                black_high: 128,
+                white_low: 255,
+                white_high: 255,
+            },
+            underlying: BlendIfRange::default(),
+        });
+        doc.layers.push(layer);
+
+        let pixels = HashMap::from([
+            (doc.layers[0].id.clone(), [0, 0, 255, 255].repeat(2)),
+            (
+                doc.layers[1].id.clone(),
+                vec![20, 20, 20, 255, 240, 240, 240, 255],
+            ),
+        ]);
+        let composite = composite_layers(&doc, &pixels).unwrap();
+        assert_eq!(composite.get_pixel(0, 0).0, [0, 0, 255, 255]);
+        assert_eq!(composite.get_pixel(1, 0).0, [240, 240, 240, 255]);
+    }
+
+    #[test]
+    fn linear_blending_mixes_black_and_white_to_a_brighter_gray() {
+        let mut doc = Document::new("Test", 1, 1, 72);
+        doc.layers.push(Layer::new_raster("Top", 1, 1));
+        let pixels = HashMap::from([
+            (doc.layers[0].id.clone(), vec![0, 0, 0, 255]),
+            (doc.layers[1].id.clone(), vec![255, 255, 255, 128]),
+        ]);
+
+        let gamma = composite_layers(&doc, &pixels).unwrap().get_pixel(0, 0).0;
+        assert_close(gamma, [128, 128, 128, 255]);
+
+        // Half of full intensity in linear light encodes to 0.735 in sRGB
+        doc.linear_blending = true;
+        let linear = composite_layers(&doc, &pixels).unwrap().get_pixel(0, 0).0;
+        assert_close(linear, [188, 188, 188, 255]);
+
+        for value in [0.0, 0.02, 0.5, 1.0] {
+            assert!((linear_to_srgb(srgb_to_linear(value)) - value).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn color_mode_recolors_without_changing_luminosity() {
+        let lum = |p: [u8; 4]| 0.3 * p[0] as f32 + 0.59 * p[1] as f32 + 0.11 * p[2] as f32;
+
+        let gray = [128, 128, 128, 255];
+        let colored = composite_pixel(gray, [255, 0, 0, 255], BlendMode::Color);
+        assert!(
+            colored[0] > colored[1] && colored[1] == colored[2],
+            "{:?}",
+            colored
+        );
+        assert!((lum(colored) - 128.0).abs() <= 1.0, "{:?}", colored);
+
+        // Luminosity is the inverse: the backdrop's color at the source's brightness
+        let red = [200, 40, 40, 255];
+        let relit = composite_pixel(red, gray, BlendMode::Luminosity);
+        assert!((lum(relit) - 128.0).abs() <= 1.0, "{:?}", relit);
+        assert!(relit[0] > relit[1] && relit[1] == relit[2], "{:?}", relit);
+
+        // Gray has no saturation: Hue over it stays gray, and Saturation with it
+        // leaves the backdrop's luminosity (0.3 * 200 + 0.7 * 40 = 88) as gray
+        assert_close(
+            composite_pixel(gray, [0, 0, 255, 255], BlendMode::Hue),
+            gray,
+        );
+        assert_close(
+            composite_pixel(red, gray, BlendMode::Saturation),
+            [88, 88, 88, 255],
+        );
+    }
+}
@@ -1,11 +1,30 @@
-use super::history::HistoryManager;
-use super::layer::Layer;
+use super::brush_tip::BrushTip;
+use super::buffer::{checked_rgba_buffer_len, copy_rect, paste_rect, rgba_buffer_len};
+use super::channels::{combine_channels, extract_channel, Channel};
+use super::compositor::{composite_layers, composite_region, DirtyRect};
+use super::history::{
+    HistoryEntry, HistoryManager, HistoryState, LayerPixels, LayerStructure, PixelState,
+    SavedPixels, DEFAULT_HISTORY_LIMIT,
+};
+use super::layer::{Layer, LayerType, ShapeContent, TextContent};
+use super::selection::{Selection, SelectionMask, SelectionOp, SelectionTransform};
+use super::shape::render_shape;
+use super::snapshot::{DocumentSnapshot, SnapshotInfo};
+use super::text::render_text;
+use super::tile_cache::{TileCache, TileCacheStats};
+use super::transform::resize_premultiplied;
+use crate::commands::brush::BrushStrokeSettings;
 use crate::commands::crop::CropResult;
 use crate::error::{AppError, AppResult};
+use image::RgbaImage;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use uuid::Uuid;
 
+/// Third-party data stored in a DRKR file: extension name -> path within the
+/// extension's folder -> contents
+pub type ExtensionFiles = BTreeMap<String, BTreeMap<String, Vec<u8>>>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Document {
@@ -20,6 +39,51 @@ pub struct Document {
     /// The file path where this document is saved (if any)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub source_path: Option<String>,
+    /// How the background layer was filled when the document was created
+    #[serde(default)]
+    pub background: Background,
+    /// When set, only this layer is composited, whatever the visibility flags say
+    #[serde(default)]
+    pub isolated_layer: Option<String>,
+    /// Blend layers (and blur) in linear light rather than gamma-encoded sRGB
+    #[serde(default)]
+    pub linear_blending: bool,
+}
+
+/// Where to put a layer relative to another in the bottom-to-top layer list:
+/// `Before` is directly beneath the anchor, `After` directly above it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RelativePosition {
+    Before,
+    After,
+}
+
+/// Initial fill of a new document's background layer. Layers are always
+/// composited onto transparency, so hiding or erasing that layer shows through.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Background {
+    Transparent,
+    Color { color: [u8; 4] }, // RGBA
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Color {
+            color: [255, 255, 255, 255],
+        }
+    }
+}
+
+impl Background {
+    /// RGBA value every background pixel starts as
+    pub fn pixel(&self) -> [u8; 4] {
+        match self {
+            Background::Transparent => [0, 0, 0, 0],
+            Background::Color { color } => *color,
+        }
+    }
 }
 
 impl Document {
@@ -40,6 +104,9 @@ impl Document {
             created_at: now,
             modified_at: now,
             source_path: None,
+            background: Background::default(),
+            isolated_layer: None,
+            linear_blending: false,
         }
     }
 
@@ -51,6 +118,11 @@ impl Document {
         self.layers.iter_mut().find(|l| l.id == layer_id)
     }
 
+    /// Position of a layer in the bottom-to-top layer list
+    pub fn layer_index(&self, layer_id: &str) -> Option<usize> {
+        self.layers.iter().position(|l| l.id == layer_id)
+    }
+
     pub fn add_layer(&mut self, layer: Layer) {
         self.layers.push(layer);
         self.mark_modified();
@@ -58,16 +130,25 @@ impl Document {
 
     pub fn remove_layer(&mut self, layer_id: &str) -> AppResult<Layer> {
         let index = self
-            .layers
-            .iter()
-            .position(|l| l.id == layer_id)
+            .layer_index(layer_id)
             .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
 
         let layer = self.layers.remove(index);
+        if self.isolated_layer.as_deref() == Some(layer_id) {
+            self.isolated_layer = None;
+        }
         self.mark_modified();
         Ok(layer)
     }
 
+    /// Whether a layer shows up in the composite
+    pub fn is_layer_shown(&self, layer: &Layer) -> bool {
+        match &self.isolated_layer {
+            Some(isolated) => layer.id == *isolated,
+            None => layer.visible,
+        }
+    }
+
     pub fn reorder_layers(&mut self, from_index: usize, to_index: usize) -> AppResult<()> {
         if from_index >= self.layers.len() || to_index >= self.layers.len() {
             return Err(AppError::InvalidOperation("Invalid layer indices".into()));
@@ -79,50 +160,167 @@ impl Document {
         Ok(())
     }
 
-    fn mark_modified(&mut self) {
+    /// Move a layer directly before or after another one in the (bottom to
+    /// top) layer list, identifying both by id
+    pub fn move_layer_relative(
+        &mut self,
+        layer_id: &str,
+        position: RelativePosition,
+        anchor_id: &str,
+    ) -> AppResult<()> {
+        if layer_id == anchor_id {
+            return Err(AppError::InvalidOperation(
+                "Cannot move a layer relative to itself".into(),
+            ));
+        }
+        if self.get_layer(anchor_id).is_none() {
+            return Err(AppError::LayerNotFound(anchor_id.to_string()));
+        }
+
+        let from = self
+            .layer_index(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+        let layer = self.layers.remove(from);
+
+        // Look the anchor up again now the list has shifted
+        let anchor = self
+            .layer_index(anchor_id)
+            .ok_or_else(|| AppError::LayerNotFound(anchor_id.to_string()))?;
+        let to = match position {
+            RelativePosition::Before => anchor,
+            RelativePosition::After => anchor + 1,
+        };
+
+        self.layers.insert(to, layer);
+        self.mark_modified();
+        Ok(())
+    }
+
+    pub fn mark_modified(&mut self) {
         self.modified_at = chrono::Utc::now().timestamp_millis();
     }
 }
 
+/// Cached composite of a document's visible layers
+struct CompositeCache {
+    image: RgbaImage,
+    /// Set by any edit that can change the composited result
+    dirty: bool,
+}
+
 pub struct DocumentManager {
     documents: HashMap<String, Document>,
     history: HashMap<String, HistoryManager>,
     pixel_data: HashMap<String, Vec<u8>>, // layer_id -> RGBA pixel data
+    composites: HashMap<String, CompositeCache>, // doc_id -> cached composite
+    snapshots: HashMap<String, Vec<DocumentSnapshot>>, // doc_id -> named snapshots
+    selections: HashMap<String, SelectionMask>, // doc_id -> active selection
+    brush_tips: HashMap<String, BrushTip>, // tip_id -> registered brush tip
+    brush_presets: HashMap<String, BrushStrokeSettings>, // preset_id -> loaded brush preset
+    extensions: HashMap<String, ExtensionFiles>, // doc_id -> extension files to carry through saves
+    /// Holds the layer pixels of suspended documents when a memory budget is set
+    tile_cache: Option<TileCache>,
+    suspended: HashSet<String>, // doc_ids whose layer pixels live in the tile cache
+    /// Largest canvas, in pixels, a crop may produce
+    max_canvas_area: u64,
 }
 
+/// Largest canvas (in pixels) a crop may produce unless reconfigured
+const DEFAULT_MAX_CANVAS_AREA: u64 = 16384 * 16384;
+
+/// Hard ceiling on the configurable limit, keeping `(y * width + x) * 4`
+/// buffer offsets within u32
+const MAX_CANVAS_AREA_LIMIT: u64 = u32::MAX as u64 / 4;
+
+/// Largest feather radius, keeping the blur kernel a sensible size
+const MAX_FEATHER_RADIUS: f32 = 1000.0;
+
 impl DocumentManager {
     pub fn new() -> Self {
         Self {
             documents: HashMap::new(),
             history: HashMap::new(),
             pixel_data: HashMap::new(),
+            composites: HashMap::new(),
+            snapshots: HashMap::new(),
+            selections: HashMap::new(),
+            brush_tips: HashMap::new(),
+            brush_presets: HashMap::new(),
+            extensions: HashMap::new(),
+            tile_cache: None,
+            suspended: HashSet::new(),
+            max_canvas_area: DEFAULT_MAX_CANVAS_AREA,
+        }
+    }
+
+    /// Change the largest canvas area (in pixels) a crop may produce.
+    /// Returns the limit actually applied.
+    pub fn set_max_canvas_area(&mut self, pixels: u64) -> AppResult<u64> {
+        if pixels == 0 {
+            return Err(AppError::InvalidOperation(
+                "Maximum canvas area must be greater than zero".into(),
+            ));
         }
+
+        self.max_canvas_area = pixels.min(MAX_CANVAS_AREA_LIMIT);
+        Ok(self.max_canvas_area)
+    }
+
+    /// Create a document with a transparent background, for images opened from
+    /// a file so their own transparency survives compositing
+    pub fn create(
+        &mut self,
+        name: &str,
+        width: u32,
+        height: u32,
+        resolution: u32,
+    ) -> AppResult<Document> {
+        self.create_with_background(name, width, height, resolution, Background::Transparent)
     }
 
-    pub fn create(&mut self, name: &str, width: u32, height: u32, resolution: u32) -> Document {
-        let doc = Document::new(name, width, height, resolution);
+    /// Create a document whose background layer starts filled with `background`
+    pub fn create_with_background(
+        &mut self,
+        name: &str,
+        width: u32,
+        height: u32,
+        resolution: u32,
+        background: Background,
+    ) -> AppResult<Document> {
+        let len = checked_rgba_buffer_len(width, height)?;
+        let mut doc = Document::new(name, width, height, resolution);
 
         // Initialize pixel data for the background layer
         if let Some(bg_layer) = doc.layers.first() {
-            let pixels = vec![255u8; (width * height * 4) as usize]; // White background
+            let pixels = background.pixel().repeat(len / 4);
             self.pixel_data.insert(bg_layer.id.clone(), pixels);
         }
+        doc.background = background;
 
         // Initialize history for this document
         self.history
-            .insert(doc.id.clone(), HistoryManager::new(50));
+            .insert(doc.id.clone(), HistoryManager::new(DEFAULT_HISTORY_LIMIT));
 
         let doc_clone = doc.clone();
         self.documents.insert(doc.id.clone(), doc);
 
-        doc_clone
+        Ok(doc_clone)
     }
 
     pub fn get(&self, doc_id: &str) -> Option<&Document> {
         self.documents.get(doc_id)
     }
 
+    /// Get a document for mutation.
+    ///
+    /// Callers may change layer order, visibility, opacity or blend mode, so the
+    /// cached composite is marked dirty. A suspended document is resumed first.
     pub fn get_mut(&mut self, doc_id: &str) -> Option<&mut Document> {
+        if let Err(e) = self.resume_document(doc_id) {
+            log::warn!("Failed to resume document {}: {}", doc_id, e);
+            return None;
+        }
+        self.invalidate_composite(doc_id);
         self.documents.get_mut(doc_id)
     }
 
@@ -140,179 +338,2055 @@ impl DocumentManager {
         // Clean up history
         self.history.remove(doc_id);
 
+        // Drop the cached composite, any snapshots, the selection and extension data
+        self.composites.remove(doc_id);
+        self.snapshots.remove(doc_id);
+        self.selections.remove(doc_id);
+        self.extensions.remove(doc_id);
+
+        if self.suspended.remove(doc_id) {
+            if let Some(cache) = self.tile_cache.as_mut() {
+                for layer in &doc.layers {
+                    cache.remove_layer(&layer.id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Set the memory budget (in bytes) for suspended documents' tiles, or
+    /// with None resume every suspended document and drop the cache
+    pub fn set_tile_cache_budget(&mut self, budget: Option<usize>) -> AppResult<()> {
+        match (budget, self.tile_cache.as_mut()) {
+            (Some(budget), Some(cache)) => cache.set_budget(budget),
+            (Some(budget), None) => {
+                self.tile_cache = Some(TileCache::new(budget));
+                Ok(())
+            }
+            (None, _) => {
+                let suspended: Vec<String> = self.suspended.iter().cloned().collect();
+                for doc_id in suspended {
+                    self.resume_document(&doc_id)?;
+                }
+                self.tile_cache = None;
+                Ok(())
+            }
+        }
+    }
+
+    pub fn tile_cache_stats(&self) -> Option<TileCacheStats> {
+        self.tile_cache.as_ref().map(TileCache::stats)
+    }
+
+    /// Move a document's layer pixels into the tile cache, where cold tiles
+    /// spill to disk. Any later pixel read or edit (`get_layer_pixels`,
+    /// `get_mut`, compositing, saving) resumes the whole document first.
+    pub fn suspend_document(&mut self, doc_id: &str) -> AppResult<()> {
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let cache = self
+            .tile_cache
+            .as_mut()
+            .ok_or_else(|| AppError::InvalidOperation("The tile cache is disabled".into()))?;
+
+        if self.suspended.contains(doc_id) {
+            return Ok(());
+        }
+
+        for layer in &doc.layers {
+            if let Some(pixels) = self.pixel_data.remove(&layer.id) {
+                if let Err(e) = cache.insert_layer(&layer.id, layer.width, layer.height, &pixels) {
+                    self.pixel_data.insert(layer.id.clone(), pixels);
+                    return Err(e);
+                }
+            }
+        }
+
+        self.suspended.insert(doc_id.to_string());
+        self.composites.remove(doc_id);
+        Ok(())
+    }
+
+    /// Move a suspended document's layer pixels back out of the tile cache
+    pub fn resume_document(&mut self, doc_id: &str) -> AppResult<()> {
+        if !self.suspended.contains(doc_id) {
+            return Ok(());
+        }
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let cache = self
+            .tile_cache
+            .as_mut()
+            .ok_or_else(|| AppError::InvalidOperation("The tile cache is disabled".into()))?;
+
+        for layer in &doc.layers {
+            if cache.contains_layer(&layer.id) {
+                let pixels = cache.take_layer(&layer.id)?;
+                // Pixels set while suspended are newer than the cached ones
+                self.pixel_data.entry(layer.id.clone()).or_insert(pixels);
+            }
+        }
+
+        self.suspended.remove(doc_id);
+        self.invalidate_composite(doc_id);
         Ok(())
     }
 
-    pub fn get_layer_pixels(&self, layer_id: &str) -> Option<&Vec<u8>> {
+    /// A layer's pixels, first resuming its document if it is suspended
+    pub fn get_layer_pixels(&mut self, layer_id: &str) -> Option<&Vec<u8>> {
+        if let Some(doc_id) = self.find_layer_document(layer_id) {
+            if let Err(e) = self.resume_document(&doc_id) {
+                log::warn!("Failed to resume document {}: {}", doc_id, e);
+                return None;
+            }
+        }
         self.pixel_data.get(layer_id)
     }
 
     pub fn set_layer_pixels(&mut self, layer_id: &str, pixels: Vec<u8>) {
+        if let Some(doc_id) = self.find_layer_document(layer_id) {
+            if let Some(layer) = self
+                .get_mut(&doc_id)
+                .and_then(|doc| doc.get_layer_mut(layer_id))
+            {
+                layer.mark_modified();
+            }
+        }
         self.pixel_data.insert(layer_id.to_string(), pixels);
     }
 
-    pub fn add_layer_to_document(
+    /// Replace a layer's pixels after an edit confined to `region` (document space).
+    ///
+    /// Unlike `set_layer_pixels`, a clean cached composite is updated in place
+    /// for just that region instead of being invalidated.
+    pub fn set_layer_pixels_in_region(
         &mut self,
         doc_id: &str,
-        name: &str,
-        width: u32,
-        height: u32,
-    ) -> AppResult<Layer> {
-        // Check document exists first
-        if !self.documents.contains_key(doc_id) {
-            return Err(AppError::DocumentNotFound(doc_id.to_string()));
-        }
+        layer_id: &str,
+        pixels: Vec<u8>,
+        region: &DirtyRect,
+    ) -> AppResult<()> {
+        let doc = self
+            .documents
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
 
-        let layer = Layer::new_raster(name, width, height);
-        let layer_clone = layer.clone();
+        doc.get_layer_mut(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?
+            .mark_modified();
 
-        // Initialize transparent pixel data
-        let pixels = vec![0u8; (width * height * 4) as usize];
-        self.pixel_data.insert(layer.id.clone(), pixels);
+        doc.mark_modified();
+        self.pixel_data.insert(layer_id.to_string(), pixels);
 
-        // Now add layer to document
-        if let Some(doc) = self.documents.get_mut(doc_id) {
-            doc.add_layer(layer);
+        if let Some(cache) = self.composites.get_mut(doc_id) {
+            if !cache.dirty {
+                let doc = &self.documents[doc_id];
+                composite_region(doc, &self.pixel_data, &mut cache.image, region)?;
+            }
         }
 
-        Ok(layer_clone)
+        Ok(())
     }
 
-    /// Register a document that was loaded from a file
-    /// This is used when loading DRKR files
-    pub fn register_loaded_document(
+    /// Get the bounding box of a layer's non-transparent pixels.
+    ///
+    /// Returns `(x, y, width, height)` in layer-local coordinates, or None if
+    /// the layer is entirely transparent.
+    pub fn get_layer_content_bounds(
         &mut self,
-        doc: Document,
-        layer_pixels: std::collections::HashMap<String, Vec<u8>>,
-    ) -> Document {
-        let doc_id = doc.id.clone();
-        let doc_clone = doc.clone();
+        doc_id: &str,
+        layer_id: &str,
+    ) -> AppResult<Option<(u32, u32, u32, u32)>> {
+        self.resume_document(doc_id)?;
 
-        // Initialize history for this document
-        self.history.insert(doc_id.clone(), HistoryManager::new(50));
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
 
-        // Store all layer pixels
-        for (layer_id, pixels) in layer_pixels {
-            self.pixel_data.insert(layer_id, pixels);
-        }
+        let layer = doc
+            .get_layer(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
 
-        // Add document to manager
-        self.documents.insert(doc_id, doc);
+        let pixels = match self.pixel_data.get(layer_id) {
+            Some(pixels) => pixels,
+            None => return Ok(None),
+        };
 
-        doc_clone
+        Ok(Self::alpha_bounds(pixels, layer.width, layer.height))
     }
 
-    /// Get all layer pixel data for a document (for saving)
-    pub fn get_all_layer_pixels(&self, doc_id: &str) -> Option<std::collections::HashMap<String, Vec<u8>>> {
-        let doc = self.documents.get(doc_id)?;
-        let mut result = std::collections::HashMap::new();
+    /// Scan the alpha channel for the bounding box of non-transparent pixels
+    fn alpha_bounds(pixels: &[u8], width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+        let mut min_x = u32::MAX;
+        let mut min_y = u32::MAX;
+        let mut max_x = 0u32;
+        let mut max_y = 0u32;
 
-        for layer in &doc.layers {
-            if let Some(pixels) = self.pixel_data.get(&layer.id) {
-                result.insert(layer.id.clone(), pixels.clone());
+        for y in 0..height {
+            let row_start = y as usize * width as usize * 4;
+            for x in 0..width {
+                let idx = row_start + x as usize * 4 + 3;
+                if idx >= pixels.len() {
+                    break;
+                }
+
+                if pixels[idx] > 0 {
+                    min_x = min_x.min(x);
+                    min_y = min_y.min(y);
+                    max_x = max_x.max(x);
+                    max_y = max_y.max(y);
+                }
             }
         }
 
-        Some(result)
-    }
+        if min_x > max_x || min_y > max_y {
+            return None;
+        }
 
-    /// Set the source path for a document (called after saving)
-    pub fn set_source_path(&mut self, doc_id: &str, path: &str) -> AppResult<()> {
-        let doc = self.documents.get_mut(doc_id)
-            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
-        doc.source_path = Some(path.to_string());
-        Ok(())
+        Some((min_x, min_y, max_x - min_x + 1, max_y - min_y + 1))
     }
 
-    /// Rename a document
-    pub fn rename_document(&mut self, doc_id: &str, name: &str) -> AppResult<()> {
-        let doc = self.documents.get_mut(doc_id)
+    /// Move a layer by `dx`/`dy`.
+    ///
+    /// Without `bake` only the layer's position changes. With `bake` the
+    /// pixels shift inside the layer's existing buffer instead; whatever moves
+    /// past the edge is dropped and the vacated area becomes transparent.
+    pub fn move_layer(
+        &mut self,
+        doc_id: &str,
+        layer_id: &str,
+        dx: i32,
+        dy: i32,
+        bake: bool,
+    ) -> AppResult<Layer> {
+        let doc = self
+            .get_mut(doc_id)
             .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
-        doc.name = name.to_string();
-        Ok(())
-    }
 
-    /// List all open document IDs
-    #[allow(dead_code)]
-    pub fn list_documents(&self) -> Vec<String> {
-        self.documents.keys().cloned().collect()
-    }
+        let layer = doc
+            .get_layer_mut(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
 
-    /// Get all open documents
-    pub fn get_all_documents(&self) -> Vec<&Document> {
-        self.documents.values().collect()
+        if !bake {
+            if layer.position_locked() {
+                return Err(AppError::InvalidOperation(
+                    "Layer position is locked".into(),
+                ));
+            }
+
+            let before = doc.layers.clone();
+            let layer = doc
+                .get_layer_mut(layer_id)
+                .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+            layer.x = layer.x.saturating_add(dx);
+            layer.y = layer.y.saturating_add(dy);
+            layer.mark_modified();
+            let layer = layer.clone();
+            doc.mark_modified();
+            self.push_layer_history(doc_id, "Move Layer", before);
+            return Ok(layer);
+        }
+
+        if layer.pixels_locked() {
+            return Err(AppError::InvalidOperation("Layer is locked".into()));
+        }
+        let before = self.capture_pixels(doc_id, &[layer_id.to_string()])?;
+        let layer = self
+            .documents
+            .get(doc_id)
+            .and_then(|doc| doc.get_layer(layer_id))
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+        let (width, height) = (layer.width as i64, layer.height as i64);
+        let source = self
+            .pixel_data
+            .get(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+        let mut pixels = vec![0u8; source.len()];
+        for y in 0..height {
+            let src_y = y - dy as i64;
+            if !(0..height).contains(&src_y) {
+                continue;
+            }
+
+            for x in 0..width {
+                let src_x = x - dx as i64;
+                if !(0..width).contains(&src_x) {
+                    continue;
+                }
+
+                let src = ((src_y * width + src_x) * 4) as usize;
+                let dst = ((y * width + x) * 4) as usize;
+                pixels[dst..dst + 4].copy_from_slice(&source[src..src + 4]);
+            }
+        }
+
+        self.set_layer_pixels(layer_id, pixels);
+        self.push_pixel_history(doc_id, "Move Layer", before);
+
+        let layer = self
+            .get(doc_id)
+            .and_then(|doc| doc.get_layer(layer_id))
+            .cloned()
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+        Ok(layer)
     }
 
-    /// Crop the document to the specified region.
-    ///
-    /// This modifies the document dimensions and crops/expands all layer pixel buffers.
-    /// Negative x/y values extend the canvas, positive values crop into existing content.
-    ///
-    /// # Arguments
-    /// * `doc_id` - The document ID
-    /// * `crop_x` - X coordinate of crop region origin (in original document space)
-    /// * `crop_y` - Y coordinate of crop region origin
-    /// * `new_width` - Width of the cropped document
-    /// * `new_height` - Height of the cropped document
-    pub fn crop_document(
+    /// Grow a layer so it covers `bounds` (document space), keeping existing
+    /// pixels in place and filling the new area with transparency
+    pub fn expand_layer(
         &mut self,
         doc_id: &str,
-        crop_x: i32,
-        crop_y: i32,
-        new_width: u32,
-        new_height: u32,
-    ) -> AppResult<CropResult> {
+        layer_id: &str,
+        bounds: &DirtyRect,
+    ) -> AppResult<()> {
+        self.resume_document(doc_id)?;
+
         let doc = self
             .documents
             .get_mut(doc_id)
             .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
 
-        let mut layers_affected: Vec<String> = Vec::new();
+        let layer = doc
+            .get_layer_mut(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
 
-        // Process each layer
-        for layer in &mut doc.layers {
-            // Get current pixel data for this layer
-            let old_pixels = self
-                .pixel_data
-                .get(&layer.id)
-                .cloned()
-                .unwrap_or_else(|| vec![0u8; (layer.width * layer.height * 4) as usize]);
+        let old = DirtyRect {
+            x: layer.x,
+            y: layer.y,
+            width: layer.width,
+            height: layer.height,
+        };
+        let new = old.union(bounds);
+        if new == old {
+            return Ok(());
+        }
 
-            // Calculate new layer pixels
-            let new_pixels = Self::crop_layer_pixels(
-                &old_pixels,
-                layer.width,
-                layer.height,
-                layer.x,
-                layer.y,
-                crop_x,
-                crop_y,
-                new_width,
-                new_height,
-            );
+        let old_pixels = self
+            .pixel_data
+            .get(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
 
-            // Update layer dimensions and position
-            // After crop, layer position is adjusted relative to new document origin
-            layer.x -= crop_x;
-            layer.y -= crop_y;
-            layer.width = new_width;
-            layer.height = new_height;
+        let mut pixels = vec![0u8; checked_rgba_buffer_len(new.width, new.height)?];
+        let row_len = old.width as usize * 4;
+        let offset_x = (old.x - new.x) as usize;
+        let offset_y = (old.y - new.y) as usize;
 
-            // Store new pixel data
-            self.pixel_data.insert(layer.id.clone(), new_pixels);
-            layers_affected.push(layer.id.clone());
+        for row in 0..old.height as usize {
+            let src = row * row_len;
+            let dst = ((row + offset_y) * new.width as usize + offset_x) * 4;
+            pixels[dst..dst + row_len].copy_from_slice(&old_pixels[src..src + row_len]);
         }
 
-        // Update document dimensions
-        doc.width = new_width;
-        doc.height = new_height;
+        // The new area is transparent, so the cached composite stays valid
+        self.pixel_data.insert(layer_id.to_string(), pixels);
+        layer.x = new.x;
+        layer.y = new.y;
+        layer.width = new.width;
+        layer.height = new.height;
+        layer.mark_modified();
         doc.mark_modified();
 
-        Ok(CropResult {
-            doc_id: doc_id.to_string(),
-            new_width,
-            new_height,
-            layers_affected,
+        Ok(())
+    }
+
+    /// Find the ID of the document that owns a layer
+    pub fn find_layer_document(&self, layer_id: &str) -> Option<String> {
+        self.documents
+            .values()
+            .find(|doc| doc.get_layer(layer_id).is_some())
+            .map(|doc| doc.id.clone())
+    }
+
+    /// Record an undo step for a change to a document's layer list.
+    ///
+    /// Rapid same-named edits and edits inside an open history group are
+    /// coalesced into a single step, as with the other `push_*_history`
+    /// methods. `before` is the list prior to the change. Layers the change removed take
+    /// their pixels with them into the step, so undoing brings them back intact.
+    pub fn push_layer_history(&mut self, doc_id: &str, name: &str, before: Vec<Layer>) {
+        let Some(structure) = self.take_layer_structure(doc_id, before) else {
+            return;
+        };
+
+        if let Some(history) = self.history.get_mut(doc_id) {
+            history.push(HistoryEntry::with_structure(name, structure));
+        }
+    }
+
+    /// Record an undo step for an edit that replaces the layer list and the
+    /// pixels together (resampling, restoring a snapshot, re-rendering a text
+    /// or shape layer). `layers` is the list before the edit and `pixels` a
+    /// capture of the layers it touched from then.
+    pub fn push_document_history(
+        &mut self,
+        doc_id: &str,
+        name: &str,
+        layers: Vec<Layer>,
+        pixels: PixelState,
+    ) {
+        let Some(structure) = self.take_layer_structure(doc_id, layers) else {
+            return;
+        };
+
+        if let Some(history) = self.history.get_mut(doc_id) {
+            history.push(HistoryEntry {
+                structure: Some(structure),
+                ..HistoryEntry::with_pixels(name, pixels)
+            });
+        }
+    }
+
+    /// Pair a layer list from before an edit with the pixels of its layers the
+    /// edit removed, taking those out of the document's pixel data
+    fn take_layer_structure(&mut self, doc_id: &str, before: Vec<Layer>) -> Option<LayerStructure> {
+        let doc = self.documents.get(doc_id)?;
+
+        let pixels = before
+            .iter()
+            .filter(|layer| doc.get_layer(&layer.id).is_none())
+            .filter_map(|layer| Some((layer.id.clone(), self.pixel_data.remove(&layer.id)?)))
+            .collect();
+
+        Some(LayerStructure {
+            layers: before,
+            pixels,
+        })
+    }
+
+    /// Step a document back one undo step.
+    ///
+    /// Structural steps restore the layer list (and removed layers' pixels);
+    /// other steps only move through the stack.
+    pub fn undo(&mut self, doc_id: &str) -> AppResult<Document> {
+        self.step_history(doc_id, HistoryManager::undo, true, "Nothing to undo")
+    }
+
+    /// Step a document forward one redo step
+    pub fn redo(&mut self, doc_id: &str) -> AppResult<Document> {
+        self.step_history(doc_id, HistoryManager::redo, false, "Nothing to redo")
+    }
+
+    fn step_history(
+        &mut self,
+        doc_id: &str,
+        step: fn(&mut HistoryManager) -> Option<&mut HistoryEntry>,
+        undoing: bool,
+        empty_message: &str,
+    ) -> AppResult<Document> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let history = self
+            .history
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let entry =
+            step(history).ok_or_else(|| AppError::InvalidOperation(empty_message.into()))?;
+
+        // The step now holds the state to go back to the other way. Within a
+        // step pixel edits can only touch layers that exist after its layer
+        // changes, so those are reverted first and redone last.
+        if !undoing {
+            if let Some(pixels) = entry.pixels.take() {
+                entry.pixels = Some(Self::swap_pixel_state(doc, &mut self.pixel_data, pixels));
+            }
+        }
+        if let Some(structure) = entry.structure.take() {
+            entry.structure = Some(Self::swap_layer_structure(
+                doc,
+                &mut self.pixel_data,
+                structure,
+            ));
+        }
+        if undoing {
+            if let Some(pixels) = entry.pixels.take() {
+                entry.pixels = Some(Self::swap_pixel_state(doc, &mut self.pixel_data, pixels));
+            }
+        }
+        let doc = doc.clone();
+
+        self.invalidate_composite(doc_id);
+        Ok(doc)
+    }
+
+    /// Put `target`'s layer list in place and return the one it replaced, with
+    /// the pixels of layers that are no longer in the document
+    fn swap_layer_structure(
+        doc: &mut Document,
+        pixel_data: &mut HashMap<String, Vec<u8>>,
+        target: LayerStructure,
+    ) -> LayerStructure {
+        let replaced = std::mem::replace(&mut doc.layers, target.layers);
+        pixel_data.extend(target.pixels);
+
+        let pixels = replaced
+            .iter()
+            .filter(|layer| doc.get_layer(&layer.id).is_none())
+            .filter_map(|layer| Some((layer.id.clone(), pixel_data.remove(&layer.id)?)))
+            .collect();
+
+        if let Some(isolated) = &doc.isolated_layer {
+            if doc.get_layer(isolated).is_none() {
+                doc.isolated_layer = None;
+            }
+        }
+        doc.mark_modified();
+
+        LayerStructure {
+            layers: replaced,
+            pixels,
+        }
+    }
+
+    /// Put `target`'s canvas size, resolution and layer pixels in place and
+    /// return what they replaced.
+    ///
+    /// Layers are restored last edit first. Each one's current state is saved
+    /// just before it is overwritten, which leaves the returned list in the
+    /// order the other direction has to apply it (again from the back).
+    fn swap_pixel_state(
+        doc: &mut Document,
+        pixel_data: &mut HashMap<String, Vec<u8>>,
+        target: PixelState,
+    ) -> PixelState {
+        let mut replaced = PixelState {
+            width: doc.width,
+            height: doc.height,
+            resolution: doc.resolution,
+            layers: Vec::with_capacity(target.layers.len()),
+        };
+        doc.width = target.width;
+        doc.height = target.height;
+        doc.resolution = target.resolution;
+
+        for saved in target.layers.into_iter().rev() {
+            let Some(layer) = doc.get_layer_mut(&saved.layer_id) else {
+                // Removed by a later step; keep it for when the layer returns
+                replaced.layers.push(saved);
+                continue;
+            };
+
+            let pixels = match saved.pixels {
+                SavedPixels::Full(pixels) => {
+                    let current = pixel_data.remove(&saved.layer_id);
+                    if let Some(pixels) = pixels {
+                        pixel_data.insert(saved.layer_id.clone(), pixels);
+                    }
+                    SavedPixels::Full(current)
+                }
+                SavedPixels::Region { rect, bytes } => {
+                    let expected_len = rgba_buffer_len(layer.width, layer.height);
+                    let fits = layer.width == saved.width && layer.height == saved.height;
+                    let pixels = pixel_data
+                        .get_mut(&saved.layer_id)
+                        .filter(|p| fits && Some(p.len()) == expected_len);
+                    let Some(pixels) = pixels else {
+                        log::warn!(
+                            "Layer {} changed size since its undo step was recorded; skipping it",
+                            saved.layer_id
+                        );
+                        replaced.layers.push(LayerPixels {
+                            pixels: SavedPixels::Region { rect, bytes },
+                            ..saved
+                        });
+                        continue;
+                    };
+
+                    let current = copy_rect(pixels, layer.width, &rect);
+                    paste_rect(pixels, layer.width, &rect, &bytes);
+                    SavedPixels::Region {
+                        rect,
+                        bytes: current,
+                    }
+                }
+            };
+
+            replaced.layers.push(LayerPixels {
+                layer_id: saved.layer_id,
+                x: layer.x,
+                y: layer.y,
+                width: layer.width,
+                height: layer.height,
+                pixels,
+            });
+
+            layer.x = saved.x;
+            layer.y = saved.y;
+            layer.width = saved.width;
+            layer.height = saved.height;
+            layer.mark_modified();
+        }
+        doc.mark_modified();
+
+        replaced
+    }
+
+    /// Copy the canvas size and resolution and the pixels and placement of
+    /// `layer_ids`, to pass to `push_pixel_history` once they have been edited
+    pub fn capture_pixels(&mut self, doc_id: &str, layer_ids: &[String]) -> AppResult<PixelState> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        let layers = layer_ids
+            .iter()
+            .map(|layer_id| {
+                let layer = doc
+                    .get_layer(layer_id)
+                    .ok_or_else(|| AppError::LayerNotFound(layer_id.clone()))?;
+                Ok(LayerPixels {
+                    layer_id: layer_id.clone(),
+                    x: layer.x,
+                    y: layer.y,
+                    width: layer.width,
+                    height: layer.height,
+                    pixels: SavedPixels::Full(self.pixel_data.get(layer_id).cloned()),
+                })
+            })
+            .collect::<AppResult<Vec<_>>>()?;
+
+        Ok(PixelState {
+            width: doc.width,
+            height: doc.height,
+            resolution: doc.resolution,
+            layers,
+        })
+    }
+
+    /// Copy just the part of a layer inside `region` (document space), for
+    /// edits that don't change the layer's size or position. Much smaller than
+    /// `capture_pixels` for a brush stroke on a big layer.
+    pub fn capture_pixel_region(
+        &mut self,
+        doc_id: &str,
+        layer_id: &str,
+        region: &DirtyRect,
+    ) -> AppResult<PixelState> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let layer = doc
+            .get_layer(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+        let pixels = self
+            .pixel_data
+            .get(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+        let layer_rect = DirtyRect {
+            x: layer.x,
+            y: layer.y,
+            width: layer.width,
+            height: layer.height,
+        };
+        let layers = match region.intersect(&layer_rect) {
+            Some(clipped) => {
+                let rect = DirtyRect {
+                    x: clipped.x - layer.x,
+                    y: clipped.y - layer.y,
+                    ..clipped
+                };
+                vec![LayerPixels {
+                    layer_id: layer_id.to_string(),
+                    x: layer.x,
+                    y: layer.y,
+                    width: layer.width,
+                    height: layer.height,
+                    pixels: SavedPixels::Region {
+                        bytes: copy_rect(pixels, layer.width, &rect),
+                        rect,
+                    },
+                }]
+            }
+            None => Vec::new(),
+        };
+
+        Ok(PixelState {
+            width: doc.width,
+            height: doc.height,
+            resolution: doc.resolution,
+            layers,
+        })
+    }
+
+    /// `capture_pixels` for every layer, for edits to the whole canvas
+    pub fn capture_all_pixels(&mut self, doc_id: &str) -> AppResult<PixelState> {
+        let layer_ids: Vec<String> = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?
+            .layers
+            .iter()
+            .map(|layer| layer.id.clone())
+            .collect();
+
+        self.capture_pixels(doc_id, &layer_ids)
+    }
+
+    /// Record an undo step for a pixel edit. `before` is from `capture_pixels`
+    /// or `capture_pixel_region`, taken before the edit was stored.
+    pub fn push_pixel_history(&mut self, doc_id: &str, name: &str, before: PixelState) {
+        if let Some(history) = self.history.get_mut(doc_id) {
+            history.push(HistoryEntry::with_pixels(name, before));
+        }
+    }
+
+    /// Get the undo/redo state of a document
+    pub fn get_history(&self, doc_id: &str) -> AppResult<HistoryState> {
+        self.history
+            .get(doc_id)
+            .map(HistoryManager::state)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))
+    }
+
+    /// Change how many undo steps a document keeps, trimming existing entries
+    pub fn set_history_limit(&mut self, doc_id: &str, limit: usize) -> AppResult<HistoryState> {
+        if limit == 0 {
+            return Err(AppError::InvalidOperation(
+                "History limit must be at least 1".into(),
+            ));
+        }
+
+        let history = self
+            .history
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        history.set_max_entries(limit);
+        Ok(history.state())
+    }
+
+    /// Open a history group so subsequent edits undo as one step named `name`
+    pub fn begin_history_group(&mut self, doc_id: &str, name: &str) -> AppResult<String> {
+        let history = self
+            .history
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        Ok(history.begin_group(name))
+    }
+
+    /// Close the open history group for a document
+    pub fn end_history_group(&mut self, doc_id: &str) -> AppResult<()> {
+        let history = self
+            .history
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        history.end_group();
+        Ok(())
+    }
+
+    /// Mark a document's cached composite as stale
+    pub fn invalidate_composite(&mut self, doc_id: &str) {
+        if let Some(cache) = self.composites.get_mut(doc_id) {
+            cache.dirty = true;
+        }
+    }
+
+    /// Get the composite of all visible layers, recompositing only if an edit
+    /// has invalidated the cached result
+    pub fn get_composite(&mut self, doc_id: &str) -> AppResult<&RgbaImage> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        let is_fresh = self
+            .composites
+            .get(doc_id)
+            .map(|cache| !cache.dirty)
+            .unwrap_or(false);
+
+        if !is_fresh {
+            let image = composite_layers(doc, &self.pixel_data)?;
+            self.composites.insert(
+                doc_id.to_string(),
+                CompositeCache {
+                    image,
+                    dirty: false,
+                },
+            );
+        }
+
+        self.composites
+            .get(doc_id)
+            .map(|cache| &cache.image)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))
+    }
+
+    /// RGBA at a document-space pixel, read from one layer or, with
+    /// `sample_merged`, from the composite of the visible layers.
+    ///
+    /// Returns None outside the canvas; a point on the canvas but outside the
+    /// layer reads as transparent.
+    pub fn sample_color(
+        &mut self,
+        doc_id: &str,
+        layer_id: &str,
+        x: i32,
+        y: i32,
+        sample_merged: bool,
+    ) -> AppResult<Option<[u8; 4]>> {
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        if x < 0 || y < 0 || x >= doc.width as i32 || y >= doc.height as i32 {
+            return Ok(None);
+        }
+
+        if sample_merged {
+            let composite = self.get_composite(doc_id)?;
+            return Ok(Some(composite.get_pixel(x as u32, y as u32).0));
+        }
+
+        let layer = doc
+            .get_layer(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+        let local_x = x - layer.x;
+        let local_y = y - layer.y;
+        if local_x < 0
+            || local_y < 0
+            || local_x >= layer.width as i32
+            || local_y >= layer.height as i32
+        {
+            return Ok(Some([0, 0, 0, 0]));
+        }
+
+        let idx = (local_y as usize * layer.width as usize + local_x as usize) * 4;
+        let color = self
+            .pixel_data
+            .get(layer_id)
+            .and_then(|pixels| pixels.get(idx..idx + 4)?.try_into().ok())
+            .unwrap_or([0, 0, 0, 0]);
+
+        Ok(Some(color))
+    }
+
+    /// Show only `layer_id` in the composite, or go back to normal visibility
+    /// with None. This is a viewing aid and doesn't count as an edit.
+    pub fn set_isolated_layer(&mut self, doc_id: &str, layer_id: Option<&str>) -> AppResult<()> {
+        let doc = self
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        if let Some(layer_id) = layer_id {
+            if doc.get_layer(layer_id).is_none() {
+                return Err(AppError::LayerNotFound(layer_id.to_string()));
+            }
+        }
+
+        doc.isolated_layer = layer_id.map(str::to_string);
+        Ok(())
+    }
+
+    /// Composite just the given layers, in document stacking order.
+    ///
+    /// Listed layers are drawn even if hidden. The result isn't cached.
+    pub fn composite_layer_subset(
+        &mut self,
+        doc_id: &str,
+        layer_ids: &[String],
+    ) -> AppResult<RgbaImage> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        if let Some(missing) = layer_ids.iter().find(|id| doc.get_layer(id).is_none()) {
+            return Err(AppError::LayerNotFound(missing.clone()));
+        }
+
+        let mut subset = doc.clone();
+        subset.isolated_layer = None;
+        subset.layers.retain(|layer| layer_ids.contains(&layer.id));
+        for layer in &mut subset.layers {
+            layer.visible = true;
+        }
+
+        composite_layers(&subset, &self.pixel_data)
+    }
+
+    /// The composite to write into saved files: the same as `get_composite`,
+    /// except that layer isolation is ignored
+    pub fn get_export_composite(&mut self, doc_id: &str) -> AppResult<RgbaImage> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        if doc.isolated_layer.is_none() {
+            return self.get_composite(doc_id).cloned();
+        }
+
+        let mut unisolated = doc.clone();
+        unisolated.isolated_layer = None;
+        composite_layers(&unisolated, &self.pixel_data)
+    }
+
+    pub fn add_layer_to_document(
+        &mut self,
+        doc_id: &str,
+        name: &str,
+        width: u32,
+        height: u32,
+    ) -> AppResult<Layer> {
+        // Check document exists first
+        if !self.documents.contains_key(doc_id) {
+            return Err(AppError::DocumentNotFound(doc_id.to_string()));
+        }
+
+        let layer = Layer::new_raster(name, width, height);
+        let layer_clone = layer.clone();
+
+        // Initialize transparent pixel data
+        let pixels = vec![0u8; checked_rgba_buffer_len(width, height)?];
+        self.pixel_data.insert(layer.id.clone(), pixels);
+
+        // Now add layer to document
+        if let Some(doc) = self.get_mut(doc_id) {
+            doc.add_layer(layer);
+        }
+
+        Ok(layer_clone)
+    }
+
+    /// Add a layer on top holding a merged copy of all visible layers.
+    ///
+    /// Unlike flattening, the original layers are left as they are.
+    pub fn new_layer_from_visible(&mut self, doc_id: &str) -> AppResult<Layer> {
+        let merged = self.get_export_composite(doc_id)?;
+
+        let layer = Layer::new_raster("Merged", merged.width(), merged.height());
+        let layer_clone = layer.clone();
+        self.pixel_data.insert(layer.id.clone(), merged.into_raw());
+
+        let doc = self
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let before = doc.layers.clone();
+        doc.add_layer(layer);
+        self.push_layer_history(doc_id, "Merge Visible", before);
+
+        Ok(layer_clone)
+    }
+
+    /// Add a new top layer holding one channel of a layer as grayscale, at the
+    /// same position and size
+    pub fn extract_channel(
+        &mut self,
+        doc_id: &str,
+        layer_id: &str,
+        channel: Channel,
+    ) -> AppResult<Layer> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let source = doc
+            .get_layer(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+        let pixels = self.layer_pixels_checked(source)?;
+
+        let mut layer = Layer::new_raster(
+            &format!("{} {}", source.name, channel.name()),
+            source.width,
+            source.height,
+        );
+        layer.x = source.x;
+        layer.y = source.y;
+        let layer_clone = layer.clone();
+        self.pixel_data
+            .insert(layer.id.clone(), extract_channel(pixels, channel));
+
+        let doc = self
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let before = doc.layers.clone();
+        doc.add_layer(layer);
+        self.push_layer_history(doc_id, "Extract Channel", before);
+
+        Ok(layer_clone)
+    }
+
+    /// Add a new top layer whose channels are the red channels of the given
+    /// grayscale layers, placed where the red source is. Every source must be
+    /// the same size; without an alpha source the result is opaque.
+    pub fn combine_channels(
+        &mut self,
+        doc_id: &str,
+        red_id: &str,
+        green_id: &str,
+        blue_id: &str,
+        alpha_id: Option<&str>,
+    ) -> AppResult<Layer> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let find = |layer_id: &str| {
+            doc.get_layer(layer_id)
+                .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))
+        };
+        let red = find(red_id)?;
+        let mut sources = vec![red, find(green_id)?, find(blue_id)?];
+        if let Some(alpha_id) = alpha_id {
+            sources.push(find(alpha_id)?);
+        }
+
+        if let Some(other) = sources
+            .iter()
+            .find(|layer| (layer.width, layer.height) != (red.width, red.height))
+        {
+            return Err(AppError::InvalidOperation(format!(
+                "Layer '{}' is {}x{} but '{}' is {}x{}",
+                other.name, other.width, other.height, red.name, red.width, red.height
+            )));
+        }
+
+        let channels = sources
+            .iter()
+            .map(|layer| self.layer_pixels_checked(layer))
+            .collect::<AppResult<Vec<_>>>()?;
+        let pixels = combine_channels(
+            channels[0],
+            channels[1],
+            channels[2],
+            channels.get(3).copied(),
+        );
+
+        let mut layer = Layer::new_raster("Combined", red.width, red.height);
+        layer.x = red.x;
+        layer.y = red.y;
+        let layer_clone = layer.clone();
+        self.pixel_data.insert(layer.id.clone(), pixels);
+
+        let doc = self
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let before = doc.layers.clone();
+        doc.add_layer(layer);
+        self.push_layer_history(doc_id, "Combine Channels", before);
+
+        Ok(layer_clone)
+    }
+
+    /// A layer's pixels, checked to match its size
+    fn layer_pixels_checked(&self, layer: &Layer) -> AppResult<&[u8]> {
+        let pixels = self
+            .pixel_data
+            .get(&layer.id)
+            .ok_or_else(|| AppError::LayerNotFound(layer.id.clone()))?;
+        if rgba_buffer_len(layer.width, layer.height) != Some(pixels.len()) {
+            return Err(AppError::InvalidOperation(
+                "Invalid layer pixel data".into(),
+            ));
+        }
+        Ok(pixels)
+    }
+
+    /// Add a text layer, rasterizing its content into the layer's pixels
+    pub fn add_text_layer(
+        &mut self,
+        doc_id: &str,
+        name: &str,
+        content: TextContent,
+    ) -> AppResult<Layer> {
+        if !self.documents.contains_key(doc_id) {
+            return Err(AppError::DocumentNotFound(doc_id.to_string()));
+        }
+
+        let (width, height, pixels) = render_text(&content)?;
+        let layer = Layer::new_text(name, content, width, height);
+        let layer_clone = layer.clone();
+
+        self.pixel_data.insert(layer.id.clone(), pixels);
+
+        if let Some(doc) = self.get_mut(doc_id) {
+            let before = doc.layers.clone();
+            doc.add_layer(layer);
+            self.push_layer_history(doc_id, "Add Text Layer", before);
+        }
+
+        Ok(layer_clone)
+    }
+
+    /// Add a layer linked to the image file at `path`, showing `image` (the
+    /// decoded file) with its top-left corner at (x, y)
+    pub fn add_linked_image_layer(
+        &mut self,
+        doc_id: &str,
+        name: &str,
+        path: &str,
+        image: RgbaImage,
+        x: i32,
+        y: i32,
+    ) -> AppResult<Layer> {
+        if !self.documents.contains_key(doc_id) {
+            return Err(AppError::DocumentNotFound(doc_id.to_string()));
+        }
+
+        let mut layer = Layer::new_linked_image(name, path, image.width(), image.height());
+        layer.x = x;
+        layer.y = y;
+        let layer_clone = layer.clone();
+
+        self.pixel_data.insert(layer.id.clone(), image.into_raw());
+
+        if let Some(doc) = self.get_mut(doc_id) {
+            let before = doc.layers.clone();
+            doc.add_layer(layer);
+            self.push_layer_history(doc_id, "Add Linked Image", before);
+        }
+
+        Ok(layer_clone)
+    }
+
+    /// Replace a text layer's content and re-render it.
+    ///
+    /// The layer keeps its position; its size follows the rendered text.
+    pub fn set_text_content(
+        &mut self,
+        doc_id: &str,
+        layer_id: &str,
+        content: TextContent,
+    ) -> AppResult<Layer> {
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        let layer = doc
+            .get_layer(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+        if !matches!(layer.layer_type, LayerType::Text) {
+            return Err(AppError::InvalidOperation("Layer is not a text layer".into()));
+        }
+        if layer.pixels_locked() {
+            return Err(AppError::InvalidOperation("Layer is locked".into()));
+        }
+
+        let (width, height, pixels) = render_text(&content)?;
+        let before_layers = doc.layers.clone();
+        let before = self.capture_pixels(doc_id, &[layer_id.to_string()])?;
+
+        let doc = self
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let layer = doc
+            .get_layer_mut(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+        layer.text = Some(content);
+        layer.width = width;
+        layer.height = height;
+        layer.mark_modified();
+        let layer_clone = layer.clone();
+
+        doc.mark_modified();
+        self.pixel_data.insert(layer_id.to_string(), pixels);
+        self.push_document_history(doc_id, "Edit Text", before_layers, before);
+
+        Ok(layer_clone)
+    }
+
+    /// Add a shape layer, rasterizing its geometry into the layer's pixels
+    pub fn add_shape_layer(
+        &mut self,
+        doc_id: &str,
+        name: &str,
+        content: ShapeContent,
+    ) -> AppResult<Layer> {
+        if !self.documents.contains_key(doc_id) {
+            return Err(AppError::DocumentNotFound(doc_id.to_string()));
+        }
+
+        let (width, height, pixels) = render_shape(&content)?;
+        let layer = Layer::new_shape(name, content, width, height);
+        let layer_clone = layer.clone();
+
+        self.pixel_data.insert(layer.id.clone(), pixels);
+
+        if let Some(doc) = self.get_mut(doc_id) {
+            let before = doc.layers.clone();
+            doc.add_layer(layer);
+            self.push_layer_history(doc_id, "Add Shape Layer", before);
+        }
+
+        Ok(layer_clone)
+    }
+
+    /// Replace a shape layer's geometry or style and re-render it.
+    ///
+    /// The layer keeps its position; its size follows the rendered shape.
+    pub fn set_shape_content(
+        &mut self,
+        doc_id: &str,
+        layer_id: &str,
+        content: ShapeContent,
+    ) -> AppResult<Layer> {
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        let layer = doc
+            .get_layer(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+        if !matches!(layer.layer_type, LayerType::Shape) {
+            return Err(AppError::InvalidOperation("Layer is not a shape layer".into()));
+        }
+        if layer.pixels_locked() {
+            return Err(AppError::InvalidOperation("Layer is locked".into()));
+        }
+
+        let (width, height, pixels) = render_shape(&content)?;
+        let before_layers = doc.layers.clone();
+        let before = self.capture_pixels(doc_id, &[layer_id.to_string()])?;
+
+        let doc = self
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let layer = doc
+            .get_layer_mut(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+        layer.shape = Some(content);
+        layer.width = width;
+        layer.height = height;
+        layer.mark_modified();
+        let layer_clone = layer.clone();
+
+        doc.mark_modified();
+        self.pixel_data.insert(layer_id.to_string(), pixels);
+        self.push_document_history(doc_id, "Edit Shape", before_layers, before);
+
+        Ok(layer_clone)
+    }
+
+    /// Turn a text, shape or linked image layer into a plain raster layer,
+    /// keeping its rendered pixels, position and blend settings but dropping
+    /// the content it was rendered from. Recorded as an undo step.
+    pub fn rasterize_layer(&mut self, doc_id: &str, layer_id: &str) -> AppResult<Layer> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let layer = doc
+            .get_layer(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+        match &layer.layer_type {
+            LayerType::Raster => {
+                return Err(AppError::InvalidOperation(
+                    "Layer is already a raster layer".into(),
+                ))
+            }
+            LayerType::Group | LayerType::Adjustment => {
+                return Err(AppError::InvalidOperation(
+                    "Group and adjustment layers can't be rasterized yet".into(),
+                ))
+            }
+            _ => {}
+        }
+        if layer.pixels_locked() {
+            return Err(AppError::InvalidOperation("Layer is locked".into()));
+        }
+        if !self.pixel_data.contains_key(layer_id) {
+            return Err(AppError::LayerNotFound(layer_id.to_string()));
+        }
+
+        let before = doc.layers.clone();
+        let layer = self
+            .documents
+            .get_mut(doc_id)
+            .and_then(|doc| doc.get_layer_mut(layer_id))
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+        layer.layer_type = LayerType::Raster;
+        layer.text = None;
+        layer.shape = None;
+        layer.linked_path = None;
+        layer.mark_modified();
+        let layer = layer.clone();
+
+        if let Some(doc) = self.documents.get_mut(doc_id) {
+            doc.mark_modified();
+        }
+        self.push_layer_history(doc_id, "Rasterize Layer", before);
+        Ok(layer)
+    }
+
+    /// Delete every pixel layer that is entirely transparent, as one undo
+    /// step, and return the removed ids. Group and adjustment layers are
+    /// kept, and so is the bottom layer if it would otherwise remove them all.
+    pub fn prune_empty_layers(&mut self, doc_id: &str) -> AppResult<Vec<String>> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        let mut empty: Vec<String> = doc
+            .layers
+            .iter()
+            .filter(|layer| !matches!(layer.layer_type, LayerType::Group | LayerType::Adjustment))
+            .filter(|layer| {
+                self.pixel_data.get(&layer.id).is_none_or(|pixels| {
+                    Self::alpha_bounds(pixels, layer.width, layer.height).is_none()
+                })
+            })
+            .map(|layer| layer.id.clone())
+            .collect();
+
+        // A document always keeps at least one layer
+        if empty.len() == doc.layers.len() {
+            empty.remove(0);
+        }
+        if empty.is_empty() {
+            return Ok(empty);
+        }
+
+        let before = doc.layers.clone();
+        let doc = self
+            .documents
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        for layer_id in &empty {
+            doc.remove_layer(layer_id)?;
+        }
+
+        // Moves the removed layers' pixels into the undo step
+        self.push_layer_history(doc_id, "Prune Empty Layers", before);
+        self.invalidate_composite(doc_id);
+        Ok(empty)
+    }
+
+    /// Replace a layer's bounds and pixels in one step (e.g. after a transform)
+    pub fn replace_layer_raster(
+        &mut self,
+        doc_id: &str,
+        layer_id: &str,
+        bounds: DirtyRect,
+        pixels: Vec<u8>,
+    ) -> AppResult<Layer> {
+        if rgba_buffer_len(bounds.width, bounds.height) != Some(pixels.len()) {
+            return Err(AppError::InvalidOperation(
+                "Invalid layer pixel data".into(),
+            ));
+        }
+
+        let doc = self
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        let layer = doc
+            .get_layer_mut(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+        layer.x = bounds.x;
+        layer.y = bounds.y;
+        layer.width = bounds.width;
+        layer.height = bounds.height;
+        layer.mark_modified();
+        let layer_clone = layer.clone();
+
+        doc.mark_modified();
+        self.pixel_data.insert(layer_id.to_string(), pixels);
+
+        Ok(layer_clone)
+    }
+
+    /// Register a document that was loaded from a file
+    /// This is used when loading DRKR files
+    pub fn register_loaded_document(
+        &mut self,
+        doc: Document,
+        layer_pixels: std::collections::HashMap<String, Vec<u8>>,
+    ) -> Document {
+        let doc_id = doc.id.clone();
+        let doc_clone = doc.clone();
+
+        // Initialize history for this document
+        self.history
+            .insert(doc_id.clone(), HistoryManager::new(DEFAULT_HISTORY_LIMIT));
+
+        // Store all layer pixels
+        for (layer_id, pixels) in layer_pixels {
+            self.pixel_data.insert(layer_id, pixels);
+        }
+
+        // Add document to manager
+        self.documents.insert(doc_id, doc);
+
+        doc_clone
+    }
+
+    /// Keep a loaded document's extension files so saving writes them back
+    pub fn set_extensions(&mut self, doc_id: &str, extensions: ExtensionFiles) {
+        if extensions.is_empty() {
+            self.extensions.remove(doc_id);
+        } else {
+            self.extensions.insert(doc_id.to_string(), extensions);
+        }
+    }
+
+    pub fn get_extensions(&self, doc_id: &str) -> Option<&ExtensionFiles> {
+        self.extensions.get(doc_id)
+    }
+
+    /// Get all layer pixel data for a document (for saving), resuming it first
+    /// if it is suspended
+    pub fn get_all_layer_pixels(&mut self, doc_id: &str) -> AppResult<HashMap<String, Vec<u8>>> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let mut result = HashMap::new();
+
+        for layer in &doc.layers {
+            if let Some(pixels) = self.pixel_data.get(&layer.id) {
+                result.insert(layer.id.clone(), pixels.clone());
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Store a named snapshot of the document and all its layer pixels
+    pub fn create_snapshot(&mut self, doc_id: &str, name: &str) -> AppResult<SnapshotInfo> {
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?
+            .clone();
+
+        let layer_pixels = self.get_all_layer_pixels(doc_id)?;
+        let snapshot = DocumentSnapshot::new(name, doc, layer_pixels);
+        let info = snapshot.info();
+
+        self.snapshots
+            .entry(doc_id.to_string())
+            .or_default()
+            .push(snapshot);
+
+        Ok(info)
+    }
+
+    /// List a document's snapshots, oldest first
+    pub fn list_snapshots(&self, doc_id: &str) -> AppResult<Vec<SnapshotInfo>> {
+        if !self.documents.contains_key(doc_id) {
+            return Err(AppError::DocumentNotFound(doc_id.to_string()));
+        }
+
+        Ok(self
+            .snapshots
+            .get(doc_id)
+            .map(|snapshots| snapshots.iter().map(DocumentSnapshot::info).collect())
+            .unwrap_or_default())
+    }
+
+    /// Restore a document's layers, pixels, background and isolated layer from
+    /// a snapshot.
+    ///
+    /// The snapshot itself is kept so it can be restored again. The document's
+    /// name and source path are left as they are now.
+    pub fn restore_snapshot(&mut self, doc_id: &str, snapshot_id: &str) -> AppResult<Document> {
+        let snapshot = self
+            .snapshots
+            .get(doc_id)
+            .and_then(|snapshots| snapshots.iter().find(|s| s.id == snapshot_id))
+            .cloned()
+            .ok_or_else(|| {
+                AppError::InvalidOperation(format!("Snapshot not found: {}", snapshot_id))
+            })?;
+
+        let before = self.capture_all_pixels(doc_id)?;
+        let doc = self
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        let old_layer_ids: Vec<String> = doc.layers.iter().map(|l| l.id.clone()).collect();
+        let before_layers = doc.layers.clone();
+
+        doc.width = snapshot.document.width;
+        doc.height = snapshot.document.height;
+        doc.resolution = snapshot.document.resolution;
+        doc.linear_blending = snapshot.document.linear_blending;
+        doc.background = snapshot.document.background;
+        doc.layers = snapshot.document.layers;
+        doc.isolated_layer = snapshot
+            .document
+            .isolated_layer
+            .filter(|layer_id| doc.get_layer(layer_id).is_some());
+        doc.mark_modified();
+        let doc_clone = doc.clone();
+
+        // Swap in the snapshot's pixels, dropping buffers of layers that no longer exist
+        for layer_id in old_layer_ids {
+            self.pixel_data.remove(&layer_id);
+        }
+        self.pixel_data.extend(snapshot.layer_pixels);
+
+        self.push_document_history(doc_id, "Restore Snapshot", before_layers, before);
+
+        Ok(doc_clone)
+    }
+
+    /// The document's active selection, if any.
+    ///
+    /// A selection made on a different canvas size (e.g. before a crop) no
+    /// longer lines up with the pixels and is ignored.
+    pub fn get_selection(&self, doc_id: &str) -> Option<&SelectionMask> {
+        let doc = self.documents.get(doc_id)?;
+        self.selections
+            .get(doc_id)
+            .filter(|mask| mask.width == doc.width && mask.height == doc.height)
+    }
+
+    /// Replace the document's active selection and return its bounds.
+    /// A mask with nothing selected clears the selection.
+    pub fn set_selection(
+        &mut self,
+        doc_id: &str,
+        mask: SelectionMask,
+    ) -> AppResult<Option<DirtyRect>> {
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        if mask.width != doc.width || mask.height != doc.height {
+            return Err(AppError::InvalidOperation(
+                "Selection does not match the canvas size".into(),
+            ));
+        }
+
+        let bounds = mask.bounds();
+        if bounds.is_some() {
+            self.selections.insert(doc_id.to_string(), mask);
+        } else {
+            self.selections.remove(doc_id);
+        }
+        Ok(bounds)
+    }
+
+    /// Deselect everything
+    pub fn clear_selection(&mut self, doc_id: &str) -> AppResult<()> {
+        if !self.documents.contains_key(doc_id) {
+            return Err(AppError::DocumentNotFound(doc_id.to_string()));
+        }
+
+        self.selections.remove(doc_id);
+        Ok(())
+    }
+
+    /// Combine a selection shape with the active selection and return the
+    /// bounds of the result
+    pub fn modify_selection(
+        &mut self,
+        doc_id: &str,
+        op: SelectionOp,
+        selection: &Selection,
+    ) -> AppResult<Option<DirtyRect>> {
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        let shape = SelectionMask::from_selection(selection, doc.width, doc.height);
+        let mut mask = match self.get_selection(doc_id) {
+            Some(active) => active.clone(),
+            None => SelectionMask::empty(doc.width, doc.height),
+        };
+        mask.combine(&shape, op);
+
+        self.set_selection(doc_id, mask)
+    }
+
+    /// Grow, shrink, feather or invert the active selection and return the
+    /// bounds of the result. Inverting with nothing selected selects all.
+    pub fn transform_selection(
+        &mut self,
+        doc_id: &str,
+        op: SelectionTransform,
+    ) -> AppResult<Option<DirtyRect>> {
+        if let SelectionTransform::Feather { radius } = op {
+            if !(0.0..=MAX_FEATHER_RADIUS).contains(&radius) {
+                return Err(AppError::InvalidOperation(format!(
+                    "Feather radius must be between 0 and {}",
+                    MAX_FEATHER_RADIUS
+                )));
+            }
+        }
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        let mut mask = match self.get_selection(doc_id) {
+            Some(active) => active.clone(),
+            None => SelectionMask::empty(doc.width, doc.height),
+        };
+        mask.transform(op);
+
+        self.set_selection(doc_id, mask)
+    }
+
+    /// Replace the active selection with a layer's alpha channel
+    pub fn selection_from_layer_alpha(
+        &mut self,
+        doc_id: &str,
+        layer_id: &str,
+    ) -> AppResult<Option<DirtyRect>> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let layer = doc
+            .get_layer(layer_id)
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))?;
+
+        let layer_bounds = DirtyRect {
+            x: layer.x,
+            y: layer.y,
+            width: layer.width,
+            height: layer.height,
+        };
+        let pixels = self
+            .pixel_data
+            .get(layer_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let mask = SelectionMask::from_alpha(pixels, layer_bounds, doc.width, doc.height);
+
+        self.set_selection(doc_id, mask)
+    }
+
+    /// Store a brush tip for use by any document's strokes. Returns its id.
+    pub fn register_brush_tip(&mut self, tip: BrushTip) -> String {
+        let tip_id = Uuid::new_v4().to_string();
+        self.brush_tips.insert(tip_id.clone(), tip);
+        tip_id
+    }
+
+    pub fn get_brush_tip(&self, tip_id: &str) -> AppResult<&BrushTip> {
+        self.brush_tips
+            .get(tip_id)
+            .ok_or_else(|| AppError::InvalidOperation(format!("Brush tip not found: {}", tip_id)))
+    }
+
+    /// Store brush settings loaded from a preset file. Returns its id.
+    pub fn register_brush_preset(&mut self, settings: BrushStrokeSettings) -> String {
+        let preset_id = Uuid::new_v4().to_string();
+        self.brush_presets.insert(preset_id.clone(), settings);
+        preset_id
+    }
+
+    pub fn get_brush_preset(&self, preset_id: &str) -> AppResult<&BrushStrokeSettings> {
+        self.brush_presets.get(preset_id).ok_or_else(|| {
+            AppError::InvalidOperation(format!("Brush preset not found: {}", preset_id))
+        })
+    }
+
+    /// Set the source path for a document (called after saving)
+    pub fn set_source_path(&mut self, doc_id: &str, path: &str) -> AppResult<()> {
+        let doc = self.documents.get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        doc.source_path = Some(path.to_string());
+        Ok(())
+    }
+
+    /// Rename a document
+    pub fn rename_document(&mut self, doc_id: &str, name: &str) -> AppResult<()> {
+        let doc = self.documents.get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        doc.name = name.to_string();
+        doc.mark_modified();
+        Ok(())
+    }
+
+    /// Switch a document between blending in linear light and in sRGB
+    pub fn set_linear_blending(&mut self, doc_id: &str, enabled: bool) -> AppResult<Document> {
+        let doc = self
+            .documents
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        doc.linear_blending = enabled;
+        doc.mark_modified();
+        let doc = doc.clone();
+
+        self.invalidate_composite(doc_id);
+        Ok(doc)
+    }
+
+    /// Replace a document's metadata and layer list with `state`, as exported
+    /// by another window or peer.
+    ///
+    /// Pixels aren't part of the state, so every layer in it must already be in
+    /// the document at the same size; layers it leaves out are removed. The id,
+    /// canvas size, source path and creation time are kept. Recorded as one
+    /// undo step.
+    pub fn apply_document_state(&mut self, doc_id: &str, state: Document) -> AppResult<Document> {
+        self.resume_document(doc_id)?;
+
+        let doc = self
+            .documents
+            .get(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        if state.id != doc.id {
+            return Err(AppError::InvalidOperation(format!(
+                "State belongs to document {}",
+                state.id
+            )));
+        }
+        if (state.width, state.height) != (doc.width, doc.height) {
+            return Err(AppError::InvalidOperation(
+                "State canvas size does not match the document".into(),
+            ));
+        }
+
+        let mut seen = HashSet::new();
+        for layer in &state.layers {
+            if !seen.insert(layer.id.as_str()) {
+                return Err(AppError::InvalidOperation(format!(
+                    "Duplicate layer in state: {}",
+                    layer.id
+                )));
+            }
+            let existing = doc
+                .get_layer(&layer.id)
+                .ok_or_else(|| AppError::LayerNotFound(layer.id.clone()))?;
+            if (layer.width, layer.height) != (existing.width, existing.height) {
+                return Err(AppError::InvalidOperation(format!(
+                    "Layer {} changed size",
+                    layer.id
+                )));
+            }
+        }
+        if let Some(isolated) = &state.isolated_layer {
+            if !seen.contains(isolated.as_str()) {
+                return Err(AppError::LayerNotFound(isolated.clone()));
+            }
+        }
+
+        let before = doc.layers.clone();
+        let doc = self
+            .documents
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        doc.name = state.name;
+        doc.resolution = state.resolution;
+        doc.layers = state.layers;
+        doc.background = state.background;
+        doc.isolated_layer = state.isolated_layer;
+        doc.linear_blending = state.linear_blending;
+        doc.mark_modified();
+        let doc = doc.clone();
+
+        self.push_layer_history(doc_id, "Apply State", before);
+        self.invalidate_composite(doc_id);
+        Ok(doc)
+    }
+
+    /// List all open document IDs, in the order they were opened
+    #[allow(dead_code)]
+    pub fn list_documents(&self) -> Vec<String> {
+        self.get_all_documents()
+            .into_iter()
+            .map(|doc| doc.id.clone())
+            .collect()
+    }
+
+    /// Get all open documents, oldest first.
+    ///
+    /// Sorted by creation time (id breaks ties) so the order doesn't depend on
+    /// HashMap iteration and stays the same between calls.
+    pub fn get_all_documents(&self) -> Vec<&Document> {
+        let mut documents: Vec<&Document> = self.documents.values().collect();
+        documents.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        documents
+    }
+
+    /// Collapse every layer into a single raster layer holding the composite.
+    ///
+    /// Hidden layers are discarded. The old layers' pixel data moves into the
+    /// undo step, so undoing brings them all back.
+    pub fn flatten_document(&mut self, doc_id: &str) -> AppResult<Document> {
+        let merged = self.get_export_composite(doc_id)?;
+
+        let doc = self
+            .documents
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        let flattened = Layer::new_raster("Background", doc.width, doc.height);
+        self.pixel_data
+            .insert(flattened.id.clone(), merged.into_raw());
+        let before = std::mem::replace(&mut doc.layers, vec![flattened]);
+        doc.isolated_layer = None;
+        doc.mark_modified();
+
+        let doc = doc.clone();
+        self.invalidate_composite(doc_id);
+        self.push_layer_history(doc_id, "Flatten", before);
+
+        Ok(doc)
+    }
+
+    /// Change the document's resolution (pixels per inch).
+    ///
+    /// Without `resample` only the metadata changes, so the print size follows
+    /// the new resolution. With it, the canvas and every layer are scaled so the
+    /// print size stays the same; text and shape layers are re-rendered from
+    /// their content rather than resized.
+    pub fn set_resolution(
+        &mut self,
+        doc_id: &str,
+        resolution: u32,
+        resample: bool,
+    ) -> AppResult<Document> {
+        if resolution == 0 {
+            return Err(AppError::InvalidOperation(
+                "Resolution must be greater than zero".into(),
+            ));
+        }
+
+        self.resume_document(doc_id)?;
+        let doc = self
+            .documents
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        if !resample || resolution == doc.resolution {
+            let before = PixelState {
+                width: doc.width,
+                height: doc.height,
+                resolution: doc.resolution,
+                layers: Vec::new(),
+            };
+            doc.resolution = resolution;
+            doc.mark_modified();
+            let doc = doc.clone();
+            self.push_pixel_history(doc_id, "Resolution", before);
+            return Ok(doc);
+        }
+
+        let before = self.capture_all_pixels(doc_id)?;
+        let doc = self
+            .documents
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+        let before_layers = doc.layers.clone();
+
+        let scale = resolution as f64 / doc.resolution as f64;
+        let scale_len = |len: u32| ((len as f64 * scale).round() as u32).max(1);
+        let new_width = scale_len(doc.width);
+        let new_height = scale_len(doc.height);
+
+        let area = new_width as u64 * new_height as u64;
+        if area > self.max_canvas_area {
+            return Err(AppError::InvalidOperation(format!(
+                "A {}x{} canvas exceeds the maximum of {} pixels",
+                new_width, new_height, self.max_canvas_area
+            )));
+        }
+
+        // Render everything before touching the document so a failure leaves
+        // it as it was
+        let mut scaled: Vec<Layer> = Vec::with_capacity(doc.layers.len());
+        let mut scaled_pixels: Vec<Option<Vec<u8>>> = Vec::with_capacity(doc.layers.len());
+        for layer in &doc.layers {
+            let mut layer = layer.clone();
+            layer.x = (layer.x as f64 * scale).round() as i32;
+            layer.y = (layer.y as f64 * scale).round() as i32;
+
+            let pixels = if let Some(text) = layer.text.as_mut() {
+                text.size *= scale as f32;
+                let (width, height, pixels) = render_text(text)?;
+                layer.width = width;
+                layer.height = height;
+                Some(pixels)
+            } else if let Some(shape) = layer.shape.as_mut() {
+                *shape = shape.scaled(scale as f32);
+                let (width, height, pixels) = render_shape(shape)?;
+                layer.width = width;
+                layer.height = height;
+                Some(pixels)
+            } else {
+                let width = scale_len(layer.width);
+                let height = scale_len(layer.height);
+                let layer_area = width as u64 * height as u64;
+                if layer_area > self.max_canvas_area {
+                    return Err(AppError::InvalidOperation(format!(
+                        "Layer '{}' would be {}x{}, exceeding the maximum of {} pixels",
+                        layer.name, width, height, self.max_canvas_area
+                    )));
+                }
+
+                let pixels = self.pixel_data.get(&layer.id).and_then(|pixels| {
+                    RgbaImage::from_raw(layer.width, layer.height, pixels.clone())
+                });
+                layer.width = width;
+                layer.height = height;
+                pixels.map(|img| resize_premultiplied(&img, width, height).into_raw())
+            };
+
+            layer.mark_modified();
+            scaled.push(layer);
+            scaled_pixels.push(pixels);
+        }
+
+        for (layer, pixels) in scaled.iter().zip(scaled_pixels) {
+            if let Some(pixels) = pixels {
+                self.pixel_data.insert(layer.id.clone(), pixels);
+            }
+        }
+
+        doc.layers = scaled;
+        doc.width = new_width;
+        doc.height = new_height;
+        doc.resolution = resolution;
+        doc.mark_modified();
+        let doc = doc.clone();
+
+        // A selection drawn at the old size no longer lines up with anything
+        self.selections.remove(doc_id);
+        self.invalidate_composite(doc_id);
+        self.push_document_history(doc_id, "Resample", before_layers, before);
+
+        Ok(doc)
+    }
+
+    /// Crop the document to the specified region.
+    ///
+    /// This modifies the document dimensions and crops/expands all layer pixel buffers.
+    /// Negative x/y values extend the canvas, positive values crop into existing content.
+    ///
+    /// # Arguments
+    /// * `doc_id` - The document ID
+    /// * `crop_x` - X coordinate of crop region origin (in original document space)
+    /// * `crop_y` - Y coordinate of crop region origin
+    /// * `new_width` - Width of the cropped document
+    /// * `new_height` - Height of the cropped document
+    pub fn crop_document(
+        &mut self,
+        doc_id: &str,
+        crop_x: i32,
+        crop_y: i32,
+        new_width: u32,
+        new_height: u32,
+    ) -> AppResult<CropResult> {
+        if new_width == 0 || new_height == 0 {
+            return Err(AppError::InvalidOperation(
+                "Crop dimensions must be greater than zero".into(),
+            ));
+        }
+        self.resume_document(doc_id)?;
+
+        // Every layer gets a buffer of this size, so refuse before allocating
+        let area = new_width as u64 * new_height as u64;
+        if area > self.max_canvas_area {
+            return Err(AppError::InvalidOperation(format!(
+                "A {}x{} canvas exceeds the maximum of {} pixels",
+                new_width, new_height, self.max_canvas_area
+            )));
+        }
+
+        let doc = self
+            .documents
+            .get_mut(doc_id)
+            .ok_or_else(|| AppError::DocumentNotFound(doc_id.to_string()))?;
+
+        let mut layers_affected: Vec<String> = Vec::new();
+
+        // Process each layer
+        for layer in &mut doc.layers {
+            // Get current pixel data for this layer; a layer without any reads as
+            // transparent since crop_layer_pixels bounds-checks every copy
+            let old_pixels = self
+                .pixel_data
+                .get(&layer.id)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+
+            // Calculate new layer pixels
+            let new_pixels = Self::crop_layer_pixels(
+                old_pixels,
+                layer.width,
+                layer.height,
+                layer.x,
+                layer.y,
+                crop_x,
+                crop_y,
+                new_width,
+                new_height,
+            );
+
+            // Update layer dimensions and position
+            // After crop, layer position is adjusted relative to new document origin
+            layer.x -= crop_x;
+            layer.y -= crop_y;
+            layer.width = new_width;
+            layer.height = new_height;
+            layer.mark_modified();
+
+            // Store new pixel data
+            self.pixel_data.insert(layer.id.clone(), new_pixels);
+            layers_affected.push(layer.id.clone());
+        }
+
+        // Update document dimensions
+        doc.width = new_width;
+        doc.height = new_height;
+        doc.mark_modified();
+
+        self.invalidate_composite(doc_id);
+
+        Ok(CropResult {
+            doc_id: doc_id.to_string(),
+            new_width,
+            new_height,
+            layers_affected,
         })
     }
 
@@ -331,7 +2405,8 @@ impl DocumentManager {
         new_width: u32,
         new_height: u32,
     ) -> Vec<u8> {
-        let mut new_pixels = vec![0u8; (new_width * new_height * 4) as usize];
+        // Widen before multiplying; the area limit keeps this in range
+        let mut new_pixels = vec![0u8; new_width as usize * new_height as usize * 4];
 
         // For each pixel in the new buffer, determine if it should come from old buffer
         for new_y in 0..new_height as i32 {
@@ -351,8 +2426,9 @@ impl DocumentManager {
                     && old_layer_y < old_height as i32
                 {
                     // Copy pixel from old buffer
-                    let old_idx = ((old_layer_y as u32 * old_width + old_layer_x as u32) * 4) as usize;
-                    let new_idx = ((new_y as u32 * new_width + new_x as u32) * 4) as usize;
+                    let old_idx =
+                        (old_layer_y as usize * old_width as usize + old_layer_x as usize) * 4;
+                    let new_idx = (new_y as usize * new_width as usize + new_x as usize) * 4;
 
                     if old_idx + 3 < old_pixels.len() && new_idx + 3 < new_pixels.len() {
                         new_pixels[new_idx] = old_pixels[old_idx];
@@ -374,3 +2450,1113 @@ impl Default for DocumentManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::brush::{stroke_layer, BrushColor, BrushStrokePoint, BrushStrokeSettings};
+    use crate::engine::layer::{BlendMode, ShapeGeometry};
+
+    fn white_document(manager: &mut DocumentManager, width: u32, height: u32) -> Document {
+        manager
+            .create_with_background("Test", width, height, 72, Background::default())
+            .unwrap()
+    }
+
+    fn round_brush(size: f64) -> BrushStrokeSettings {
+        serde_json::from_value(serde_json::json!({
+            "size": size,
+            "hardness": 100.0,
+            "opacity": 100.0,
+            "flow": 100.0,
+            "spacing": 25.0,
+        }))
+        .unwrap()
+    }
+
+    fn point(x: f64, y: f64) -> BrushStrokePoint {
+        BrushStrokePoint {
+            x,
+            y,
+            pressure: None,
+            timestamp: 0,
+        }
+    }
+
+    fn red() -> BrushColor {
+        BrushColor {
+            r: 255,
+            g: 0,
+            b: 0,
+            a: 1.0,
+        }
+    }
+
+    fn pixels(manager: &mut DocumentManager, layer_id: &str) -> Vec<u8> {
+        manager.get_layer_pixels(layer_id).unwrap().clone()
+    }
+
+    #[test]
+    fn undoing_a_brush_stroke_restores_the_layer() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 64, 64);
+        let layer_id = doc.layers[0].id.clone();
+        let before = pixels(&mut manager, &layer_id);
+
+        let points = [point(10.0, 10.0), point(50.0, 40.0)];
+        let dirty = stroke_layer(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &points,
+            &round_brush(8.0),
+            &red(),
+            false,
+        )
+        .unwrap()
+        .dirty;
+        assert!(dirty.is_some());
+        let after = pixels(&mut manager, &layer_id);
+        assert_ne!(after, before);
+
+        manager.undo(&doc.id).unwrap();
+        assert_eq!(pixels(&mut manager, &layer_id), before);
+
+        manager.redo(&doc.id).unwrap();
+        assert_eq!(pixels(&mut manager, &layer_id), after);
+    }
+
+    #[test]
+    fn undoing_a_stroke_that_grew_the_layer_restores_its_bounds() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 64, 64);
+        let layer = manager
+            .add_layer_to_document(&doc.id, "Small", 8, 8)
+            .unwrap();
+        let before = pixels(&mut manager, &layer.id);
+
+        let points = [point(4.0, 4.0), point(40.0, 40.0)];
+        stroke_layer(
+            &mut manager,
+            &doc.id,
+            &layer.id,
+            &points,
+            &round_brush(6.0),
+            &red(),
+            false,
+        )
+        .unwrap();
+        let grown = manager
+            .get(&doc.id)
+            .unwrap()
+            .get_layer(&layer.id)
+            .unwrap()
+            .clone();
+        assert!(grown.width > 8 && grown.height > 8);
+        let after = pixels(&mut manager, &layer.id);
+
+        let undone = manager.undo(&doc.id).unwrap();
+        let restored = undone.get_layer(&layer.id).unwrap();
+        assert_eq!((restored.x, restored.y), (0, 0));
+        assert_eq!((restored.width, restored.height), (8, 8));
+        assert_eq!(pixels(&mut manager, &layer.id), before);
+
+        let redone = manager.redo(&doc.id).unwrap();
+        let layer = redone.get_layer(&layer.id).unwrap();
+        assert_eq!((layer.width, layer.height), (grown.width, grown.height));
+        assert_eq!(pixels(&mut manager, &layer.id), after);
+    }
+
+    #[test]
+    fn a_small_stroke_on_a_large_layer_saves_only_its_region() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 2000, 2000);
+        let layer_id = doc.layers[0].id.clone();
+
+        let points = [point(1000.0, 1000.0), point(1004.0, 1002.0)];
+        stroke_layer(
+            &mut manager,
+            &doc.id,
+            &layer_id,
+            &points,
+            &round_brush(4.0),
+            &red(),
+            false,
+        )
+        .unwrap();
+
+        let entry = manager.history.get_mut(&doc.id).unwrap().undo().unwrap();
+        let saved = &entry.pixels.as_ref().unwrap().layers;
+        assert_eq!(saved.len(), 1);
+        let SavedPixels::Region { bytes, .. } = &saved[0].pixels else {
+            panic!("expected a region, got a full copy");
+        };
+        assert!(!bytes.is_empty());
+        assert!(bytes.len() * 1000 < 2000 * 2000 * 4);
+    }
+
+    #[test]
+    fn undoing_a_move_restores_position_and_baked_pixels() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 4, 4);
+        let layer_id = doc.layers[0].id.clone();
+
+        manager
+            .move_layer(&doc.id, &layer_id, 3, -2, false)
+            .unwrap();
+        let undone = manager.undo(&doc.id).unwrap();
+        let layer = undone.get_layer(&layer_id).unwrap();
+        assert_eq!((layer.x, layer.y), (0, 0));
+
+        let before = pixels(&mut manager, &layer_id);
+        manager.move_layer(&doc.id, &layer_id, 2, 0, true).unwrap();
+        assert_ne!(pixels(&mut manager, &layer_id), before);
+        manager.undo(&doc.id).unwrap();
+        assert_eq!(pixels(&mut manager, &layer_id), before);
+    }
+
+    #[test]
+    fn undoing_merge_visible_removes_the_merged_layer() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 4, 4);
+
+        let merged = manager.new_layer_from_visible(&doc.id).unwrap();
+        let merged_pixels = pixels(&mut manager, &merged.id);
+
+        let undone = manager.undo(&doc.id).unwrap();
+        assert!(undone.get_layer(&merged.id).is_none());
+        assert!(manager.get_layer_pixels(&merged.id).is_none());
+
+        let redone = manager.redo(&doc.id).unwrap();
+        assert!(redone.get_layer(&merged.id).is_some());
+        assert_eq!(pixels(&mut manager, &merged.id), merged_pixels);
+    }
+
+    #[test]
+    fn undoing_a_resample_restores_size_resolution_and_pixels() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 10, 6);
+        let layer_id = doc.layers[0].id.clone();
+        let before = pixels(&mut manager, &layer_id);
+
+        let resampled = manager.set_resolution(&doc.id, 144, true).unwrap();
+        assert_eq!((resampled.width, resampled.height), (20, 12));
+        let after = pixels(&mut manager, &layer_id);
+
+        let undone = manager.undo(&doc.id).unwrap();
+        assert_eq!(
+            (undone.width, undone.height, undone.resolution),
+            (10, 6, 72)
+        );
+        let layer = undone.get_layer(&layer_id).unwrap();
+        assert_eq!((layer.width, layer.height), (10, 6));
+        assert_eq!(pixels(&mut manager, &layer_id), before);
+
+        let redone = manager.redo(&doc.id).unwrap();
+        assert_eq!(
+            (redone.width, redone.height, redone.resolution),
+            (20, 12, 144)
+        );
+        assert_eq!(pixels(&mut manager, &layer_id), after);
+    }
+
+    #[test]
+    fn flattening_leaves_one_document_sized_layer() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 3, 2);
+        let background = doc.layers[0].id.clone();
+        let multiply = manager
+            .add_layer_to_document(&doc.id, "Multiply", 3, 2)
+            .unwrap();
+        let hidden = manager
+            .add_layer_to_document(&doc.id, "Hidden", 1, 1)
+            .unwrap();
+        manager.set_layer_pixels(&background, [200u8, 100, 50, 255].repeat(6));
+        manager.set_layer_pixels(&multiply.id, [128u8, 255, 0, 255].repeat(6));
+        {
+            let doc = manager.get_mut(&doc.id).unwrap();
+            doc.get_layer_mut(&multiply.id).unwrap().blend_mode = BlendMode::Multiply;
+            doc.get_layer_mut(&hidden.id).unwrap().visible = false;
+        }
+
+        let flat = manager.flatten_document(&doc.id).unwrap();
+        assert_eq!(flat.layers.len(), 1);
+        let layer = &flat.layers[0];
+        assert_eq!((layer.x, layer.y, layer.width, layer.height), (0, 0, 3, 2));
+        assert_eq!(&pixels(&mut manager, &layer.id)[..4], &[100, 100, 0, 255]);
+        for old in [&background, &multiply.id, &hidden.id] {
+            assert!(manager.get_layer_pixels(old).is_none());
+        }
+
+        let undone = manager.undo(&doc.id).unwrap();
+        assert_eq!(undone.layers.len(), 3);
+        assert_eq!(
+            pixels(&mut manager, &multiply.id),
+            [128u8, 255, 0, 255].repeat(6)
+        );
+        assert!(manager.get_layer_pixels(&layer.id).is_none());
+    }
+
+    #[test]
+    fn undoing_a_snapshot_restore_brings_back_the_replaced_layers() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 4, 4);
+        let snapshot = manager.create_snapshot(&doc.id, "Plain").unwrap();
+
+        let added = manager
+            .add_layer_to_document(&doc.id, "Extra", 4, 4)
+            .unwrap();
+        manager.set_layer_pixels(&added.id, [1u8, 2, 3, 255].repeat(16));
+
+        let restored = manager.restore_snapshot(&doc.id, &snapshot.id).unwrap();
+        assert!(restored.get_layer(&added.id).is_none());
+
+        let undone = manager.undo(&doc.id).unwrap();
+        assert!(undone.get_layer(&added.id).is_some());
+        assert_eq!(pixels(&mut manager, &added.id), [1u8, 2, 3, 255].repeat(16));
+
+        let redone = manager.redo(&doc.id).unwrap();
+        assert!(redone.get_layer(&added.id).is_none());
+        assert!(manager.get_layer_pixels(&added.id).is_none());
+    }
+
+    #[test]
+    fn a_suspended_document_saves_and_reopens_with_its_pixels() {
+        use crate::io::drkr::{DrkrReader, DrkrWriter, PixelFormat};
+        use std::io::Cursor;
+
+        let mut manager = DocumentManager::new();
+        manager.set_tile_cache_budget(Some(1024)).unwrap();
+        let doc = white_document(&mut manager, 300, 300);
+        let layer_id = doc.layers[0].id.clone();
+        let painted: Vec<u8> = (0..300 * 300)
+            .flat_map(|i| [(i % 251) as u8, (i % 13) as u8, 7, 255])
+            .collect();
+        manager.set_layer_pixels(&layer_id, painted.clone());
+        manager.suspend_document(&doc.id).unwrap();
+
+        let layer_pixels = manager.get_all_layer_pixels(&doc.id).unwrap();
+        let merged = manager.get_export_composite(&doc.id).unwrap();
+        let mut writer =
+            DrkrWriter::new(Cursor::new(Vec::new())).with_pixel_format(PixelFormat::Png);
+        writer
+            .write_document_with_composite(&doc, &layer_pixels, merged)
+            .unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        let reopened = DrkrReader::new(Cursor::new(bytes))
+            .unwrap()
+            .read_all()
+            .unwrap();
+        assert_eq!(reopened.layer_pixels[&layer_id], painted);
+    }
+
+    #[test]
+    fn a_snapshot_of_a_suspended_document_keeps_its_pixels() {
+        let mut manager = DocumentManager::new();
+        manager.set_tile_cache_budget(Some(1024)).unwrap();
+        let doc = white_document(&mut manager, 8, 8);
+        let layer_id = doc.layers[0].id.clone();
+        manager.suspend_document(&doc.id).unwrap();
+
+        let snapshot = manager.create_snapshot(&doc.id, "Suspended").unwrap();
+        manager.set_layer_pixels(&layer_id, vec![0; 8 * 8 * 4]);
+        manager.restore_snapshot(&doc.id, &snapshot.id).unwrap();
+
+        assert_eq!(pixels(&mut manager, &layer_id), [255u8; 4].repeat(64));
+    }
+
+    fn text(text: &str) -> TextContent {
+        TextContent {
+            text: text.into(),
+            font_family: "Sans".into(),
+            size: 12.0,
+            color: [0, 0, 0, 255],
+        }
+    }
+
+    #[test]
+    fn a_locked_text_layer_keeps_its_text() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 4, 4);
+        let mut layer = Layer::new_text("Text", text("Before"), 2, 2);
+        layer.lock_pixels = true;
+        let layer_id = layer.id.clone();
+        manager.set_layer_pixels(&layer_id, vec![0; 16]);
+        manager.get_mut(&doc.id).unwrap().layers.push(layer);
+
+        let result = manager.set_text_content(&doc.id, &layer_id, text("After"));
+        assert!(matches!(result, Err(AppError::InvalidOperation(_))));
+        let layer = manager.get(&doc.id).unwrap().get_layer(&layer_id).unwrap();
+        assert_eq!(layer.text.as_ref().unwrap().text, "Before");
+    }
+
+    fn rectangle(width: f32, height: f32) -> ShapeContent {
+        ShapeContent {
+            shape: ShapeGeometry::Rectangle { width, height },
+            fill: Some([255, 0, 0, 255]),
+            stroke: None,
+            stroke_width: 0.0,
+        }
+    }
+
+    #[test]
+    fn shape_layer_edits_undo_one_step_at_a_time() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 16, 16);
+        let layer = manager
+            .add_shape_layer(&doc.id, "Box", rectangle(4.0, 4.0))
+            .unwrap();
+        let small = pixels(&mut manager, &layer.id);
+
+        let grown = manager
+            .set_shape_content(&doc.id, &layer.id, rectangle(8.0, 6.0))
+            .unwrap();
+        assert_ne!((grown.width, grown.height), (layer.width, layer.height));
+
+        // Undo the edit: old size, geometry and pixels
+        let undone = manager.undo(&doc.id).unwrap();
+        let restored = undone.get_layer(&layer.id).unwrap();
+        assert_eq!(
+            (restored.width, restored.height),
+            (layer.width, layer.height)
+        );
+        assert!(matches!(
+            restored.shape.as_ref().unwrap().shape,
+            ShapeGeometry::Rectangle { width, .. } if width == 4.0
+        ));
+        assert_eq!(pixels(&mut manager, &layer.id), small);
+
+        // Undo the add: the layer is gone
+        let undone = manager.undo(&doc.id).unwrap();
+        assert!(undone.get_layer(&layer.id).is_none());
+    }
+
+    #[test]
+    fn a_locked_shape_layer_keeps_its_shape() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 16, 16);
+        let layer = manager
+            .add_shape_layer(&doc.id, "Box", rectangle(4.0, 4.0))
+            .unwrap();
+        manager
+            .get_mut(&doc.id)
+            .unwrap()
+            .get_layer_mut(&layer.id)
+            .unwrap()
+            .locked = true;
+
+        let result = manager.set_shape_content(&doc.id, &layer.id, rectangle(8.0, 8.0));
+        assert!(matches!(result, Err(AppError::InvalidOperation(_))));
+        let kept = manager.get(&doc.id).unwrap().get_layer(&layer.id).unwrap();
+        assert_eq!((kept.width, kept.height), (layer.width, layer.height));
+    }
+
+    #[test]
+    fn adding_a_linked_image_can_be_undone_and_redone() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 8, 8);
+        let image = RgbaImage::from_pixel(2, 3, image::Rgba([9, 8, 7, 255]));
+        let layer = manager
+            .add_linked_image_layer(&doc.id, "Linked", "/tmp/linked.png", image, 1, 2)
+            .unwrap();
+
+        let undone = manager.undo(&doc.id).unwrap();
+        assert!(undone.get_layer(&layer.id).is_none());
+
+        let redone = manager.redo(&doc.id).unwrap();
+        let restored = redone.get_layer(&layer.id).unwrap();
+        assert_eq!(restored.linked_path.as_deref(), Some("/tmp/linked.png"));
+        assert_eq!((restored.x, restored.y), (1, 2));
+        assert_eq!(pixels(&mut manager, &layer.id), [9u8, 8, 7, 255].repeat(6));
+    }
+
+    #[test]
+    fn rasterizing_respects_the_pixel_lock() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 16, 16);
+        let layer = manager
+            .add_shape_layer(&doc.id, "Box", rectangle(4.0, 4.0))
+            .unwrap();
+        let set_lock_pixels = |manager: &mut DocumentManager, locked: bool| {
+            manager
+                .get_mut(&doc.id)
+                .unwrap()
+                .get_layer_mut(&layer.id)
+                .unwrap()
+                .lock_pixels = locked;
+        };
+
+        set_lock_pixels(&mut manager, true);
+        let result = manager.rasterize_layer(&doc.id, &layer.id);
+        assert!(matches!(result, Err(AppError::InvalidOperation(_))));
+
+        set_lock_pixels(&mut manager, false);
+        let rasterized = manager.rasterize_layer(&doc.id, &layer.id).unwrap();
+        assert!(matches!(rasterized.layer_type, LayerType::Raster));
+        assert!(rasterized.shape.is_none());
+    }
+
+    #[test]
+    fn restoring_a_snapshot_brings_back_its_background_and_isolation() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 4, 4);
+        let snapshot = manager.create_snapshot(&doc.id, "White").unwrap();
+
+        let added = manager
+            .add_layer_to_document(&doc.id, "Extra", 4, 4)
+            .unwrap();
+        let current = manager.get_mut(&doc.id).unwrap();
+        current.background = Background::Transparent;
+        current.isolated_layer = Some(added.id.clone());
+
+        let restored = manager.restore_snapshot(&doc.id, &snapshot.id).unwrap();
+        assert_eq!(restored.background, Background::default());
+        assert_eq!(restored.isolated_layer, None);
+    }
+
+    #[test]
+    fn the_cached_composite_follows_pixel_and_visibility_edits() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 2, 2);
+        let layer_id = doc.layers[0].id.clone();
+
+        let composite = manager.get_composite(&doc.id).unwrap();
+        assert_eq!(composite.get_pixel(0, 0).0, [255, 255, 255, 255]);
+        assert!(!manager.composites[&doc.id].dirty);
+
+        manager.set_layer_pixels(&layer_id, [255u8, 0, 0, 255].repeat(4));
+        assert!(manager.composites[&doc.id].dirty);
+        let composite = manager.get_composite(&doc.id).unwrap();
+        assert_eq!(composite.get_pixel(1, 1).0, [255, 0, 0, 255]);
+
+        manager.get_mut(&doc.id).unwrap().layers[0].visible = false;
+        let composite = manager.get_composite(&doc.id).unwrap();
+        assert_eq!(composite.get_pixel(1, 1).0, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn content_bounds_cover_the_opaque_pixels() {
+        let mut manager = DocumentManager::new();
+        let doc = manager.create("Test", 6, 4, 72).unwrap();
+        let layer_id = doc.layers[0].id.clone();
+        assert_eq!(
+            manager
+                .get_layer_content_bounds(&doc.id, &layer_id)
+                .unwrap(),
+            None
+        );
+
+        let mut pixels = vec![0u8; 6 * 4 * 4];
+        for (x, y) in [(1, 1), (4, 2)] {
+            pixels[(y * 6 + x) * 4 + 3] = 255;
+        }
+        manager.set_layer_pixels(&layer_id, pixels);
+        assert_eq!(
+            manager
+                .get_layer_content_bounds(&doc.id, &layer_id)
+                .unwrap(),
+            Some((1, 1, 4, 2))
+        );
+    }
+
+    #[test]
+    fn history_state_lists_undo_and_redo_labels_in_order() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 4, 4);
+        manager.push_layer_history(&doc.id, "Add Layer", doc.layers.clone());
+        manager.push_layer_history(&doc.id, "Rename Layer", doc.layers.clone());
+        manager.push_layer_history(&doc.id, "Move Layer", doc.layers.clone());
+
+        manager.undo(&doc.id).unwrap();
+        manager.undo(&doc.id).unwrap();
+        let state = manager.get_history(&doc.id).unwrap();
+        assert!(state.can_undo);
+        assert!(state.can_redo);
+        assert_eq!(state.undo_labels, ["Add Layer"]);
+        assert_eq!(state.redo_labels, ["Rename Layer", "Move Layer"]);
+
+        manager.redo(&doc.id).unwrap();
+        let state = manager.get_history(&doc.id).unwrap();
+        assert_eq!(state.undo_labels, ["Add Layer", "Rename Layer"]);
+        assert_eq!(state.redo_labels, ["Move Layer"]);
+        assert!(manager.get_history("missing").is_err());
+    }
+
+    #[test]
+    fn a_layer_subset_composites_in_stacking_order_even_if_hidden() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 2, 2);
+        let red_layer = manager.add_layer_to_document(&doc.id, "Red", 2, 2).unwrap();
+        manager.set_layer_pixels(&red_layer.id, [255, 0, 0, 255].repeat(4));
+        let blue_layer = manager
+            .add_layer_to_document(&doc.id, "Blue", 2, 2)
+            .unwrap();
+        manager.set_layer_pixels(&blue_layer.id, [0, 0, 255, 255].repeat(4));
+        let doc_mut = manager.get_mut(&doc.id).unwrap();
+        doc_mut.get_layer_mut(&red_layer.id).unwrap().visible = false;
+
+        let red_only = manager
+            .composite_layer_subset(&doc.id, std::slice::from_ref(&red_layer.id))
+            .unwrap();
+        assert_eq!(red_only.get_pixel(0, 0).0, [255, 0, 0, 255]);
+
+        // The blue layer sits above the red one whatever order they're listed in
+        let both = manager
+            .composite_layer_subset(&doc.id, &[blue_layer.id.clone(), red_layer.id.clone()])
+            .unwrap();
+        assert_eq!(both.get_pixel(1, 1).0, [0, 0, 255, 255]);
+
+        let missing = manager.composite_layer_subset(&doc.id, &["missing".to_string()]);
+        assert!(matches!(missing, Err(AppError::LayerNotFound(_))));
+    }
+
+    #[test]
+    fn an_isolated_layer_is_composited_alone_until_cleared() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 2, 2);
+        let red_layer = manager.add_layer_to_document(&doc.id, "Red", 2, 2).unwrap();
+        manager.set_layer_pixels(&red_layer.id, [255, 0, 0, 128].repeat(4));
+        let blue_layer = manager
+            .add_layer_to_document(&doc.id, "Blue", 2, 2)
+            .unwrap();
+        manager.set_layer_pixels(&blue_layer.id, [0, 0, 255, 255].repeat(4));
+
+        manager
+            .set_isolated_layer(&doc.id, Some(&red_layer.id))
+            .unwrap();
+        let isolated = manager.get_composite(&doc.id).unwrap().get_pixel(0, 0).0;
+        assert_eq!(isolated, [255, 0, 0, 128]);
+        let export = manager.get_export_composite(&doc.id).unwrap();
+        assert_eq!(export.get_pixel(0, 0).0, [0, 0, 255, 255]);
+
+        manager.set_isolated_layer(&doc.id, None).unwrap();
+        let normal = manager.get_composite(&doc.id).unwrap().get_pixel(0, 0).0;
+        assert_eq!(normal, [0, 0, 255, 255]);
+
+        let missing = manager.set_isolated_layer(&doc.id, Some("missing"));
+        assert!(matches!(missing, Err(AppError::LayerNotFound(_))));
+    }
+
+    #[test]
+    fn stamping_visible_layers_adds_their_composite_on_top() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 2, 1);
+        let hidden = manager
+            .add_layer_to_document(&doc.id, "Hidden", 2, 1)
+            .unwrap();
+        manager.set_layer_pixels(&hidden.id, [0, 0, 255, 255].repeat(2));
+        let doc_mut = manager.get_mut(&doc.id).unwrap();
+        doc_mut.get_layer_mut(&hidden.id).unwrap().visible = false;
+        let half_red = manager.add_layer_to_document(&doc.id, "Red", 2, 1).unwrap();
+        manager.set_layer_pixels(&half_red.id, vec![255, 0, 0, 255, 0, 0, 0, 0]);
+
+        let stamp = manager.new_layer_from_visible(&doc.id).unwrap();
+        assert_eq!(
+            pixels(&mut manager, &stamp.id),
+            [255, 0, 0, 255, 255, 255, 255, 255]
+        );
+
+        let layers = &manager.get(&doc.id).unwrap().layers;
+        assert_eq!(layers.len(), 4);
+        assert_eq!(layers.last().unwrap().id, stamp.id);
+        assert_eq!(pixels(&mut manager, &hidden.id), [0, 0, 255, 255].repeat(2));
+    }
+
+    #[test]
+    fn layers_move_before_or_after_an_anchor_by_id() {
+        let mut doc = Document::new("Test", 1, 1, 72);
+        for name in ["B", "C", "D"] {
+            doc.add_layer(Layer::new_raster(name, 1, 1));
+        }
+        let id = |doc: &Document, name: &str| {
+            doc.layers
+                .iter()
+                .find(|l| l.name == name)
+                .unwrap()
+                .id
+                .clone()
+        };
+        let names = |doc: &Document| {
+            doc.layers
+                .iter()
+                .map(|l| l.name.clone())
+                .collect::<Vec<_>>()
+        };
+        doc.layers[0].name = "A".into();
+        let (a, b, c, d) = (id(&doc, "A"), id(&doc, "B"), id(&doc, "C"), id(&doc, "D"));
+
+        doc.move_layer_relative(&a, RelativePosition::After, &c)
+            .unwrap();
+        assert_eq!(names(&doc), ["B", "C", "A", "D"]);
+        doc.move_layer_relative(&d, RelativePosition::Before, &b)
+            .unwrap();
+        assert_eq!(names(&doc), ["D", "B", "C", "A"]);
+        doc.move_layer_relative(&b, RelativePosition::After, &a)
+            .unwrap();
+        assert_eq!(names(&doc), ["D", "C", "A", "B"]);
+
+        assert!(doc
+            .move_layer_relative(&a, RelativePosition::After, &a)
+            .is_err());
+        assert!(matches!(
+            doc.move_layer_relative(&a, RelativePosition::After, "missing"),
+            Err(AppError::LayerNotFound(_))
+        ));
+        assert_eq!(names(&doc), ["D", "C", "A", "B"]);
+    }
+
+    #[test]
+    fn crops_beyond_the_canvas_limit_are_refused_before_allocating() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 4, 4);
+
+        let huge = manager.crop_document(&doc.id, 0, 0, 100_000, 100_000);
+        assert!(matches!(huge, Err(AppError::InvalidOperation(_))));
+        let empty = manager.crop_document(&doc.id, 0, 0, 0, 3);
+        assert!(matches!(empty, Err(AppError::InvalidOperation(_))));
+
+        assert_eq!(
+            manager.set_max_canvas_area(u64::MAX).unwrap(),
+            u32::MAX as u64 / 4
+        );
+        assert!(manager.set_max_canvas_area(0).is_err());
+        manager.set_max_canvas_area(10).unwrap();
+        assert!(manager.crop_document(&doc.id, 0, 0, 4, 3).is_err());
+        manager.crop_document(&doc.id, 1, 1, 2, 2).unwrap();
+
+        let doc = manager.get(&doc.id).unwrap();
+        assert_eq!((doc.width, doc.height), (2, 2));
+    }
+
+    #[test]
+    fn a_selection_from_layer_alpha_follows_the_layer_position() {
+        let mut manager = DocumentManager::new();
+        let doc = manager.create("Test", 4, 2, 72).unwrap();
+        let layer = manager.add_layer_to_document(&doc.id, "Dot", 2, 1).unwrap();
+        manager.set_layer_pixels(&layer.id, vec![0, 0, 0, 128, 9, 9, 9, 255]);
+        let doc_mut = manager.get_mut(&doc.id).unwrap();
+        let layer_mut = doc_mut.get_layer_mut(&layer.id).unwrap();
+        (layer_mut.x, layer_mut.y) = (1, 1);
+
+        let bounds = manager
+            .selection_from_layer_alpha(&doc.id, &layer.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (bounds.x, bounds.y, bounds.width, bounds.height),
+            (1, 1, 2, 1)
+        );
+        let mask = manager.get_selection(&doc.id).unwrap();
+        assert_eq!(mask.data, [0, 0, 0, 0, 0, 128, 255, 0]);
+
+        // A transparent layer selects nothing
+        let background = doc.layers[0].id.clone();
+        assert!(manager
+            .selection_from_layer_alpha(&doc.id, &background)
+            .unwrap()
+            .is_none());
+        assert!(manager.get_selection(&doc.id).is_none());
+    }
+
+    #[test]
+    fn subtracting_everything_clears_the_selection() {
+        let mut manager = DocumentManager::new();
+        let doc = manager.create("Test", 4, 4, 72).unwrap();
+        let rect = |x, width| Selection::Rectangle {
+            x,
+            y: 0,
+            width,
+            height: 4,
+        };
+
+        manager
+            .modify_selection(&doc.id, SelectionOp::Add, &rect(0, 2))
+            .unwrap();
+        let bounds = manager
+            .modify_selection(&doc.id, SelectionOp::Add, &rect(3, 1))
+            .unwrap()
+            .unwrap();
+        assert_eq!((bounds.x, bounds.width), (0, 4));
+
+        let bounds = manager
+            .modify_selection(&doc.id, SelectionOp::Intersect, &rect(1, 3))
+            .unwrap()
+            .unwrap();
+        assert_eq!((bounds.x, bounds.width), (1, 3));
+
+        let cleared = manager.modify_selection(&doc.id, SelectionOp::Subtract, &rect(0, 4));
+        assert!(cleared.unwrap().is_none());
+        assert!(manager.get_selection(&doc.id).is_none());
+    }
+
+    #[test]
+    fn sampling_merged_picks_the_blended_color() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 2, 2);
+        let layer = manager.add_layer_to_document(&doc.id, "Top", 1, 1).unwrap();
+        manager.set_layer_pixels(&layer.id, vec![0, 0, 0, 128]);
+        let doc_mut = manager.get_mut(&doc.id).unwrap();
+        doc_mut.get_layer_mut(&layer.id).unwrap().x = 1;
+
+        let from_layer = manager
+            .sample_color(&doc.id, &layer.id, 1, 0, false)
+            .unwrap();
+        assert_eq!(from_layer, Some([0, 0, 0, 128]));
+        let [r, g, b, a] = manager
+            .sample_color(&doc.id, &layer.id, 1, 0, true)
+            .unwrap()
+            .unwrap();
+        // Half-transparent black over white
+        assert!(r.abs_diff(127) <= 1 && r == g && g == b, "{:?}", [r, g, b]);
+        assert_eq!(a, 255);
+
+        // Off the layer is transparent; off the canvas is nothing at all
+        assert_eq!(
+            manager
+                .sample_color(&doc.id, &layer.id, 0, 0, false)
+                .unwrap(),
+            Some([0, 0, 0, 0])
+        );
+        assert_eq!(
+            manager
+                .sample_color(&doc.id, &layer.id, 2, 0, true)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn documents_are_listed_in_creation_order() {
+        let mut manager = DocumentManager::new();
+        let ids: Vec<String> = (0..5)
+            .map(|_| white_document(&mut manager, 8, 8).id)
+            .collect();
+        // Same-millisecond creations are common, so spread them out explicitly
+        for (i, id) in ids.iter().enumerate() {
+            manager.documents.get_mut(id).unwrap().created_at = 1_000 + i as i64;
+        }
+
+        let listed = |manager: &DocumentManager| -> Vec<String> {
+            manager
+                .get_all_documents()
+                .iter()
+                .map(|doc| doc.id.clone())
+                .collect()
+        };
+        assert_eq!(listed(&manager), ids);
+        assert_eq!(listed(&manager), listed(&manager));
+
+        // Ties fall back to the id, still giving a fixed order
+        for id in &ids {
+            manager.documents.get_mut(id).unwrap().created_at = 0;
+        }
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(listed(&manager), sorted);
+    }
+
+    #[test]
+    fn resampling_to_a_new_resolution_keeps_the_print_size() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 40, 20);
+        let layer = manager
+            .add_layer_to_document(&doc.id, "Offset", 40, 20)
+            .unwrap();
+        manager
+            .get_mut(&doc.id)
+            .unwrap()
+            .get_layer_mut(&layer.id)
+            .unwrap()
+            .x = 5;
+
+        let doc = manager.set_resolution(&doc.id, 144, false).unwrap();
+        assert_eq!((doc.width, doc.height, doc.resolution), (40, 20, 144));
+
+        let doc = manager.set_resolution(&doc.id, 288, true).unwrap();
+        assert_eq!((doc.width, doc.height, doc.resolution), (80, 40, 288));
+        let offset = doc.get_layer(&layer.id).unwrap();
+        assert_eq!((offset.x, offset.width, offset.height), (10, 80, 40));
+        let background = doc.layers[0].id.clone();
+        assert_eq!(pixels(&mut manager, &background), vec![255u8; 80 * 40 * 4]);
+
+        assert!(manager.set_resolution(&doc.id, 0, false).is_err());
+    }
+
+    #[test]
+    fn layer_indices_follow_a_reorder() {
+        let mut doc = Document::new("Test", 4, 4, 72);
+        doc.add_layer(Layer::new_raster("Middle", 4, 4));
+        doc.add_layer(Layer::new_raster("Top", 4, 4));
+        let ids: Vec<String> = doc.layers.iter().map(|l| l.id.clone()).collect();
+        assert_eq!(doc.layer_index(&ids[2]), Some(2));
+
+        // Index-based reordering, fed from a fresh lookup
+        let from = doc.layer_index(&ids[2]).unwrap();
+        doc.reorder_layers(from, 0).unwrap();
+        assert_eq!(doc.layer_index(&ids[2]), Some(0));
+        assert_eq!(doc.layer_index(&ids[0]), Some(1));
+        assert_eq!(doc.layer_index("missing"), None);
+    }
+
+    #[test]
+    fn extracted_channels_combine_back_into_the_original() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 2, 2);
+        let source = doc.layers[0].id.clone();
+        let original: Vec<u8> = (0..16).map(|i| i * 15).collect();
+        manager.set_layer_pixels(&source, original.clone());
+
+        let [red, green, blue, alpha] =
+            [Channel::Red, Channel::Green, Channel::Blue, Channel::Alpha]
+                .map(|channel| manager.extract_channel(&doc.id, &source, channel).unwrap());
+        assert_eq!(green.name, format!("{} Green", doc.layers[0].name));
+        assert_eq!(
+            pixels(&mut manager, &green.id),
+            [15, 15, 15, 255, 75, 75, 75, 255, 135, 135, 135, 255, 195, 195, 195, 255]
+        );
+
+        let combined = manager
+            .combine_channels(&doc.id, &red.id, &green.id, &blue.id, Some(&alpha.id))
+            .unwrap();
+        assert_eq!(pixels(&mut manager, &combined.id), original);
+
+        let small = manager
+            .add_layer_to_document(&doc.id, "Small", 1, 1)
+            .unwrap();
+        assert!(manager
+            .combine_channels(&doc.id, &red.id, &small.id, &blue.id, None)
+            .is_err());
+    }
+
+    #[test]
+    fn undoing_a_layer_delete_brings_back_its_exact_pixels() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 2, 2);
+        let painted = manager
+            .add_layer_to_document(&doc.id, "Painted", 2, 2)
+            .unwrap();
+        let top = manager.add_layer_to_document(&doc.id, "Top", 2, 2).unwrap();
+        let strokes: Vec<u8> = (0..16).map(|i| i * 16 + 3).collect();
+        manager.set_layer_pixels(&painted.id, strokes.clone());
+        let order = |manager: &DocumentManager| -> Vec<String> {
+            manager
+                .get(&doc.id)
+                .unwrap()
+                .layers
+                .iter()
+                .map(|l| l.id.clone())
+                .collect()
+        };
+        let original = order(&manager);
+
+        // What the delete and reorder commands do
+        let document = manager.get_mut(&doc.id).unwrap();
+        let before = document.layers.clone();
+        document.remove_layer(&painted.id).unwrap();
+        manager.push_layer_history(&doc.id, "Delete Layer", before);
+        assert!(manager.get_layer_pixels(&painted.id).is_none());
+
+        let document = manager.get_mut(&doc.id).unwrap();
+        let before = document.layers.clone();
+        document.reorder_layers(1, 0).unwrap();
+        manager.push_layer_history(&doc.id, "Reorder Layers", before);
+        assert_eq!(order(&manager), [top.id.clone(), doc.layers[0].id.clone()]);
+
+        manager.undo(&doc.id).unwrap();
+        assert_eq!(order(&manager), [doc.layers[0].id.clone(), top.id.clone()]);
+        manager.undo(&doc.id).unwrap();
+        assert_eq!(order(&manager), original);
+        assert_eq!(pixels(&mut manager, &painted.id), strokes);
+
+        manager.redo(&doc.id).unwrap();
+        assert!(manager
+            .get(&doc.id)
+            .unwrap()
+            .get_layer(&painted.id)
+            .is_none());
+        manager.undo(&doc.id).unwrap();
+        assert_eq!(pixels(&mut manager, &painted.id), strokes);
+    }
+
+    #[test]
+    fn document_state_round_trips_through_json_and_is_validated() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 4, 4);
+        let extra = manager
+            .add_layer_to_document(&doc.id, "Extra", 4, 4)
+            .unwrap();
+        manager.set_layer_pixels(&extra.id, vec![7; 64]);
+        let exported = serde_json::to_value(manager.get(&doc.id).unwrap()).unwrap();
+
+        // A peer renames the document, hides the background and drops the extra layer
+        let mut state: Document = serde_json::from_value(exported.clone()).unwrap();
+        state.name = "Synced".into();
+        state.layers[0].visible = false;
+        state.layers.pop();
+        let applied = manager.apply_document_state(&doc.id, state).unwrap();
+        assert_eq!(applied.name, "Synced");
+        assert_eq!(applied.layers.len(), 1);
+        assert!(!applied.layers[0].visible);
+
+        // The dropped layer's pixels come back with an undo
+        manager.undo(&doc.id).unwrap();
+        assert_eq!(pixels(&mut manager, &extra.id), vec![7; 64]);
+
+        let edited = |edit: fn(&mut Document)| {
+            let mut state: Document = serde_json::from_value(exported.clone()).unwrap();
+            edit(&mut state);
+            state
+        };
+        for state in [
+            edited(|state| state.layers[1].id = "unknown".into()),
+            edited(|state| state.layers[1].width = 8),
+            edited(|state| {
+                let duplicate = state.layers[0].clone();
+                state.layers.push(duplicate);
+            }),
+            edited(|state| state.width = 5),
+        ] {
+            assert!(manager.apply_document_state(&doc.id, state).is_err());
+        }
+    }
+
+    #[test]
+    fn pruning_removes_transparent_layers_but_keeps_one() {
+        let mut manager = DocumentManager::new();
+        let doc = manager
+            .create_with_background("Test", 4, 4, 72, Background::Transparent)
+            .unwrap();
+        let empty = manager
+            .add_layer_to_document(&doc.id, "Empty", 4, 4)
+            .unwrap();
+        let dot = manager.add_layer_to_document(&doc.id, "Dot", 4, 4).unwrap();
+        let mut pixels = vec![0u8; 64];
+        pixels[63] = 1;
+        manager.set_layer_pixels(&dot.id, pixels);
+
+        let removed = manager.prune_empty_layers(&doc.id).unwrap();
+        assert_eq!(removed, [doc.layers[0].id.clone(), empty.id.clone()]);
+        let layers = &manager.get(&doc.id).unwrap().layers;
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].id, dot.id);
+        assert!(manager.get_layer_pixels(&empty.id).is_none());
+
+        manager.undo(&doc.id).unwrap();
+        assert_eq!(manager.get(&doc.id).unwrap().layers.len(), 3);
+
+        // With nothing painted anywhere the bottom layer survives
+        manager.set_layer_pixels(&dot.id, vec![0u8; 64]);
+        let removed = manager.prune_empty_layers(&doc.id).unwrap();
+        assert_eq!(removed, [empty.id.clone(), dot.id.clone()]);
+        assert_eq!(manager.get(&doc.id).unwrap().layers[0].id, doc.layers[0].id);
+    }
+
+    #[test]
+    fn edits_inside_a_history_group_undo_as_one_step() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 4, 4);
+        let layer_id = doc.layers[0].id.clone();
+        manager.push_layer_history(&doc.id, "Rename Layer", doc.layers.clone());
+
+        manager.begin_history_group(&doc.id, "Nudge").unwrap();
+        manager.move_layer(&doc.id, &layer_id, 1, 0, false).unwrap();
+        manager.move_layer(&doc.id, &layer_id, 0, 2, false).unwrap();
+        manager.new_layer_from_visible(&doc.id).unwrap();
+        manager.end_history_group(&doc.id).unwrap();
+
+        let state = manager.get_history(&doc.id).unwrap();
+        assert_eq!(state.undo_labels, ["Rename Layer", "Nudge"]);
+
+        let undone = manager.undo(&doc.id).unwrap();
+        assert_eq!(undone.layers.len(), 1);
+        let layer = undone.get_layer(&layer_id).unwrap();
+        assert_eq!((layer.x, layer.y), (0, 0));
+        assert!(manager.end_history_group("missing").is_err());
+    }
+
+    #[test]
+    fn snapshots_are_listed_restored_and_freed_on_close() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 2, 2);
+        let layer_id = doc.layers[0].id.clone();
+        let white = manager.create_snapshot(&doc.id, "White").unwrap();
+        manager.set_layer_pixels(&layer_id, [9u8, 8, 7, 255].repeat(4));
+        let dark = manager.create_snapshot(&doc.id, "Dark").unwrap();
+
+        let names: Vec<String> = manager
+            .list_snapshots(&doc.id)
+            .unwrap()
+            .into_iter()
+            .map(|info| info.name)
+            .collect();
+        assert_eq!(names, ["White", "Dark"]);
+
+        manager.restore_snapshot(&doc.id, &white.id).unwrap();
+        assert_eq!(pixels(&mut manager, &layer_id), [255u8; 16]);
+        manager.restore_snapshot(&doc.id, &dark.id).unwrap();
+        assert_eq!(pixels(&mut manager, &layer_id), [9u8, 8, 7, 255].repeat(4));
+        assert!(manager.restore_snapshot(&doc.id, "missing").is_err());
+
+        manager.close(&doc.id).unwrap();
+        assert!(!manager.snapshots.contains_key(&doc.id));
+    }
+
+    #[test]
+    fn lowering_the_history_limit_trims_the_oldest_undo_steps() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 2, 2);
+        for name in ["Add Layer", "Rename Layer", "Move Layer"] {
+            manager.push_layer_history(&doc.id, name, doc.layers.clone());
+        }
+
+        let state = manager.set_history_limit(&doc.id, 2).unwrap();
+        assert_eq!(state.undo_labels, ["Rename Layer", "Move Layer"]);
+
+        manager.push_layer_history(&doc.id, "Delete Layer", doc.layers.clone());
+        let state = manager.get_history(&doc.id).unwrap();
+        assert_eq!(state.undo_labels, ["Move Layer", "Delete Layer"]);
+        assert!(manager.set_history_limit(&doc.id, 0).is_err());
+    }
+
+    #[test]
+    fn flattening_matches_the_composite_pixel_for_pixel() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 4, 3);
+        let screen = manager
+            .add_layer_to_document(&doc.id, "Screen", 2, 2)
+            .unwrap();
+        manager.set_layer_pixels(&screen.id, [40u8, 90, 200, 180].repeat(4));
+        {
+            let doc = manager.get_mut(&doc.id).unwrap();
+            let layer = doc.get_layer_mut(&screen.id).unwrap();
+            layer.blend_mode = BlendMode::Screen;
+            layer.opacity = 0.6;
+            layer.x = 1;
+            layer.y = 1;
+        }
+        let expected = manager.get_composite(&doc.id).unwrap().clone().into_raw();
+
+        let flat = manager.flatten_document(&doc.id).unwrap();
+        assert_eq!(pixels(&mut manager, &flat.layers[0].id), expected);
+    }
+
+    #[test]
+    fn baking_a_move_shifts_pixels_and_clears_the_vacated_area() {
+        let mut manager = DocumentManager::new();
+        let doc = white_document(&mut manager, 3, 1);
+        let layer_id = doc.layers[0].id.clone();
+        let row = [[1u8, 0, 0, 255], [2, 0, 0, 255], [3, 0, 0, 255]].concat();
+        manager.set_layer_pixels(&layer_id, row);
+
+        let layer = manager.move_layer(&doc.id, &layer_id, 1, 0, true).unwrap();
+        assert_eq!((layer.x, layer.y), (0, 0));
+        assert_eq!(
+            pixels(&mut manager, &layer_id),
+            [[0u8, 0, 0, 0], [1, 0, 0, 255], [2, 0, 0, 255]].concat()
+        );
+
+        manager.move_layer(&doc.id, &layer_id, 0, 5, true).unwrap();
+        assert_eq!(pixels(&mut manager, &layer_id), [0u8; 12]);
+    }
+}
@@ -1,4 +1,55 @@
-use std::collections::VecDeque;
+use super::compositor::DirtyRect;
+use super::layer::Layer;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+/// Undo steps kept per document unless configured otherwise
+pub const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+/// Same-named entries pushed within this window merge into one undo step
+const COALESCE_WINDOW_MS: i64 = 500;
+
+/// A document's layer list as it was on one side of a structural edit, with
+/// the pixels of any layer missing from the list on the other side
+#[derive(Debug, Clone)]
+pub struct LayerStructure {
+    pub layers: Vec<Layer>,
+    pub pixels: HashMap<String, Vec<u8>>,
+}
+
+/// One layer's pixels and placement on one side of a pixel edit
+#[derive(Debug, Clone)]
+pub struct LayerPixels {
+    pub layer_id: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub pixels: SavedPixels,
+}
+
+#[derive(Debug, Clone)]
+pub enum SavedPixels {
+    /// The whole buffer; None if the layer had no pixel buffer
+    Full(Option<Vec<u8>>),
+    /// Only the bytes inside a layer-local rectangle, for edits that keep the
+    /// layer's size (a brush stroke stores just what it touched)
+    Region { rect: DirtyRect, bytes: Vec<u8> },
+}
+
+/// The canvas size and resolution and the pixels of the layers an edit
+/// touched, as they were on one side of it.
+///
+/// `layers` is in edit order and may name a layer more than once: restoring
+/// walks it backwards so the earliest copy of every pixel wins.
+#[derive(Debug, Clone)]
+pub struct PixelState {
+    pub width: u32,
+    pub height: u32,
+    pub resolution: u32,
+    pub layers: Vec<LayerPixels>,
+}
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
@@ -6,7 +57,63 @@ pub struct HistoryEntry {
     pub id: String,
     pub name: String,
     pub timestamp: i64,
-    // In a real implementation, this would store document snapshots or deltas
+    /// Entries sharing a group id collapse into a single undo step
+    pub group_id: Option<String>,
+    /// Layer list to swap in when the step is undone or redone. Only structural
+    /// edits (adding, removing and reordering layers) carry one.
+    pub structure: Option<LayerStructure>,
+    /// Pixels to swap in when the step is undone or redone, for edits that
+    /// change layer contents or the canvas size. A step carrying both restores
+    /// the layer list first when undone and last when redone.
+    pub pixels: Option<PixelState>,
+}
+
+impl HistoryEntry {
+    pub fn new(name: &str) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            group_id: None,
+            structure: None,
+            pixels: None,
+        }
+    }
+
+    /// An entry that restores `structure` when undone
+    pub fn with_structure(name: &str, structure: LayerStructure) -> Self {
+        Self {
+            structure: Some(structure),
+            ..Self::new(name)
+        }
+    }
+
+    /// An entry that restores `pixels` when undone
+    pub fn with_pixels(name: &str, pixels: PixelState) -> Self {
+        Self {
+            pixels: Some(pixels),
+            ..Self::new(name)
+        }
+    }
+}
+
+/// Serializable view of a document's undo/redo stacks
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryState {
+    pub can_undo: bool,
+    pub can_redo: bool,
+    /// Oldest first; the last label is the step `undo` would revert
+    pub undo_labels: Vec<String>,
+    /// Next redo first
+    pub redo_labels: Vec<String>,
+}
+
+/// A group opened by `begin_group`; every push until `end_group` joins it
+#[derive(Debug, Clone)]
+struct HistoryGroup {
+    id: String,
+    name: String,
 }
 
 #[allow(dead_code)]
@@ -14,6 +121,7 @@ pub struct HistoryManager {
     undo_stack: VecDeque<HistoryEntry>,
     redo_stack: Vec<HistoryEntry>,
     max_entries: usize,
+    open_group: Option<HistoryGroup>,
 }
 
 #[allow(dead_code)]
@@ -23,13 +131,63 @@ impl HistoryManager {
             undo_stack: VecDeque::with_capacity(max_entries),
             redo_stack: Vec::new(),
             max_entries,
+            open_group: None,
         }
     }
 
-    pub fn push(&mut self, entry: HistoryEntry) {
+    pub fn push(&mut self, mut entry: HistoryEntry) {
         // Clear redo stack on new action
         self.redo_stack.clear();
 
+        // Entries pushed while a group is open belong to it and take its name
+        if entry.group_id.is_none() {
+            if let Some(group) = &self.open_group {
+                entry.group_id = Some(group.id.clone());
+                entry.name = group.name.clone();
+            }
+        }
+
+        // Coalesce into the previous entry if it is part of the same gesture
+        if let Some(last) = self.undo_stack.back_mut() {
+            let same_gesture = match (&last.group_id, &entry.group_id) {
+                (Some(last_group), Some(group)) => last_group == group,
+                (None, None) => {
+                    last.name == entry.name
+                        && entry.timestamp - last.timestamp <= COALESCE_WINDOW_MS
+                }
+                _ => false,
+            };
+
+            if same_gesture {
+                // Keep the first entry so undo reverts the whole gesture
+                last.timestamp = entry.timestamp;
+                match (&mut last.structure, entry.structure) {
+                    // Layers removed by the later edit must come back too
+                    (Some(first), Some(later)) => first.pixels.extend(later.pixels),
+                    (first @ None, later) => *first = later,
+                    _ => {}
+                }
+                match (&mut last.pixels, entry.pixels) {
+                    (Some(first), Some(later)) => {
+                        for layer in later.layers {
+                            // A full copy from earlier in the gesture already
+                            // restores everything the later edit could touch
+                            let covered = first.layers.iter().any(|l| {
+                                l.layer_id == layer.layer_id
+                                    && matches!(l.pixels, SavedPixels::Full(_))
+                            });
+                            if !covered {
+                                first.layers.push(layer);
+                            }
+                        }
+                    }
+                    (first @ None, later) => *first = later,
+                    _ => {}
+                }
+                return;
+            }
+        }
+
         // Remove oldest entry if at capacity
         if self.undo_stack.len() >= self.max_entries {
             self.undo_stack.pop_front();
@@ -38,6 +196,51 @@ impl HistoryManager {
         self.undo_stack.push_back(entry);
     }
 
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    /// Change the stack depth, dropping the oldest undo steps (and the furthest
+    /// redo steps) that no longer fit
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+
+        while self.undo_stack.len() > max_entries {
+            self.undo_stack.pop_front();
+        }
+        if self.redo_stack.len() > max_entries {
+            // Redo is a stack with the next step last, so the furthest are first
+            let excess = self.redo_stack.len() - max_entries;
+            self.redo_stack.drain(..excess);
+        }
+    }
+
+    /// Start a group: every entry pushed until `end_group` merges into one
+    /// undo step named `name`. Returns the group id.
+    pub fn begin_group(&mut self, name: &str) -> String {
+        let id = Uuid::new_v4().to_string();
+        self.open_group = Some(HistoryGroup {
+            id: id.clone(),
+            name: name.to_string(),
+        });
+        id
+    }
+
+    /// Close the open group, if any
+    pub fn end_group(&mut self) {
+        self.open_group = None;
+    }
+
+    /// Snapshot of both stacks for the frontend
+    pub fn state(&self) -> HistoryState {
+        HistoryState {
+            can_undo: self.can_undo(),
+            can_redo: self.can_redo(),
+            undo_labels: self.undo_stack.iter().map(|e| e.name.clone()).collect(),
+            redo_labels: self.redo_stack.iter().rev().map(|e| e.name.clone()).collect(),
+        }
+    }
+
     pub fn can_undo(&self) -> bool {
         !self.undo_stack.is_empty()
     }
@@ -46,19 +249,21 @@ impl HistoryManager {
         !self.redo_stack.is_empty()
     }
 
-    pub fn undo(&mut self) -> Option<&HistoryEntry> {
+    /// Move the latest step to the redo stack and return it, so the caller
+    /// can revert it and store the state to redo into
+    pub fn undo(&mut self) -> Option<&mut HistoryEntry> {
         if let Some(entry) = self.undo_stack.pop_back() {
             self.redo_stack.push(entry);
-            self.redo_stack.last()
+            self.redo_stack.last_mut()
         } else {
             None
         }
     }
 
-    pub fn redo(&mut self) -> Option<&HistoryEntry> {
+    pub fn redo(&mut self) -> Option<&mut HistoryEntry> {
         if let Some(entry) = self.redo_stack.pop() {
             self.undo_stack.push_back(entry);
-            self.undo_stack.back()
+            self.undo_stack.back_mut()
         } else {
             None
         }
@@ -67,5 +272,112 @@ impl HistoryManager {
     pub fn clear(&mut self) {
         self.undo_stack.clear();
         self.redo_stack.clear();
+        self.open_group = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel_state(layers: Vec<LayerPixels>) -> PixelState {
+        PixelState {
+            width: 4,
+            height: 4,
+            resolution: 72,
+            layers,
+        }
+    }
+
+    fn saved(layer_id: &str, pixels: SavedPixels) -> LayerPixels {
+        LayerPixels {
+            layer_id: layer_id.to_string(),
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 4,
+            pixels,
+        }
+    }
+
+    #[test]
+    fn undo_and_redo_move_entries_between_stacks() {
+        let mut history = HistoryManager::new(DEFAULT_HISTORY_LIMIT);
+        history.push(HistoryEntry::new("Add Layer"));
+        history.push(HistoryEntry::new("Delete Layer"));
+
+        assert_eq!(history.undo().unwrap().name, "Delete Layer");
+        assert_eq!(history.state().redo_labels, ["Delete Layer"]);
+        assert_eq!(history.redo().unwrap().name, "Delete Layer");
+        assert!(!history.can_redo());
+
+        history.undo();
+        history.push(HistoryEntry::new("Rename"));
+        assert!(!history.can_redo());
+        assert_eq!(history.state().undo_labels, ["Add Layer", "Rename"]);
+    }
+
+    #[test]
+    fn rapid_same_named_edits_coalesce() {
+        let mut history = HistoryManager::new(DEFAULT_HISTORY_LIMIT);
+        history.push(HistoryEntry::new("Move Layer"));
+        history.push(HistoryEntry::new("Move Layer"));
+        history.push(HistoryEntry::new("Brush Stroke"));
+
+        assert_eq!(history.state().undo_labels, ["Move Layer", "Brush Stroke"]);
+    }
+
+    #[test]
+    fn coalesced_pixels_skip_layers_already_saved_in_full() {
+        let mut history = HistoryManager::new(DEFAULT_HISTORY_LIMIT);
+        let region = |bytes: Vec<u8>| SavedPixels::Region {
+            rect: DirtyRect {
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1,
+            },
+            bytes,
+        };
+        history.push(HistoryEntry::with_pixels(
+            "Brush Stroke",
+            pixel_state(vec![saved("a", SavedPixels::Full(Some(vec![0; 64])))]),
+        ));
+        history.push(HistoryEntry::with_pixels(
+            "Brush Stroke",
+            pixel_state(vec![
+                saved("a", region(vec![1; 4])),
+                saved("b", region(vec![2; 4])),
+            ]),
+        ));
+
+        let entry = history.undo().unwrap();
+        let layers = &entry.pixels.as_ref().unwrap().layers;
+        let ids: Vec<&str> = layers.iter().map(|l| l.layer_id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+        assert!(matches!(layers[0].pixels, SavedPixels::Full(_)));
+    }
+
+    #[test]
+    fn grouped_entries_merge_under_the_group_name() {
+        let mut history = HistoryManager::new(DEFAULT_HISTORY_LIMIT);
+        history.begin_group("Apply Filters");
+        history.push(HistoryEntry::new("Blur"));
+        history.push(HistoryEntry::new("Sharpen"));
+        history.end_group();
+        history.push(HistoryEntry::new("Blur"));
+
+        assert_eq!(history.state().undo_labels, ["Apply Filters", "Blur"]);
+    }
+
+    #[test]
+    fn shrinking_the_limit_drops_the_oldest_steps() {
+        let mut history = HistoryManager::new(DEFAULT_HISTORY_LIMIT);
+        for name in ["One", "Two", "Three"] {
+            history.push(HistoryEntry::new(name));
+        }
+
+        history.set_max_entries(2);
+        assert_eq!(history.state().undo_labels, ["Two", "Three"]);
     }
 }
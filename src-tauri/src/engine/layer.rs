@@ -1,3 +1,4 @@
+use crate::error::{AppError, AppResult};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -9,6 +10,11 @@ pub enum LayerType {
     Group,
     Text,
     Shape,
+    /// Pixels come from an image file on disk (see `Layer::linked_path`)
+    LinkedImage,
+    /// A type this version doesn't know (e.g. from another DRKR producer),
+    /// kept verbatim so saving the file again preserves it
+    Unknown(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,6 +44,182 @@ impl Default for BlendMode {
     }
 }
 
+impl BlendMode {
+    pub const ALL: [BlendMode; 16] = [
+        BlendMode::Normal,
+        BlendMode::Multiply,
+        BlendMode::Screen,
+        BlendMode::Overlay,
+        BlendMode::Darken,
+        BlendMode::Lighten,
+        BlendMode::ColorDodge,
+        BlendMode::ColorBurn,
+        BlendMode::HardLight,
+        BlendMode::SoftLight,
+        BlendMode::Difference,
+        BlendMode::Exclusion,
+        BlendMode::Hue,
+        BlendMode::Saturation,
+        BlendMode::Color,
+        BlendMode::Luminosity,
+    ];
+}
+
+/// Organizational color tag shown in the layers panel; doesn't affect rendering
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ColorLabel {
+    #[default]
+    None,
+    Red,
+    Orange,
+    Yellow,
+    Green,
+    Blue,
+    Violet,
+    Gray,
+}
+
+/// One "Blend If" slider pair, in luminance levels (0-255).
+///
+/// Pixels fade in between `black_low` and `black_high` and fade back out
+/// between `white_low` and `white_high`; splitting each pair gives a soft knee.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlendIfRange {
+    pub black_low: u8,
+    pub black_high: u8,
+    pub white_low: u8,
+    pub white_high: u8,
+}
+
+impl Default for BlendIfRange {
+    fn default() -> Self {
+        Self {
+            black_low: 0,
+            black_high: 0,
+            white_low: 255,
+            white_high: 255,
+        }
+    }
+}
+
+impl BlendIfRange {
+    /// How much of a pixel with this luminance shows, 0.0-1.0
+    pub fn factor(&self, luminance: f32) -> f32 {
+        let rise = ramp(luminance, self.black_low as f32, self.black_high as f32);
+        // The white side is the same ramp seen from 255 downwards
+        let fall = ramp(
+            255.0 - luminance,
+            255.0 - self.white_high as f32,
+            255.0 - self.white_low as f32,
+        );
+        rise * fall
+    }
+
+    fn is_full_range(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// 0 up to `low`, 1 from `high`, linear in between. A zero-width ramp steps
+/// at `low`, so a slider at 0 (or 255) lets everything through.
+fn ramp(value: f32, low: f32, high: f32) -> f32 {
+    if high <= low {
+        return if value >= low { 1.0 } else { 0.0 };
+    }
+    ((value - low) / (high - low)).clamp(0.0, 1.0)
+}
+
+/// Conditional blending: hide a layer's pixels by their own luminance or by
+/// the luminance of what's underneath
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlendIf {
+    pub this_layer: BlendIfRange,
+    pub underlying: BlendIfRange,
+}
+
+impl BlendIf {
+    /// Opacity multiplier for blending `src` over `dst` (both RGBA)
+    pub fn factor(&self, src: &[u8], dst: &[u8]) -> f32 {
+        self.this_layer.factor(luminance(src)) * self.underlying.factor(luminance(dst))
+    }
+
+    /// Whether both ranges let everything through, i.e. Blend If is off
+    pub fn is_noop(&self) -> bool {
+        self.this_layer.is_full_range() && self.underlying.is_full_range()
+    }
+}
+
+fn luminance(pixel: &[u8]) -> f32 {
+    0.2126 * pixel[0] as f32 + 0.7152 * pixel[1] as f32 + 0.0722 * pixel[2] as f32
+}
+
+/// Editable text carried by a text layer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextContent {
+    pub text: String,
+    pub font_family: String,
+    pub size: f32,      // pixels
+    pub color: [u8; 4], // RGBA
+}
+
+/// Geometry of a shape layer, in layer-local coordinates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ShapeGeometry {
+    Rectangle { width: f32, height: f32 },
+    Ellipse { width: f32, height: f32 },
+    Line { x1: f32, y1: f32, x2: f32, y2: f32 },
+    Polygon { points: Vec<(f32, f32)> },
+}
+
+/// Editable vector content carried by a shape layer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShapeContent {
+    pub shape: ShapeGeometry,
+    pub fill: Option<[u8; 4]>,   // RGBA, None for no fill
+    pub stroke: Option<[u8; 4]>, // RGBA, None for no stroke
+    pub stroke_width: f32,
+}
+
+impl ShapeContent {
+    /// The same shape with its geometry and stroke scaled by `scale`
+    pub fn scaled(&self, scale: f32) -> Self {
+        let shape = match &self.shape {
+            ShapeGeometry::Rectangle { width, height } => ShapeGeometry::Rectangle {
+                width: width * scale,
+                height: height * scale,
+            },
+            ShapeGeometry::Ellipse { width, height } => ShapeGeometry::Ellipse {
+                width: width * scale,
+                height: height * scale,
+            },
+            ShapeGeometry::Line { x1, y1, x2, y2 } => ShapeGeometry::Line {
+                x1: x1 * scale,
+                y1: y1 * scale,
+                x2: x2 * scale,
+                y2: y2 * scale,
+            },
+            ShapeGeometry::Polygon { points } => ShapeGeometry::Polygon {
+                points: points
+                    .iter()
+                    .map(|&(x, y)| (x * scale, y * scale))
+                    .collect(),
+            },
+        };
+
+        Self {
+            shape,
+            stroke_width: self.stroke_width * scale,
+            ..self.clone()
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Layer {
@@ -45,72 +227,307 @@ pub struct Layer {
     pub name: String,
     pub layer_type: LayerType,
     pub visible: bool,
+    /// Lock everything: pixels, position and transparency
     pub locked: bool,
-    pub opacity: u8, // 0-100
+    /// Block painting and filters
+    #[serde(default)]
+    pub lock_pixels: bool,
+    /// Block moving the layer
+    #[serde(default)]
+    pub lock_position: bool,
+    /// Paint only where the layer is already opaque
+    #[serde(default)]
+    pub lock_alpha: bool,
+    /// 0.0-1.0; the frontend sends and reads a 0-100 percentage
+    #[serde(with = "opacity_percent")]
+    pub opacity: f32,
     pub blend_mode: BlendMode,
+    #[serde(default)]
+    pub color_label: ColorLabel,
+    /// Luminance-based conditional blending (None when off)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blend_if: Option<BlendIf>,
     pub x: i32,
     pub y: i32,
     pub width: u32,
     pub height: u32,
+    /// Text properties (text layers only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<TextContent>,
+    /// Shape parameters (shape layers only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shape: Option<ShapeContent>,
+    /// Source file of a linked image layer, re-read when the document opens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linked_path: Option<String>,
+    pub created_at: i64,
+    pub modified_at: i64,
 }
 
 impl Layer {
     pub fn new_raster(name: &str, width: u32, height: u32) -> Self {
+        let now = chrono::Utc::now().timestamp_millis();
         Self {
             id: Uuid::new_v4().to_string(),
             name: name.to_string(),
             layer_type: LayerType::Raster,
             visible: true,
             locked: false,
-            opacity: 100,
+            lock_pixels: false,
+            lock_position: false,
+            lock_alpha: false,
+            opacity: 1.0,
             blend_mode: BlendMode::Normal,
+            color_label: ColorLabel::None,
+            blend_if: None,
             x: 0,
             y: 0,
             width,
             height,
+            text: None,
+            shape: None,
+            linked_path: None,
+            created_at: now,
+            modified_at: now,
+        }
+    }
+
+    pub fn new_text(name: &str, content: TextContent, width: u32, height: u32) -> Self {
+        Self {
+            layer_type: LayerType::Text,
+            text: Some(content),
+            ..Self::new_raster(name, width, height)
+        }
+    }
+
+    pub fn new_shape(name: &str, content: ShapeContent, width: u32, height: u32) -> Self {
+        Self {
+            layer_type: LayerType::Shape,
+            shape: Some(content),
+            ..Self::new_raster(name, width, height)
+        }
+    }
+
+    pub fn new_linked_image(name: &str, path: &str, width: u32, height: u32) -> Self {
+        Self {
+            layer_type: LayerType::LinkedImage,
+            linked_path: Some(path.to_string()),
+            ..Self::new_raster(name, width, height)
         }
     }
 
+    pub fn mark_modified(&mut self) {
+        self.modified_at = chrono::Utc::now().timestamp_millis();
+    }
+
+    /// Whether painting and filters are blocked
+    pub fn pixels_locked(&self) -> bool {
+        self.locked || self.lock_pixels
+    }
+
+    /// Whether moving the layer is blocked
+    pub fn position_locked(&self) -> bool {
+        self.locked || self.lock_position
+    }
+
+    /// Map a document-space point into this layer's pixel space
+    pub fn doc_to_layer(&self, x: f64, y: f64) -> (f64, f64) {
+        (x - self.x as f64, y - self.y as f64)
+    }
+
+    /// Map a point in this layer's pixel space into document space
+    pub fn layer_to_doc(&self, x: f64, y: f64) -> (f64, f64) {
+        (x + self.x as f64, y + self.y as f64)
+    }
+
     #[allow(dead_code)]
     pub fn bounds(&self) -> (i32, i32, u32, u32) {
         (self.x, self.y, self.width, self.height)
     }
 }
 
+/// (De)serialize a 0.0-1.0 opacity as the 0-100 percentage the frontend uses
+mod opacity_percent {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(opacity: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f32(opacity * 100.0)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+        f32::deserialize(deserializer).map(|percent| percent / 100.0)
+    }
+
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            opacity: &Option<f32>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match opacity {
+                Some(opacity) => super::serialize(opacity, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<f32>, D::Error> {
+            Option::<f32>::deserialize(deserializer).map(|percent| percent.map(|p| p / 100.0))
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LayerUpdate {
     pub name: Option<String>,
     pub visible: Option<bool>,
     pub locked: Option<bool>,
-    pub opacity: Option<u8>,
+    pub lock_pixels: Option<bool>,
+    pub lock_position: Option<bool>,
+    pub lock_alpha: Option<bool>,
+    /// 0-100 percentage, like `Layer::opacity` on the wire
+    #[serde(default, with = "opacity_percent::option")]
+    pub opacity: Option<f32>,
     pub blend_mode: Option<BlendMode>,
+    pub color_label: Option<ColorLabel>,
+    /// Full-range sliders turn Blend If off
+    pub blend_if: Option<BlendIf>,
     pub x: Option<i32>,
     pub y: Option<i32>,
 }
 
 impl Layer {
-    pub fn apply_update(&mut self, update: LayerUpdate) {
+    /// Apply an update. Lock flags are applied first, so an update may unlock
+    /// the position and move the layer in one go.
+    pub fn apply_update(&mut self, update: LayerUpdate) -> AppResult<()> {
+        if let Some(locked) = update.locked {
+            self.locked = locked;
+        }
+        if let Some(lock_pixels) = update.lock_pixels {
+            self.lock_pixels = lock_pixels;
+        }
+        if let Some(lock_position) = update.lock_position {
+            self.lock_position = lock_position;
+        }
+        if let Some(lock_alpha) = update.lock_alpha {
+            self.lock_alpha = lock_alpha;
+        }
+
+        if (update.x.is_some() || update.y.is_some()) && self.position_locked() {
+            return Err(AppError::InvalidOperation(
+                "Layer position is locked".into(),
+            ));
+        }
+
         if let Some(name) = update.name {
             self.name = name;
         }
         if let Some(visible) = update.visible {
             self.visible = visible;
         }
-        if let Some(locked) = update.locked {
-            self.locked = locked;
-        }
         if let Some(opacity) = update.opacity {
-            self.opacity = opacity.min(100);
+            if !opacity.is_finite() {
+                return Err(AppError::InvalidOperation(
+                    "Layer opacity must be a number".into(),
+                ));
+            }
+            self.opacity = opacity.clamp(0.0, 1.0);
         }
         if let Some(blend_mode) = update.blend_mode {
             self.blend_mode = blend_mode;
         }
+        if let Some(color_label) = update.color_label {
+            self.color_label = color_label;
+        }
+        if let Some(blend_if) = update.blend_if {
+            self.blend_if = (!blend_if.is_noop()).then_some(blend_if);
+        }
         if let Some(x) = update.x {
             self.x = x;
         }
         if let Some(y) = update.y {
             self.y = y;
         }
+
+        self.mark_modified();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opacity_crosses_the_wire_as_a_percentage() {
+        let mut layer = Layer::new_raster("Layer", 1, 1);
+        layer.opacity = 0.375;
+        let json = serde_json::to_value(&layer).unwrap();
+        assert_eq!(json["opacity"], 37.5);
+        assert_eq!(
+            serde_json::from_value::<Layer>(json).unwrap().opacity,
+            0.375
+        );
+
+        let update: LayerUpdate = serde_json::from_str(r#"{"opacity": 50}"#).unwrap();
+        layer.apply_update(update).unwrap();
+        assert_eq!(layer.opacity, 0.5);
+
+        let update: LayerUpdate = serde_json::from_str(r#"{"name": "Renamed"}"#).unwrap();
+        assert_eq!(update.opacity, None);
+    }
+
+    #[test]
+    fn a_position_lock_blocks_moves_until_lifted() {
+        let mut layer = Layer::new_raster("Layer", 1, 1);
+        layer.lock_position = true;
+
+        let update: LayerUpdate = serde_json::from_str(r#"{"x": 5}"#).unwrap();
+        assert!(layer.apply_update(update).is_err());
+        assert_eq!(layer.x, 0);
+
+        let update: LayerUpdate = serde_json::from_str(r#"{"name": "Renamed"}"#).unwrap();
+        layer.apply_update(update).unwrap();
+        assert_eq!(layer.name, "Renamed");
+
+        let update: LayerUpdate =
+            serde_json::from_str(r#"{"lockPosition": false, "x": 5, "y": 3}"#).unwrap();
+        layer.apply_update(update).unwrap();
+        assert_eq!((layer.x, layer.y), (5, 3));
+    }
+
+    #[test]
+    fn blend_if_ranges_fade_across_split_sliders() {
+        let range = BlendIfRange {
+            black_low: 50,
+            black_high: 100,
+            white_low: 200,
+            white_high: 200,
+        };
+        assert_eq!(range.factor(40.0), 0.0);
+        assert_eq!(range.factor(75.0), 0.5);
+        assert_eq!(range.factor(150.0), 1.0);
+        assert_eq!(range.factor(201.0), 0.0);
+
+        assert_eq!(BlendIfRange::default().factor(0.0), 1.0);
+        assert_eq!(BlendIfRange::default().factor(255.0), 1.0);
+        assert!(BlendIf::default().is_noop());
+    }
+
+    #[test]
+    fn coordinates_convert_through_the_layer_offset_and_back() {
+        let mut layer = Layer::new_raster("Layer", 10, 10);
+        layer.x = -4;
+        layer.y = 7;
+
+        assert_eq!(layer.doc_to_layer(0.0, 0.0), (4.0, -7.0));
+        assert_eq!(layer.layer_to_doc(0.0, 0.0), (-4.0, 7.0));
+        for (x, y) in [(2.5, 9.25), (-100.0, 3.0)] {
+            let (lx, ly) = layer.doc_to_layer(x, y);
+            assert_eq!(layer.layer_to_doc(lx, ly), (x, y));
+        }
     }
 }
@@ -1,6 +1,25 @@
+mod brush_tip;
+mod buffer;
+mod channels;
+mod compositor;
 mod document;
 mod history;
 pub mod layer;
+mod selection;
+mod shape;
+mod snapshot;
+mod text;
+mod tile_cache;
+mod transform;
 
-pub use document::{Document, DocumentManager};
-pub use layer::{Layer, LayerUpdate};
+pub use brush_tip::BrushTip;
+pub use buffer::checked_rgba_buffer_len;
+pub use channels::Channel;
+pub use compositor::{blend_pixel, composite_layers, linear_to_srgb, srgb_to_linear, DirtyRect};
+pub use document::{Background, Document, DocumentManager, ExtensionFiles, RelativePosition};
+pub use history::HistoryState;
+pub use layer::{BlendMode, Layer, LayerUpdate, ShapeContent, TextContent};
+pub use selection::{Selection, SelectionMask, SelectionOp, SelectionTransform};
+pub use snapshot::SnapshotInfo;
+pub use tile_cache::TileCacheStats;
+pub use transform::{perspective_warp, resize_premultiplied, sample_bilinear};
@@ -0,0 +1,416 @@
+use super::compositor::DirtyRect;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// A selection shape sent by the frontend, in document coordinates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Selection {
+    Rectangle {
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+    },
+    Ellipse {
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+    },
+}
+
+impl Selection {
+    /// Bounding rectangle of the shape in document space
+    pub fn bounds(&self) -> DirtyRect {
+        match *self {
+            Selection::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            }
+            | Selection::Ellipse {
+                x,
+                y,
+                width,
+                height,
+            } => DirtyRect {
+                x,
+                y,
+                width,
+                height,
+            },
+        }
+    }
+}
+
+/// How a new selection combines with the active one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SelectionOp {
+    Replace,
+    Add,
+    Subtract,
+    Intersect,
+}
+
+/// A refinement applied to the active selection
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SelectionTransform {
+    /// Expand the selection outward by `pixels`
+    Grow { pixels: u32 },
+    /// Contract the selection inward by `pixels`
+    Shrink { pixels: u32 },
+    /// Soften the selection edge with a Gaussian of this standard deviation
+    Feather { radius: f32 },
+    /// Select everything that was unselected and vice versa
+    Invert,
+}
+
+/// Per-pixel selection coverage over the document canvas.
+///
+/// 0 means unselected and 255 fully selected; values in between partially
+/// select a pixel (e.g. feathered edges).
+#[derive(Debug, Clone)]
+pub struct SelectionMask {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl SelectionMask {
+    /// A `width` x `height` mask with nothing selected
+    pub fn empty(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0u8; width as usize * height as usize],
+        }
+    }
+
+    /// Rasterize a selection shape onto a `width` x `height` canvas
+    pub fn from_selection(selection: &Selection, width: u32, height: u32) -> Self {
+        let mut data = vec![0u8; width as usize * height as usize];
+
+        let bounds = selection.bounds();
+
+        if let Some(rect) = bounds.clip_to(width, height) {
+            // Ellipse center and radii, tested against pixel centers
+            let rx = bounds.width as f64 / 2.0;
+            let ry = bounds.height as f64 / 2.0;
+            let cx = bounds.x as f64 + rx;
+            let cy = bounds.y as f64 + ry;
+
+            for y in rect.y..rect.y + rect.height as i32 {
+                for x in rect.x..rect.x + rect.width as i32 {
+                    let inside = match selection {
+                        Selection::Rectangle { .. } => true,
+                        Selection::Ellipse { .. } => {
+                            let dx = (x as f64 + 0.5 - cx) / rx;
+                            let dy = (y as f64 + 0.5 - cy) / ry;
+                            dx * dx + dy * dy <= 1.0
+                        }
+                    };
+
+                    if inside {
+                        data[(y as u32 * width + x as u32) as usize] = 255;
+                    }
+                }
+            }
+        }
+
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    /// Selection equal to a layer's alpha channel, placed at the layer's
+    /// document position and clipped to a `width` x `height` canvas
+    pub fn from_alpha(pixels: &[u8], layer_bounds: DirtyRect, width: u32, height: u32) -> Self {
+        let mut data = vec![0u8; width as usize * height as usize];
+
+        if let Some(rect) = layer_bounds.clip_to(width, height) {
+            for y in rect.y..rect.y + rect.height as i32 {
+                let src_row = (y - layer_bounds.y) as usize * layer_bounds.width as usize;
+                let dst_row = y as usize * width as usize;
+
+                for x in rect.x..rect.x + rect.width as i32 {
+                    let src = (src_row + (x - layer_bounds.x) as usize) * 4 + 3;
+                    if let Some(&alpha) = pixels.get(src) {
+                        data[dst_row + x as usize] = alpha;
+                    }
+                }
+            }
+        }
+
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    /// Combine `other` into this mask pixel by pixel.
+    ///
+    /// Partial coverage combines like fuzzy sets: add takes the max, intersect
+    /// the min, and subtract keeps what `other` leaves unselected.
+    pub fn combine(&mut self, other: &SelectionMask, op: SelectionOp) {
+        for (a, &b) in self.data.iter_mut().zip(&other.data) {
+            *a = match op {
+                SelectionOp::Replace => b,
+                SelectionOp::Add => (*a).max(b),
+                SelectionOp::Subtract => (*a).min(255 - b),
+                SelectionOp::Intersect => (*a).min(b),
+            };
+        }
+    }
+
+    /// Apply a grow, shrink, feather or invert to the whole mask.
+    ///
+    /// Grow and shrink use a square neighbourhood and only consider pixels on
+    /// the canvas, so edges touching the canvas border stay put.
+    pub fn transform(&mut self, op: SelectionTransform) {
+        match op {
+            SelectionTransform::Grow { pixels } => self.dilate(pixels as usize),
+            SelectionTransform::Shrink { pixels } => {
+                // Erosion is dilation of the unselected area
+                self.invert();
+                self.dilate(pixels as usize);
+                self.invert();
+            }
+            SelectionTransform::Feather { radius } => self.feather(radius),
+            SelectionTransform::Invert => self.invert(),
+        }
+    }
+
+    fn invert(&mut self) {
+        for value in &mut self.data {
+            *value = 255 - *value;
+        }
+    }
+
+    /// Grayscale dilation: each pixel takes the max within `radius`, done as a
+    /// row pass followed by a column pass
+    fn dilate(&mut self, radius: usize) {
+        if radius == 0 || self.data.is_empty() {
+            return;
+        }
+
+        let width = self.width as usize;
+        let height = self.height as usize;
+
+        for row in self.data.chunks_exact_mut(width) {
+            sliding_max(row, radius);
+        }
+
+        let mut column = vec![0u8; height];
+        for x in 0..width {
+            for (y, value) in column.iter_mut().enumerate() {
+                *value = self.data[y * width + x];
+            }
+            sliding_max(&mut column, radius);
+            for (y, value) in column.iter().enumerate() {
+                self.data[y * width + x] = *value;
+            }
+        }
+    }
+
+    /// Separable Gaussian blur of the coverage, clamping at the canvas edges
+    fn feather(&mut self, radius: f32) {
+        if radius <= 0.0 {
+            return;
+        }
+
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let kernel = gaussian_kernel(radius);
+        let half = (kernel.len() / 2) as isize;
+
+        let mut horizontal = vec![0.0f32; self.data.len()];
+        for y in 0..height {
+            let row = &self.data[y * width..(y + 1) * width];
+            for x in 0..width {
+                let mut sum = 0.0;
+                for (k, weight) in kernel.iter().enumerate() {
+                    let sx = (x as isize + k as isize - half).clamp(0, width as isize - 1);
+                    sum += row[sx as usize] as f32 * weight;
+                }
+                horizontal[y * width + x] = sum;
+            }
+        }
+
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = 0.0;
+                for (k, weight) in kernel.iter().enumerate() {
+                    let sy = (y as isize + k as isize - half).clamp(0, height as isize - 1);
+                    sum += horizontal[sy as usize * width + x] * weight;
+                }
+                self.data[y * width + x] = sum.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+
+    /// Coverage at a document-space pixel; 0 outside the canvas
+    pub fn coverage(&self, x: i32, y: i32) -> u8 {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return 0;
+        }
+        self.data[(y as u32 * self.width + x as u32) as usize]
+    }
+
+    /// Bounding rectangle of all selected pixels, or None if nothing is selected
+    pub fn bounds(&self) -> Option<DirtyRect> {
+        let mut min_x = u32::MAX;
+        let mut min_y = u32::MAX;
+        let mut max_x = 0;
+        let mut max_y = 0;
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.data[(y * self.width + x) as usize] > 0 {
+                    min_x = min_x.min(x);
+                    min_y = min_y.min(y);
+                    max_x = max_x.max(x);
+                    max_y = max_y.max(y);
+                }
+            }
+        }
+
+        if min_x > max_x {
+            return None;
+        }
+
+        Some(DirtyRect {
+            x: min_x as i32,
+            y: min_y as i32,
+            width: max_x - min_x + 1,
+            height: max_y - min_y + 1,
+        })
+    }
+}
+
+/// Replace each value with the max of its neighbours within `radius`.
+///
+/// Keeps a deque of candidate indices in decreasing value order, so the cost
+/// doesn't grow with the radius.
+fn sliding_max(line: &mut [u8], radius: usize) {
+    let source = line.to_vec();
+    let mut window: VecDeque<usize> = VecDeque::new();
+    let mut next = 0;
+
+    for (i, value) in line.iter_mut().enumerate() {
+        let last = (i + radius).min(source.len() - 1);
+        while next <= last {
+            while window.back().is_some_and(|&j| source[j] <= source[next]) {
+                window.pop_back();
+            }
+            window.push_back(next);
+            next += 1;
+        }
+        while window.front().is_some_and(|&j| j + radius < i) {
+            window.pop_front();
+        }
+
+        *value = source[window[0]];
+    }
+}
+
+/// Normalized 1D Gaussian kernel with standard deviation `sigma`, out to 3 sigma
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let half = (sigma * 3.0).ceil() as isize;
+    let mut kernel: Vec<f32> = (-half..=half)
+        .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+
+    let total: f32 = kernel.iter().sum();
+    for weight in &mut kernel {
+        *weight /= total;
+    }
+    kernel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selected(mask: &SelectionMask) -> usize {
+        mask.data.iter().filter(|&&v| v == 255).count()
+    }
+
+    #[test]
+    fn growing_then_shrinking_a_rect_gives_it_back() {
+        let rect = Selection::Rectangle {
+            x: 10,
+            y: 8,
+            width: 12,
+            height: 6,
+        };
+        let original = SelectionMask::from_selection(&rect, 40, 30);
+
+        let mut mask = original.clone();
+        mask.transform(SelectionTransform::Grow { pixels: 3 });
+        assert_eq!(selected(&mask), 18 * 12);
+        assert_eq!(mask.coverage(7, 5), 255);
+        assert_eq!(mask.coverage(6, 5), 0);
+
+        mask.transform(SelectionTransform::Shrink { pixels: 3 });
+        assert_eq!(mask.data, original.data);
+    }
+
+    #[test]
+    fn growing_then_shrinking_an_ellipse_stays_close() {
+        let ellipse = Selection::Ellipse {
+            x: 10,
+            y: 10,
+            width: 30,
+            height: 20,
+        };
+        let original = SelectionMask::from_selection(&ellipse, 50, 40);
+
+        let mut mask = original.clone();
+        mask.transform(SelectionTransform::Grow { pixels: 2 });
+        mask.transform(SelectionTransform::Shrink { pixels: 2 });
+
+        // Closing only fills in along the curve; nothing inside is lost
+        assert!(original.data.iter().zip(&mask.data).all(|(a, b)| b >= a));
+        let changed = original
+            .data
+            .iter()
+            .zip(&mask.data)
+            .filter(|(a, b)| a != b)
+            .count();
+        assert!(
+            changed * 20 < selected(&original),
+            "{} pixels changed",
+            changed
+        );
+    }
+
+    #[test]
+    fn selection_ops_combine_masks_per_pixel() {
+        let mask = |data: &[u8]| SelectionMask {
+            width: 4,
+            height: 1,
+            data: data.to_vec(),
+        };
+        let active = mask(&[255, 255, 100, 0]);
+        let shape = mask(&[0, 255, 200, 255]);
+
+        let expected = [
+            (SelectionOp::Replace, [0, 255, 200, 255]),
+            (SelectionOp::Add, [255, 255, 200, 255]),
+            (SelectionOp::Subtract, [255, 0, 55, 0]),
+            (SelectionOp::Intersect, [0, 255, 100, 0]),
+        ];
+        for (op, data) in expected {
+            let mut combined = active.clone();
+            combined.combine(&shape, op);
+            assert_eq!(combined.data, data, "{:?}", op);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use super::layer::{ShapeContent, ShapeGeometry};
+use crate::error::{AppError, AppResult};
+use resvg::tiny_skia::{
+    FillRule, Paint, Path, PathBuilder, Pixmap, Rect, Stroke, Transform,
+};
+
+/// Build the vector path for a shape in layer-local coordinates
+fn build_path(geometry: &ShapeGeometry) -> Option<Path> {
+    match geometry {
+        ShapeGeometry::Rectangle { width, height } => {
+            Rect::from_xywh(0.0, 0.0, *width, *height).map(PathBuilder::from_rect)
+        }
+        ShapeGeometry::Ellipse { width, height } => {
+            Rect::from_xywh(0.0, 0.0, *width, *height).and_then(PathBuilder::from_oval)
+        }
+        ShapeGeometry::Line { x1, y1, x2, y2 } => {
+            let mut builder = PathBuilder::new();
+            builder.move_to(*x1, *y1);
+            builder.line_to(*x2, *y2);
+            builder.finish()
+        }
+        ShapeGeometry::Polygon { points } => {
+            let (first, rest) = points.split_first()?;
+            let mut builder = PathBuilder::new();
+            builder.move_to(first.0, first.1);
+            for point in rest {
+                builder.line_to(point.0, point.1);
+            }
+            builder.close();
+            builder.finish()
+        }
+    }
+}
+
+/// Rasterize a shape into a tightly sized, anti-aliased RGBA buffer.
+///
+/// Returns `(width, height, pixels)`. The buffer covers the shape's bounds plus
+/// half the stroke width on every side so strokes are not clipped.
+pub fn render_shape(content: &ShapeContent) -> AppResult<(u32, u32, Vec<u8>)> {
+    if !content.stroke_width.is_finite() || content.stroke_width < 0.0 {
+        return Err(AppError::InvalidOperation(
+            "Stroke width must be a non-negative number".into(),
+        ));
+    }
+
+    let path = build_path(&content.shape)
+        .ok_or_else(|| AppError::InvalidOperation("Shape has no drawable area".into()))?;
+
+    // Strokes extend half their width outside the geometry
+    let stroke_pad = if content.stroke.is_some() {
+        content.stroke_width / 2.0
+    } else {
+        0.0
+    };
+    let bounds = path.bounds();
+    let left = (bounds.left() - stroke_pad).floor();
+    let top = (bounds.top() - stroke_pad).floor();
+    let width = ((bounds.right() + stroke_pad).ceil() - left).max(1.0) as u32;
+    let height = ((bounds.bottom() + stroke_pad).ceil() - top).max(1.0) as u32;
+
+    let mut pixmap = Pixmap::new(width, height)
+        .ok_or_else(|| AppError::InvalidOperation("Invalid shape size".into()))?;
+    let transform = Transform::from_translate(-left, -top);
+
+    let mut paint = Paint {
+        anti_alias: true,
+        ..Paint::default()
+    };
+
+    if let Some([r, g, b, a]) = content.fill {
+        paint.set_color_rgba8(r, g, b, a);
+        pixmap.fill_path(&path, &paint, FillRule::Winding, transform, None);
+    }
+
+    if let Some([r, g, b, a]) = content.stroke {
+        if content.stroke_width > 0.0 {
+            let stroke = Stroke {
+                width: content.stroke_width,
+                ..Stroke::default()
+            };
+            paint.set_color_rgba8(r, g, b, a);
+            pixmap.stroke_path(&path, &paint, &stroke, transform, None);
+        }
+    }
+
+    // tiny-skia renders premultiplied alpha; layers store straight RGBA
+    let mut pixels = Vec::with_capacity(pixmap.data().len());
+    for pixel in pixmap.pixels() {
+        let color = pixel.demultiply();
+        pixels.extend_from_slice(&[color.red(), color.green(), color.blue(), color.alpha()]);
+    }
+
+    Ok((width, height, pixels))
+}
@@ -0,0 +1,53 @@
+use super::document::Document;
+use serde::Serialize;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// A named, full copy of a document's state that can be restored later.
+///
+/// Snapshots are independent of the undo stack. Each one holds a complete copy
+/// of every layer's RGBA buffer, so it costs roughly `4 * width * height` bytes
+/// per layer (about 64 MB per layer for a 4000x4000 canvas). They live only in
+/// memory and are freed when the document is closed.
+#[derive(Debug, Clone)]
+pub struct DocumentSnapshot {
+    pub id: String,
+    pub name: String,
+    pub created_at: i64,
+    pub document: Document,
+    pub layer_pixels: HashMap<String, Vec<u8>>,
+}
+
+impl DocumentSnapshot {
+    pub fn new(name: &str, document: Document, layer_pixels: HashMap<String, Vec<u8>>) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            created_at: chrono::Utc::now().timestamp_millis(),
+            document,
+            layer_pixels,
+        }
+    }
+
+    pub fn info(&self) -> SnapshotInfo {
+        SnapshotInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            created_at: self.created_at,
+            layer_count: self.document.layers.len(),
+            byte_size: self.layer_pixels.values().map(Vec::len).sum(),
+        }
+    }
+}
+
+/// Snapshot summary returned to the frontend (without pixel data)
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub name: String,
+    pub created_at: i64,
+    pub layer_count: usize,
+    /// Memory held by the snapshot's pixel buffers
+    pub byte_size: usize,
+}
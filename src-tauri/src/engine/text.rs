@@ -0,0 +1,113 @@
+use super::buffer::checked_rgba_buffer_len;
+use super::layer::TextContent;
+use crate::error::{AppError, AppResult};
+use ab_glyph::{point, Font, FontVec, Glyph, PxScale, ScaleFont};
+use resvg::usvg::fontdb;
+use std::sync::OnceLock;
+
+/// System font database, loaded once on first use
+static FONT_DB: OnceLock<fontdb::Database> = OnceLock::new();
+
+fn font_db() -> &'static fontdb::Database {
+    FONT_DB.get_or_init(|| {
+        let mut db = fontdb::Database::new();
+        db.load_system_fonts();
+        db
+    })
+}
+
+/// Load a font by family name, falling back to the system sans-serif font
+fn load_font(family: &str) -> AppResult<FontVec> {
+    let db = font_db();
+
+    let query = |families: &[fontdb::Family]| {
+        db.query(&fontdb::Query {
+            families,
+            ..fontdb::Query::default()
+        })
+    };
+
+    let id = query(&[fontdb::Family::Name(family)])
+        .or_else(|| query(&[fontdb::Family::SansSerif]))
+        .or_else(|| db.faces().next().map(|face| face.id))
+        .ok_or_else(|| AppError::InvalidOperation(format!("No font found for '{}'", family)))?;
+
+    db.with_face_data(id, |data, index| FontVec::try_from_vec_and_index(data.to_vec(), index))
+        .and_then(Result::ok)
+        .ok_or_else(|| AppError::InvalidOperation(format!("Failed to load font '{}'", family)))
+}
+
+/// Rasterize left-aligned text into a tightly sized RGBA buffer.
+///
+/// Returns `(width, height, pixels)`. Lines are separated by `\n`; the buffer
+/// is sized to the widest line and the total line height.
+pub fn render_text(content: &TextContent) -> AppResult<(u32, u32, Vec<u8>)> {
+    if !content.size.is_finite() || content.size <= 0.0 {
+        return Err(AppError::InvalidOperation(
+            "Text size must be greater than zero".into(),
+        ));
+    }
+
+    let font = load_font(&content.font_family)?;
+    let scale = PxScale::from(content.size);
+    let scaled = font.as_scaled(scale);
+    let line_height = scaled.height() + scaled.line_gap();
+
+    // Lay out glyphs line by line
+    let mut glyphs: Vec<Glyph> = Vec::new();
+    let mut max_width = 0.0f32;
+    let mut line_count = 0;
+
+    for (line_index, line) in content.text.split('\n').enumerate() {
+        let mut caret = point(0.0, scaled.ascent() + line_index as f32 * line_height);
+        let mut previous = None;
+
+        for c in line.chars() {
+            let id = scaled.glyph_id(c);
+            if let Some(previous) = previous {
+                caret.x += scaled.kern(previous, id);
+            }
+            glyphs.push(id.with_scale_and_position(scale, caret));
+            caret.x += scaled.h_advance(id);
+            previous = Some(id);
+        }
+
+        max_width = max_width.max(caret.x);
+        line_count = line_index + 1;
+    }
+
+    let width = (max_width.ceil() as u32).max(1);
+    let height = ((line_count as f32 * line_height).ceil() as u32).max(1);
+    let mut pixels = vec![0u8; checked_rgba_buffer_len(width, height)?];
+
+    let [r, g, b, a] = content.color;
+
+    for glyph in glyphs {
+        let outlined = match font.outline_glyph(glyph) {
+            Some(outlined) => outlined,
+            None => continue, // Whitespace has no outline
+        };
+
+        let bounds = outlined.px_bounds();
+        outlined.draw(|gx, gy, coverage| {
+            let x = bounds.min.x as i32 + gx as i32;
+            let y = bounds.min.y as i32 + gy as i32;
+            if x < 0 || y < 0 || x >= width as i32 || y >= height as i32 {
+                return;
+            }
+
+            let idx = ((y as u32 * width + x as u32) * 4) as usize;
+            let alpha = (coverage.clamp(0.0, 1.0) * a as f32).round() as u8;
+
+            // Overlapping glyph edges keep the strongest coverage
+            if alpha > pixels[idx + 3] {
+                pixels[idx] = r;
+                pixels[idx + 1] = g;
+                pixels[idx + 2] = b;
+                pixels[idx + 3] = alpha;
+            }
+        });
+    }
+
+    Ok((width, height, pixels))
+}
@@ -0,0 +1,369 @@
+use super::buffer::checked_rgba_buffer_len;
+use crate::error::{AppError, AppResult};
+use crate::io::drkr::{decode_to_rgba, encode_rgba, PixelFormat};
+use lru::LruCache;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Side of a square tile in pixels; tiles on the right and bottom edges are
+/// cropped to the layer
+pub const TILE_SIZE: u32 = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct TileKey {
+    layer_id: String,
+    column: u32,
+    row: u32,
+}
+
+/// Where an evicted tile's encoded bytes sit in the spill file
+#[derive(Debug, Clone, Copy)]
+struct SpilledTile {
+    offset: u64,
+    len: u64,
+}
+
+/// Memory use of a tile cache, for the frontend
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TileCacheStats {
+    pub budget: usize,
+    pub resident_bytes: usize,
+    pub resident_tiles: usize,
+    pub spilled_tiles: usize,
+    pub spill_file_bytes: u64,
+}
+
+/// Layer pixels split into tiles, keeping at most `budget` bytes of recently
+/// used tiles in memory and spilling the rest to a temporary file.
+///
+/// Tiles are read-only once inserted; a layer is taken back out whole before it
+/// is edited or read by anything but `read_region`. This parks documents that
+/// are not being worked on; it does not let a single document larger than
+/// memory be edited tile by tile. Spilled tiles are stored PNG-encoded, like DRKR layer pixel
+/// files. The spill file only grows while tiles are spilled; it is truncated
+/// once none are and deleted when the cache is dropped.
+pub struct TileCache {
+    budget: usize,
+    resident_bytes: usize,
+    resident: LruCache<TileKey, Vec<u8>>,
+    spilled: HashMap<TileKey, SpilledTile>,
+    /// layer_id -> (width, height)
+    layers: HashMap<String, (u32, u32)>,
+    spill_path: PathBuf,
+    spill: Option<File>,
+    spill_len: u64,
+}
+
+impl TileCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            resident_bytes: 0,
+            resident: LruCache::unbounded(),
+            spilled: HashMap::new(),
+            layers: HashMap::new(),
+            spill_path: std::env::temp_dir().join(format!("drkr-tiles-{}.bin", Uuid::new_v4())),
+            spill: None,
+            spill_len: 0,
+        }
+    }
+
+    /// Change the memory budget, spilling tiles that no longer fit
+    pub fn set_budget(&mut self, budget: usize) -> AppResult<()> {
+        self.budget = budget;
+        self.evict_to(budget)
+    }
+
+    pub fn stats(&self) -> TileCacheStats {
+        TileCacheStats {
+            budget: self.budget,
+            resident_bytes: self.resident_bytes,
+            resident_tiles: self.resident.len(),
+            spilled_tiles: self.spilled.len(),
+            spill_file_bytes: self.spill_len,
+        }
+    }
+
+    pub fn contains_layer(&self, layer_id: &str) -> bool {
+        self.layers.contains_key(layer_id)
+    }
+
+    /// Store a layer's pixels, replacing any it already had
+    pub fn insert_layer(
+        &mut self,
+        layer_id: &str,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+    ) -> AppResult<()> {
+        if pixels.len() != checked_rgba_buffer_len(width, height)? {
+            return Err(AppError::InvalidOperation(
+                "Invalid layer pixel data".into(),
+            ));
+        }
+
+        self.remove_layer(layer_id);
+        self.layers.insert(layer_id.to_string(), (width, height));
+
+        for (column, row) in tile_grid(width, height) {
+            let (x, y, tile_width, tile_height) = tile_rect(width, height, column, row);
+            let mut tile = Vec::with_capacity(tile_width as usize * tile_height as usize * 4);
+            for ty in y..y + tile_height {
+                let start = (ty as usize * width as usize + x as usize) * 4;
+                tile.extend_from_slice(&pixels[start..start + tile_width as usize * 4]);
+            }
+
+            self.resident_bytes += tile.len();
+            self.resident.put(
+                TileKey {
+                    layer_id: layer_id.to_string(),
+                    column,
+                    row,
+                },
+                tile,
+            );
+            self.evict_to(self.budget)?;
+        }
+
+        Ok(())
+    }
+
+    /// Drop a layer's tiles, wherever they are
+    pub fn remove_layer(&mut self, layer_id: &str) {
+        let Some((width, height)) = self.layers.remove(layer_id) else {
+            return;
+        };
+
+        for (column, row) in tile_grid(width, height) {
+            let key = TileKey {
+                layer_id: layer_id.to_string(),
+                column,
+                row,
+            };
+            if let Some(tile) = self.resident.pop(&key) {
+                self.resident_bytes -= tile.len();
+            }
+            self.spilled.remove(&key);
+        }
+
+        if self.spilled.is_empty() {
+            self.truncate_spill();
+        }
+    }
+
+    /// Reassemble a layer's full pixel buffer and remove it from the cache
+    pub fn take_layer(&mut self, layer_id: &str) -> AppResult<Vec<u8>> {
+        let (width, height) = self.layer_size(layer_id)?;
+        let pixels = self.read_region(layer_id, 0, 0, width, height)?;
+        self.remove_layer(layer_id);
+        Ok(pixels)
+    }
+
+    /// Copy a rectangle (layer pixels, clipped to the layer) out of the cache.
+    /// Only the tiles it touches are loaded.
+    pub fn read_region(
+        &mut self,
+        layer_id: &str,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> AppResult<Vec<u8>> {
+        let (layer_width, layer_height) = self.layer_size(layer_id)?;
+        let width = width.min(layer_width.saturating_sub(x));
+        let height = height.min(layer_height.saturating_sub(y));
+        let mut out = vec![0u8; checked_rgba_buffer_len(width, height)?];
+
+        let stride = width as usize * 4;
+        self.for_each_tile_span(layer_id, x, y, width, height, |tile, span| {
+            let start = span.region_row * stride + span.region_offset;
+            out[start..start + span.len].copy_from_slice(&tile[span.tile..span.tile + span.len]);
+        })?;
+
+        Ok(out)
+    }
+
+    fn layer_size(&self, layer_id: &str) -> AppResult<(u32, u32)> {
+        self.layers
+            .get(layer_id)
+            .copied()
+            .ok_or_else(|| AppError::LayerNotFound(layer_id.to_string()))
+    }
+
+    /// Visit every tile row segment inside a clipped region, loading tiles as
+    /// needed
+    fn for_each_tile_span(
+        &mut self,
+        layer_id: &str,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        mut visit: impl FnMut(&[u8], TileSpan),
+    ) -> AppResult<()> {
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+
+        let (layer_width, layer_height) = self.layer_size(layer_id)?;
+        for row in y / TILE_SIZE..=(y + height - 1) / TILE_SIZE {
+            for column in x / TILE_SIZE..=(x + width - 1) / TILE_SIZE {
+                let (tile_x, tile_y, tile_width, tile_height) =
+                    tile_rect(layer_width, layer_height, column, row);
+                let left = x.max(tile_x);
+                let right = (x + width).min(tile_x + tile_width);
+                let top = y.max(tile_y);
+                let bottom = (y + height).min(tile_y + tile_height);
+
+                let tile = self.tile(TileKey {
+                    layer_id: layer_id.to_string(),
+                    column,
+                    row,
+                })?;
+                for py in top..bottom {
+                    visit(
+                        tile,
+                        TileSpan {
+                            tile: (((py - tile_y) * tile_width + (left - tile_x)) * 4) as usize,
+                            region_row: (py - y) as usize,
+                            region_offset: ((left - x) * 4) as usize,
+                            len: ((right - left) * 4) as usize,
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A tile in memory, reading it back from the spill file if it was evicted
+    fn tile(&mut self, key: TileKey) -> AppResult<&[u8]> {
+        if !self.resident.contains(&key) {
+            let spilled = *self.spilled.get(&key).ok_or_else(|| {
+                AppError::InvalidOperation("Tile missing from the tile cache".into())
+            })?;
+            let pixels = self.read_spilled(spilled)?;
+
+            // Make room first so the tile being returned isn't evicted
+            self.evict_to(self.budget.saturating_sub(pixels.len()))?;
+            self.resident_bytes += pixels.len();
+            self.resident.put(key.clone(), pixels);
+        }
+
+        Ok(self
+            .resident
+            .get(&key)
+            .map(Vec::as_slice)
+            .expect("tile was just made resident"))
+    }
+
+    /// Spill least recently used tiles until at most `budget` bytes are resident
+    fn evict_to(&mut self, budget: usize) -> AppResult<()> {
+        while self.resident_bytes > budget {
+            let Some((key, tile)) = self.resident.pop_lru() else {
+                break;
+            };
+            self.resident_bytes -= tile.len();
+
+            // Tiles never change once cached, so one spilled copy is enough
+            if !self.spilled.contains_key(&key) {
+                let (width, height) = self.layers[&key.layer_id];
+                let (_, _, tile_width, tile_height) = tile_rect(width, height, key.column, key.row);
+                let encoded = encode_rgba(&tile, tile_width, tile_height, PixelFormat::Png)?;
+                let spilled = self.append_spill(&encoded)?;
+                self.spilled.insert(key, spilled);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn append_spill(&mut self, data: &[u8]) -> AppResult<SpilledTile> {
+        if self.spill.is_none() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&self.spill_path)
+                .map_err(|e| {
+                    AppError::IoError(format!("Failed to create tile spill file: {}", e))
+                })?;
+            self.spill = Some(file);
+        }
+        let file = self.spill.as_mut().unwrap();
+
+        let offset = self.spill_len;
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(data))
+            .map_err(|e| AppError::IoError(format!("Failed to spill tile: {}", e)))?;
+        self.spill_len += data.len() as u64;
+
+        Ok(SpilledTile {
+            offset,
+            len: data.len() as u64,
+        })
+    }
+
+    fn read_spilled(&mut self, spilled: SpilledTile) -> AppResult<Vec<u8>> {
+        let file = self
+            .spill
+            .as_mut()
+            .ok_or_else(|| AppError::InvalidOperation("Tile spill file is missing".into()))?;
+
+        let mut data = vec![0u8; spilled.len as usize];
+        file.seek(SeekFrom::Start(spilled.offset))
+            .and_then(|_| file.read_exact(&mut data))
+            .map_err(|e| AppError::IoError(format!("Failed to read spilled tile: {}", e)))?;
+
+        decode_to_rgba(&data)
+    }
+
+    fn truncate_spill(&mut self) {
+        if let Some(file) = &self.spill {
+            if let Err(e) = file.set_len(0) {
+                log::warn!("Failed to truncate tile spill file: {}", e);
+            }
+        }
+        self.spill_len = 0;
+    }
+}
+
+impl Drop for TileCache {
+    fn drop(&mut self) {
+        if self.spill.take().is_some() {
+            if let Err(e) = fs::remove_file(&self.spill_path) {
+                log::warn!("Failed to remove tile spill file: {}", e);
+            }
+        }
+    }
+}
+
+/// One row of a tile that falls inside a region: the byte offset into the
+/// tile, the row and byte offset within the region, and the length in bytes
+struct TileSpan {
+    tile: usize,
+    region_row: usize,
+    region_offset: usize,
+    len: usize,
+}
+
+/// Every (column, row) of the tiles covering a layer
+fn tile_grid(width: u32, height: u32) -> impl Iterator<Item = (u32, u32)> {
+    let columns = width.div_ceil(TILE_SIZE);
+    let rows = height.div_ceil(TILE_SIZE);
+    (0..rows).flat_map(move |row| (0..columns).map(move |column| (column, row)))
+}
+
+/// Layer-space `(x, y, width, height)` of a tile
+fn tile_rect(width: u32, height: u32, column: u32, row: u32) -> (u32, u32, u32, u32) {
+    let x = column * TILE_SIZE;
+    let y = row * TILE_SIZE;
+    (x, y, TILE_SIZE.min(width - x), TILE_SIZE.min(height - y))
+}
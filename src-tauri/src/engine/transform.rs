@@ -0,0 +1,313 @@
+use super::buffer::checked_rgba_buffer_len;
+use super::compositor::DirtyRect;
+use crate::error::{AppError, AppResult};
+use image::{imageops, Rgba, Rgba32FImage, RgbaImage};
+
+/// Largest side a transformed layer may grow to
+const MAX_TRANSFORM_SIZE: u32 = 16384;
+
+/// Sample an RGBA buffer at a continuous position with bilinear interpolation.
+///
+/// Pixel `(i, j)` covers `[i, i + 1) x [j, j + 1)`, so its center is at
+/// `(i + 0.5, j + 0.5)`. Neighbours past the edge repeat the edge pixel.
+/// Interpolation is done on premultiplied values to avoid dark fringes.
+pub fn sample_bilinear(pixels: &[u8], width: u32, height: u32, x: f64, y: f64) -> [u8; 4] {
+    let fx = x - 0.5;
+    let fy = y - 0.5;
+    let x0 = fx.floor();
+    let y0 = fy.floor();
+    let tx = fx - x0;
+    let ty = fy - y0;
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let max_x = width as i64 - 1;
+    let max_y = height as i64 - 1;
+
+    let mut acc = [0.0f64; 4];
+    for (dx, dy, weight) in [
+        (0, 0, (1.0 - tx) * (1.0 - ty)),
+        (1, 0, tx * (1.0 - ty)),
+        (0, 1, (1.0 - tx) * ty),
+        (1, 1, tx * ty),
+    ] {
+        if weight == 0.0 {
+            continue;
+        }
+        let sx = (x0 + dx).clamp(0, max_x);
+        let sy = (y0 + dy).clamp(0, max_y);
+
+        let idx = ((sy as usize) * width as usize + sx as usize) * 4;
+        let alpha = pixels[idx + 3] as f64 * weight;
+        for c in 0..3 {
+            acc[c] += pixels[idx + c] as f64 * alpha;
+        }
+        acc[3] += alpha;
+    }
+
+    if acc[3] <= 0.0 {
+        return [0, 0, 0, 0];
+    }
+
+    [
+        (acc[0] / acc[3]).round().clamp(0.0, 255.0) as u8,
+        (acc[1] / acc[3]).round().clamp(0.0, 255.0) as u8,
+        (acc[2] / acc[3]).round().clamp(0.0, 255.0) as u8,
+        acc[3].round().clamp(0.0, 255.0) as u8,
+    ]
+}
+
+/// A 3x3 projective transform, row-major with the last entry fixed at 1
+#[derive(Debug, Clone, Copy)]
+pub struct Homography([f64; 9]);
+
+impl Homography {
+    /// Solve for the transform mapping each `from[i]` onto `to[i]`.
+    ///
+    /// Returns None if the quads are degenerate (three points collinear).
+    pub fn from_quads(from: &[(f64, f64); 4], to: &[(f64, f64); 4]) -> Option<Self> {
+        // Each correspondence gives two linear equations in h0..h7:
+        //   u = (h0 x + h1 y + h2) / (h6 x + h7 y + 1)
+        //   v = (h3 x + h4 y + h5) / (h6 x + h7 y + 1)
+        let mut system = [[0.0f64; 9]; 8];
+        for (i, (&(x, y), &(u, v))) in from.iter().zip(to.iter()).enumerate() {
+            system[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y, u];
+            system[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y, v];
+        }
+
+        let h = solve_linear_system(system)?;
+        Some(Self([h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], 1.0]))
+    }
+
+    /// Map a point, or None if it lands on the line at infinity
+    pub fn apply(&self, x: f64, y: f64) -> Option<(f64, f64)> {
+        let m = &self.0;
+        let w = m[6] * x + m[7] * y + m[8];
+        if w.abs() < 1e-12 {
+            return None;
+        }
+        Some((
+            (m[0] * x + m[1] * y + m[2]) / w,
+            (m[3] * x + m[4] * y + m[5]) / w,
+        ))
+    }
+}
+
+/// Gaussian elimination with partial pivoting on an 8x8 augmented matrix
+fn solve_linear_system(mut m: [[f64; 9]; 8]) -> Option<[f64; 8]> {
+    for col in 0..8 {
+        let pivot = (col..8).max_by(|&a, &b| m[a][col].abs().total_cmp(&m[b][col].abs()))?;
+        if m[pivot][col].abs() < 1e-10 {
+            return None;
+        }
+        m.swap(col, pivot);
+
+        let pivot_row = m[col];
+        for (row, values) in m.iter_mut().enumerate() {
+            if row != col {
+                let factor = values[col] / pivot_row[col];
+                for (value, pivot) in values[col..].iter_mut().zip(&pivot_row[col..]) {
+                    *value -= factor * pivot;
+                }
+            }
+        }
+    }
+
+    let mut result = [0.0; 8];
+    for (i, value) in result.iter_mut().enumerate() {
+        *value = m[i][8] / m[i][i];
+    }
+    Some(result)
+}
+
+/// Warp a layer buffer so its corners land on `corners` (top-left, top-right,
+/// bottom-right, bottom-left, in document space).
+///
+/// Returns the document-space bounds of the result and its pixels. Output
+/// pixels that map outside the source are transparent.
+pub fn perspective_warp(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    corners: &[(f64, f64); 4],
+) -> AppResult<(DirtyRect, Vec<u8>)> {
+    if corners
+        .iter()
+        .any(|(x, y)| !x.is_finite() || !y.is_finite())
+    {
+        return Err(AppError::InvalidOperation(
+            "Transform corners must be finite".into(),
+        ));
+    }
+
+    let (w, h) = (width as f64, height as f64);
+    let source = [(0.0, 0.0), (w, 0.0), (w, h), (0.0, h)];
+    // Map document-space destination pixels back into the layer (inverse mapping)
+    let inverse = Homography::from_quads(corners, &source)
+        .ok_or_else(|| AppError::InvalidOperation("Transform corners are degenerate".into()))?;
+
+    let min_x = corners
+        .iter()
+        .map(|c| c.0)
+        .fold(f64::INFINITY, f64::min)
+        .floor();
+    let min_y = corners
+        .iter()
+        .map(|c| c.1)
+        .fold(f64::INFINITY, f64::min)
+        .floor();
+    let max_x = corners
+        .iter()
+        .map(|c| c.0)
+        .fold(f64::NEG_INFINITY, f64::max)
+        .ceil();
+    let max_y = corners
+        .iter()
+        .map(|c| c.1)
+        .fold(f64::NEG_INFINITY, f64::max)
+        .ceil();
+
+    let out_width = (max_x - min_x) as u32;
+    let out_height = (max_y - min_y) as u32;
+    if out_width == 0 || out_height == 0 {
+        return Err(AppError::InvalidOperation(
+            "Transformed layer has no area".into(),
+        ));
+    }
+    if out_width > MAX_TRANSFORM_SIZE || out_height > MAX_TRANSFORM_SIZE {
+        return Err(AppError::InvalidOperation(format!(
+            "Transformed layer exceeds {}px",
+            MAX_TRANSFORM_SIZE
+        )));
+    }
+
+    let mut out = vec![0u8; checked_rgba_buffer_len(out_width, out_height)?];
+    for oy in 0..out_height {
+        for ox in 0..out_width {
+            // Destination pixel center in document space
+            let dx = min_x + ox as f64 + 0.5;
+            let dy = min_y + oy as f64 + 0.5;
+
+            let (sx, sy) = match inverse.apply(dx, dy) {
+                Some(point) => point,
+                None => continue,
+            };
+            if sx < 0.0 || sy < 0.0 || sx > w || sy > h {
+                continue;
+            }
+
+            let idx = ((oy * out_width + ox) * 4) as usize;
+            out[idx..idx + 4].copy_from_slice(&sample_bilinear(pixels, width, height, sx, sy));
+        }
+    }
+
+    let bounds = DirtyRect {
+        x: min_x as i32,
+        y: min_y as i32,
+        width: out_width,
+        height: out_height,
+    };
+
+    Ok((bounds, out))
+}
+
+/// Resize with Lanczos3 on premultiplied alpha.
+///
+/// Filtering straight RGBA lets the (usually black) color of fully transparent
+/// pixels bleed into the edges of opaque content, leaving dark fringes.
+pub fn resize_premultiplied(img: &RgbaImage, new_width: u32, new_height: u32) -> RgbaImage {
+    let premultiplied = Rgba32FImage::from_fn(img.width(), img.height(), |x, y| {
+        let [r, g, b, a] = img.get_pixel(x, y).0;
+        let alpha = a as f32 / 255.0;
+        Rgba([
+            r as f32 / 255.0 * alpha,
+            g as f32 / 255.0 * alpha,
+            b as f32 / 255.0 * alpha,
+            alpha,
+        ])
+    });
+
+    let resized = imageops::resize(
+        &premultiplied,
+        new_width,
+        new_height,
+        imageops::FilterType::Lanczos3,
+    );
+
+    RgbaImage::from_fn(new_width, new_height, |x, y| {
+        let [r, g, b, a] = resized.get_pixel(x, y).0;
+        // Lanczos can ring slightly outside 0..1
+        let alpha = a.clamp(0.0, 1.0);
+        if alpha <= 0.0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+
+        let channel = |c: f32| ((c / alpha).clamp(0.0, 1.0) * 255.0).round() as u8;
+        Rgba([
+            channel(r),
+            channel(g),
+            channel(b),
+            (alpha * 255.0).round() as u8,
+        ])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQUARE: [(f64, f64); 4] = [(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0)];
+
+    fn assert_close(actual: (f64, f64), expected: (f64, f64)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-9 && (actual.1 - expected.1).abs() < 1e-9,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn a_homography_maps_each_corner_onto_its_target() {
+        let to = [(1.0, 2.0), (9.0, 0.0), (7.0, 6.0), (0.0, 5.0)];
+        let homography = Homography::from_quads(&SQUARE, &to).unwrap();
+
+        for (from, to) in SQUARE.iter().zip(&to) {
+            assert_close(homography.apply(from.0, from.1).unwrap(), *to);
+        }
+    }
+
+    #[test]
+    fn collinear_corners_have_no_homography() {
+        let line = [(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (3.0, 3.0)];
+        assert!(Homography::from_quads(&SQUARE, &line).is_none());
+
+        let result = perspective_warp(&[0; 64], 4, 4, &line);
+        assert!(matches!(result, Err(AppError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn warping_onto_a_shifted_square_moves_the_pixels_unchanged() {
+        let pixels: Vec<u8> = (0..64).collect();
+        let shifted = SQUARE.map(|(x, y)| (x + 3.0, y - 2.0));
+
+        let (bounds, out) = perspective_warp(&pixels, 4, 4, &shifted).unwrap();
+        assert_eq!(
+            (bounds.x, bounds.y, bounds.width, bounds.height),
+            (3, -2, 4, 4)
+        );
+        assert_eq!(out, pixels);
+    }
+
+    #[test]
+    fn pixels_outside_a_tapered_quad_are_transparent() {
+        let pixels = [255u8; 8 * 8 * 4];
+        let taper = [(3.0, 0.0), (5.0, 0.0), (8.0, 8.0), (0.0, 8.0)];
+
+        let (bounds, out) = perspective_warp(&pixels, 8, 8, &taper).unwrap();
+        assert_eq!((bounds.width, bounds.height), (8, 8));
+        let alpha = |x: u32, y: u32| out[((y * 8 + x) * 4 + 3) as usize];
+        assert_eq!(alpha(0, 0), 0);
+        assert_eq!(alpha(7, 0), 0);
+        assert_eq!(alpha(4, 0), 255);
+        assert_eq!(alpha(0, 7), 255);
+    }
+}
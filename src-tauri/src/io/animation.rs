@@ -0,0 +1,284 @@
+use crate::error::{AppError, AppResult};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::io::Reader as ImageReader;
+use image::{AnimationDecoder, Frames, ImageFormat, RgbaImage};
+use std::fs::File;
+use std::io::{BufReader, Cursor};
+use std::path::Path;
+
+/// Largest value the 24-bit fields of an animated WebP can hold
+const WEBP_MAX_24BIT: u32 = (1 << 24) - 1;
+
+/// Decode every frame of an animated GIF or APNG.
+///
+/// Each frame is returned as a canvas-sized RGBA image. Returns None for
+/// other formats and for animations with only a single frame, so callers can
+/// fall back to the regular single-image import.
+pub fn read_animation_frames<P: AsRef<Path>>(path: P) -> AppResult<Option<Vec<RgbaImage>>> {
+    let path = path.as_ref();
+
+    let format = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| AppError::IoError(e.to_string()))?
+        .format();
+
+    let (width, height) =
+        image::image_dimensions(path).map_err(|e| AppError::ImageError(e.to_string()))?;
+
+    let frames = match format {
+        Some(ImageFormat::Gif) => {
+            let decoder = GifDecoder::new(open_buffered(path)?)
+                .map_err(|e| AppError::ImageError(e.to_string()))?;
+            decoder.into_frames()
+        }
+        Some(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(open_buffered(path)?)
+                .map_err(|e| AppError::ImageError(e.to_string()))?;
+            if !decoder.is_apng() {
+                return Ok(None);
+            }
+            decoder.apng().into_frames()
+        }
+        _ => return Ok(None),
+    };
+
+    let canvases = frames_to_canvases(frames, width, height)?;
+    if canvases.len() < 2 {
+        return Ok(None);
+    }
+
+    Ok(Some(canvases))
+}
+
+fn open_buffered(path: &Path) -> AppResult<BufReader<File>> {
+    let file = File::open(path).map_err(|e| AppError::IoError(e.to_string()))?;
+    Ok(BufReader::new(file))
+}
+
+/// Place each decoded frame at its offset on a transparent canvas
+fn frames_to_canvases(frames: Frames, width: u32, height: u32) -> AppResult<Vec<RgbaImage>> {
+    let mut canvases = Vec::new();
+
+    for frame in frames {
+        let frame = frame.map_err(|e| AppError::ImageError(e.to_string()))?;
+        let (left, top) = (frame.left(), frame.top());
+        let buffer = frame.into_buffer();
+
+        if left == 0 && top == 0 && buffer.dimensions() == (width, height) {
+            canvases.push(buffer);
+            continue;
+        }
+
+        let mut canvas = RgbaImage::new(width, height);
+        image::imageops::replace(&mut canvas, &buffer, left as i64, top as i64);
+        canvases.push(canvas);
+    }
+
+    Ok(canvases)
+}
+
+/// Encode canvas-sized frames as a lossless animated WebP.
+///
+/// Each frame comes with its duration in milliseconds; a `loop_count` of 0
+/// loops forever.
+pub fn write_animated_webp<P: AsRef<Path>>(
+    path: P,
+    frames: &[(RgbaImage, u32)],
+    loop_count: u16,
+) -> AppResult<()> {
+    let (width, height) = frames
+        .first()
+        .map(|(frame, _)| frame.dimensions())
+        .ok_or_else(|| {
+            AppError::InvalidOperation("An animation needs at least one frame".into())
+        })?;
+
+    if width == 0 || height == 0 || width > WEBP_MAX_24BIT + 1 || height > WEBP_MAX_24BIT + 1 {
+        return Err(AppError::InvalidOperation(format!(
+            "Cannot encode a {}x{} animated WebP",
+            width, height
+        )));
+    }
+
+    let mut chunks = Vec::new();
+
+    // Extended header: animation and alpha flags, then the canvas size
+    let mut vp8x = vec![0x12, 0, 0, 0];
+    vp8x.extend_from_slice(&u24(width - 1));
+    vp8x.extend_from_slice(&u24(height - 1));
+    push_chunk(&mut chunks, b"VP8X", &vp8x)?;
+
+    // Transparent background color, then the loop count
+    let mut anim = vec![0, 0, 0, 0];
+    anim.extend_from_slice(&loop_count.to_le_bytes());
+    push_chunk(&mut chunks, b"ANIM", &anim)?;
+
+    for (frame, duration) in frames {
+        if frame.dimensions() != (width, height) {
+            return Err(AppError::InvalidOperation(
+                "All animation frames must be the same size".into(),
+            ));
+        }
+        if *duration > WEBP_MAX_24BIT {
+            return Err(AppError::InvalidOperation(format!(
+                "Frame duration must be at most {}ms",
+                WEBP_MAX_24BIT
+            )));
+        }
+
+        let mut anmf = Vec::new();
+        anmf.extend_from_slice(&u24(0)); // x offset / 2
+        anmf.extend_from_slice(&u24(0)); // y offset / 2
+        anmf.extend_from_slice(&u24(width - 1));
+        anmf.extend_from_slice(&u24(height - 1));
+        anmf.extend_from_slice(&u24(*duration));
+        // Frames cover the whole canvas: replace instead of blending, no disposal
+        anmf.push(0x02);
+        anmf.extend_from_slice(&encode_webp_frame(frame)?);
+        push_chunk(&mut chunks, b"ANMF", &anmf)?;
+    }
+
+    let riff_size = u32::try_from(chunks.len() + 4)
+        .map_err(|_| AppError::InvalidOperation("Animation is too large for WebP".into()))?;
+
+    let mut data = Vec::with_capacity(chunks.len() + 12);
+    data.extend_from_slice(b"RIFF");
+    data.extend_from_slice(&riff_size.to_le_bytes());
+    data.extend_from_slice(b"WEBP");
+    data.extend_from_slice(&chunks);
+
+    std::fs::write(path, data).map_err(|e| AppError::IoError(e.to_string()))
+}
+
+/// Little-endian 24-bit field
+fn u24(value: u32) -> [u8; 3] {
+    let [a, b, c, _] = value.to_le_bytes();
+    [a, b, c]
+}
+
+/// Append a RIFF chunk, padded to an even length
+fn push_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) -> AppResult<()> {
+    let size = u32::try_from(payload.len())
+        .map_err(|_| AppError::InvalidOperation("Animation frame is too large for WebP".into()))?;
+
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        out.push(0);
+    }
+    Ok(())
+}
+
+/// Encode one frame as a still WebP and keep only its image chunks
+/// (`VP8L`, or `ALPH` + `VP8 `), ready to nest inside an `ANMF` chunk
+fn encode_webp_frame(frame: &RgbaImage) -> AppResult<Vec<u8>> {
+    let mut cursor = Cursor::new(Vec::new());
+    frame
+        .write_to(&mut cursor, ImageFormat::WebP)
+        .map_err(|e| AppError::IoError(format!("Failed to encode WebP: {}", e)))?;
+    let still = cursor.into_inner();
+
+    // Skip the RIFF header and walk the chunks
+    let mut image_chunks = Vec::new();
+    let mut pos = 12;
+    while let Some(header) = still.get(pos..pos + 8) {
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let padded = size + size % 2;
+        let chunk = still
+            .get(pos..pos + 8 + padded)
+            .ok_or_else(|| AppError::IoError("Truncated WebP chunk".into()))?;
+
+        if matches!(&header[..4], b"ALPH" | b"VP8 " | b"VP8L") {
+            image_chunks.extend_from_slice(chunk);
+        }
+        pos += 8 + padded;
+    }
+
+    if image_chunks.is_empty() {
+        return Err(AppError::IoError(
+            "WebP encoder produced no image data".into(),
+        ));
+    }
+    Ok(image_chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{Frame, Rgba};
+
+    /// Write solid-color 3x2 frames to a temporary GIF
+    fn write_gif(name: &str, colors: &[[u8; 4]]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("drkr-{}-{}.gif", name, std::process::id()));
+        let mut encoder = GifEncoder::new(File::create(&path).unwrap());
+        let frames = colors
+            .iter()
+            .map(|&color| Frame::new(RgbaImage::from_pixel(3, 2, Rgba(color))));
+        encoder.encode_frames(frames).unwrap();
+        drop(encoder);
+        path
+    }
+
+    #[test]
+    fn each_gif_frame_becomes_a_canvas() {
+        let path = write_gif("frames", &[[255, 0, 0, 255], [0, 0, 255, 255]]);
+        let frames = read_animation_frames(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].dimensions(), (3, 2));
+        assert_eq!(frames[0].get_pixel(2, 1).0, [255, 0, 0, 255]);
+        assert_eq!(frames[1].get_pixel(2, 1).0, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn a_single_frame_gif_is_left_to_the_still_importer() {
+        let path = write_gif("still", &[[0, 255, 0, 255]]);
+        let frames = read_animation_frames(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(frames.is_none());
+    }
+
+    #[test]
+    fn animated_webp_keeps_frames_durations_and_loop_count() {
+        use image::codecs::webp::WebPDecoder;
+
+        let path = std::env::temp_dir().join(format!("drkr-anim-{}.webp", std::process::id()));
+        let frames = [
+            (RgbaImage::from_pixel(3, 2, Rgba([255, 0, 0, 255])), 100),
+            (RgbaImage::from_pixel(3, 2, Rgba([0, 0, 255, 128])), 250),
+        ];
+        write_animated_webp(&path, &frames, 3).unwrap();
+        let data = std::fs::read(&path).unwrap();
+
+        // RIFF size covers everything after the size field
+        assert_eq!(&data[..4], b"RIFF");
+        let riff_size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        assert_eq!(riff_size as usize, data.len() - 8);
+        let anim = data.windows(4).position(|w| w == b"ANIM").unwrap();
+        assert_eq!(&data[anim + 12..anim + 14], &3u16.to_le_bytes());
+
+        let decoder = WebPDecoder::new(BufReader::new(File::open(&path).unwrap())).unwrap();
+        let decoded = decoder.into_frames().collect_frames().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(decoded.len(), 2);
+        for (frame, (expected, duration)) in decoded.iter().zip(&frames) {
+            assert_eq!(frame.buffer(), expected);
+            assert_eq!(frame.delay().numer_denom_ms(), (*duration, 1));
+        }
+    }
+
+    #[test]
+    fn animated_webp_rejects_mismatched_frames() {
+        let path = std::env::temp_dir().join(format!("drkr-anim-bad-{}.webp", std::process::id()));
+        let frames = [(RgbaImage::new(3, 2), 100), (RgbaImage::new(2, 2), 100)];
+        assert!(write_animated_webp(&path, &frames, 0).is_err());
+        assert!(write_animated_webp(&path, &[], 0).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::error::{AppError, AppResult};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Recovery files kept per document; older ones are pruned after each autosave
+pub const AUTOSAVES_PER_DOCUMENT: usize = 3;
+
+/// A recovery file found on disk, named `{doc_id}_{saved_at}.drkr`
+#[derive(Debug, Clone)]
+pub struct AutosaveFile {
+    pub doc_id: String,
+    /// Milliseconds since the epoch
+    pub saved_at: i64,
+    pub path: PathBuf,
+}
+
+/// Where the next autosave of a document goes
+pub fn autosave_path(dir: &Path, doc_id: &str, saved_at: i64) -> PathBuf {
+    dir.join(format!("{}_{}.drkr", doc_id, saved_at))
+}
+
+/// Every recovery file in `dir`, newest first. A missing directory has none.
+pub fn list_autosaves(dir: &Path) -> AppResult<Vec<AutosaveFile>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(AppError::IoError(e.to_string())),
+    };
+
+    let mut files: Vec<AutosaveFile> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            let stem = path
+                .file_name()?
+                .to_str()?
+                .strip_suffix(".drkr")?
+                .to_string();
+            let (doc_id, saved_at) = stem.rsplit_once('_')?;
+            Some(AutosaveFile {
+                doc_id: doc_id.to_string(),
+                saved_at: saved_at.parse().ok()?,
+                path,
+            })
+        })
+        .collect();
+
+    files.sort_by_key(|file| std::cmp::Reverse(file.saved_at));
+    Ok(files)
+}
+
+/// Delete all but the newest `keep` recovery files of a document
+pub fn prune_autosaves(dir: &Path, doc_id: &str, keep: usize) -> AppResult<()> {
+    let stale = list_autosaves(dir)?
+        .into_iter()
+        .filter(|file| file.doc_id == doc_id)
+        .skip(keep);
+
+    for file in stale {
+        if let Err(e) = fs::remove_file(&file.path) {
+            log::warn!("Failed to remove autosave {}: {}", file.path.display(), e);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pruning_keeps_the_newest_autosaves_of_one_document() {
+        let dir = std::env::temp_dir().join(format!("drkr-autosave-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for saved_at in [100, 300, 200] {
+            fs::write(autosave_path(&dir, "doc_a", saved_at), b"").unwrap();
+        }
+        fs::write(autosave_path(&dir, "doc-b", 50), b"").unwrap();
+        fs::write(dir.join("notes.txt"), b"").unwrap();
+
+        let listed: Vec<(String, i64)> = list_autosaves(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|file| (file.doc_id, file.saved_at))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("doc_a".to_string(), 300),
+                ("doc_a".to_string(), 200),
+                ("doc_a".to_string(), 100),
+                ("doc-b".to_string(), 50),
+            ]
+        );
+
+        prune_autosaves(&dir, "doc_a", 2).unwrap();
+        let remaining: Vec<i64> = list_autosaves(&dir)
+            .unwrap()
+            .iter()
+            .map(|file| file.saved_at)
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(remaining, [300, 200, 50]);
+    }
+
+    #[test]
+    fn a_missing_recovery_directory_has_no_autosaves() {
+        let dir = std::env::temp_dir().join(format!("drkr-no-autosaves-{}", std::process::id()));
+        assert!(list_autosaves(&dir).unwrap().is_empty());
+        prune_autosaves(&dir, "doc", 0).unwrap();
+    }
+}
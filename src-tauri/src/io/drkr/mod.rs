@@ -2,7 +2,7 @@ mod reader;
 mod types;
 mod writer;
 
-pub use reader::DrkrReader;
+pub use reader::{decode_to_rgba, DrkrReadResult, DrkrReader};
 #[allow(unused_imports)]
 pub use types::*;
-pub use writer::DrkrWriter;
+pub use writer::{encode_rgba, encode_thumbnail_webp, DrkrWriter};
@@ -1,7 +1,10 @@
 use super::types::*;
-use crate::engine::Document;
+use crate::engine::layer::LayerType;
+use crate::engine::{checked_rgba_buffer_len, Background, Document, ExtensionFiles};
 use crate::error::{AppError, AppResult};
 use image::io::Reader as ImageReader;
+use image::RgbaImage;
+use rayon::prelude::*;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, Cursor, Read};
@@ -12,6 +15,55 @@ use zip::ZipArchive;
 pub struct DrkrReadResult {
     pub document: Document,
     pub layer_pixels: HashMap<String, Vec<u8>>,
+    /// Extension data, kept as-is so it survives a save
+    pub extensions: ExtensionFiles,
+}
+
+/// A layer's encoded pixels, read from the archive but not yet decoded
+struct PendingPixels {
+    layer_id: String,
+    data: AppResult<Vec<u8>>,
+    /// Size of the transparent buffer to use if the pixels can't be read.
+    /// None for unknown layer types, which may legitimately have no pixels.
+    fallback_len: Option<usize>,
+    type_name: String,
+}
+
+/// Pixels decoded for a layer, with the size they actually came out at
+struct DecodedPixels {
+    layer_id: String,
+    size: Option<(u32, u32)>,
+    pixels: Vec<u8>,
+}
+
+impl PendingPixels {
+    fn decode(self) -> Option<DecodedPixels> {
+        let decoded = self.data.and_then(|data| decode_image(&data));
+        match (decoded, self.fallback_len) {
+            (Ok(image), _) => Some(DecodedPixels {
+                layer_id: self.layer_id,
+                size: Some(image.dimensions()),
+                pixels: image.into_raw(),
+            }),
+            (Err(e), Some(len)) => {
+                log::warn!("Failed to read pixels for layer {}: {}", self.layer_id, e);
+                // Create transparent pixels as fallback
+                Some(DecodedPixels {
+                    layer_id: self.layer_id,
+                    size: None,
+                    pixels: vec![0u8; len],
+                })
+            }
+            (Err(_), None) => {
+                log::debug!(
+                    "Layer {} of unknown type '{}' has no pixels",
+                    self.layer_id,
+                    self.type_name
+                );
+                None
+            }
+        }
+    }
 }
 
 /// Reader for DRKR format files
@@ -91,18 +143,32 @@ impl<R: Read + std::io::Seek> DrkrReader<R> {
             .map_err(|e| AppError::SerializationError(format!("Failed to parse {}: {}", path, e)))
     }
 
-    /// Read a layer's pixel data (decodes WebP to RGBA)
-    pub fn read_layer_pixels(&mut self, layer_id: &str) -> AppResult<Vec<u8>> {
-        let path = format!("layers/{}/pixels.webp", layer_id);
-        let webp_data = self.read_file_as_bytes(&path)?;
-        decode_webp_to_rgba(&webp_data)
+    /// Read a layer's pixel data (decodes the WebP or PNG named in its
+    /// storage info to RGBA)
+    pub fn read_layer_pixels(
+        &mut self,
+        layer_id: &str,
+        storage: Option<&DrkrStorage>,
+    ) -> AppResult<Vec<u8>> {
+        let data = self.read_layer_pixel_bytes(layer_id, storage)?;
+        decode_to_rgba(&data)
+    }
+
+    /// Read a layer's encoded pixel file without decoding it
+    fn read_layer_pixel_bytes(
+        &mut self,
+        layer_id: &str,
+        storage: Option<&DrkrStorage>,
+    ) -> AppResult<Vec<u8>> {
+        let path = PixelFormat::from_storage(storage).layer_pixels_path(layer_id);
+        self.read_file_as_bytes(&path)
     }
 
     /// Read the thumbnail
     #[allow(dead_code)]
     pub fn read_thumbnail(&mut self) -> AppResult<Vec<u8>> {
         let webp_data = self.read_file_as_bytes("preview/thumbnail.webp")?;
-        decode_webp_to_rgba(&webp_data)
+        decode_to_rgba(&webp_data)
     }
 
     /// Read the complete document with all layer pixels
@@ -113,52 +179,261 @@ impl<R: Read + std::io::Seek> DrkrReader<R> {
         // Read document metadata
         let drkr_doc = self.read_document_json()?;
 
-        // Build layers and read pixels
+        // Build layers and read the encoded pixels. The archive has a single
+        // cursor, so this part is serial; decoding happens in parallel below.
         let mut layers = Vec::new();
-        let mut layer_pixels = HashMap::new();
+        let mut pending = Vec::new();
 
         for layer_ref in &drkr_doc.layers {
             // Read layer metadata
             let meta = self.read_layer_meta(&layer_ref.id)?;
             let layer = meta.to_layer();
 
-            // Read pixel data if it's a raster layer
-            if layer_ref.layer_type == "raster" || layer_ref.layer_type == "ai_generated" {
-                match self.read_layer_pixels(&layer_ref.id) {
-                    Ok(pixels) => {
-                        layer_pixels.insert(layer_ref.id.clone(), pixels);
-                    }
-                    Err(e) => {
-                        log::warn!("Failed to read pixels for layer {}: {}", layer_ref.id, e);
-                        // Create transparent pixels as fallback
-                        let size = (layer.width * layer.height * 4) as usize;
-                        layer_pixels.insert(layer_ref.id.clone(), vec![0u8; size]);
-                    }
-                }
+            // Read pixel data if it's a raster layer (text and shape layers store their
+            // rendered pixels)
+            if let LayerType::Unknown(type_name) = &layer.layer_type {
+                // Unknown layer types keep whatever pixels they were saved with
+                pending.push(PendingPixels {
+                    layer_id: layer_ref.id.clone(),
+                    data: self.read_layer_pixel_bytes(&layer_ref.id, meta.storage.as_ref()),
+                    fallback_len: None,
+                    type_name: type_name.clone(),
+                });
+            } else if matches!(
+                layer_ref.layer_type.as_str(),
+                "raster" | "ai_generated" | "text" | "shape" | "linked_image"
+            ) {
+                pending.push(PendingPixels {
+                    layer_id: layer_ref.id.clone(),
+                    data: self.read_layer_pixel_bytes(&layer_ref.id, meta.storage.as_ref()),
+                    fallback_len: Some(checked_rgba_buffer_len(layer.width, layer.height)?),
+                    type_name: layer_ref.layer_type.clone(),
+                });
             }
 
             layers.push(layer);
         }
 
+        let decoded: Vec<DecodedPixels> = pending
+            .into_par_iter()
+            .filter_map(PendingPixels::decode)
+            .collect();
+
+        // A layer whose meta.json size disagrees with its pixels would break
+        // every buffer index into it, so trust the pixels
+        let mut layer_pixels = HashMap::new();
+        for entry in decoded {
+            if let Some((width, height)) = entry.size {
+                if let Some(layer) = layers.iter_mut().find(|l| l.id == entry.layer_id) {
+                    if (layer.width, layer.height) != (width, height) {
+                        log::warn!(
+                            "Layer {} is recorded as {}x{} but its pixels are {}x{}; using the pixel size",
+                            layer.id,
+                            layer.width,
+                            layer.height,
+                            width,
+                            height
+                        );
+                        layer.width = width;
+                        layer.height = height;
+                    }
+                }
+            }
+            layer_pixels.insert(entry.layer_id, entry.pixels);
+        }
+
         // Build Document
         let document = Document {
             id: drkr_doc.id,
             name: drkr_doc.name,
             width: drkr_doc.width,
             height: drkr_doc.height,
-            resolution: drkr_doc.resolution.map(|r| r.value).unwrap_or(72),
+            resolution: drkr_doc.resolution.as_ref().map_or(72, |r| r.to_ppi()),
             layers,
             created_at: chrono::Utc::now().timestamp_millis(),
             modified_at: chrono::Utc::now().timestamp_millis(),
             source_path: None, // Will be set by the caller
+            background: drkr_doc
+                .background
+                .as_ref()
+                .map_or(Background::Transparent, DrkrBackground::to_background),
+            isolated_layer: None,
+            linear_blending: drkr_doc.color.blending.as_deref() == Some("linear"),
         };
 
+        let extensions = self.read_extensions();
+
         Ok(DrkrReadResult {
             document,
             layer_pixels,
+            extensions,
         })
     }
 
+    /// Salvage what can be read from a damaged archive.
+    ///
+    /// Unlike `read_all`, this ignores the mimetype, manifest and
+    /// `document.json` and rebuilds the document from the layer folders it
+    /// finds, in archive order. The canvas is sized to fit every layer.
+    /// Layers whose metadata doesn't parse are skipped, and layers whose
+    /// pixels don't decode come back transparent.
+    pub fn recover(&mut self, name: &str) -> AppResult<DrkrReadResult> {
+        let mut layer_ids = Vec::new();
+        for index in 0..self.archive.len() {
+            let Ok(file) = self.archive.by_index(index) else {
+                continue;
+            };
+            let layer_id = file
+                .name()
+                .strip_prefix("layers/")
+                .and_then(|rest| rest.strip_suffix("/meta.json"));
+            if let Some(layer_id) = layer_id {
+                layer_ids.push(layer_id.to_string());
+            }
+        }
+
+        let mut layers = Vec::new();
+        let mut layer_pixels = HashMap::new();
+
+        for layer_id in layer_ids {
+            let meta = match self.read_layer_meta(&layer_id) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    log::warn!("Skipping unrecoverable layer {}: {}", layer_id, e);
+                    continue;
+                }
+            };
+            let layer = meta.to_layer();
+
+            // Pixels that don't match the recorded size would break compositing
+            let expected_len = checked_rgba_buffer_len(layer.width, layer.height)?;
+            let pixels = self
+                .read_layer_pixels(&layer_id, meta.storage.as_ref())
+                .and_then(|pixels| {
+                    if pixels.len() == expected_len {
+                        Ok(pixels)
+                    } else {
+                        Err(AppError::InvalidOperation(
+                            "Pixel data has the wrong size".into(),
+                        ))
+                    }
+                });
+
+            match pixels {
+                Ok(pixels) => {
+                    layer_pixels.insert(layer.id.clone(), pixels);
+                }
+                Err(e) => {
+                    log::warn!("Failed to recover pixels for layer {}: {}", layer_id, e);
+                    if !matches!(layer.layer_type, LayerType::Group | LayerType::Adjustment) {
+                        layer_pixels.insert(layer.id.clone(), vec![0u8; expected_len]);
+                    }
+                }
+            }
+
+            layers.push(layer);
+        }
+
+        if layers.is_empty() {
+            return Err(AppError::InvalidOperation(
+                "No layers could be recovered from the file".into(),
+            ));
+        }
+
+        let width = layers
+            .iter()
+            .map(|layer| (layer.x.max(0) as u32).saturating_add(layer.width))
+            .max()
+            .unwrap_or(1)
+            .max(1);
+        let height = layers
+            .iter()
+            .map(|layer| (layer.y.max(0) as u32).saturating_add(layer.height))
+            .max()
+            .unwrap_or(1)
+            .max(1);
+
+        let mut document = Document::new(name, width, height, 72);
+        document.layers = layers;
+        document.background = Background::Transparent;
+
+        let extensions = self.read_extensions();
+
+        Ok(DrkrReadResult {
+            document,
+            layer_pixels,
+            extensions,
+        })
+    }
+
+    /// Names of the extensions in the archive: those listed in the manifest
+    /// plus any with files under `extensions/`
+    pub fn extension_names(&mut self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .read_manifest()
+            .ok()
+            .and_then(|manifest| manifest.extensions_used)
+            .unwrap_or_default();
+
+        for (name, _) in self.extension_file_paths() {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+
+        names
+    }
+
+    /// Read one file stored under `extensions/{name}/`
+    pub fn read_extension_file(&mut self, name: &str, path: &str) -> AppResult<Vec<u8>> {
+        self.read_file_as_bytes(&format!("extensions/{}/{}", name, path))
+    }
+
+    /// Read every extension's files.
+    ///
+    /// Extension data is optional to the document, so names or paths the
+    /// writer wouldn't accept, and files that can't be read, are skipped with a
+    /// warning rather than failing the whole read.
+    pub fn read_extensions(&mut self) -> ExtensionFiles {
+        let mut extensions = ExtensionFiles::new();
+        for name in self.extension_names() {
+            match validate_extension_path(&name, "") {
+                Ok(()) => {
+                    extensions.insert(name, Default::default());
+                }
+                Err(e) => log::warn!("Skipping extension data: {}", e),
+            }
+        }
+
+        for (name, path) in self.extension_file_paths() {
+            if !extensions.contains_key(&name) {
+                continue;
+            }
+            let data = validate_extension_path(&name, &path)
+                .and_then(|()| self.read_extension_file(&name, &path));
+            match data {
+                Ok(data) => {
+                    extensions.entry(name).or_default().insert(path, data);
+                }
+                Err(e) => log::warn!("Skipping extension file '{}/{}': {}", name, path, e),
+            }
+        }
+
+        extensions
+    }
+
+    /// `(extension name, path)` of every file under `extensions/`
+    fn extension_file_paths(&self) -> Vec<(String, String)> {
+        self.archive
+            .file_names()
+            .filter_map(|file| {
+                let (name, path) = file.strip_prefix("extensions/")?.split_once('/')?;
+                (!name.is_empty() && !path.is_empty() && !path.ends_with('/'))
+                    .then(|| (name.to_string(), path.to_string()))
+            })
+            .collect()
+    }
+
     /// Read a file from the archive as a string
     fn read_file_as_string(&mut self, name: &str) -> AppResult<String> {
         let mut file = self
@@ -188,16 +463,243 @@ impl<R: Read + std::io::Seek> DrkrReader<R> {
     }
 }
 
-/// Decode WebP data to RGBA pixels
-fn decode_webp_to_rgba(webp_data: &[u8]) -> AppResult<Vec<u8>> {
-    let cursor = Cursor::new(webp_data);
+/// Decode WebP or PNG data to RGBA pixels
+pub fn decode_to_rgba(data: &[u8]) -> AppResult<Vec<u8>> {
+    decode_image(data).map(RgbaImage::into_raw)
+}
+
+/// Decode an image file to RGBA, keeping its dimensions
+fn decode_image(data: &[u8]) -> AppResult<RgbaImage> {
+    let cursor = Cursor::new(data);
     let reader = ImageReader::new(cursor)
         .with_guessed_format()
         .map_err(|e| AppError::IoError(format!("Failed to detect image format: {}", e)))?;
 
     let img = reader
         .decode()
-        .map_err(|e| AppError::IoError(format!("Failed to decode WebP: {}", e)))?;
+        .map_err(|e| AppError::IoError(format!("Failed to decode image: {}", e)))?;
+
+    Ok(img.to_rgba8())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::drkr::DrkrWriter;
+    use std::io::{Cursor, Write};
+    use zip::write::{FileOptions, ZipWriter};
+
+    /// A one-layer document saved with a valid extension, then with `extra`
+    /// files appended to the archive behind the writer's back
+    fn archive_with(extra: &[&str]) -> Vec<u8> {
+        let doc = Document::new("Test", 2, 2, 72);
+        let pixels = HashMap::from([(doc.layers[0].id.clone(), vec![0u8; 16])]);
+        let mut writer = DrkrWriter::new(Cursor::new(Vec::new()));
+        writer
+            .add_extension_file("good-ext", "data.json", b"{}".to_vec())
+            .unwrap();
+        writer.write_document(&doc, &pixels).unwrap();
+        let mut bytes = writer.finish().unwrap();
+
+        bytes.set_position(0);
+        let mut zip = ZipWriter::new_append(bytes).unwrap();
+        for name in extra {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(b"x").unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn extensions_the_writer_would_reject_are_skipped_on_read() {
+        let bytes = archive_with(&["extensions/bad name/a.json", "extensions/good-ext/a\\b"]);
+        let result = DrkrReader::new(Cursor::new(bytes))
+            .unwrap()
+            .read_all()
+            .unwrap();
+
+        let names: Vec<&String> = result.extensions.keys().collect();
+        assert_eq!(names, ["good-ext"]);
+        let files: Vec<&String> = result.extensions["good-ext"].keys().collect();
+        assert_eq!(files, ["data.json"]);
+
+        // What was read can be written again
+        let mut writer = DrkrWriter::new(Cursor::new(Vec::new()));
+        writer.add_extensions(result.extensions).unwrap();
+    }
 
-    Ok(img.to_rgba8().into_raw())
+    #[test]
+    fn unknown_layer_types_survive_a_save_and_reload() {
+        let mut doc = Document::new("Test", 2, 2, 72);
+        doc.layers[0].layer_type = LayerType::Unknown("vector".into());
+        let layer_id = doc.layers[0].id.clone();
+        let pixels: Vec<u8> = (0..16).map(|i| i * 16).collect();
+        let mut layer_pixels = HashMap::from([(layer_id.clone(), pixels.clone())]);
+
+        for _ in 0..2 {
+            let mut writer = DrkrWriter::new(Cursor::new(Vec::new()))
+                .with_pixel_format(crate::io::drkr::PixelFormat::Png);
+            writer.write_document(&doc, &layer_pixels).unwrap();
+            let bytes = writer.finish().unwrap().into_inner();
+
+            let result = DrkrReader::new(Cursor::new(bytes))
+                .unwrap()
+                .read_all()
+                .unwrap();
+            doc = result.document;
+            layer_pixels = result.layer_pixels;
+            assert!(
+                matches!(&doc.layers[0].layer_type, LayerType::Unknown(name) if name == "vector")
+            );
+            assert_eq!(layer_pixels[&layer_id], pixels);
+        }
+    }
+
+    #[test]
+    fn layers_are_recovered_when_the_document_json_is_corrupt() {
+        let mut doc = Document::new("Test", 2, 2, 72);
+        let mut wide = crate::engine::Layer::new_raster("Wide", 5, 3);
+        wide.x = 1;
+        doc.layers.push(wide);
+        let mut broken = crate::engine::Layer::new_raster("Broken", 2, 2);
+        broken.y = 4;
+        doc.layers.push(broken);
+
+        let pixels: Vec<u8> = (0..16).collect();
+        let layer_pixels = HashMap::from([
+            (doc.layers[0].id.clone(), pixels.clone()),
+            (doc.layers[1].id.clone(), vec![200u8; 60]),
+            (doc.layers[2].id.clone(), vec![9u8; 16]),
+        ]);
+        let mut writer = DrkrWriter::new(Cursor::new(Vec::new()));
+        writer.write_document(&doc, &layer_pixels).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        // Copy the archive, garbling document.json and the last layer's pixels
+        let broken_dir = format!("layers/{}/", doc.layers[2].id);
+        let mut original = ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for index in 0..original.len() {
+            let mut file = original.by_index(index).unwrap();
+            let name = file.name().to_string();
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+            if name == "document.json"
+                || (name.starts_with(&broken_dir) && !name.ends_with("meta.json"))
+            {
+                data = b"{ not json".to_vec();
+            }
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(&data).unwrap();
+        }
+        let damaged = zip.finish().unwrap().into_inner();
+
+        let mut reader = DrkrReader::new(Cursor::new(damaged)).unwrap();
+        assert!(reader.read_all().is_err());
+
+        let result = reader.recover("Recovered").unwrap();
+        let ids: Vec<&String> = result.document.layers.iter().map(|l| &l.id).collect();
+        let expected: Vec<&String> = doc.layers.iter().map(|l| &l.id).collect();
+        assert_eq!(ids, expected);
+        assert_eq!(result.document.name, "Recovered");
+        // Sized to fit the offset layers: 1 + 5 wide, 4 + 2 tall
+        assert_eq!((result.document.width, result.document.height), (6, 6));
+
+        assert_eq!(result.layer_pixels[&doc.layers[0].id], pixels);
+        assert_eq!(result.layer_pixels[&doc.layers[1].id], vec![200u8; 60]);
+        assert_eq!(result.layer_pixels[&doc.layers[2].id], vec![0u8; 16]);
+    }
+
+    /// Copy an archive entry by entry, passing each file's name and contents
+    /// through `edit`
+    fn rewrite_archive(bytes: Vec<u8>, edit: impl Fn(&str, Vec<u8>) -> Vec<u8>) -> Vec<u8> {
+        let mut original = ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for index in 0..original.len() {
+            let mut file = original.by_index(index).unwrap();
+            let name = file.name().to_string();
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+            zip.start_file(name.as_str(), FileOptions::default())
+                .unwrap();
+            zip.write_all(&edit(&name, data)).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn layers_decoded_in_parallel_keep_their_own_pixels() {
+        let mut doc = Document::new("Test", 3, 2, 72);
+        for i in 0..11 {
+            doc.layers.push(crate::engine::Layer::new_raster(
+                &format!("Layer {}", i),
+                3,
+                2,
+            ));
+        }
+        let layer_pixels: HashMap<String, Vec<u8>> = doc
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(i, layer)| (layer.id.clone(), vec![i as u8 * 20; 24]))
+            .collect();
+        let mut writer = DrkrWriter::new(Cursor::new(Vec::new()));
+        writer.write_document(&doc, &layer_pixels).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        // One layer's pixel file is damaged; the rest must still line up
+        let damaged = doc.layers[5].id.clone();
+        let bytes = rewrite_archive(bytes, |name, data| {
+            if name.starts_with(&format!("layers/{}/pixels", damaged)) {
+                b"garbage".to_vec()
+            } else {
+                data
+            }
+        });
+
+        let result = DrkrReader::new(Cursor::new(bytes))
+            .unwrap()
+            .read_all()
+            .unwrap();
+        assert_eq!(result.layer_pixels.len(), 12);
+        for (id, pixels) in &layer_pixels {
+            if *id == damaged {
+                assert_eq!(result.layer_pixels[id], vec![0u8; 24]);
+            } else {
+                assert_eq!(&result.layer_pixels[id], pixels);
+            }
+        }
+    }
+
+    #[test]
+    fn a_layer_sized_wrong_in_meta_json_takes_the_size_of_its_pixels() {
+        let doc = Document::new("Test", 4, 2, 72);
+        let layer_id = doc.layers[0].id.clone();
+        let pixels: Vec<u8> = (0..32).collect();
+        let mut writer = DrkrWriter::new(Cursor::new(Vec::new()));
+        writer
+            .write_document(&doc, &HashMap::from([(layer_id.clone(), pixels.clone())]))
+            .unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        // Hand-edited metadata claiming a 2x2 layer
+        let meta_path = format!("layers/{}/meta.json", layer_id);
+        let bytes = rewrite_archive(bytes, |name, data| {
+            if name != meta_path {
+                return data;
+            }
+            let mut meta: serde_json::Value = serde_json::from_slice(&data).unwrap();
+            assert_eq!(meta["size"]["width"], 4);
+            meta["size"] = serde_json::json!({ "width": 2, "height": 2 });
+            serde_json::to_vec(&meta).unwrap()
+        });
+
+        let result = DrkrReader::new(Cursor::new(bytes))
+            .unwrap()
+            .read_all()
+            .unwrap();
+        let layer = &result.document.layers[0];
+        assert_eq!((layer.width, layer.height), (4, 2));
+        assert_eq!(result.layer_pixels[&layer_id], pixels);
+    }
 }
@@ -66,12 +66,26 @@ pub struct DrkrResolution {
     pub unit: String,
 }
 
+impl DrkrResolution {
+    /// Pixels per inch, converting from pixels per centimeter when the unit
+    /// says so
+    pub fn to_ppi(&self) -> u32 {
+        match self.unit.as_str() {
+            "ppcm" => ((self.value as f64 * 2.54).round() as u32).max(1),
+            _ => self.value,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DrkrColorConfig {
     pub space: String,
     pub depth: u8,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub profile: Option<String>,
+    /// "linear" when layers are blended in linear light; sRGB otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blending: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -81,6 +95,26 @@ pub enum DrkrBackground {
     Color { color: String },
 }
 
+impl DrkrBackground {
+    pub fn from_background(background: &Background) -> Self {
+        match background {
+            Background::Transparent => DrkrBackground::Transparent,
+            Background::Color { color } => DrkrBackground::Color {
+                color: color_to_hex(*color),
+            },
+        }
+    }
+
+    pub fn to_background(&self) -> Background {
+        match self {
+            DrkrBackground::Transparent => Background::Transparent,
+            DrkrBackground::Color { color } => hex_to_color(color)
+                .map(|color| Background::Color { color })
+                .unwrap_or_default(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DrkrLayerRef {
     pub id: String,
@@ -124,10 +158,24 @@ pub struct DrkrLayerMeta {
     pub visible: bool,
     #[serde(default)]
     pub locked: bool,
+    #[serde(default)]
+    pub lock_pixels: bool,
+    #[serde(default)]
+    pub lock_position: bool,
+    #[serde(default)]
+    pub lock_alpha: bool,
+    /// Whole percent (0-100), kept for readers that predate `opacity_exact`
     #[serde(default = "default_opacity")]
     pub opacity: u8,
+    /// Exact opacity (0.0-1.0); takes precedence over `opacity` when present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opacity_exact: Option<f32>,
     #[serde(default = "default_blend_mode")]
     pub blend_mode: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color_label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blend_if: Option<BlendIf>,
     pub position: DrkrPosition,
     pub size: DrkrSize,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -140,6 +188,12 @@ pub struct DrkrLayerMeta {
     pub created_at: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub modified_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<DrkrTextContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shape: Option<DrkrShapeContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linked_path: Option<String>,
 }
 
 fn default_true() -> bool {
@@ -168,6 +222,7 @@ pub struct DrkrSize {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DrkrStorage {
+    /// Image format of the layer's pixel file ("webp" or "png")
     pub format: String,
     pub mode: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -176,6 +231,38 @@ pub struct DrkrStorage {
     pub tiles: Option<DrkrTileInfo>,
 }
 
+/// Image format used for layer pixel files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelFormat {
+    #[default]
+    Webp,
+    Png,
+}
+
+impl PixelFormat {
+    /// Name recorded in `DrkrStorage.format`, also used as the file extension
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PixelFormat::Webp => "webp",
+            PixelFormat::Png => "png",
+        }
+    }
+
+    /// Format named by a layer's storage info; files without one used WebP
+    pub fn from_storage(storage: Option<&DrkrStorage>) -> Self {
+        match storage.map(|storage| storage.format.as_str()) {
+            Some("png") => PixelFormat::Png,
+            _ => PixelFormat::Webp,
+        }
+    }
+
+    /// Path of a layer's pixel file inside the archive
+    pub fn layer_pixels_path(&self, layer_id: &str) -> String {
+        format!("layers/{}/pixels.{}", layer_id, self.as_str())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DrkrTileInfo {
     pub columns: u32,
@@ -186,30 +273,55 @@ pub struct DrkrTileInfo {
     pub empty_tiles: Option<Vec<String>>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrkrTextContent {
+    pub text: String,
+    pub font_family: String,
+    pub size: f32,
+    /// Hex color, `#RRGGBBAA`
+    pub color: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrkrShapeContent {
+    pub geometry: ShapeGeometry,
+    /// Hex color, `#RRGGBBAA`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fill: Option<String>,
+    /// Hex color, `#RRGGBBAA`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stroke: Option<String>,
+    #[serde(default)]
+    pub stroke_width: f32,
+}
+
 // ============================================================================
 // Conversion helpers
 // ============================================================================
 
-use crate::engine::layer::{BlendMode, Layer, LayerType};
-use crate::engine::Document;
+use crate::engine::layer::{
+    BlendIf, BlendMode, ColorLabel, Layer, LayerType, ShapeContent, ShapeGeometry, TextContent,
+};
+use crate::engine::{Background, Document};
+use crate::error::{AppError, AppResult};
 
 impl DrkrLayerMeta {
-    /// Convert from internal Layer type
-    pub fn from_layer(layer: &Layer) -> Self {
+    /// Convert from internal Layer type, recording pixels stored as `pixel_format`
+    pub fn from_layer(layer: &Layer, pixel_format: PixelFormat) -> Self {
         Self {
             id: layer.id.clone(),
-            layer_type: match layer.layer_type {
-                LayerType::Raster => "raster".to_string(),
-                LayerType::Adjustment => "adjustment".to_string(),
-                LayerType::Group => "group".to_string(),
-                LayerType::Text => "text".to_string(),
-                LayerType::Shape => "shape".to_string(),
-            },
+            layer_type: layer_type_to_string(&layer.layer_type),
             name: layer.name.clone(),
             visible: layer.visible,
             locked: layer.locked,
-            opacity: layer.opacity,
+            lock_pixels: layer.lock_pixels,
+            lock_position: layer.lock_position,
+            lock_alpha: layer.lock_alpha,
+            opacity: (layer.opacity * 100.0).round() as u8,
+            opacity_exact: Some(layer.opacity),
             blend_mode: blend_mode_to_string(&layer.blend_mode),
+            color_label: color_label_to_string(layer.color_label),
+            blend_if: layer.blend_if,
             position: DrkrPosition {
                 x: layer.x,
                 y: layer.y,
@@ -221,38 +333,83 @@ impl DrkrLayerMeta {
             mask_id: None,
             clipping_mask: false,
             storage: Some(DrkrStorage {
-                format: "webp".to_string(),
+                format: pixel_format.as_str().to_string(),
                 mode: "single".to_string(),
                 tile_size: None,
                 tiles: None,
             }),
-            created_at: None,
-            modified_at: None,
+            created_at: Some(timestamp_to_rfc3339(layer.created_at)),
+            modified_at: Some(timestamp_to_rfc3339(layer.modified_at)),
+            text: layer.text.as_ref().map(|text| DrkrTextContent {
+                text: text.text.clone(),
+                font_family: text.font_family.clone(),
+                size: text.size,
+                color: color_to_hex(text.color),
+            }),
+            shape: layer.shape.as_ref().map(|shape| DrkrShapeContent {
+                geometry: shape.shape.clone(),
+                fill: shape.fill.map(color_to_hex),
+                stroke: shape.stroke.map(color_to_hex),
+                stroke_width: shape.stroke_width,
+            }),
+            linked_path: layer.linked_path.clone(),
         }
     }
 
     /// Convert to internal Layer type
     pub fn to_layer(&self) -> Layer {
+        let now = chrono::Utc::now().timestamp_millis();
+        let created_at = self
+            .created_at
+            .as_deref()
+            .and_then(rfc3339_to_timestamp)
+            .unwrap_or(now);
+        let modified_at = self
+            .modified_at
+            .as_deref()
+            .and_then(rfc3339_to_timestamp)
+            .unwrap_or(created_at);
+
         Layer {
             id: self.id.clone(),
             name: self.name.clone(),
-            layer_type: match self.layer_type.as_str() {
-                "raster" => LayerType::Raster,
-                "adjustment" => LayerType::Adjustment,
-                "group" => LayerType::Group,
-                "text" => LayerType::Text,
-                "shape" => LayerType::Shape,
-                "ai_generated" => LayerType::Raster, // Treat as raster
-                _ => LayerType::Raster,
-            },
+            layer_type: string_to_layer_type(&self.layer_type),
             visible: self.visible,
             locked: self.locked,
-            opacity: self.opacity,
+            lock_pixels: self.lock_pixels,
+            lock_position: self.lock_position,
+            lock_alpha: self.lock_alpha,
+            opacity: self
+                .opacity_exact
+                .filter(|opacity| opacity.is_finite())
+                .unwrap_or(self.opacity as f32 / 100.0)
+                .clamp(0.0, 1.0),
             blend_mode: string_to_blend_mode(&self.blend_mode),
+            color_label: self
+                .color_label
+                .as_deref()
+                .map(string_to_color_label)
+                .unwrap_or_default(),
+            blend_if: self.blend_if.filter(|blend_if| !blend_if.is_noop()),
             x: self.position.x,
             y: self.position.y,
             width: self.size.width,
             height: self.size.height,
+            text: self.text.as_ref().map(|text| TextContent {
+                text: text.text.clone(),
+                font_family: text.font_family.clone(),
+                size: text.size,
+                color: hex_to_color(&text.color).unwrap_or([0, 0, 0, 255]),
+            }),
+            shape: self.shape.as_ref().map(|shape| ShapeContent {
+                shape: shape.geometry.clone(),
+                fill: shape.fill.as_deref().and_then(hex_to_color),
+                stroke: shape.stroke.as_deref().and_then(hex_to_color),
+                stroke_width: shape.stroke_width,
+            }),
+            linked_path: self.linked_path.clone(),
+            created_at,
+            modified_at,
         }
     }
 }
@@ -273,20 +430,15 @@ impl DrkrDocument {
                 space: "srgb".to_string(),
                 depth: 8,
                 profile: None,
+                blending: doc.linear_blending.then(|| "linear".to_string()),
             },
-            background: Some(DrkrBackground::Transparent),
+            background: Some(DrkrBackground::from_background(&doc.background)),
             layers: doc
                 .layers
                 .iter()
                 .map(|l| DrkrLayerRef {
                     id: l.id.clone(),
-                    layer_type: match l.layer_type {
-                        LayerType::Raster => "raster".to_string(),
-                        LayerType::Adjustment => "adjustment".to_string(),
-                        LayerType::Group => "group".to_string(),
-                        LayerType::Text => "text".to_string(),
-                        LayerType::Shape => "shape".to_string(),
-                    },
+                    layer_type: layer_type_to_string(&l.layer_type),
                     adjustment_id: None,
                     children: None,
                 })
@@ -297,6 +449,121 @@ impl DrkrDocument {
     }
 }
 
+/// Reject extension names and paths that could escape `extensions/{name}/`.
+/// An empty `path` only checks the name. Used when reading as well as writing,
+/// so every extension a file opens with can be saved again.
+pub(crate) fn validate_extension_path(name: &str, path: &str) -> AppResult<()> {
+    let valid_name = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid_name {
+        return Err(AppError::InvalidOperation(format!(
+            "Invalid extension name '{}'",
+            name
+        )));
+    }
+
+    let invalid_segment = |segment: &str| segment.is_empty() || segment == "." || segment == "..";
+    if !path.is_empty() && (path.contains('\\') || path.split('/').any(invalid_segment)) {
+        return Err(AppError::InvalidOperation(format!(
+            "Invalid extension file path '{}'",
+            path
+        )));
+    }
+
+    Ok(())
+}
+
+/// Format an RGBA color as `#RRGGBBAA`
+fn color_to_hex(color: [u8; 4]) -> String {
+    format!(
+        "#{:02x}{:02x}{:02x}{:02x}",
+        color[0], color[1], color[2], color[3]
+    )
+}
+
+/// Parse a `#RRGGBB` or `#RRGGBBAA` hex color
+fn hex_to_color(s: &str) -> Option<[u8; 4]> {
+    let hex = s.strip_prefix('#')?;
+    if hex.len() != 6 && hex.len() != 8 {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    let alpha = if hex.len() == 8 { channel(6)? } else { 255 };
+
+    Some([channel(0)?, channel(2)?, channel(4)?, alpha])
+}
+
+/// Format a millisecond Unix timestamp as RFC 3339
+fn timestamp_to_rfc3339(millis: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(millis)
+        .unwrap_or_default()
+        .to_rfc3339()
+}
+
+/// Parse an RFC 3339 timestamp into milliseconds since the Unix epoch
+fn rfc3339_to_timestamp(s: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc3339(s)
+        .ok()
+        .map(|dt| dt.timestamp_millis())
+}
+
+fn layer_type_to_string(layer_type: &LayerType) -> String {
+    match layer_type {
+        LayerType::Raster => "raster",
+        LayerType::Adjustment => "adjustment",
+        LayerType::Group => "group",
+        LayerType::Text => "text",
+        LayerType::Shape => "shape",
+        LayerType::LinkedImage => "linked_image",
+        LayerType::Unknown(name) => name,
+    }
+    .to_string()
+}
+
+fn string_to_layer_type(s: &str) -> LayerType {
+    match s {
+        "raster" => LayerType::Raster,
+        "adjustment" => LayerType::Adjustment,
+        "group" => LayerType::Group,
+        "text" => LayerType::Text,
+        "shape" => LayerType::Shape,
+        "linked_image" => LayerType::LinkedImage,
+        "ai_generated" => LayerType::Raster, // Treat as raster
+        other => LayerType::Unknown(other.to_string()),
+    }
+}
+
+fn color_label_to_string(label: ColorLabel) -> Option<String> {
+    let name = match label {
+        ColorLabel::None => return None,
+        ColorLabel::Red => "red",
+        ColorLabel::Orange => "orange",
+        ColorLabel::Yellow => "yellow",
+        ColorLabel::Green => "green",
+        ColorLabel::Blue => "blue",
+        ColorLabel::Violet => "violet",
+        ColorLabel::Gray => "gray",
+    };
+    Some(name.to_string())
+}
+
+fn string_to_color_label(s: &str) -> ColorLabel {
+    match s {
+        "red" => ColorLabel::Red,
+        "orange" => ColorLabel::Orange,
+        "yellow" => ColorLabel::Yellow,
+        "green" => ColorLabel::Green,
+        "blue" => ColorLabel::Blue,
+        "violet" => ColorLabel::Violet,
+        "gray" => ColorLabel::Gray,
+        _ => ColorLabel::None,
+    }
+}
+
 fn blend_mode_to_string(mode: &BlendMode) -> String {
     match mode {
         BlendMode::Normal => "normal",
@@ -340,3 +607,90 @@ fn string_to_blend_mode(s: &str) -> BlendMode {
         _ => BlendMode::Normal,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::layer::BlendIfRange;
+    use crate::engine::LayerUpdate;
+
+    #[test]
+    fn layer_timestamps_round_trip_through_meta_json() {
+        let mut layer = Layer::new_raster("Layer", 1, 1);
+        layer.created_at = 1_600_000_000_123;
+        layer.modified_at = 1_700_000_000_456;
+
+        let meta = DrkrLayerMeta::from_layer(&layer, PixelFormat::Png);
+        let json = serde_json::to_string(&meta).unwrap();
+        let restored = serde_json::from_str::<DrkrLayerMeta>(&json)
+            .unwrap()
+            .to_layer();
+        assert_eq!(restored.created_at, 1_600_000_000_123);
+        assert_eq!(restored.modified_at, 1_700_000_000_456);
+    }
+
+    #[test]
+    fn a_missing_modified_time_falls_back_to_the_creation_time() {
+        let layer = Layer::new_raster("Layer", 1, 1);
+        let mut meta = DrkrLayerMeta::from_layer(&layer, PixelFormat::Png);
+        meta.created_at = Some("2021-01-01T00:00:00Z".into());
+        meta.modified_at = None;
+
+        let restored = meta.to_layer();
+        assert_eq!(restored.created_at, 1_609_459_200_000);
+        assert_eq!(restored.modified_at, restored.created_at);
+    }
+
+    #[test]
+    fn color_labels_round_trip_and_unlabelled_layers_omit_them() {
+        let mut layer = Layer::new_raster("Layer", 1, 1);
+        let update: LayerUpdate = serde_json::from_str(r#"{"colorLabel": "violet"}"#).unwrap();
+        layer.apply_update(update).unwrap();
+
+        let meta = DrkrLayerMeta::from_layer(&layer, PixelFormat::Png);
+        assert_eq!(meta.color_label.as_deref(), Some("violet"));
+        assert_eq!(meta.to_layer().color_label, ColorLabel::Violet);
+
+        layer.color_label = ColorLabel::None;
+        let meta = DrkrLayerMeta::from_layer(&layer, PixelFormat::Png);
+        let json = serde_json::to_value(&meta).unwrap();
+        assert!(json.get("color_label").is_none() && json.get("colorLabel").is_none());
+
+        let mut meta: DrkrLayerMeta = serde_json::from_value(json).unwrap();
+        meta.color_label = Some("chartreuse".into());
+        assert_eq!(meta.to_layer().color_label, ColorLabel::None);
+    }
+
+    #[test]
+    fn blend_if_ranges_round_trip_through_meta_json() {
+        let mut layer = Layer::new_raster("Layer", 1, 1);
+        let blend_if = BlendIf {
+            this_layer: BlendIfRange {
+                black_low: 10,
+                black_high: 40,
+                white_low: 200,
+                white_high: 250,
+            },
+            underlying: BlendIfRange::default(),
+        };
+        layer.blend_if = Some(blend_if);
+
+        let meta = DrkrLayerMeta::from_layer(&layer, PixelFormat::Png);
+        let json = serde_json::to_string(&meta).unwrap();
+        let restored = serde_json::from_str::<DrkrLayerMeta>(&json)
+            .unwrap()
+            .to_layer();
+        assert_eq!(restored.blend_if, Some(blend_if));
+    }
+
+    #[test]
+    fn resolutions_in_pixels_per_centimeter_convert_to_ppi() {
+        let resolution = |value: u32, unit: &str| DrkrResolution {
+            value,
+            unit: unit.into(),
+        };
+        assert_eq!(resolution(118, "ppcm").to_ppi(), 300);
+        assert_eq!(resolution(300, "ppi").to_ppi(), 300);
+        assert_eq!(resolution(0, "ppcm").to_ppi(), 1);
+    }
+}
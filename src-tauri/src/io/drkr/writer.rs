@@ -1,5 +1,5 @@
 use super::types::*;
-use crate::engine::Document;
+use crate::engine::{composite_layers, resize_premultiplied, Document, ExtensionFiles};
 use crate::error::{AppError, AppResult};
 use image::{DynamicImage, ImageFormat, RgbaImage};
 use std::collections::HashMap;
@@ -12,6 +12,8 @@ use zip::CompressionMethod;
 /// Writer for DRKR format files
 pub struct DrkrWriter<W: Write + std::io::Seek> {
     zip: ZipWriter<W>,
+    pixel_format: PixelFormat,
+    extensions: ExtensionFiles,
 }
 
 impl DrkrWriter<BufWriter<File>> {
@@ -19,27 +21,71 @@ impl DrkrWriter<BufWriter<File>> {
     pub fn create<P: AsRef<Path>>(path: P) -> AppResult<Self> {
         let file = File::create(path).map_err(|e| AppError::IoError(e.to_string()))?;
         let writer = BufWriter::new(file);
-        Ok(Self {
-            zip: ZipWriter::new(writer),
-        })
+        Ok(Self::new(writer))
     }
 }
 
 impl<W: Write + std::io::Seek> DrkrWriter<W> {
     /// Create a new DRKR writer from a writer
-    #[allow(dead_code)]
     pub fn new(writer: W) -> Self {
         Self {
             zip: ZipWriter::new(writer),
+            pixel_format: PixelFormat::default(),
+            extensions: ExtensionFiles::new(),
+        }
+    }
+
+    /// Store layer pixels as `pixel_format` instead of the default WebP
+    pub fn with_pixel_format(mut self, pixel_format: PixelFormat) -> Self {
+        self.pixel_format = pixel_format;
+        self
+    }
+
+    /// Queue a file to store as `extensions/{name}/{path}` and record `name` in
+    /// the manifest's `extensions_used`. Must be called before writing the document.
+    pub fn add_extension_file(&mut self, name: &str, path: &str, data: Vec<u8>) -> AppResult<()> {
+        validate_extension_path(name, path)?;
+        self.extensions
+            .entry(name.to_string())
+            .or_default()
+            .insert(path.to_string(), data);
+        Ok(())
+    }
+
+    /// Queue every file of a set of extensions, keeping extensions that have
+    /// no files listed in the manifest
+    pub fn add_extensions(&mut self, extensions: ExtensionFiles) -> AppResult<()> {
+        for (name, files) in extensions {
+            validate_extension_path(&name, "")?;
+            self.extensions.entry(name.clone()).or_default();
+            for (path, data) in files {
+                self.add_extension_file(&name, &path, data)?;
+            }
         }
+        Ok(())
     }
 
     /// Write a complete document to the DRKR file
+    #[allow(dead_code)]
     pub fn write_document(
         &mut self,
         doc: &Document,
         layer_pixels: &HashMap<String, Vec<u8>>,
     ) -> AppResult<()> {
+        // Composite all visible layers once for both previews
+        let merged = composite_layers(doc, layer_pixels)?;
+        self.write_document_with_composite(doc, layer_pixels, merged)
+    }
+
+    /// Write a complete document using an already composited image for the previews
+    pub fn write_document_with_composite(
+        &mut self,
+        doc: &Document,
+        layer_pixels: &HashMap<String, Vec<u8>>,
+        merged: RgbaImage,
+    ) -> AppResult<()> {
+        let merged = DynamicImage::ImageRgba8(merged);
+
         // 1. Write mimetype (MUST be first, uncompressed)
         self.write_mimetype()?;
 
@@ -50,10 +96,10 @@ impl<W: Write + std::io::Seek> DrkrWriter<W> {
         self.write_document_json(doc)?;
 
         // 4. Write thumbnail
-        self.write_thumbnail(doc, layer_pixels)?;
+        self.write_thumbnail(&merged)?;
 
         // 5. Write merged preview
-        self.write_merged_preview(doc, layer_pixels)?;
+        self.write_merged_preview(&merged)?;
 
         // 6. Write layers
         for layer in &doc.layers {
@@ -62,6 +108,9 @@ impl<W: Write + std::io::Seek> DrkrWriter<W> {
             }
         }
 
+        // 7. Write extension files
+        self.write_extensions()?;
+
         Ok(())
     }
 
@@ -96,7 +145,8 @@ impl<W: Write + std::io::Seek> DrkrWriter<W> {
             created_at: now.clone(),
             modified_at: now,
             files: None, // Optional file offset table
-            extensions_used: None,
+            extensions_used: (!self.extensions.is_empty())
+                .then(|| self.extensions.keys().cloned().collect()),
         };
 
         let json = serde_json::to_string_pretty(&manifest)
@@ -137,7 +187,7 @@ impl<W: Write + std::io::Seek> DrkrWriter<W> {
         let layer_dir = format!("layers/{}", layer.id);
 
         // Write meta.json
-        let meta = DrkrLayerMeta::from_layer(layer);
+        let meta = DrkrLayerMeta::from_layer(layer, self.pixel_format);
         let meta_json = serde_json::to_string_pretty(&meta)
             .map_err(|e| AppError::SerializationError(e.to_string()))?;
 
@@ -149,29 +199,38 @@ impl<W: Write + std::io::Seek> DrkrWriter<W> {
             .write_all(meta_json.as_bytes())
             .map_err(|e| AppError::IoError(e.to_string()))?;
 
-        // Write pixels.webp
-        let webp_data = encode_rgba_to_webp(pixels, layer.width, layer.height)?;
+        // Write pixels.webp / pixels.png
+        let pixel_data = encode_rgba(pixels, layer.width, layer.height, self.pixel_format)?;
 
         self.zip
-            .start_file(format!("{}/pixels.webp", layer_dir), options)
+            .start_file(self.pixel_format.layer_pixels_path(&layer.id), options)
             .map_err(|e| AppError::IoError(e.to_string()))?;
         self.zip
-            .write_all(&webp_data)
+            .write_all(&pixel_data)
             .map_err(|e| AppError::IoError(e.to_string()))?;
 
         Ok(())
     }
 
-    fn write_thumbnail(
-        &mut self,
-        doc: &Document,
-        layer_pixels: &HashMap<String, Vec<u8>>,
-    ) -> AppResult<()> {
-        // Composite all visible layers and scale to max 256x256
-        let merged = composite_layers(doc, layer_pixels)?;
+    fn write_extensions(&mut self) -> AppResult<()> {
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        for (name, files) in &self.extensions {
+            for (path, data) in files {
+                self.zip
+                    .start_file(format!("extensions/{}/{}", name, path), options)
+                    .map_err(|e| AppError::IoError(e.to_string()))?;
+                self.zip
+                    .write_all(data)
+                    .map_err(|e| AppError::IoError(e.to_string()))?;
+            }
+        }
 
+        Ok(())
+    }
+
+    fn write_thumbnail(&mut self, merged: &DynamicImage) -> AppResult<()> {
         // Scale to thumbnail size (max 256x256)
-        let thumbnail = scale_to_fit(&merged, 256, 256);
+        let thumbnail = scale_to_fit(merged, 256, 256);
 
         // Encode as WebP
         let webp_data = encode_dynamic_image_to_webp(&thumbnail)?;
@@ -187,16 +246,9 @@ impl<W: Write + std::io::Seek> DrkrWriter<W> {
         Ok(())
     }
 
-    fn write_merged_preview(
-        &mut self,
-        doc: &Document,
-        layer_pixels: &HashMap<String, Vec<u8>>,
-    ) -> AppResult<()> {
-        // Composite all visible layers at full resolution
-        let merged = composite_layers(doc, layer_pixels)?;
-
-        // Encode as WebP
-        let webp_data = encode_dynamic_image_to_webp(&merged)?;
+    fn write_merged_preview(&mut self, merged: &DynamicImage) -> AppResult<()> {
+        // Encode the full resolution composite as WebP
+        let webp_data = encode_dynamic_image_to_webp(merged)?;
 
         let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
         self.zip
@@ -210,13 +262,42 @@ impl<W: Write + std::io::Seek> DrkrWriter<W> {
     }
 }
 
-/// Encode RGBA pixels to WebP format
-fn encode_rgba_to_webp(pixels: &[u8], width: u32, height: u32) -> AppResult<Vec<u8>> {
+/// Encode RGBA pixels as a layer pixel file
+pub fn encode_rgba(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    pixel_format: PixelFormat,
+) -> AppResult<Vec<u8>> {
     let img = RgbaImage::from_raw(width, height, pixels.to_vec())
         .ok_or_else(|| AppError::InvalidOperation("Invalid pixel data dimensions".into()))?;
 
     let dynamic = DynamicImage::ImageRgba8(img);
-    encode_dynamic_image_to_webp(&dynamic)
+    match pixel_format {
+        PixelFormat::Webp => encode_dynamic_image_to_webp(&dynamic),
+        PixelFormat::Png => {
+            let mut cursor = Cursor::new(Vec::new());
+            dynamic
+                .write_to(&mut cursor, ImageFormat::Png)
+                .map_err(|e| AppError::IoError(format!("Failed to encode PNG: {}", e)))?;
+            Ok(cursor.into_inner())
+        }
+    }
+}
+
+/// Encode a downscaled preview of RGBA pixels as WebP, fitting within
+/// `max_size` on both sides
+pub fn encode_thumbnail_webp(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    max_size: u32,
+) -> AppResult<Vec<u8>> {
+    let img = RgbaImage::from_raw(width, height, pixels.to_vec())
+        .ok_or_else(|| AppError::InvalidOperation("Invalid pixel data dimensions".into()))?;
+
+    let thumbnail = scale_to_fit(&DynamicImage::ImageRgba8(img), max_size, max_size);
+    encode_dynamic_image_to_webp(&thumbnail)
 }
 
 /// Encode a DynamicImage to WebP
@@ -227,68 +308,6 @@ fn encode_dynamic_image_to_webp(img: &DynamicImage) -> AppResult<Vec<u8>> {
     Ok(cursor.into_inner())
 }
 
-/// Composite all visible layers into a single image
-fn composite_layers(
-    doc: &Document,
-    layer_pixels: &HashMap<String, Vec<u8>>,
-) -> AppResult<DynamicImage> {
-    // Create transparent base image
-    let mut result = RgbaImage::new(doc.width, doc.height);
-
-    // Composite layers from bottom to top
-    for layer in &doc.layers {
-        if !layer.visible {
-            continue;
-        }
-
-        if let Some(pixels) = layer_pixels.get(&layer.id) {
-            let layer_img = RgbaImage::from_raw(layer.width, layer.height, pixels.clone())
-                .ok_or_else(|| {
-                    AppError::InvalidOperation("Invalid layer pixel data".into())
-                })?;
-
-            // Simple alpha compositing
-            for y in 0..layer.height {
-                for x in 0..layer.width {
-                    let dst_x = (layer.x + x as i32) as u32;
-                    let dst_y = (layer.y + y as i32) as u32;
-
-                    if dst_x < doc.width && dst_y < doc.height {
-                        let src_pixel = layer_img.get_pixel(x, y);
-                        let dst_pixel = result.get_pixel_mut(dst_x, dst_y);
-
-                        // Apply layer opacity
-                        let src_alpha =
-                            (src_pixel[3] as u32 * layer.opacity as u32 / 100) as u8;
-
-                        if src_alpha == 255 {
-                            *dst_pixel = *src_pixel;
-                        } else if src_alpha > 0 {
-                            // Alpha blend
-                            let src_a = src_alpha as f32 / 255.0;
-                            let dst_a = dst_pixel[3] as f32 / 255.0;
-                            let out_a = src_a + dst_a * (1.0 - src_a);
-
-                            if out_a > 0.0 {
-                                for c in 0..3 {
-                                    let src_c = src_pixel[c] as f32;
-                                    let dst_c = dst_pixel[c] as f32;
-                                    let out_c = (src_c * src_a + dst_c * dst_a * (1.0 - src_a))
-                                        / out_a;
-                                    dst_pixel[c] = out_c.min(255.0) as u8;
-                                }
-                                dst_pixel[3] = (out_a * 255.0) as u8;
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    Ok(DynamicImage::ImageRgba8(result))
-}
-
 /// Scale image to fit within max dimensions while preserving aspect ratio
 fn scale_to_fit(img: &DynamicImage, max_width: u32, max_height: u32) -> DynamicImage {
     let (width, height) = (img.width(), img.height());
@@ -301,8 +320,89 @@ fn scale_to_fit(img: &DynamicImage, max_width: u32, max_height: u32) -> DynamicI
     let scale_y = max_height as f32 / height as f32;
     let scale = scale_x.min(scale_y);
 
-    let new_width = (width as f32 * scale) as u32;
-    let new_height = (height as f32 * scale) as u32;
+    let new_width = ((width as f32 * scale) as u32).max(1);
+    let new_height = ((height as f32 * scale) as u32).max(1);
+
+    DynamicImage::ImageRgba8(resize_premultiplied(&img.to_rgba8(), new_width, new_height))
+}
 
-    img.resize(new_width, new_height, image::imageops::FilterType::Lanczos3)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn previews_keep_clean_colors_next_to_transparency() {
+        let logo = RgbaImage::from_fn(64, 32, |x, _| {
+            if x < 32 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        });
+
+        let preview = scale_to_fit(&DynamicImage::ImageRgba8(logo), 16, 16).to_rgba8();
+        assert_eq!(preview.dimensions(), (16, 8));
+        for pixel in preview.pixels().filter(|p| p[3] > 0) {
+            assert_eq!(pixel.0[..3], [255, 0, 0], "fringe pixel {:?}", pixel);
+        }
+        assert!(preview.pixels().any(|p| p[3] > 0 && p[3] < 255));
+    }
+
+    #[test]
+    fn images_that_already_fit_are_not_scaled() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(10, 5));
+        assert_eq!(scale_to_fit(&img, 16, 16).width(), 10);
+    }
+
+    #[test]
+    fn layer_thumbnails_fit_the_requested_size() {
+        let pixels = RgbaImage::from_pixel(40, 10, Rgba([0, 0, 255, 255])).into_raw();
+
+        let webp = encode_thumbnail_webp(&pixels, 40, 10, 8).unwrap();
+        let preview = image::load_from_memory_with_format(&webp, image::ImageFormat::WebP)
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(preview.dimensions(), (8, 2));
+        assert_eq!(preview.get_pixel(4, 1).0, [0, 0, 255, 255]);
+
+        assert!(encode_thumbnail_webp(&pixels, 41, 10, 8).is_err());
+    }
+
+    #[test]
+    fn png_storage_is_recorded_and_reads_back_exactly() {
+        use std::io::Read;
+
+        let doc = Document::new("Test", 2, 2, 72);
+        let layer_id = doc.layers[0].id.clone();
+        let pixels: Vec<u8> = (0..16).map(|i| i * 13 + 1).collect();
+        let layer_pixels = HashMap::from([(layer_id.clone(), pixels.clone())]);
+
+        let mut writer =
+            DrkrWriter::new(Cursor::new(Vec::new())).with_pixel_format(PixelFormat::Png);
+        writer.write_document(&doc, &layer_pixels).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes.clone())).unwrap();
+        let mut png = Vec::new();
+        archive
+            .by_name(&PixelFormat::Png.layer_pixels_path(&layer_id))
+            .unwrap()
+            .read_to_end(&mut png)
+            .unwrap();
+        assert_eq!(&png[..4], b"\x89PNG");
+        let meta: DrkrLayerMeta = serde_json::from_reader(
+            archive
+                .by_name(&format!("layers/{}/meta.json", layer_id))
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(meta.storage.unwrap().format, "png");
+
+        let result = crate::io::drkr::DrkrReader::new(Cursor::new(bytes))
+            .unwrap()
+            .read_all()
+            .unwrap();
+        assert_eq!(result.layer_pixels[&layer_id], pixels);
+    }
 }
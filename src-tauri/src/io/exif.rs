@@ -0,0 +1,157 @@
+use crate::error::{AppError, AppResult};
+use exif::{In, Reader, Tag};
+use image::DynamicImage;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek};
+use std::path::Path;
+
+/// Read the EXIF orientation (1-8) of an image file.
+///
+/// Returns None for files without EXIF, or malformed data, all of which mean
+/// "display as stored".
+pub fn read_exif_orientation<P: AsRef<Path>>(path: P) -> Option<u16> {
+    let file = File::open(path).ok()?;
+    container_orientation(&mut BufReader::new(file))
+}
+
+/// Orientation from the EXIF of any container kamadak-exif understands
+/// (JPEG, TIFF, PNG, WebP, HEIF)
+fn container_orientation<R: BufRead + Seek>(reader: &mut R) -> Option<u16> {
+    let exif = Reader::new().read_from_container(reader).ok()?;
+    let orientation = exif
+        .get_field(Tag::Orientation, In::PRIMARY)?
+        .value
+        .get_uint(0)?;
+    u16::try_from(orientation)
+        .ok()
+        .filter(|o| (1..=8).contains(o))
+}
+
+/// Decode an image file, turning camera photos upright
+pub fn open_image_upright<P: AsRef<Path>>(path: P) -> AppResult<DynamicImage> {
+    let path = path.as_ref();
+    let img = image::open(path).map_err(|e| AppError::ImageError(e.to_string()))?;
+
+    Ok(match read_exif_orientation(path) {
+        Some(orientation) => apply_exif_orientation(img, orientation),
+        None => img,
+    })
+}
+
+/// Rotate/flip an image so it displays upright for the given EXIF orientation
+pub fn apply_exif_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// TIFF structure whose IFD0 holds just an orientation tag
+    fn tiff(little_endian: bool, orientation: u16) -> Vec<u8> {
+        let u16_bytes = |v: u16| {
+            if little_endian {
+                v.to_le_bytes()
+            } else {
+                v.to_be_bytes()
+            }
+        };
+        let u32_bytes = |v: u32| {
+            if little_endian {
+                v.to_le_bytes()
+            } else {
+                v.to_be_bytes()
+            }
+        };
+
+        let mut tiff = if little_endian {
+            b"II".to_vec()
+        } else {
+            b"MM".to_vec()
+        };
+        tiff.extend(u16_bytes(42));
+        tiff.extend(u32_bytes(8)); // IFD0 offset
+        tiff.extend(u16_bytes(1)); // entry count
+        tiff.extend(u16_bytes(0x0112)); // Orientation
+        tiff.extend(u16_bytes(3)); // SHORT
+        tiff.extend(u32_bytes(1)); // value count
+        tiff.extend(u16_bytes(orientation));
+        tiff.extend([0, 0]);
+        tiff.extend(u32_bytes(0)); // no next IFD
+        tiff
+    }
+
+    /// A JPEG holding `tiff` in an Exif APP1 segment and no image data
+    fn jpeg(tiff: &[u8]) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend((tiff.len() as u16 + 8).to_be_bytes());
+        jpeg.extend(b"Exif\0\0");
+        jpeg.extend(tiff);
+        jpeg.extend([0xFF, 0xD9]);
+        jpeg
+    }
+
+    fn orientation_of(bytes: &[u8]) -> Option<u16> {
+        container_orientation(&mut Cursor::new(bytes))
+    }
+
+    #[test]
+    fn reads_the_orientation_in_either_byte_order() {
+        assert_eq!(orientation_of(&jpeg(&tiff(true, 6))), Some(6));
+        assert_eq!(orientation_of(&jpeg(&tiff(false, 8))), Some(8));
+    }
+
+    #[test]
+    fn out_of_range_orientations_are_ignored() {
+        assert_eq!(orientation_of(&jpeg(&tiff(true, 0))), None);
+        assert_eq!(orientation_of(&jpeg(&tiff(true, 9))), None);
+    }
+
+    #[test]
+    fn truncated_files_are_ignored() {
+        let full = jpeg(&tiff(true, 6));
+        for len in 0..full.len() - 2 {
+            assert_eq!(orientation_of(&full[..len]), None, "truncated to {}", len);
+        }
+    }
+
+    #[test]
+    fn malformed_segments_and_ifds_are_ignored() {
+        // APP1 length running past the end of the file
+        let mut overlong = jpeg(&tiff(true, 6));
+        overlong[4..6].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(orientation_of(&overlong), None);
+
+        // APP1 length too short to hold its own length field
+        let mut underlong = jpeg(&tiff(true, 6));
+        underlong[4..6].copy_from_slice(&1u16.to_be_bytes());
+        assert_eq!(orientation_of(&underlong), None);
+
+        // IFD0 offset far outside the TIFF structure
+        let mut far_ifd = tiff(true, 6);
+        far_ifd[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(orientation_of(&jpeg(&far_ifd)), None);
+
+        // More IFD entries than there are bytes for
+        let mut many_entries = tiff(true, 6);
+        many_entries[8..10].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(orientation_of(&jpeg(&many_entries)), None);
+
+        // Not a TIFF header at all
+        let mut bad_magic = tiff(true, 6);
+        bad_magic[2] = 0;
+        assert_eq!(orientation_of(&jpeg(&bad_magic)), None);
+
+        assert_eq!(orientation_of(b"not an image"), None);
+    }
+}
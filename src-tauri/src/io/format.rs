@@ -0,0 +1,138 @@
+use super::drkr::DRKR_MIMETYPE;
+use crate::error::{AppError, AppResult};
+use serde::Serialize;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// How many leading bytes are inspected when sniffing a file
+const SNIFF_LEN: usize = 512;
+
+/// The importer a file should be opened with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FileFormat {
+    Drkr,
+    Psd,
+    Svg,
+    /// Anything the `image` crate can decode (PNG, JPEG, WebP, GIF, ...)
+    Raster,
+}
+
+/// Detect a file's format from its leading bytes, falling back to the extension.
+///
+/// DRKR archives store an uncompressed `mimetype` entry first, so the
+/// signature check only needs the first local file header.
+pub fn detect_format<P: AsRef<Path>>(path: P) -> AppResult<FileFormat> {
+    let path = path.as_ref();
+
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)
+        .and_then(|file| file.take(SNIFF_LEN as u64).read_to_end(&mut header))
+        .map_err(|e| AppError::IoError(format!("Failed to read '{}': {}", path.display(), e)))?;
+
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    if is_drkr(&header) {
+        return Ok(FileFormat::Drkr);
+    }
+    if header.starts_with(b"8BPS") {
+        return Ok(FileFormat::Psd);
+    }
+    if is_svg(&header) {
+        return Ok(FileFormat::Svg);
+    }
+
+    // A ZIP without the mimetype entry first may still be a DRKR from a lax writer
+    Ok(match extension.as_deref() {
+        Some("drkr") if header.starts_with(b"PK\x03\x04") => FileFormat::Drkr,
+        Some("svg") => FileFormat::Svg,
+        _ => FileFormat::Raster,
+    })
+}
+
+/// ZIP local header whose first entry is `mimetype` holding the DRKR mimetype
+fn is_drkr(header: &[u8]) -> bool {
+    const NAME_OFFSET: usize = 30;
+    let name = b"mimetype";
+
+    if !header.starts_with(b"PK\x03\x04") || header.len() < NAME_OFFSET {
+        return false;
+    }
+
+    let name_len = u16::from_le_bytes([header[26], header[27]]) as usize;
+    let extra_len = u16::from_le_bytes([header[28], header[29]]) as usize;
+    let content_start = NAME_OFFSET + name_len + extra_len;
+
+    name_len == name.len()
+        && header.get(NAME_OFFSET..NAME_OFFSET + name_len) == Some(&name[..])
+        && header.get(content_start..content_start + DRKR_MIMETYPE.len())
+            == Some(DRKR_MIMETYPE.as_bytes())
+}
+
+/// XML text whose root element is `<svg`
+fn is_svg(header: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(header);
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+
+    text.starts_with("<svg") || (text.starts_with("<?xml") && text.contains("<svg"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Document;
+    use crate::io::drkr::DrkrWriter;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    /// Detect the format of `bytes` saved under a file named `name`
+    fn detect(name: &str, bytes: &[u8]) -> AppResult<FileFormat> {
+        let path =
+            std::env::temp_dir().join(format!("drkr-format-{}-{}", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        let format = detect_format(&path);
+        std::fs::remove_file(&path).unwrap();
+        format
+    }
+
+    #[test]
+    fn contents_win_over_a_misleading_extension() {
+        let doc = Document::new("Test", 1, 1, 72);
+        let pixels = HashMap::from([(doc.layers[0].id.clone(), vec![0u8; 4])]);
+        let mut writer = DrkrWriter::new(Cursor::new(Vec::new()));
+        writer.write_document(&doc, &pixels).unwrap();
+        let drkr = writer.finish().unwrap().into_inner();
+
+        assert_eq!(detect("a.png", &drkr).unwrap(), FileFormat::Drkr);
+        assert_eq!(detect("b.png", b"8BPS\x00\x01").unwrap(), FileFormat::Psd);
+        let svg = "\u{feff}<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>";
+        assert_eq!(detect("c.txt", svg.as_bytes()).unwrap(), FileFormat::Svg);
+        assert_eq!(
+            detect("d.drkr", b"\x89PNG\r\n").unwrap(),
+            FileFormat::Raster
+        );
+    }
+
+    #[test]
+    fn extensions_decide_when_the_contents_are_ambiguous() {
+        assert_eq!(
+            detect("e.drkr", b"PK\x03\x04rest").unwrap(),
+            FileFormat::Drkr
+        );
+        assert_eq!(
+            detect("f.zip", b"PK\x03\x04rest").unwrap(),
+            FileFormat::Raster
+        );
+        assert_eq!(detect("g.SVG", b"").unwrap(), FileFormat::Svg);
+    }
+
+    #[test]
+    fn missing_files_are_an_error() {
+        let path = std::env::temp_dir().join("drkr-format-missing-file.png");
+        assert!(matches!(detect_format(path), Err(AppError::IoError(_))));
+    }
+}
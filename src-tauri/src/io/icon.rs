@@ -0,0 +1,103 @@
+use crate::engine::resize_premultiplied;
+use crate::error::{AppError, AppResult};
+use image::codecs::ico::{IcoEncoder, IcoFrame};
+use image::{ColorType, RgbaImage};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// Largest icon side an ICO directory entry can describe
+const ICO_MAX_SIZE: u32 = 256;
+
+/// Write an ICO holding one square PNG image per requested size.
+///
+/// Each image is a Lanczos downscale (on premultiplied alpha) of `image`;
+/// non-square images are fitted and centered on a transparent square.
+pub fn write_ico<P: AsRef<Path>>(path: P, image: &RgbaImage, sizes: &[u32]) -> AppResult<()> {
+    let path = path.as_ref();
+
+    let mut sizes = sizes.to_vec();
+    sizes.sort_unstable();
+    sizes.dedup();
+    if sizes.is_empty() {
+        return Err(AppError::InvalidOperation(
+            "An icon needs at least one size".into(),
+        ));
+    }
+    if sizes.iter().any(|size| !(1..=ICO_MAX_SIZE).contains(size)) {
+        return Err(AppError::InvalidOperation(format!(
+            "Icon sizes must be between 1 and {}",
+            ICO_MAX_SIZE
+        )));
+    }
+
+    let frames = sizes
+        .iter()
+        .map(|&size| {
+            let icon = fit_square(image, size);
+            IcoFrame::as_png(icon.as_raw(), size, size, ColorType::Rgba8)
+                .map_err(|e| AppError::ImageError(e.to_string()))
+        })
+        .collect::<AppResult<Vec<_>>>()?;
+
+    let file = File::create(path)
+        .map_err(|e| AppError::IoError(format!("Failed to create '{}': {}", path.display(), e)))?;
+    IcoEncoder::new(BufWriter::new(file))
+        .encode_images(&frames)
+        .map_err(|e| AppError::ImageError(e.to_string()))
+}
+
+/// Scale `image` to fit a `size` x `size` square, centering it on transparency
+fn fit_square(image: &RgbaImage, size: u32) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let scale = size as f64 / width.max(height) as f64;
+    let scaled_width = ((width as f64 * scale).round() as u32).clamp(1, size);
+    let scaled_height = ((height as f64 * scale).round() as u32).clamp(1, size);
+
+    let scaled = resize_premultiplied(image, scaled_width, scaled_height);
+    if scaled_width == size && scaled_height == size {
+        return scaled;
+    }
+
+    let mut square = RgbaImage::new(size, size);
+    image::imageops::replace(
+        &mut square,
+        &scaled,
+        ((size - scaled_width) / 2) as i64,
+        ((size - scaled_height) / 2) as i64,
+    );
+    square
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn icons_hold_each_requested_size_once() {
+        let path = std::env::temp_dir().join(format!("drkr-icon-{}.ico", std::process::id()));
+        let image = RgbaImage::from_pixel(64, 64, Rgba([0, 128, 255, 255]));
+        write_ico(&path, &image, &[32, 16, 256, 16]).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // ICONDIR header, then one 16-byte entry per image; 0 means 256 wide
+        assert_eq!(u16::from_le_bytes([data[4], data[5]]), 3);
+        let widths: Vec<u8> = (0..3).map(|i| data[6 + i * 16]).collect();
+        assert_eq!(widths, [16, 32, 0]);
+
+        assert!(write_ico(&path, &image, &[]).is_err());
+        assert!(write_ico(&path, &image, &[512]).is_err());
+    }
+
+    #[test]
+    fn wide_images_are_centered_on_a_transparent_square() {
+        let banner = RgbaImage::from_pixel(8, 4, Rgba([255, 0, 0, 255]));
+        let icon = fit_square(&banner, 4);
+        assert_eq!(icon.dimensions(), (4, 4));
+        assert_eq!(icon.get_pixel(1, 0).0[3], 0);
+        assert_eq!(icon.get_pixel(1, 3).0[3], 0);
+        assert_eq!(icon.get_pixel(1, 1).0, [255, 0, 0, 255]);
+    }
+}
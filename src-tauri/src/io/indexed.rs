@@ -0,0 +1,261 @@
+use crate::error::{AppError, AppResult};
+use color_quant::NeuQuant;
+use image::RgbaImage;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// NeuQuant sampling factor: 1 is slowest and best, 30 fastest
+const NEUQUANT_SAMPLE_FACTOR: i32 = 10;
+
+/// A palette image: one palette index per pixel
+pub struct IndexedImage {
+    pub width: u32,
+    pub height: u32,
+    pub palette: Vec<[u8; 4]>, // RGBA
+    pub indices: Vec<u8>,
+}
+
+/// Reduce an image to at most `colors` (2-256) colors with NeuQuant,
+/// optionally spreading the quantization error with Floyd-Steinberg dithering
+pub fn quantize(image: &RgbaImage, colors: u16, dither: bool) -> AppResult<IndexedImage> {
+    if !(2..=256).contains(&colors) {
+        return Err(AppError::InvalidOperation(
+            "Palette size must be between 2 and 256 colors".into(),
+        ));
+    }
+
+    let (width, height) = image.dimensions();
+    let quantizer = NeuQuant::new(NEUQUANT_SAMPLE_FACTOR, colors as usize, image.as_raw());
+    let palette: Vec<[u8; 4]> = quantizer
+        .color_map_rgba()
+        .chunks_exact(4)
+        .map(|c| [c[0], c[1], c[2], c[3]])
+        .collect();
+
+    let mut indices = Vec::with_capacity((width * height) as usize);
+
+    if !dither {
+        for pixel in image.pixels() {
+            indices.push(quantizer.index_of(&pixel.0) as u8);
+        }
+    } else {
+        // Work on floats so diffused error can push channels past 0-255
+        let mut work: Vec<f32> = image.as_raw().iter().map(|&v| v as f32).collect();
+        let (w, h) = (width as usize, height as usize);
+
+        for y in 0..h {
+            for x in 0..w {
+                let idx = (y * w + x) * 4;
+                let pixel: [u8; 4] =
+                    std::array::from_fn(|c| work[idx + c].round().clamp(0.0, 255.0) as u8);
+                let index = quantizer.index_of(&pixel);
+                indices.push(index as u8);
+
+                let chosen = palette[index];
+                let error: [f32; 4] = std::array::from_fn(|c| work[idx + c] - chosen[c] as f32);
+
+                let mut spread = |dx: isize, dy: usize, weight: f32| {
+                    let nx = x as isize + dx;
+                    let ny = y + dy;
+                    if nx < 0 || nx >= w as isize || ny >= h {
+                        return;
+                    }
+                    let n = (ny * w + nx as usize) * 4;
+                    for c in 0..4 {
+                        work[n + c] += error[c] * weight;
+                    }
+                };
+
+                spread(1, 0, 7.0 / 16.0);
+                spread(-1, 1, 3.0 / 16.0);
+                spread(0, 1, 5.0 / 16.0);
+                spread(1, 1, 1.0 / 16.0);
+            }
+        }
+    }
+
+    Ok(IndexedImage {
+        width,
+        height,
+        palette,
+        indices,
+    })
+}
+
+/// Write an indexed image as PNG or GIF, chosen by the path's extension
+pub fn write_indexed<P: AsRef<Path>>(path: P, image: &IndexedImage) -> AppResult<()> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    let file = File::create(path)
+        .map_err(|e| AppError::IoError(format!("Failed to create '{}': {}", path.display(), e)))?;
+    let writer = BufWriter::new(file);
+
+    match extension.as_deref() {
+        Some("png") => write_png(writer, image),
+        Some("gif") => write_gif(writer, image),
+        _ => Err(AppError::InvalidOperation(
+            "Indexed export supports .png and .gif files".into(),
+        )),
+    }
+}
+
+fn write_png(writer: BufWriter<File>, image: &IndexedImage) -> AppResult<()> {
+    let rgb: Vec<u8> = image
+        .palette
+        .iter()
+        .flat_map(|c| [c[0], c[1], c[2]])
+        .collect();
+    let alpha: Vec<u8> = image.palette.iter().map(|c| c[3]).collect();
+
+    let mut encoder = png::Encoder::new(writer, image.width, image.height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(rgb);
+    // Only needed when some palette entry isn't opaque
+    if alpha.iter().any(|&a| a < 255) {
+        encoder.set_trns(alpha);
+    }
+
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&image.indices))
+        .map_err(|e| AppError::ImageError(e.to_string()))
+}
+
+fn write_gif(writer: BufWriter<File>, image: &IndexedImage) -> AppResult<()> {
+    let (width, height) = match (u16::try_from(image.width), u16::try_from(image.height)) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => {
+            return Err(AppError::InvalidOperation(
+                "GIF images can be at most 65535 pixels on a side".into(),
+            ))
+        }
+    };
+
+    // GIF has a single on/off transparent color: use the most transparent
+    // entry, and send every mostly-transparent pixel to it
+    let transparent = image
+        .palette
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, c)| c[3])
+        .filter(|(_, c)| c[3] < 128)
+        .map(|(i, _)| i as u8);
+
+    let indices: Cow<[u8]> = match transparent {
+        Some(transparent) => image
+            .indices
+            .iter()
+            .map(|&i| {
+                if image.palette[i as usize][3] < 128 {
+                    transparent
+                } else {
+                    i
+                }
+            })
+            .collect(),
+        None => Cow::Borrowed(&image.indices),
+    };
+
+    let rgb: Vec<u8> = image
+        .palette
+        .iter()
+        .flat_map(|c| [c[0], c[1], c[2]])
+        .collect();
+
+    let mut encoder = gif::Encoder::new(writer, width, height, &rgb)
+        .map_err(|e| AppError::ImageError(e.to_string()))?;
+
+    let frame = gif::Frame {
+        width,
+        height,
+        buffer: indices,
+        transparent,
+        ..Default::default()
+    };
+
+    encoder
+        .write_frame(&frame)
+        .map_err(|e| AppError::ImageError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn expand(indexed: &IndexedImage) -> Vec<[u8; 4]> {
+        indexed
+            .indices
+            .iter()
+            .map(|&i| indexed.palette[i as usize])
+            .collect()
+    }
+
+    #[test]
+    fn palette_sizes_outside_2_to_256_are_rejected() {
+        let image = RgbaImage::new(1, 1);
+        for colors in [0, 1, 257] {
+            assert!(matches!(
+                quantize(&image, colors, false),
+                Err(AppError::InvalidOperation(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn dithering_keeps_the_average_tone_of_a_gradient() {
+        let gradient = RgbaImage::from_fn(64, 16, |x, _| {
+            let v = (x * 255 / 63) as u8;
+            Rgba([v, v, v, 255])
+        });
+        // Mean absolute error of each column's average gray
+        let tone_error = |indexed: &IndexedImage| {
+            let pixels = expand(indexed);
+            (0..64)
+                .map(|x| {
+                    let sum: f32 = (0..16).map(|y| pixels[y * 64 + x][0] as f32).sum();
+                    (sum / 16.0 - (x * 255 / 63) as f32).abs()
+                })
+                .sum::<f32>()
+                / 64.0
+        };
+
+        let banded = quantize(&gradient, 2, false).unwrap();
+        let dithered = quantize(&gradient, 2, true).unwrap();
+        assert_eq!(dithered.indices.len(), 64 * 16);
+        assert!(
+            tone_error(&dithered) * 2.0 < tone_error(&banded),
+            "dithered {} vs banded {}",
+            tone_error(&dithered),
+            tone_error(&banded)
+        );
+    }
+
+    #[test]
+    fn indexed_pngs_decode_to_the_palette_colors() {
+        let image = RgbaImage::from_fn(4, 2, |x, _| match x {
+            0 => Rgba([255, 0, 0, 255]),
+            1 => Rgba([0, 0, 255, 255]),
+            _ => Rgba([0, 0, 0, 0]),
+        });
+        let indexed = quantize(&image, 4, false).unwrap();
+        let path = std::env::temp_dir().join(format!("drkr-indexed-{}.png", std::process::id()));
+
+        write_indexed(&path, &indexed).unwrap();
+        let decoded = image::open(&path).unwrap().to_rgba8();
+        std::fs::remove_file(&path).unwrap();
+
+        let expected: Vec<u8> = expand(&indexed).concat();
+        assert_eq!(decoded.into_raw(), expected);
+        let bmp = path.with_extension("bmp");
+        assert!(write_indexed(&bmp, &indexed).is_err());
+        std::fs::remove_file(&bmp).ok();
+    }
+}
@@ -1,3 +1,19 @@
+pub mod animation;
+pub mod autosave;
 pub mod drkr;
+pub mod exif;
+pub mod format;
+pub mod icon;
+pub mod indexed;
+pub mod sprite_sheet;
+pub mod svg;
 
-pub use drkr::{DrkrReader, DrkrWriter};
+pub use animation::{read_animation_frames, write_animated_webp};
+pub use autosave::{autosave_path, list_autosaves, prune_autosaves, AUTOSAVES_PER_DOCUMENT};
+pub use drkr::{encode_thumbnail_webp, DrkrReadResult, DrkrReader, DrkrWriter, PixelFormat};
+pub use exif::open_image_upright;
+pub use format::{detect_format, FileFormat};
+pub use icon::write_ico;
+pub use indexed::{quantize, write_indexed};
+pub use sprite_sheet::{write_sprite_sheet, SpriteAtlas, SpriteCell};
+pub use svg::rasterize_svg;
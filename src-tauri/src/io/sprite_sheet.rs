@@ -0,0 +1,179 @@
+use crate::engine::checked_rgba_buffer_len;
+use crate::error::{AppError, AppResult};
+use image::RgbaImage;
+use serde::Serialize;
+use std::path::Path;
+
+/// One sprite to place on a sheet: the layer it came from and its pixels
+pub struct SpriteCell {
+    pub layer_id: String,
+    pub name: String,
+    pub image: RgbaImage,
+}
+
+/// JSON atlas written next to a sprite sheet, describing where each layer's
+/// cell sits in the image
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpriteAtlas {
+    /// File name of the sheet image, relative to the atlas
+    pub image: String,
+    pub width: u32,
+    pub height: u32,
+    pub columns: u32,
+    pub rows: u32,
+    pub frames: Vec<SpriteAtlasFrame>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpriteAtlasFrame {
+    pub layer_id: String,
+    pub name: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Lay equally sized cells out left to right, top to bottom in a grid of
+/// `columns` (default: as close to square as possible) and write it as a PNG
+/// at `path`, with the atlas beside it as `<name>.json`
+pub fn write_sprite_sheet<P: AsRef<Path>>(
+    path: P,
+    cells: &[SpriteCell],
+    columns: Option<u32>,
+) -> AppResult<SpriteAtlas> {
+    let path = path.as_ref();
+
+    let is_png = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("png"));
+    if !is_png {
+        return Err(AppError::InvalidOperation(
+            "Sprite sheets are exported as .png files".into(),
+        ));
+    }
+
+    let Some(first) = cells.first() else {
+        return Err(AppError::InvalidOperation(
+            "A sprite sheet needs at least one layer".into(),
+        ));
+    };
+    let (cell_width, cell_height) = first.image.dimensions();
+    if cells
+        .iter()
+        .any(|c| c.image.dimensions() != (cell_width, cell_height))
+    {
+        return Err(AppError::InvalidOperation(
+            "Sprite sheet cells must all be the same size".into(),
+        ));
+    }
+
+    let count = cells.len() as u32;
+    let columns = columns
+        .unwrap_or_else(|| (count as f64).sqrt().ceil() as u32)
+        .clamp(1, count);
+    let rows = count.div_ceil(columns);
+
+    let too_large = || AppError::InvalidOperation("Sprite sheet would be too large".into());
+    let width = cell_width.checked_mul(columns).ok_or_else(too_large)?;
+    let height = cell_height.checked_mul(rows).ok_or_else(too_large)?;
+    checked_rgba_buffer_len(width, height)?;
+
+    let mut sheet = RgbaImage::new(width, height);
+    let mut frames = Vec::with_capacity(cells.len());
+    for (i, cell) in cells.iter().enumerate() {
+        let x = (i as u32 % columns) * cell_width;
+        let y = (i as u32 / columns) * cell_height;
+        image::imageops::replace(&mut sheet, &cell.image, x as i64, y as i64);
+        frames.push(SpriteAtlasFrame {
+            layer_id: cell.layer_id.clone(),
+            name: cell.name.clone(),
+            x,
+            y,
+            width: cell_width,
+            height: cell_height,
+        });
+    }
+
+    sheet
+        .save(path)
+        .map_err(|e| AppError::ImageError(e.to_string()))?;
+
+    let atlas = SpriteAtlas {
+        image: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        width,
+        height,
+        columns,
+        rows,
+        frames,
+    };
+
+    let json = serde_json::to_string_pretty(&atlas)
+        .map_err(|e| AppError::SerializationError(e.to_string()))?;
+    let atlas_path = path.with_extension("json");
+    std::fs::write(&atlas_path, json).map_err(|e| {
+        AppError::IoError(format!("Failed to write '{}': {}", atlas_path.display(), e))
+    })?;
+
+    Ok(atlas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn cell(name: &str, color: [u8; 4]) -> SpriteCell {
+        SpriteCell {
+            layer_id: format!("{}-id", name),
+            name: name.into(),
+            image: RgbaImage::from_pixel(3, 2, Rgba(color)),
+        }
+    }
+
+    #[test]
+    fn cells_are_laid_out_in_a_near_square_grid_with_an_atlas() {
+        let path = std::env::temp_dir().join(format!("drkr-sprites-{}.png", std::process::id()));
+        let cells = [
+            cell("a", [255, 0, 0, 255]),
+            cell("b", [0, 255, 0, 255]),
+            cell("c", [0, 0, 255, 255]),
+        ];
+
+        let atlas = write_sprite_sheet(&path, &cells, None).unwrap();
+        let sheet = image::open(&path).unwrap().to_rgba8();
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(path.with_extension("json")).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("json")).unwrap();
+
+        assert_eq!((atlas.columns, atlas.rows), (2, 2));
+        assert_eq!(sheet.dimensions(), (6, 4));
+        let third = &atlas.frames[2];
+        assert_eq!((third.x, third.y, third.layer_id.as_str()), (0, 2, "c-id"));
+        assert_eq!(sheet.get_pixel(3, 0).0, [0, 255, 0, 255]);
+        assert_eq!(sheet.get_pixel(2, 3).0, [0, 0, 255, 255]);
+        assert_eq!(sheet.get_pixel(5, 3).0, [0, 0, 0, 0]);
+        assert_eq!(json["frames"][1]["x"], 3);
+        assert_eq!(json["image"], path.file_name().unwrap().to_str().unwrap());
+    }
+
+    #[test]
+    fn mismatched_cells_and_other_formats_are_rejected() {
+        let dir = std::env::temp_dir();
+        let mut odd = cell("b", [0; 4]);
+        odd.image = RgbaImage::new(2, 2);
+        let cells = [cell("a", [0; 4]), odd];
+        let png = dir.join(format!("drkr-sprites-bad-{}.png", std::process::id()));
+        assert!(write_sprite_sheet(&png, &cells, None).is_err());
+        assert!(write_sprite_sheet(&png, &[], None).is_err());
+        assert!(write_sprite_sheet(dir.join("sheet.webp"), &cells[..1], None).is_err());
+        assert!(!png.exists());
+    }
+}
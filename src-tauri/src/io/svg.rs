@@ -0,0 +1,113 @@
+use crate::error::{AppError, AppResult};
+use image::RgbaImage;
+use resvg::{tiny_skia, usvg};
+use std::path::Path;
+
+/// CSS reference resolution that SVG user units are defined against
+const SVG_REFERENCE_DPI: f32 = 96.0;
+
+/// Rasterize an SVG file into an RGBA image.
+///
+/// The intrinsic size comes from the SVG's `width`/`height` (falling back to
+/// its viewBox) and is scaled by `dpi / 96` so physical units render at the
+/// requested resolution. When a target width and/or height is given it
+/// overrides that size; if only one is given the other follows the viewBox
+/// aspect ratio.
+pub fn rasterize_svg<P: AsRef<Path>>(
+    path: P,
+    target_width: Option<u32>,
+    target_height: Option<u32>,
+    dpi: u32,
+) -> AppResult<RgbaImage> {
+    let data = std::fs::read(path).map_err(|e| AppError::IoError(e.to_string()))?;
+
+    let options = usvg::Options {
+        dpi: dpi as f32,
+        ..usvg::Options::default()
+    };
+    let tree = usvg::Tree::from_data(&data, &options)
+        .map_err(|e| AppError::ImageError(format!("Failed to parse SVG: {}", e)))?;
+
+    let size = tree.size();
+    let dpi_scale = dpi as f32 / SVG_REFERENCE_DPI;
+    let aspect = size.width() / size.height();
+
+    let (width, height) = match (target_width, target_height) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => (w, (w as f32 / aspect).round() as u32),
+        (None, Some(h)) => ((h as f32 * aspect).round() as u32, h),
+        (None, None) => (
+            (size.width() * dpi_scale).round() as u32,
+            (size.height() * dpi_scale).round() as u32,
+        ),
+    };
+
+    if width == 0 || height == 0 {
+        return Err(AppError::InvalidOperation(
+            "SVG rasterizes to an empty image".into(),
+        ));
+    }
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| AppError::InvalidOperation("Invalid SVG raster size".into()))?;
+
+    let transform = tiny_skia::Transform::from_scale(
+        width as f32 / size.width(),
+        height as f32 / size.height(),
+    );
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia renders premultiplied alpha; layers store straight RGBA
+    let mut rgba = Vec::with_capacity(pixmap.data().len());
+    for pixel in pixmap.pixels() {
+        let color = pixel.demultiply();
+        rgba.extend_from_slice(&[color.red(), color.green(), color.blue(), color.alpha()]);
+    }
+
+    RgbaImage::from_raw(width, height, rgba)
+        .ok_or_else(|| AppError::ImageError("Failed to create image from SVG raster".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rasterize(svg: &str, width: Option<u32>, height: Option<u32>, dpi: u32) -> RgbaImage {
+        let path = std::env::temp_dir().join(format!(
+            "drkr-svg-{}-{}.svg",
+            std::process::id(),
+            uuid::Uuid::new_v4()
+        ));
+        std::fs::write(&path, svg).unwrap();
+        let image = rasterize_svg(&path, width, height, dpi);
+        std::fs::remove_file(&path).unwrap();
+        image.unwrap()
+    }
+
+    const RED_BOX: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="5" viewBox="0 0 10 5"><rect width="10" height="5" fill="#ff0000"/></svg>"##;
+
+    #[test]
+    fn the_intrinsic_size_scales_with_dpi() {
+        let image = rasterize(RED_BOX, None, None, 96);
+        assert_eq!(image.dimensions(), (10, 5));
+        assert_eq!(image.get_pixel(5, 2).0, [255, 0, 0, 255]);
+
+        assert_eq!(rasterize(RED_BOX, None, None, 192).dimensions(), (20, 10));
+    }
+
+    #[test]
+    fn one_target_side_keeps_the_viewbox_aspect_ratio() {
+        assert_eq!(
+            rasterize(RED_BOX, Some(40), None, 96).dimensions(),
+            (40, 20)
+        );
+        assert_eq!(
+            rasterize(RED_BOX, None, Some(10), 96).dimensions(),
+            (20, 10)
+        );
+        assert_eq!(
+            rasterize(RED_BOX, Some(7), Some(7), 96).dimensions(),
+            (7, 7)
+        );
+    }
+}
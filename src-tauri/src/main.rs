@@ -6,41 +6,124 @@ mod engine;
 mod error;
 mod io;
 
-use commands::{brush, crop, document, filters, layer};
+use commands::{
+    brush, capabilities, crop, document, filters, history, layer, selection, transform,
+};
 use engine::DocumentManager;
-use std::sync::Mutex;
+use std::sync::RwLock;
 
 fn main() {
     env_logger::init();
 
     tauri::Builder::default()
-        .manage(Mutex::new(DocumentManager::new()))
+        .manage(RwLock::new(DocumentManager::new()))
         .invoke_handler(tauri::generate_handler![
             // Document commands
             document::create_document,
+            document::list_document_templates,
+            document::create_document_from_template,
             document::open_document,
+            document::open_document_svg,
+            document::create_document_from_pixels,
             document::save_document,
             document::close_document,
             document::get_document,
             document::save_document_drkr,
+            document::save_document_as,
+            document::export_indexed,
+            document::export_animated_webp,
+            document::export_ico,
+            document::export_sprite_sheet,
             document::open_document_drkr,
+            document::open_document_drkr_bytes,
+            document::recover_drkr,
+            document::autosave_document,
+            document::list_recoverable,
+            document::recover_autosave,
+            document::discard_autosave,
+            document::detect_file_format,
+            document::open_any,
+            document::save_document_drkr_bytes,
             document::list_documents,
             document::set_document_path,
             document::rename_document,
+            document::set_linear_blending,
+            document::resample_to_dpi,
+            document::export_document_state,
+            document::apply_document_state,
+            document::get_physical_size,
+            document::get_merged_pixels_base64,
+            document::composite_layers_subset,
+            document::diff_documents,
+            document::set_tile_cache_budget,
+            document::suspend_document,
+            document::resume_document,
+            document::get_tile_cache_stats,
             // Layer commands
             layer::add_layer,
+            layer::new_layer_from_visible,
+            layer::extract_channel,
+            layer::combine_channels,
+            layer::add_text_layer,
+            layer::link_image_layer,
+            layer::set_text_layer,
+            layer::add_shape_layer,
+            layer::set_shape_layer,
+            layer::rasterize_layer,
+            layer::fill_layer,
+            layer::clear_layer,
+            layer::place_image_on_layer,
             layer::remove_layer,
+            layer::prune_empty_layers,
+            layer::flatten_document,
             layer::update_layer,
+            layer::move_layer,
+            layer::set_isolated_layer,
             layer::reorder_layers,
+            layer::move_layer_relative,
+            layer::get_layer_index,
+            layer::get_layers_ordered,
             layer::get_layer_pixels,
+            layer::get_layer_content_bounds,
+            layer::sample_color,
+            layer::doc_to_layer_coords,
+            layer::layer_to_doc_coords,
             layer::get_layer_pixels_base64,
+            layer::get_layer_thumbnails,
             layer::set_layer_pixels_base64,
             // Brush commands
             brush::apply_brush_stroke,
+            brush::apply_warp_stroke,
+            brush::register_brush_tip,
+            brush::load_brush_preset,
             // Filter commands
             filters::apply_filter,
+            filters::preview_filter,
+            filters::apply_filter_stack,
+            filters::apply_filter_to_layers,
+            filters::get_layer_histogram,
+            // Selection commands
+            selection::selection_from_layer_alpha,
+            selection::modify_selection,
+            selection::transform_selection,
+            selection::clear_selection,
             // Crop commands
             crop::crop_document,
+            crop::set_max_canvas_area,
+            // Transform commands
+            transform::perspective_transform_layer,
+            // History commands
+            history::get_history,
+            history::undo,
+            history::redo,
+            history::set_history_limit,
+            history::begin_history_group,
+            history::end_history_group,
+            history::create_snapshot,
+            history::list_snapshots,
+            history::restore_snapshot,
+            // Introspection commands
+            capabilities::get_capabilities,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");